@@ -36,6 +36,7 @@ export const sidebarItems: ISidebarSection[] = [
   {
     text: "Language Support",
     links: [
+      { href: "/docs/providers/bun", text: "Bun" },
       { href: "/docs/providers/clojure", text: "Clojure" },
       { href: "/docs/providers/cobol", text: "Cobol" },
       { href: "/docs/providers/crystal", text: "Crystal" },
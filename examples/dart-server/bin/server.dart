@@ -0,0 +1,13 @@
+import 'dart:io';
+
+Future<void> main() async {
+  final port = int.parse(Platform.environment['PORT'] ?? '8080');
+  final server = await HttpServer.bind(InternetAddress.anyIPv4, port);
+  print('Hello from Dart server, listening on port ${server.port}');
+
+  await for (final request in server) {
+    request.response
+      ..write('Hello from Dart server')
+      ..close();
+  }
+}
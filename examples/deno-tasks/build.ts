@@ -0,0 +1,2 @@
+await Deno.mkdir("dist", { recursive: true });
+await Deno.writeTextFile("dist/index.html", "<h1>Hello from Deno tasks</h1>");
@@ -0,0 +1,7 @@
+import { Hono } from "hono";
+
+const app = new Hono();
+
+app.get("/", async (c) => c.html(await Deno.readTextFile("dist/index.html")));
+
+Deno.serve({ port: Number(Deno.env.get("PORT") ?? 8000) }, app.fetch);
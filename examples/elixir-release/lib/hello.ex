@@ -0,0 +1,9 @@
+defmodule Hello.Application do
+  use Application
+
+  @impl true
+  def start(_type, _args) do
+    IO.puts("Hello from Elixir release")
+    Supervisor.start_link([], strategy: :one_for_one, name: Hello.Supervisor)
+  end
+end
@@ -0,0 +1,9 @@
+package hello;
+
+import greeting.Greeting;
+
+public class HelloWorld {
+  public static void main(String[] args) {
+    System.out.println(Greeting.greet("a Gradle multi-module project"));
+  }
+}
@@ -0,0 +1,7 @@
+package greeting;
+
+public class Greeting {
+  public static String greet(String name) {
+    return "Hello from " + name;
+  }
+}
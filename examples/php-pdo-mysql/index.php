@@ -0,0 +1,5 @@
+<?php
+
+$drivers = PDO::getAvailableDrivers();
+
+echo in_array('mysql', $drivers) ? 'pdo_mysql is enabled' : 'pdo_mysql is missing';
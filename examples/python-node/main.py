@@ -0,0 +1,12 @@
+from flask import Flask
+
+app = Flask(__name__, static_folder="dist")
+
+
+@app.route("/")
+def hello():
+    return "Hello from Python"
+
+
+if __name__ == "__main__":
+    app.run(host="0.0.0.0", port=8080)
@@ -0,0 +1,3 @@
+import six
+
+print("Hello from Python-uv")
@@ -0,0 +1 @@
+//= link_directory ../stylesheets .css
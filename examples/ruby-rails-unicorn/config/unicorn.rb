@@ -0,0 +1,3 @@
+worker_processes Integer(ENV.fetch("WEB_CONCURRENCY", 2))
+timeout 30
+preload_app true
@@ -0,0 +1 @@
+pub const MESSAGE: &str = "Hello";
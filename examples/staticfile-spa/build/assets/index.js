@@ -0,0 +1,8 @@
+const app = document.getElementById("app");
+
+const render = () => {
+  app.textContent = `Hello from ${window.location.pathname}`;
+};
+
+window.addEventListener("popstate", render);
+render();
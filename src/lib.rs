@@ -29,18 +29,18 @@ use crate::nixpacks::{
     nix::pkg::Pkg,
     plan::{
         generator::{GeneratePlanOptions, NixpacksBuildPlanGenerator},
-        BuildPlan, PlanGenerator,
+        schema, BuildPlan, PlanGenerator,
     },
 };
 use anyhow::{bail, Result};
 use providers::{
-    clojure::ClojureProvider, cobol::CobolProvider, crystal::CrystalProvider,
+    bun::BunProvider, clojure::ClojureProvider, cobol::CobolProvider, crystal::CrystalProvider,
     csharp::CSharpProvider, dart::DartProvider, deno::DenoProvider, elixir::ElixirProvider,
     fsharp::FSharpProvider, gleam::GleamProvider, go::GolangProvider,
     haskell::HaskellStackProvider, java::JavaProvider, lunatic::LunaticProvider,
     node::NodeProvider, php::PhpProvider, python::PythonProvider, ruby::RubyProvider,
     rust::RustProvider, scala::ScalaProvider, staticfile::StaticfileProvider, swift::SwiftProvider,
-    zig::ZigProvider, Provider,
+    zig::ZigProvider, AppMetadata, Detection, Provider, ProviderInfo,
 };
 
 mod chain;
@@ -66,6 +66,7 @@ pub fn get_providers() -> &'static [&'static (dyn Provider)] {
         &ScalaProvider {},
         &PhpProvider {},
         &RubyProvider {},
+        &BunProvider {},
         &NodeProvider {},
         &PythonProvider {},
         &RustProvider {},
@@ -76,6 +77,14 @@ pub fn get_providers() -> &'static [&'static (dyn Provider)] {
     ]
 }
 
+/// Describes each of the built-in providers, in the order they are detected in.
+pub fn get_provider_info() -> Vec<ProviderInfo> {
+    get_providers()
+        .iter()
+        .map(|provider| ProviderInfo::from(*provider))
+        .collect()
+}
+
 /// Produces a build plan for the project based on environment variables and CLI options.
 pub fn generate_build_plan(
     path: &str,
@@ -83,10 +92,19 @@ pub fn generate_build_plan(
     options: &GeneratePlanOptions,
 ) -> Result<BuildPlan> {
     let app = App::new(path)?;
+    generate_build_plan_for_app(&app, envs, options)
+}
+
+/// Produces a build plan for an app that is already loaded, such as one created with [`App::from_files`].
+pub fn generate_build_plan_for_app(
+    app: &App,
+    envs: Vec<&str>,
+    options: &GeneratePlanOptions,
+) -> Result<BuildPlan> {
     let environment = Environment::from_envs(envs)?;
 
     let mut generator = NixpacksBuildPlanGenerator::new(get_providers(), options.clone());
-    let plan = generator.generate_plan(&app, &environment)?;
+    let plan = generator.generate_plan(app, &environment)?;
 
     Ok(plan.0)
 }
@@ -105,6 +123,53 @@ pub fn get_plan_providers(
     generator.get_plan_providers(&app, &environment)
 }
 
+/// Describe a project with the languages, frameworks and package managers detected by its providers.
+pub fn get_app_metadata(
+    path: &str,
+    envs: Vec<&str>,
+    options: &GeneratePlanOptions,
+) -> Result<AppMetadata> {
+    let app = App::new(path)?;
+    let environment = Environment::from_envs(envs)?;
+
+    let generator = NixpacksBuildPlanGenerator::new(get_providers(), options.clone());
+
+    generator.get_app_metadata(&app, &environment)
+}
+
+/// Run the detection of every provider on a project, in the order they are detected in, to explain which one matched.
+pub fn explain_providers(path: &str, envs: Vec<&str>) -> Result<Vec<(String, Detection)>> {
+    let app = App::new(path)?;
+    let environment = Environment::from_envs(envs)?;
+
+    get_providers()
+        .iter()
+        .map(|provider| {
+            Ok((
+                provider.name().to_string(),
+                provider.detect(&app, &environment)?,
+            ))
+        })
+        .collect()
+}
+
+/// Describe the providers found for a project as JSON, for scripts that run `nixpacks detect --json`.
+///
+/// The providers are always a list, which is empty if no provider matched.
+pub fn get_plan_providers_json(providers: &[String]) -> serde_json::Value {
+    serde_json::json!({ "providers": providers })
+}
+
+/// Describes build plans and `nixpacks.{toml,json}` config files with a JSON Schema.
+pub fn get_plan_schema() -> serde_json::Value {
+    let provider_names = get_providers()
+        .iter()
+        .map(|provider| provider.name())
+        .collect::<Vec<_>>();
+
+    schema::build_plan_schema(&provider_names)
+}
+
 /// Builds a Docker image based on environment data and build options from config files or existing build plans.
 pub async fn create_docker_image(
     path: &str,
@@ -125,6 +190,14 @@ pub async fn create_docker_image(
         }
     }
 
+    // Providers can key the incremental cache with files other than the common lockfiles
+    let mut build_options = build_options.clone();
+    if build_options.incremental_cache_checksum {
+        build_options
+            .incremental_cache_inputs
+            .extend(generator.get_cache_inputs(&app, &environment)?);
+    }
+
     let logger = Logger::new();
     let builder = DockerImageBuilder::new(logger, build_options.clone());
 
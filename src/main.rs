@@ -1,9 +1,19 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::{arg, Parser, Subcommand, ValueEnum};
 use nixpacks::{
-    create_docker_image, generate_build_plan, get_plan_providers,
+    create_docker_image, explain_providers, generate_build_plan, get_plan_providers,
+    get_plan_providers_json, get_plan_schema,
     nixpacks::{
-        builder::docker::DockerBuilderOptions,
+        builder::docker::{
+            file_server::UploadOptions,
+            incremental_cache::{
+                parse_max_age, parse_max_size, CacheCompression, IncrementalCacheDirs,
+            },
+            registry_auth::RegistryAuth,
+            utils::{parse_build_arg, parse_label},
+            DockerBuilderOptions,
+        },
+        git_source::GitSource,
         nix::pkg::Pkg,
         plan::{
             generator::GeneratePlanOptions,
@@ -18,6 +28,7 @@ use std::{
     hash::{Hash, Hasher},
     ops::Deref,
     string::ToString,
+    time::Duration,
 };
 
 /// The build plan config file format to use.
@@ -25,6 +36,8 @@ use std::{
 enum PlanFormat {
     Json,
     Toml,
+    /// A hash of the plan, which only changes when the plan does
+    Digest,
 }
 
 /// Arguments passed to `nixpacks`.
@@ -51,6 +64,10 @@ struct Args {
     #[arg(long, short, global = true)]
     start_cmd: Option<String>,
 
+    /// Image to run the app in, instead of the image it was built in
+    #[arg(long, global = true)]
+    start_image: Option<String>,
+
     /// Provide additional nix packages to install in the environment
     #[arg(long, short, global = true)]
     pkgs: Vec<String>,
@@ -59,41 +76,117 @@ struct Args {
     #[arg(long, short, global = true)]
     apt: Vec<String>,
 
+    /// Install apt packages from this mirror of the Ubuntu or Debian archive
+    #[arg(long, global = true)]
+    apt_mirror: Option<String>,
+
     /// Provide additional nix libraries to install in the environment
     #[arg(long, global = true)]
     libs: Vec<String>,
 
+    /// Provide a nix overlay (tarball URL or path to a .nix file) applied to all nix packages
+    #[arg(long, global = true)]
+    nix_overlay: Vec<String>,
+
     /// Provide environment variables to your build
     #[arg(long, short, global = true)]
     env: Vec<String>,
 
-    /// Path to config file
+    /// Name of a variable to pass to the build as a BuildKit secret instead of a build arg
+    #[arg(long, global = true)]
+    secret: Vec<String>,
+
+    /// Path to config file. Can be passed multiple times to merge files in order
     #[arg(long, short, global = true)]
-    config: Option<String>,
+    config: Vec<String>,
+
+    /// Build with this provider instead of the detected ones. Can be passed multiple times
+    #[arg(long, global = true)]
+    provider: Vec<String>,
+
+    /// Never detect or use this provider. Can be passed multiple times
+    #[arg(long, global = true)]
+    exclude_provider: Vec<String>,
+
+    /// Fail if no provider is detected for the app, instead of producing a plan without one
+    #[arg(long, global = true)]
+    require_provider: bool,
+}
+
+/// The subcommands of `nixpacks cache`.
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Remove the least recently used incremental cache tarballs
+    Prune {
+        /// Output directory the app was built with, see `nixpacks build --out`
+        out: String,
+
+        /// Remove tarballs not written for longer than this, e.g. `12h` or `7d`
+        #[arg(long, value_parser = parse_max_age)]
+        max_age: Option<Duration>,
+
+        /// Remove the least recently used tarballs until the rest fit in this size, e.g. `500MB` or `10GB`
+        #[arg(long, value_parser = parse_max_size)]
+        max_size: Option<u64>,
+    },
 }
 
 /// The valid subcommands passed to `nixpacks`, and their arguments.
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Generate a build plan for an app
     Plan {
-        /// App source
+        /// App source directory, or a git URL as `<url>#<ref>:<subdir>`
         path: String,
 
         /// Specify the output format of the build plan.
         #[arg(short, long, value_enum, default_value = "json")]
         format: PlanFormat,
+
+        /// Print how the plan changes when it is generated with this config file instead
+        #[arg(long, value_name = "CONFIG")]
+        diff: Option<String>,
     },
 
+    /// Check the build plan for an app for problems before building it
+    Validate {
+        /// App source directory, or a git URL as `<url>#<ref>:<subdir>`
+        path: String,
+    },
+
+    /// Print the JSON Schema of build plans and config files
+    Schema,
+
     /// List all of the providers that will be used to build the app
     Detect {
-        /// App source
+        /// App source directory, or a git URL as `<url>#<ref>:<subdir>`
+        path: String,
+
+        /// Print the providers as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Exit with a non-zero status if no provider matched
+        #[arg(long)]
+        fail_if_none: bool,
+    },
+
+    /// Explain which detection checks of each provider passed for the app
+    Explain {
+        /// App source directory, or a git URL as `<url>#<ref>:<subdir>`
         path: String,
     },
 
+    /// Manage the incremental cache saved in an output directory
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
     /// Build an app
     Build {
-        /// App source
+        /// App source directory, or a git URL as `<url>#<ref>:<subdir>`
         path: String,
 
         /// Name for the built image
@@ -104,18 +197,34 @@ enum Commands {
         #[arg(short, long)]
         out: Option<String>,
 
+        /// File name of the generated Dockerfile in the output directory
+        #[arg(long)]
+        dockerfile_name: Option<String>,
+
         /// Print the generated Dockerfile to stdout
         #[arg(short, long, hide = true)]
         dockerfile: bool,
 
+        /// Write the Dockerfile and supporting files and print the Dockerfile without running Docker
+        #[arg(long)]
+        dry_run: bool,
+
         /// Additional tags to add to the output image
         #[arg(short, long)]
         tag: Vec<String>,
 
-        /// Additional labels to add to the output image
-        #[arg(short, long)]
+        /// Additional labels to add to the output image, as key=value
+        #[arg(short, long, value_parser = parse_label)]
         label: Vec<String>,
 
+        /// Build argument to pass to the build phases, as KEY=VALUE. Unlike --env, it is not set in the image
+        #[arg(long, value_parser = parse_build_arg)]
+        build_arg: Vec<(String, String)>,
+
+        /// Do not label the image with the git commit, branch, and repository of the app
+        #[arg(long)]
+        no_git_labels: bool,
+
         /// Set target platform for your output image
         #[arg(long)]
         platform: Vec<String>,
@@ -128,6 +237,10 @@ enum Commands {
         #[arg(long)]
         current_dir: bool,
 
+        /// Shell command to run on the host in the app directory before building the image
+        #[arg(long)]
+        prebuild: Option<String>,
+
         /// Disable building with the cache
         #[arg(long)]
         no_cache: bool,
@@ -136,6 +249,82 @@ enum Commands {
         #[arg(long)]
         incremental_cache_image: Option<String>,
 
+        /// Build without the incremental cache, even if an incremental cache image is given
+        #[arg(long)]
+        no_incremental_cache: bool,
+
+        /// Do not push the incremental cache image after creating it
+        #[arg(long)]
+        no_incremental_cache_push: bool,
+
+        /// Compression to use for the incremental cache tarballs
+        #[arg(long, value_enum, default_value = "none")]
+        incremental_cache_compression: CacheCompression,
+
+        /// Number of times to retry incremental cache uploads. 0 disables retries
+        #[arg(long, default_value_t = 3)]
+        incremental_cache_upload_retries: u32,
+
+        /// Seconds to wait between incremental cache upload retries
+        #[arg(long)]
+        incremental_cache_upload_retry_delay: Option<u32>,
+
+        /// Maximum number of seconds to spend retrying incremental cache uploads
+        #[arg(long)]
+        incremental_cache_upload_retry_max_time: Option<u32>,
+
+        /// Proxy URL that incremental cache uploads go through, instead of the proxy variables of the build
+        #[arg(long)]
+        incremental_cache_upload_proxy: Option<String>,
+
+        /// Authenticate incremental cache uploads with a plain, non-expiring token
+        #[arg(long)]
+        incremental_cache_plain_token: bool,
+
+        /// Key the incremental cache image on a hash of the app's lockfiles
+        #[arg(long)]
+        incremental_cache_checksum: bool,
+
+        /// Also store the Nix store in the incremental cache image, so that Nix packages are not fetched again
+        #[arg(long)]
+        incremental_cache_nix_store: bool,
+
+        /// Remove any local incremental cache files left over from previous builds
+        #[arg(long)]
+        incremental_cache_reset: bool,
+
+        /// Tar, upload, and remove each incremental cache directory in one command behind a single directory check
+        #[arg(long)]
+        incremental_cache_collapse_commands: bool,
+
+        /// JSON file with the username and password of each registry to log in to before building
+        #[arg(long)]
+        registry_auth: Option<String>,
+
+        /// Write the image to a tarball instead of loading it into the Docker daemon
+        #[arg(long)]
+        output_tar: Option<String>,
+
+        /// Stop the build if it takes longer than this many seconds
+        #[arg(long)]
+        build_timeout: Option<u64>,
+
+        /// Path or name of the docker binary to build with [env: DOCKER_BIN]
+        #[arg(long)]
+        docker_bin: Option<String>,
+
+        /// Install Nix packages from this nixpkgs tarball or directory instead of fetching nixpkgs [env: NIXPACKS_NIXPKGS_PATH]
+        #[arg(long)]
+        nixpkgs_path: Option<String>,
+
+        /// Home directory that `~` in cache directories expands to, for images that do not build as root
+        #[arg(long)]
+        home_dir: Option<String>,
+
+        /// Run each command of a phase in its own layer instead of joining them, which is useful for debugging
+        #[arg(long)]
+        split_phase_commands: bool,
+
         /// Image to consider as cache sources
         #[arg(long)]
         cache_from: Option<String>,
@@ -173,6 +362,22 @@ async fn main() -> Result<()> {
         setup.nix_libs = Some(vec![args.libs, vec!["...".to_string()]].concat());
         cli_plan.add_phase(setup);
     }
+    if args.apt_mirror.is_some() {
+        cli_plan.apt_mirror = args.apt_mirror;
+    }
+    if !args.nix_overlay.is_empty() {
+        cli_plan.nix_overlays = Some(vec![args.nix_overlay, vec!["...".to_string()]].concat());
+    }
+    if !args.provider.is_empty() {
+        cli_plan.providers = Some(args.provider);
+    }
+    if !args.exclude_provider.is_empty() {
+        cli_plan.exclude_providers =
+            Some([args.exclude_provider, vec!["...".to_string()]].concat());
+    }
+    if !args.secret.is_empty() {
+        cli_plan.secrets = Some(vec![args.secret, vec!["...".to_string()]].concat());
+    }
     if let Some(install_cmds) = args.install_cmd {
         let mut install = Phase::install(None);
         install.cmds = Some(vec![install_cmds]);
@@ -183,8 +388,12 @@ async fn main() -> Result<()> {
         build.cmds = Some(vec![build_cmds]);
         cli_plan.add_phase(build);
     }
-    if let Some(start_cmd) = args.start_cmd {
-        let start = StartPhase::new(start_cmd);
+    if args.start_cmd.is_some() || args.start_image.is_some() {
+        let start = StartPhase {
+            cmd: args.start_cmd,
+            run_image: args.start_image,
+            ..Default::default()
+        };
         cli_plan.set_start_phase(start);
     }
 
@@ -200,39 +409,177 @@ async fn main() -> Result<()> {
     let env: Vec<&str> = args.env.iter().map(|e| e.deref()).collect();
     let options = GeneratePlanOptions {
         plan: Some(cli_plan),
-        config_file: args.config,
+        config_files: args.config,
+        require_provider: args.require_provider,
+    };
+
+    // Git URLs are cloned into a temporary directory, which is removed when the checkout is dropped
+    let git_checkout = match &args.command {
+        Commands::Plan { path, .. }
+        | Commands::Validate { path }
+        | Commands::Detect { path, .. }
+        | Commands::Explain { path }
+        | Commands::Build { path, .. } => GitSource::parse(path),
+        Commands::Schema | Commands::Cache { .. } => None,
+    }
+    .map(|source| source.checkout())
+    .transpose()?;
+    let source_path = |path: &str| {
+        git_checkout.as_ref().map_or_else(
+            || path.to_string(),
+            |checkout| checkout.path().to_string_lossy().to_string(),
+        )
     };
 
     match args.command {
         // Produce a build plan for a project and print it to stdout.
-        Commands::Plan { path, format } => {
-            let plan = generate_build_plan(&path, env, &options)?;
+        Commands::Plan {
+            path,
+            diff: Some(config_file),
+            ..
+        } => {
+            let plan = generate_build_plan(&source_path(&path), env.clone(), &options)?;
+            let other_plan = generate_build_plan(
+                &source_path(&path),
+                env,
+                &GeneratePlanOptions {
+                    config_files: vec![config_file],
+                    ..options
+                },
+            )?;
+
+            let changes = plan.diff(&other_plan)?;
+            if changes.is_empty() {
+                println!("The plans are the same");
+            }
+            for change in changes {
+                println!("{change}");
+            }
+        }
+        Commands::Plan {
+            path,
+            format,
+            diff: None,
+        } => {
+            let plan = generate_build_plan(&source_path(&path), env, &options)?;
 
             let plan_s = match format {
                 PlanFormat::Json => plan.to_json()?,
                 PlanFormat::Toml => plan.to_toml()?,
+                PlanFormat::Digest => plan.digest()?,
             };
 
             println!("{plan_s}");
         }
+        // Check a project's build plan and print any problems found.
+        Commands::Validate { path } => {
+            let plan = generate_build_plan(&source_path(&path), env, &options)?;
+            let problems = plan.validate();
+            if !problems.is_empty() {
+                for problem in &problems {
+                    println!("- {problem}");
+                }
+                bail!("Found {} problem(s) in the build plan", problems.len());
+            }
+
+            println!("Build plan is valid");
+        }
+        // Print the JSON Schema for editor tooling to autocomplete and validate config files.
+        Commands::Schema => {
+            println!("{}", serde_json::to_string_pretty(&get_plan_schema())?);
+        }
         // Detect which providers should be used to build a project and print them to stdout.
-        Commands::Detect { path } => {
-            let providers = get_plan_providers(&path, env, &options)?;
-            println!("{}", providers.join(", "));
+        Commands::Detect {
+            path,
+            json,
+            fail_if_none,
+        } => {
+            let providers = get_plan_providers(&source_path(&path), env, &options)?;
+            if json {
+                let providers_json = get_plan_providers_json(&providers);
+                println!("{}", serde_json::to_string_pretty(&providers_json)?);
+            } else {
+                println!("{}", providers.join(", "));
+            }
+
+            if fail_if_none && providers.is_empty() {
+                bail!("No provider matched the app");
+            }
+        }
+        // Print the detection checks of each provider, to explain why a provider was or was not used.
+        Commands::Explain { path } => {
+            for (name, detection) in explain_providers(&source_path(&path), env.clone())? {
+                let matched = if detection.matched { " (matched)" } else { "" };
+                println!("{name}{matched}: {}", detection.describe());
+            }
+
+            // Providers from config files and the environment take precedence over detection
+            let providers = get_plan_providers(&source_path(&path), env, &options)?;
+            if providers.is_empty() {
+                println!("\nNo provider will be used");
+            } else {
+                println!("\nProviders used: {}", providers.join(", "));
+            }
+        }
+        // Evict old incremental cache tarballs so that the output directory does not grow without bound.
+        Commands::Cache {
+            command:
+                CacheCommands::Prune {
+                    out,
+                    max_age,
+                    max_size,
+                },
+        } => {
+            if max_age.is_none() && max_size.is_none() {
+                bail!("Pass --max-age and/or --max-size to choose what to prune");
+            }
+
+            let summary = IncrementalCacheDirs::from_output_path(&out)?.prune(max_age, max_size)?;
+            for path in &summary.removed {
+                println!("Removed {}", path.display());
+            }
+            println!(
+                "Freed {} bytes, {} bytes remaining",
+                summary.freed_bytes, summary.remaining_bytes
+            );
         }
         // Generate a Dockerfile and builds a container, using any specified build options.
         Commands::Build {
             path,
             name,
             out,
+            dockerfile_name,
             dockerfile,
+            dry_run,
             tag,
             label,
+            build_arg,
+            no_git_labels,
             platform,
             cache_key,
             current_dir,
+            prebuild,
             no_cache,
             incremental_cache_image,
+            no_incremental_cache,
+            no_incremental_cache_push,
+            incremental_cache_compression,
+            incremental_cache_upload_retries,
+            incremental_cache_upload_retry_delay,
+            incremental_cache_upload_retry_max_time,
+            incremental_cache_upload_proxy,
+            incremental_cache_plain_token,
+            incremental_cache_collapse_commands,
+            split_phase_commands,
+            build_timeout,
+            docker_bin,
+            output_tar,
+            registry_auth,
+            nixpkgs_path,
+            home_dir,
+            incremental_cache_checksum,
+            incremental_cache_nix_store,
+            incremental_cache_reset,
             cache_from,
             inline_cache,
             no_error_without_start,
@@ -240,6 +587,9 @@ async fn main() -> Result<()> {
         } => {
             let verbose = verbose || args.env.contains(&"NIXPACKS_VERBOSE=1".to_string());
 
+            // Only an explicitly provided cache key is part of the incremental cache image tag
+            let incremental_cache_key = cache_key.clone();
+
             // Default to absolute `path` of the source that is being built as the cache-key if not disabled
             let cache_key = if !no_cache && cache_key.is_none() {
                 get_default_cache_key(&path)?
@@ -251,20 +601,55 @@ async fn main() -> Result<()> {
                 name,
                 tags: tag,
                 labels: label,
+                build_args: build_arg.into_iter().collect(),
+                no_git_labels,
                 out_dir: out,
+                dockerfile_name,
                 quiet: false,
                 cache_key,
                 no_cache,
                 platform,
                 print_dockerfile: dockerfile,
+                dry_run,
                 current_dir,
+                prebuild,
                 inline_cache,
                 cache_from,
                 no_error_without_start,
                 incremental_cache_image,
+                no_incremental_cache,
+                no_incremental_cache_push,
+                incremental_cache_key,
+                incremental_cache_compression,
+                incremental_cache_upload_options: UploadOptions {
+                    retries: incremental_cache_upload_retries,
+                    retry_delay: incremental_cache_upload_retry_delay,
+                    retry_max_time: incremental_cache_upload_retry_max_time,
+                    proxy: incremental_cache_upload_proxy,
+                },
+                incremental_cache_collapse_commands,
+                split_phase_commands,
+                build_timeout,
+                docker_bin,
+                output_tar,
+                registry_auth: registry_auth
+                    .as_deref()
+                    .map(RegistryAuth::from_file)
+                    .transpose()?
+                    .unwrap_or_default(),
+                incremental_cache_checksum,
+                incremental_cache_inputs: Vec::new(),
+                incremental_cache_nix_store,
+                incremental_cache_reset,
+                incremental_cache_plain_token,
+                home_dir,
+                nixpkgs_path: nixpkgs_path
+                    .or_else(|| std::env::var("NIXPACKS_NIXPKGS_PATH").ok())
+                    .filter(|path| !path.is_empty()),
                 verbose,
+                progress_sender: None,
             };
-            create_docker_image(&path, env, &options, build_options).await?;
+            create_docker_image(&source_path(&path), env, &options, build_options).await?;
         }
     }
 
@@ -272,11 +657,19 @@ async fn main() -> Result<()> {
 }
 
 /// Creates a key for storing image layers in the Docker cache.
+///
+/// Git sources are cloned to a new directory for every build, so their URL is used instead of the path.
 fn get_default_cache_key(path: &str) -> Result<Option<String>> {
     let current_dir = env::current_dir()?;
-    let source = current_dir.join(path).canonicalize();
-    if let Ok(source) = source {
-        let source_str = source.to_string_lossy().to_string();
+    let source = if GitSource::parse(path).is_some() {
+        Ok(path.to_string())
+    } else {
+        current_dir
+            .join(path)
+            .canonicalize()
+            .map(|source| source.to_string_lossy().to_string())
+    };
+    if let Ok(source_str) = source {
         let mut hasher = DefaultHasher::new();
         source_str.hash(&mut hasher);
 
@@ -1,7 +1,8 @@
 use path_slash::PathBufExt;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Component, Path};
+use std::sync::Arc;
 use std::{env, fs, path::PathBuf};
 
 use anyhow::{bail, Context, Result};
@@ -13,12 +14,22 @@ use serde::de::DeserializeOwned;
 pub type StaticAssets = BTreeMap<String, String>;
 
 pub const ASSETS_DIR: &str = "/assets/";
+pub const APP_DIR: &str = "/app/";
+
+/// The source directory of apps whose files are held in memory, which does not exist on disk.
+const IN_MEMORY_SOURCE: &str = "/nixpacks-in-memory-app";
 
 /// Represents a project's file and directory paths.
 #[derive(Debug, Clone)]
 pub struct App {
     pub source: PathBuf,
     pub paths: Vec<PathBuf>,
+
+    /// The contents of each file by relative path, for apps that are not read from disk.
+    files: Option<Arc<BTreeMap<PathBuf, Vec<u8>>>>,
+
+    /// The directory in the container that the app is copied to, if the plan sets a `workdir`.
+    workdir: Option<String>,
 }
 
 impl App {
@@ -33,12 +44,85 @@ impl App {
         let dir = fs::read_dir(source.clone()).context("Failed to read app source directory")?;
         let paths: Vec<PathBuf> = dir.map(|path| path.unwrap().path()).collect();
 
-        Ok(App { source, paths })
+        Ok(App {
+            source,
+            paths,
+            files: None,
+            workdir: None,
+        })
+    }
+
+    /// Create an app from the contents of its files by relative path, without reading from or writing to disk.
+    pub fn from_files(files: HashMap<PathBuf, Vec<u8>>) -> App {
+        let files: BTreeMap<PathBuf, Vec<u8>> = files
+            .into_iter()
+            .map(|(path, contents)| (normalize_relative_path(&path), contents))
+            .collect();
+
+        let source = PathBuf::from(IN_MEMORY_SOURCE);
+        let mut paths: Vec<PathBuf> = files
+            .keys()
+            .filter_map(|path| path.components().next())
+            .map(|component| source.join(component))
+            .collect();
+        paths.dedup();
+
+        App {
+            source,
+            paths,
+            files: Some(Arc::new(files)),
+            workdir: None,
+        }
+    }
+
+    /// Create an app for a directory within this app.
+    pub fn subdirectory(&self, dir: &Path) -> Result<App> {
+        match &self.files {
+            Some(files) => {
+                let dir = self.relative_path(dir);
+                Ok(App::from_files(
+                    files
+                        .iter()
+                        .filter_map(|(path, contents)| {
+                            let path = path.strip_prefix(&dir).ok()?;
+                            Some((path.to_path_buf(), contents.clone()))
+                        })
+                        .collect(),
+                ))
+            }
+            None => App::new(self.source.join(dir).display().to_string().as_str()),
+        }
     }
 
     /// Check if a file exists
     pub fn includes_file(&self, name: &str) -> bool {
-        self.source.join(name).is_file()
+        self.is_file(Path::new(name))
+    }
+
+    /// Whether the path, relative to the app or absolute, is a file.
+    fn is_file(&self, path: &Path) -> bool {
+        match &self.files {
+            Some(files) => files.contains_key(&self.relative_path(path)),
+            None => self.source.join(path).is_file(),
+        }
+    }
+
+    /// Whether the path, relative to the app or absolute, is a directory.
+    pub fn is_dir(&self, path: &Path) -> bool {
+        match &self.files {
+            Some(files) => {
+                let dir = self.relative_path(path);
+                files
+                    .keys()
+                    .any(|file| file != &dir && file.starts_with(&dir))
+            }
+            None => self.source.join(path).is_dir(),
+        }
+    }
+
+    /// The path of a file in an in-memory app, relative to its source.
+    fn relative_path(&self, path: &Path) -> PathBuf {
+        normalize_relative_path(path.strip_prefix(&self.source).unwrap_or(path))
     }
 
     /// Returns a list of file paths matching a glob pattern
@@ -49,7 +133,7 @@ impl App {
         let directories = self
             .find_glob(pattern)?
             .into_iter()
-            .filter(|path| path.is_file())
+            .filter(|path| self.is_file(path))
             .collect();
 
         Ok(directories)
@@ -63,7 +147,7 @@ impl App {
         let directories = self
             .find_glob(pattern)?
             .into_iter()
-            .filter(|path| path.is_dir())
+            .filter(|path| self.is_dir(path))
             .collect();
 
         Ok(directories)
@@ -73,18 +157,30 @@ impl App {
     fn find_glob(&self, pattern: &str) -> Result<Vec<PathBuf>> {
         let full_pattern = self.source.join(pattern);
 
-        let pattern_str = match full_pattern.to_str() {
-            Some(s) => s,
-            None => return Ok(Vec::new()),
+        let Some(pattern_str) = full_pattern.to_str() else {
+            return Ok(Vec::new());
         };
 
+        let glob = Glob::new(pattern_str)?.compile_matcher();
+
+        if let Some(files) = &self.files {
+            // Match the files and every directory that contains them
+            let mut paths: Vec<PathBuf> = files
+                .keys()
+                .flat_map(|file| file.ancestors().filter(|path| !path.as_os_str().is_empty()))
+                .map(|path| self.source.join(path))
+                .filter(|path| glob.is_match(path))
+                .collect();
+            paths.sort();
+            paths.dedup();
+            return Ok(paths);
+        }
+
         let walker = WalkBuilder::new(&self.source)
             // this includes hidden directories & files
             .hidden(false)
             .sort_by_file_name(OsStr::cmp)
             .build();
-        let glob = Glob::new(pattern_str)?.compile_matcher();
-
         let relative_paths = walker
             .into_iter()
             .filter_map(Result::ok) // remove bad ones
@@ -95,6 +191,33 @@ impl App {
         Ok(relative_paths)
     }
 
+    /// The names of the files and directories directly in a directory of the app.
+    ///
+    /// # Errors
+    /// This will error if the directory doesn't exist
+    pub fn list_directory(&self, dir: &Path) -> Result<Vec<String>> {
+        if let Some(files) = &self.files {
+            if !self.is_dir(dir) {
+                bail!("Error reading {}", dir.display());
+            }
+
+            let dir = self.relative_path(dir);
+            let mut names = files
+                .keys()
+                .filter_map(|file| file.strip_prefix(&dir).ok()?.components().next())
+                .map(|name| name.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            return Ok(names);
+        }
+
+        fs::read_dir(self.source.join(dir))
+            .with_context(|| format!("Error reading {}", dir.display()))?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect()
+    }
+
     /// Check if a path matching a glob exists
     pub fn has_match(&self, pattern: &str) -> bool {
         match self.find_files(pattern) {
@@ -108,6 +231,16 @@ impl App {
     /// # Errors
     /// This will error if the path doesn't exist, or if the contents isn't UTF-8
     pub fn read_file(&self, name: &str) -> Result<String> {
+        if let Some(files) = &self.files {
+            let path = self.relative_path(Path::new(name));
+            let contents = files
+                .get(&path)
+                .with_context(|| format!("Error reading {}", path.display()))?;
+            let data = String::from_utf8(contents.clone())
+                .with_context(|| format!("Error reading {}", path.display()))?;
+            return Ok(data.replace("\r\n", "\n"));
+        }
+
         let data = fs::read_to_string(PathBuf::from_slash_lossy(
             self.source.join(name).as_os_str(),
         ))
@@ -119,6 +252,15 @@ impl App {
         Ok(data.replace("\r\n", "\n"))
     }
 
+    /// Read the version of a tool pinned in the asdf `.tool-versions` file, if there is one.
+    pub fn read_tool_version(&self, tool: &str) -> Result<Option<String>> {
+        if !self.includes_file(".tool-versions") {
+            return Ok(None);
+        }
+
+        Ok(parse_tool_version(&self.read_file(".tool-versions")?, tool))
+    }
+
     /// Check whether filenames matching a pattern exist in the project.
     pub fn find_match(&self, re: &Regex, pattern: &str) -> Result<bool> {
         let paths = match self.find_files(pattern) {
@@ -127,7 +269,11 @@ impl App {
         };
 
         for path in paths {
-            let path_buf = fs::canonicalize(path)?;
+            let path_buf = if self.files.is_some() {
+                path
+            } else {
+                fs::canonicalize(path)?
+            };
 
             if let Some(p) = path_buf.to_str() {
                 let f = self.read_file(p)?;
@@ -142,7 +288,7 @@ impl App {
 
     /// Check if a directory exists
     pub fn includes_directory(&self, name: &str) -> bool {
-        self.source.join(name).is_dir()
+        self.is_dir(Path::new(name))
     }
 
     #[cfg(target_os = "windows")]
@@ -155,6 +301,11 @@ impl App {
     pub fn is_file_executable(&self, name: &str) -> bool {
         use std::os::unix::prelude::PermissionsExt;
 
+        // Files held in memory have no permissions
+        if self.files.is_some() {
+            return false;
+        }
+
         let path = self.source.join(name);
         if path.is_file() {
             let metadata = path.metadata().unwrap();
@@ -202,9 +353,8 @@ impl App {
 
     /// Convert an absolute path to a path relative to the app source directory
     pub fn strip_source_path(&self, abs_path: &Path) -> Result<PathBuf> {
-        let source_str = match self.source.to_str() {
-            Some(s) => s,
-            None => bail!("Failed to parse source path"),
+        let Some(source_str) = self.source.to_str() else {
+            bail!("Failed to parse source path");
         };
 
         // Strip source path from absolute path
@@ -221,6 +371,46 @@ impl App {
     pub fn asset_path(&self, name: &str) -> String {
         format!("{ASSETS_DIR}{name}")
     }
+
+    /// Use another directory in the container for the app, which [`App::app_path`] resolves against.
+    #[must_use]
+    pub fn with_app_dir(mut self, app_dir: String) -> App {
+        self.workdir = Some(app_dir);
+        self
+    }
+
+    /// Get the path in the container to a file of the app, which is in `/app/` unless the plan sets a `workdir`.
+    pub fn app_path(&self, name: &str) -> String {
+        format!("{}{name}", self.workdir.as_deref().unwrap_or(APP_DIR))
+    }
+}
+
+/// Remove `.` components from a relative path, so that `./package.json` and `package.json` are the same file.
+fn normalize_relative_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir | Component::RootDir))
+        .collect()
+}
+
+/// Find the version of a tool in the contents of a `.tool-versions` file.
+///
+/// asdf falls back to later versions on a line, so the first one that names a release is used.
+fn parse_tool_version(contents: &str, tool: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some(tool) {
+            return None;
+        }
+
+        fields
+            .find(|version| {
+                *version != "system"
+                    && !version.starts_with("ref:")
+                    && !version.starts_with("path:")
+            })
+            .map(ToString::to_string)
+    })
 }
 
 #[cfg(test)]
@@ -244,6 +434,66 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_tool_version() {
+        let contents =
+            "# pinned versions\nnodejs 18.17.0\npython system 3.11.4 # fallback\nruby ref:v3_2_2\n";
+        assert_eq!(
+            parse_tool_version(contents, "nodejs"),
+            Some("18.17.0".to_string())
+        );
+        assert_eq!(
+            parse_tool_version(contents, "python"),
+            Some("3.11.4".to_string())
+        );
+        assert_eq!(parse_tool_version(contents, "ruby"), None);
+        assert_eq!(parse_tool_version(contents, "elixir"), None);
+    }
+
+    #[test]
+    fn test_in_memory_app() -> Result<()> {
+        let app = App::from_files(HashMap::from([
+            (PathBuf::from("./package.json"), b"{}".to_vec()),
+            (PathBuf::from("src/index.ts"), b"export {};\r\n".to_vec()),
+            (PathBuf::from("src/lib/util.ts"), Vec::new()),
+        ]));
+
+        assert!(app.includes_file("package.json"));
+        assert!(app.includes_file("./src/index.ts"));
+        assert!(!app.includes_file("src"));
+        assert!(app.includes_directory("src"));
+        assert!(app.includes_directory("src/lib"));
+        assert!(!app.includes_directory("src/index.ts"));
+        assert_eq!(app.read_file("src/index.ts")?, "export {};\n");
+        assert!(app.read_file("missing.ts").is_err());
+
+        let files = app
+            .find_files("**/*.ts")?
+            .iter()
+            .map(|path| app.strip_source_path(path).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("src/index.ts"),
+                PathBuf::from("src/lib/util.ts")
+            ]
+        );
+        assert_eq!(app.find_directories("src/*")?.len(), 1);
+        assert_eq!(
+            app.list_directory(Path::new("src"))?,
+            vec!["index.ts", "lib"]
+        );
+        assert!(app.list_directory(Path::new("missing")).is_err());
+        assert!(app.find_match(&Regex::new("export")?, "**/*.ts")?);
+
+        let src = app.subdirectory(Path::new("src"))?;
+        assert!(src.includes_file("index.ts"));
+        assert!(src.includes_directory("lib"));
+
+        Ok(())
+    }
+
     #[test]
     fn test_read_file() -> Result<()> {
         let app = App::new("./examples/node-npm")?;
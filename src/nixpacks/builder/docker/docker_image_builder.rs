@@ -1,19 +1,27 @@
-use super::{dockerfile_generation::DockerfileGenerator, DockerBuilderOptions, ImageBuilder};
+use super::{
+    dockerfile_generation::DockerfileGenerator, git_labels, process, utils, BuildEvent,
+    DockerBuilderOptions, ImageBuilder,
+};
 use crate::nixpacks::{
     builder::docker::{
         dockerfile_generation::OutputDir,
         file_server::FileServer,
-        incremental_cache::{IncrementalCache, IncrementalCacheDirs},
+        incremental_cache::{DockerCacheBackend, IncrementalCache, IncrementalCacheDirs},
+        phase_progress::PhaseProgress,
+        registry_auth::RegistryLogins,
     },
     environment::Environment,
     files,
     logger::Logger,
-    plan::BuildPlan,
+    plan::{phase::Phase, BuildPlan},
 };
 use anyhow::{bail, Context, Ok, Result};
 use std::{
     fs::{self, remove_dir_all, File},
+    path::Path,
     process::Command,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
 };
 use tempdir::TempDir;
 use uuid::Uuid;
@@ -22,17 +30,25 @@ use uuid::Uuid;
 pub struct DockerImageBuilder {
     logger: Logger,
     options: DockerBuilderOptions,
+    /// The incremental cache to use instead of one that stores its image with Docker.
+    incremental_cache: Option<IncrementalCache>,
+    docker_bin: String,
 }
 
 /// Determine where to write project files and generated assets like Dockerfiles.
 fn get_output_dir(app_src: &str, options: &DockerBuilderOptions) -> Result<OutputDir> {
-    if let Some(value) = &options.out_dir {
+    let output = if let Some(value) = &options.out_dir {
         OutputDir::new(value.into(), false)
     } else if options.current_dir {
         OutputDir::new(app_src.into(), false)
     } else {
         let tmp = TempDir::new("nixpacks").context("Creating a temp directory")?;
         OutputDir::new(tmp.into_path(), true)
+    }?;
+
+    match &options.dockerfile_name {
+        Some(name) => output.with_dockerfile_name(name),
+        None => Ok(output),
     }
 }
 
@@ -43,59 +59,207 @@ impl ImageBuilder for DockerImageBuilder {
     /// Build a Docker image from a given BuildPlan and data from environment variables.
     async fn create_image(&self, app_src: &str, plan: &BuildPlan, env: &Environment) -> Result<()> {
         let id = Uuid::new_v4();
+        let options = &self.get_options_for_app(app_src)?;
+
+        // Fail before anything is written if the build or the incremental cache need Docker and it is missing
+        let builds_image =
+            options.out_dir.is_none() && !options.dry_run && !options.print_dockerfile;
+        if builds_image || options.incremental_cache_image.is_some() {
+            utils::find_docker_bin(&self.docker_bin)?;
+        }
+
+        // An image for multiple platforms is pushed, which needs the name of a repository to push to
+        let pushes_image = options.platform.len() > 1 && options.output_tar.is_none();
+        if builds_image && pushes_image && options.name.is_none() {
+            bail!(
+                "Building for platforms {} pushes the image to a registry, pass --name with the repository to push to or --output-tar to write a tarball",
+                options.platform.join(", ")
+            );
+        }
 
-        let output = get_output_dir(app_src, &self.options)?;
-        let name = self.options.name.clone().unwrap_or_else(|| id.to_string());
+        // The hook changes the app, which printing the Dockerfile does not
+        if !options.print_dockerfile {
+            self.run_prebuild(app_src, options)?;
+        }
+
+        let output = get_output_dir(app_src, options)?;
+        let name = options.name.clone().unwrap_or_else(|| id.to_string());
         output.ensure_output_exists()?;
 
-        let incremental_cache = IncrementalCache::default();
         let incremental_cache_dirs = IncrementalCacheDirs::new(&output);
 
-        let file_server_config = if self.options.incremental_cache_image.is_some() {
-            incremental_cache_dirs.create()?;
+        let file_server_config = if options.incremental_cache_image.is_some() {
+            if options.incremental_cache_reset {
+                incremental_cache_dirs.reset()?;
+            } else {
+                incremental_cache_dirs.create()?;
+                incremental_cache_dirs.clear_uploads()?;
+            }
 
             let file_server = FileServer {};
-            let config = file_server.start(&incremental_cache_dirs);
+            let mut config = file_server
+                .start(
+                    &incremental_cache_dirs,
+                    options.incremental_cache_plain_token,
+                    &id.to_string(),
+                )
+                .context("Starting file server")?;
+            config.upload_options = options.incremental_cache_upload_options.clone();
             Some(config)
         } else {
             None
         };
 
+        // Logged in to before the incremental cache image is looked up, and out of again once the image is built and pushed
+        let registry_auth = if builds_image || options.incremental_cache_image.is_some() {
+            options.registry_auth.as_slice()
+        } else {
+            &[]
+        };
+        let logins = RegistryLogins::login(registry_auth, &self.docker_bin)?;
+        let docker_incremental_cache;
+        let incremental_cache = if let Some(incremental_cache) = &self.incremental_cache {
+            incremental_cache
+        } else {
+            docker_incremental_cache = IncrementalCache::new(Box::new(DockerCacheBackend {
+                verbose: options.verbose,
+                docker_bin: self.docker_bin.clone(),
+                docker_config: logins.docker_config().map(Path::to_path_buf),
+            }));
+            &docker_incremental_cache
+        };
+
         let dockerfile = plan
-            .generate_dockerfile(&self.options, env, &output, file_server_config)
+            .generate_dockerfile(options, env, &output, file_server_config, incremental_cache)
             .context("Generating Dockerfile for plan")?;
 
         // If printing the Dockerfile, don't write anything to disk
-        if self.options.print_dockerfile {
+        if options.print_dockerfile {
             println!("{dockerfile}");
             return Ok(());
         }
 
         self.write_app(app_src, &output).context("Writing app")?;
-        self.write_dockerfile(dockerfile, &output)
+        self.write_dockerignore(app_src, plan, &output)
+            .context("Writing .dockerignore")?;
+        self.write_dockerfile(dockerfile.clone(), &output)
             .context("Writing Dockerfile")?;
-        plan.write_supporting_files(&self.options, env, &output)
+        plan.write_supporting_files(options, env, &output)
             .context("Writing supporting files")?;
 
+        self.emit(BuildEvent::PhasesPlanned {
+            phases: plan
+                .get_sorted_phases()?
+                .iter()
+                .map(Phase::get_name)
+                .collect(),
+        });
+        self.emit(BuildEvent::DockerfileGenerated {
+            path: output.get_dockerfile_path(),
+        });
+
+        if options.dry_run {
+            println!("{dockerfile}");
+            println!("\nSaved output to:");
+            println!("  {}", output.root.to_str().unwrap());
+            self.emit(BuildEvent::OutputSaved { path: output.root });
+            return Ok(());
+        }
+
         // Only build if the --out flag was not specified
-        if self.options.out_dir.is_none() {
-            let mut docker_build_cmd = self.get_docker_build_cmd(plan, name.as_str(), &output)?;
+        if options.out_dir.is_none() {
+            let mut docker_build_cmd =
+                self.get_docker_build_cmd(plan, env, app_src, name.as_str(), &output)?;
+            logins.configure(&mut docker_build_cmd);
 
             // Execute docker build
-            let build_result = docker_build_cmd.spawn()?.wait().context("Building image")?;
-            if !build_result.success() {
+            self.emit(BuildEvent::BuildStarted {
+                image: name.clone(),
+            });
+            let timeout = options.build_timeout.map(Duration::from_secs);
+            let build_result = match &options.progress_sender {
+                // The phases are followed in the output of the build
+                Some(sender) => {
+                    let phases = plan
+                        .get_sorted_phases()?
+                        .iter()
+                        .map(Phase::get_name)
+                        .collect::<Vec<_>>();
+                    let progress = Arc::new(Mutex::new(PhaseProgress::new(&dockerfile, &phases)));
+                    let line_progress = progress.clone();
+                    let line_sender = sender.clone();
+                    let result = process::run_with_timeout_reading_stderr(
+                        &mut docker_build_cmd,
+                        timeout,
+                        move |line| {
+                            for event in line_progress.lock().unwrap().on_line(line) {
+                                let _ = line_sender.send(event);
+                            }
+                        },
+                    );
+                    if matches!(&result, std::result::Result::Ok(status) if status.success()) {
+                        for event in progress.lock().unwrap().finish() {
+                            self.emit(event);
+                        }
+                    }
+                    result
+                }
+                None => process::run_with_timeout(&mut docker_build_cmd, timeout),
+            }
+            .context("Building image");
+            let success =
+                matches!(&build_result, std::result::Result::Ok(status) if status.success());
+            self.emit(BuildEvent::BuildFinished {
+                image: name.clone(),
+                success,
+            });
+            if !build_result?.success() {
                 bail!("Docker build failed")
             }
 
             self.logger.log_section("Successfully Built!");
-            println!("\nRun:");
-            println!("  docker run -it {name}");
+            if let Some(output_tar) = &options.output_tar {
+                // Without buildx the image is built into the daemon and saved from there
+                if !self.uses_buildx() {
+                    let save_result = logins
+                        .configure(&mut Command::new(&self.docker_bin))
+                        .args(utils::get_save_args(&name, output_tar))
+                        .status()
+                        .context("Saving image")?;
+                    if !save_result.success() {
+                        bail!("Docker save failed")
+                    }
+                }
 
-            if self.options.incremental_cache_image.is_some() {
-                incremental_cache.create_image(
-                    &incremental_cache_dirs,
-                    &self.options.incremental_cache_image.clone().unwrap(),
-                )?;
+                println!("\nSaved image to {output_tar}, load it with:");
+                println!("  docker load -i {output_tar}");
+            } else if pushes_image {
+                self.emit(BuildEvent::ImagePushed {
+                    image: name.clone(),
+                });
+                println!(
+                    "\nPushed {name} for platforms {}",
+                    options.platform.join(", ")
+                );
+            } else {
+                println!("\nRun:");
+                let ports = plan
+                    .start_phase
+                    .as_ref()
+                    .and_then(|start| start.expose_ports.clone())
+                    .unwrap_or_default();
+                println!("  {}", utils::get_run_command(&name, &ports));
+            }
+
+            if let Some(image) = &options.incremental_cache_image {
+                if incremental_cache.create_image(&incremental_cache_dirs, image)? {
+                    self.emit(BuildEvent::IncrementalCacheImageCreated { tag: image.clone() });
+
+                    // Credentials from `registry_auth` were used to log in before building
+                    if !options.no_incremental_cache_push && incremental_cache.push_image(image) {
+                        self.emit(BuildEvent::IncrementalCacheImagePushed { tag: image.clone() });
+                    }
+                }
             }
 
             if output.is_temp {
@@ -104,6 +268,7 @@ impl ImageBuilder for DockerImageBuilder {
         } else {
             println!("\nSaved output to:");
             println!("  {}", output.root.to_str().unwrap());
+            self.emit(BuildEvent::OutputSaved { path: output.root });
         }
 
         Ok(())
@@ -112,34 +277,112 @@ impl ImageBuilder for DockerImageBuilder {
 
 impl DockerImageBuilder {
     pub fn new(logger: Logger, options: DockerBuilderOptions) -> DockerImageBuilder {
-        DockerImageBuilder { logger, options }
+        let docker_bin = utils::get_docker_bin(options.docker_bin.as_deref());
+
+        DockerImageBuilder {
+            logger,
+            options,
+            incremental_cache: None,
+            docker_bin,
+        }
+    }
+
+    /// Send a progress event to the configured receiver, if any.
+    fn emit(&self, event: BuildEvent) {
+        if let Some(sender) = &self.options.progress_sender {
+            // The receiver going away should not fail the build
+            let _ = sender.send(event);
+        }
+    }
+
+    /// Use the given incremental cache, for example one backed by a non-Docker CacheBackend.
+    #[must_use]
+    pub fn with_incremental_cache(mut self, incremental_cache: IncrementalCache) -> Self {
+        self.incremental_cache = Some(incremental_cache);
+        self
+    }
+
+    /// Resolves the build options that depend on the app source, such as the checksummed incremental cache image tag.
+    fn get_options_for_app(&self, app_src: &str) -> Result<DockerBuilderOptions> {
+        let mut options = self.options.clone();
+
+        // The incremental cache needs Docker to look up and create the cache image
+        if options.dry_run || options.no_incremental_cache {
+            options.incremental_cache_image = None;
+        }
+
+        if let (Some(image), Some(cache_key)) = (
+            &options.incremental_cache_image,
+            &options.incremental_cache_key,
+        ) {
+            let key_hash = IncrementalCache::compute_inputs_hash(&[(
+                "cache-key".to_string(),
+                cache_key.as_bytes().to_vec(),
+            )]);
+            options.incremental_cache_image =
+                Some(IncrementalCache::get_image_tag(image, &key_hash));
+        }
+
+        if options.incremental_cache_checksum {
+            if let Some(image) = &options.incremental_cache_image {
+                let inputs = IncrementalCache::read_cache_inputs(
+                    Path::new(app_src),
+                    &options.incremental_cache_inputs,
+                )?;
+                let inputs_hash = IncrementalCache::compute_inputs_hash(&inputs);
+                options.incremental_cache_image =
+                    Some(IncrementalCache::get_image_tag(image, &inputs_hash));
+            }
+        }
+
+        // The same tag is looked up, created and pushed
+        options.incremental_cache_image = options
+            .incremental_cache_image
+            .map(|image| IncrementalCache::normalize_image_tag(&image));
+
+        Ok(options)
     }
 
     /// Generates the Docker command and arguments for building the project.
     fn get_docker_build_cmd(
         &self,
         plan: &BuildPlan,
+        env: &Environment,
+        app_src: &str,
         name: &str,
         output: &OutputDir,
     ) -> Result<Command> {
-        let mut docker_build_cmd = Command::new("docker");
-
-        if docker_build_cmd.output().is_err() {
-            bail!("Please install Docker to build the app https://docs.docker.com/engine/install/")
-        }
+        let mut docker_build_cmd = Command::new(&self.docker_bin);
 
-        // Enable BuildKit for all builds
+        // Enable BuildKit for all builds, unless it was disabled for a build that needs it to mount secrets
+        let secrets = plan.secrets.clone().unwrap_or_default();
+        utils::check_buildkit_for_secrets(
+            std::env::var("DOCKER_BUILDKIT").ok().as_deref(),
+            &secrets,
+        )?;
         docker_build_cmd.env("DOCKER_BUILDKIT", "1");
 
+        // Building for multiple or foreign platforms is only supported by buildx
+        let use_buildx = self.uses_buildx();
+        if use_buildx {
+            docker_build_cmd.arg("buildx");
+        } else if utils::requires_buildx(&self.options.platform) {
+            bail!(
+                "Building for platform(s) {} requires docker buildx https://docs.docker.com/build/install-buildx/",
+                self.options.platform.join(", ")
+            );
+        }
+
         docker_build_cmd
             .arg("build")
             .arg(&output.root)
             .arg("-f")
-            .arg(&output.get_absolute_path("Dockerfile"))
+            .arg(output.get_dockerfile_path())
             .arg("-t")
             .arg(name);
 
-        if self.options.verbose {
+        // Progress events are read from the plain output
+        if self.options.verbose || self.options.progress_sender.is_some() {
             docker_build_cmd.arg("--progress=plain");
         }
 
@@ -163,25 +406,101 @@ impl DockerImageBuilder {
 
         // Add build environment variables
         for (name, value) in &plan.variables.clone().unwrap_or_default() {
+            if secrets.contains(name) {
+                continue;
+            }
+
             docker_build_cmd
                 .arg("--build-arg")
                 .arg(format!("{name}={value}"));
         }
 
-        // Add user defined tags and labels to the image
+        docker_build_cmd.args(utils::get_build_arg_args(&self.options.build_args));
+
+        // Pass secrets through the environment of the docker command so they never appear in its arguments
+        for secret in &secrets {
+            let value = env
+                .get_variable(secret)
+                .or_else(|| plan.variables.as_ref()?.get(secret).map(String::as_str));
+            match value {
+                Some(value) => {
+                    docker_build_cmd
+                        .env(secret, value)
+                        .arg("--secret")
+                        .arg(format!("id={secret},env={secret}"));
+                }
+                None => bail!(
+                    "No value was provided for secret {secret}, pass it with --env {secret}=..."
+                ),
+            }
+        }
+
+        // Add user defined tags and labels to the image, which take precedence over the git labels
         for t in self.options.tags.clone() {
             docker_build_cmd.arg("-t").arg(t);
         }
+        if !self.options.no_git_labels {
+            for l in git_labels::get_git_labels(Path::new(app_src), env, SystemTime::now()) {
+                docker_build_cmd.arg("--label").arg(l);
+            }
+        }
         for l in self.options.labels.clone() {
             docker_build_cmd.arg("--label").arg(l);
         }
-        for l in self.options.platform.clone() {
-            docker_build_cmd.arg("--platform").arg(l);
+        if use_buildx {
+            if !self.options.platform.is_empty() {
+                docker_build_cmd
+                    .arg("--platform")
+                    .arg(self.options.platform.join(","));
+            }
+
+            docker_build_cmd.args(utils::get_buildx_output_args(
+                self.options.output_tar.as_deref(),
+                &self.options.platform,
+            ));
+        } else {
+            for l in self.options.platform.clone() {
+                docker_build_cmd.arg("--platform").arg(l);
+            }
         }
 
         Ok(docker_build_cmd)
     }
 
+    /// Whether the image is built with `docker buildx`, which is needed for foreign platforms and preferred for tarballs.
+    fn uses_buildx(&self) -> bool {
+        let needs_buildx =
+            utils::requires_buildx(&self.options.platform) || self.options.output_tar.is_some();
+
+        needs_buildx
+            && matches!(
+                Command::new(&self.docker_bin).args(["buildx", "version"]).output(),
+                std::result::Result::Ok(output) if output.status.success()
+            )
+    }
+
+    /// Run the `--prebuild` command in the app directory, with its output streamed to the terminal.
+    fn run_prebuild(&self, app_src: &str, options: &DockerBuilderOptions) -> Result<()> {
+        let prebuild = match &options.prebuild {
+            Some(prebuild) if !prebuild.trim().is_empty() => prebuild,
+            _ => return Ok(()),
+        };
+
+        self.logger.log_section("Running prebuild");
+        self.logger.log_step(&format!("$ {prebuild}"));
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(prebuild)
+            .current_dir(app_src)
+            .status()
+            .context("Running the prebuild command")?;
+        if !status.success() {
+            bail!("The prebuild command `{prebuild}` failed with {status}");
+        }
+
+        Ok(())
+    }
+
     /// Copies project files to temporary output dir, if that option was used.
     fn write_app(&self, app_src: &str, output: &OutputDir) -> Result<()> {
         if output.is_temp {
@@ -191,12 +510,668 @@ impl DockerImageBuilder {
         }
     }
 
+    /// Writes the plan's excluded files next to the Dockerfile, where BuildKit prefers it over the app's own `.dockerignore`.
+    ///
+    /// The patterns of the app's `.dockerignore` are kept so they still apply.
+    fn write_dockerignore(
+        &self,
+        app_src: &str,
+        plan: &BuildPlan,
+        output: &OutputDir,
+    ) -> Result<()> {
+        let exclude_files = plan.exclude_files.clone().unwrap_or_default();
+        if exclude_files.is_empty() {
+            return Ok(());
+        }
+
+        let mut dockerignore =
+            fs::read_to_string(Path::new(app_src).join(".dockerignore")).unwrap_or_default();
+        if !dockerignore.is_empty() && !dockerignore.ends_with('\n') {
+            dockerignore.push('\n');
+        }
+        for pattern in exclude_files {
+            dockerignore.push_str(&pattern);
+            dockerignore.push('\n');
+        }
+
+        fs::write(output.get_dockerignore_path(), dockerignore)
+            .context("Write Dockerfile.dockerignore")?;
+
+        Ok(())
+    }
+
     /// Writes the generated Dockerfile to the output dir.
     fn write_dockerfile(&self, dockerfile: String, output: &OutputDir) -> Result<()> {
-        let dockerfile_path = output.get_absolute_path("Dockerfile");
+        let dockerfile_path = output.get_dockerfile_path();
         File::create(dockerfile_path.clone()).context("Creating Dockerfile file")?;
         fs::write(dockerfile_path, dockerfile).context("Write Dockerfile")?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::{
+        builder::docker::fake_cache_backend::FakeCacheBackend, nix::pkg::Pkg, plan::phase::Phase,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_incremental_cache_image_uses_cache_key() {
+        let get_image = |cache_key: Option<&str>| {
+            DockerImageBuilder::new(
+                Logger::new(),
+                DockerBuilderOptions {
+                    incremental_cache_image: Some("registry.test/cache".to_string()),
+                    incremental_cache_key: cache_key.map(ToString::to_string),
+                    ..Default::default()
+                },
+            )
+            .get_options_for_app(".")
+            .unwrap()
+            .incremental_cache_image
+            .unwrap()
+        };
+
+        assert_eq!(get_image(None), "registry.test/cache");
+        assert_eq!(get_image(Some("main")), get_image(Some("main")));
+        assert_ne!(get_image(Some("main")), get_image(Some("feature")));
+        assert!(get_image(Some("main")).starts_with("registry.test/cache:"));
+    }
+
+    #[test]
+    fn test_write_dockerignore() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+        fs::write(app_dir.path().join(".dockerignore"), "node_modules").unwrap();
+
+        let output = OutputDir::new(out_dir.path().to_path_buf(), false).unwrap();
+        output.ensure_output_exists().unwrap();
+
+        let plan = BuildPlan {
+            exclude_files: Some(vec![
+                ".git".to_string(),
+                "target/**".to_string(),
+                "!target/release/app".to_string(),
+            ]),
+            ..Default::default()
+        };
+
+        DockerImageBuilder::new(Logger::new(), DockerBuilderOptions::default())
+            .write_dockerignore(app_dir.path().to_str().unwrap(), &plan, &output)
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(output.get_absolute_path("Dockerfile.dockerignore")).unwrap(),
+            "node_modules\n.git\ntarget/**\n!target/release/app\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_does_not_use_docker() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+
+        let mut build = Phase::build(Some("make".to_string()));
+        build.add_cache_directory("/root/.cache");
+        let plan = BuildPlan::new(&[build], None);
+
+        let backend = FakeCacheBackend {
+            all_images_exist: true,
+            ..Default::default()
+        };
+        let builder = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                dry_run: true,
+                out_dir: Some(out_dir.path().to_str().unwrap().to_string()),
+                incremental_cache_image: Some("registry.test/cache:latest".to_string()),
+                ..Default::default()
+            },
+        )
+        .with_incremental_cache(IncrementalCache::new(Box::new(backend.clone())));
+
+        builder
+            .create_image(
+                app_dir.path().to_str().unwrap(),
+                &plan,
+                &Environment::default(),
+            )
+            .await
+            .unwrap();
+
+        assert!(backend.calls().is_empty());
+        let output = OutputDir::new(out_dir.path().to_path_buf(), false).unwrap();
+        let dockerfile = fs::read_to_string(output.get_absolute_path("Dockerfile")).unwrap();
+        assert!(dockerfile.contains("make"));
+    }
+
+    #[tokio::test]
+    async fn test_no_incremental_cache() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+
+        let mut build = Phase::build(Some("make".to_string()));
+        build.add_cache_directory("/root/.cache");
+        let plan = BuildPlan::new(&[build], None);
+
+        let backend = FakeCacheBackend {
+            all_images_exist: true,
+            ..Default::default()
+        };
+        let builder = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                out_dir: Some(out_dir.path().to_str().unwrap().to_string()),
+                incremental_cache_image: Some("registry.test/cache:latest".to_string()),
+                no_incremental_cache: true,
+                ..Default::default()
+            },
+        )
+        .with_incremental_cache(IncrementalCache::new(Box::new(backend.clone())));
+
+        builder
+            .create_image(
+                app_dir.path().to_str().unwrap(),
+                &plan,
+                &Environment::default(),
+            )
+            .await
+            .unwrap();
+
+        assert!(backend.calls().is_empty());
+        let output = OutputDir::new(out_dir.path().to_path_buf(), false).unwrap();
+        let dockerfile = fs::read_to_string(output.get_absolute_path("Dockerfile")).unwrap();
+        assert!(dockerfile.contains("make"));
+        assert!(!dockerfile.contains("COPY --from=registry.test/cache"));
+        assert!(!dockerfile.contains("curl"));
+        assert!(!IncrementalCacheDirs::new(&output).uploads_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_progress_events() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+
+        let plan = BuildPlan::new(
+            &[
+                Phase::install(Some("npm ci".to_string())),
+                Phase::build(Some("npm run build".to_string())),
+            ],
+            None,
+        );
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let builder = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                out_dir: Some(out_dir.path().to_str().unwrap().to_string()),
+                progress_sender: Some(sender),
+                ..Default::default()
+            },
+        );
+
+        builder
+            .create_image(
+                app_dir.path().to_str().unwrap(),
+                &plan,
+                &Environment::default(),
+            )
+            .await
+            .unwrap();
+        drop(builder);
+
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+
+        let output = OutputDir::new(out_dir.path().to_path_buf(), false).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                BuildEvent::PhasesPlanned {
+                    phases: vec!["install".to_string(), "build".to_string()]
+                },
+                BuildEvent::DockerfileGenerated {
+                    path: output.get_absolute_path("Dockerfile")
+                },
+                BuildEvent::OutputSaved { path: output.root },
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_phase_progress_events() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The fake Docker CLI prints a BuildKit step for each RUN and COPY of the Dockerfile it is given
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let bin_dir = TempDir::new("nixpacks-docker-bin").unwrap();
+        let docker_bin = bin_dir.path().join("docker");
+        fs::write(
+            &docker_bin,
+            "#!/bin/sh\nwhile [ \"$1\" != -f ]; do shift; done\nawk '/^(RUN|COPY)/ { n++; print \"#\" n \" [stage-0 \" n \"/9] \" $0 }' \"$2\" >&2\n",
+        )
+        .unwrap();
+        fs::set_permissions(&docker_bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let plan = BuildPlan::new(
+            &[
+                Phase::install(Some("npm ci".to_string())),
+                Phase::build(Some("npm run build".to_string())),
+            ],
+            None,
+        );
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let builder = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                name: Some("app".to_string()),
+                docker_bin: Some(docker_bin.to_str().unwrap().to_string()),
+                no_git_labels: true,
+                progress_sender: Some(sender),
+                ..Default::default()
+            },
+        );
+
+        builder
+            .create_image(
+                app_dir.path().to_str().unwrap(),
+                &plan,
+                &Environment::default(),
+            )
+            .await
+            .unwrap();
+        drop(builder);
+
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+
+        let phase_started = |phase: &str| BuildEvent::PhaseStarted {
+            phase: phase.to_string(),
+        };
+        let phase_finished = |phase: &str| BuildEvent::PhaseFinished {
+            phase: phase.to_string(),
+        };
+        assert_eq!(
+            events[2..],
+            vec![
+                BuildEvent::BuildStarted {
+                    image: "app".to_string()
+                },
+                phase_started("install"),
+                phase_finished("install"),
+                phase_started("build"),
+                phase_finished("build"),
+                BuildEvent::BuildFinished {
+                    image: "app".to_string(),
+                    success: true
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_dockerfile_name() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+        let mut plan = BuildPlan::new(&[Phase::build(Some("make".to_string()))], None);
+        plan.exclude_files = Some(vec![".git".to_string()]);
+
+        let options = DockerBuilderOptions {
+            out_dir: Some(out_dir.path().to_str().unwrap().to_string()),
+            dockerfile_name: Some("Dockerfile.web".to_string()),
+            ..Default::default()
+        };
+        DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                dry_run: true,
+                ..options.clone()
+            },
+        )
+        .create_image(
+            app_dir.path().to_str().unwrap(),
+            &plan,
+            &Environment::default(),
+        )
+        .await
+        .unwrap();
+
+        let output = get_output_dir(app_dir.path().to_str().unwrap(), &options).unwrap();
+        assert_eq!(
+            output.get_dockerfile_path(),
+            out_dir.path().join(".nixpacks/Dockerfile.web")
+        );
+        assert!(fs::read_to_string(output.get_dockerfile_path())
+            .unwrap()
+            .contains("make"));
+        assert!(out_dir
+            .path()
+            .join(".nixpacks/Dockerfile.web.dockerignore")
+            .exists());
+        assert!(!output.get_absolute_path("Dockerfile").exists());
+
+        if Command::new("docker").output().is_ok() {
+            let cmd = DockerImageBuilder::new(Logger::new(), options)
+                .get_docker_build_cmd(&plan, &Environment::default(), ".", "app", &output)
+                .unwrap();
+            let args = cmd
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            assert!(args.windows(2).any(|pair| pair
+                == [
+                    "-f".to_string(),
+                    output.get_dockerfile_path().to_string_lossy().to_string()
+                ]));
+        }
+
+        assert!(OutputDir::default()
+            .with_dockerfile_name("../Dockerfile")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prebuild() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+        let plan = BuildPlan::new(&[Phase::build(Some("make".to_string()))], None);
+        let create_image = |prebuild: &str| {
+            let builder = DockerImageBuilder::new(
+                Logger::new(),
+                DockerBuilderOptions {
+                    dry_run: true,
+                    out_dir: Some(out_dir.path().to_str().unwrap().to_string()),
+                    prebuild: Some(prebuild.to_string()),
+                    ..Default::default()
+                },
+            );
+            let plan = &plan;
+            let app_src = app_dir.path().to_str().unwrap().to_string();
+            async move {
+                builder
+                    .create_image(&app_src, plan, &Environment::default())
+                    .await
+            }
+        };
+
+        // The hook runs in the app directory before the Dockerfile is written
+        create_image("echo 'export default {}' > generated.js")
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(app_dir.path().join("generated.js")).unwrap(),
+            "export default {}\n"
+        );
+        let output = OutputDir::new(out_dir.path().to_path_buf(), false).unwrap();
+        let dockerfile_path = output.get_absolute_path("Dockerfile");
+        assert!(dockerfile_path.exists());
+
+        fs::remove_file(&dockerfile_path).unwrap();
+        let err = create_image("echo codegen failed >&2; exit 3")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The prebuild command `echo codegen failed >&2; exit 3` failed with exit status: 3"
+        );
+        assert!(!dockerfile_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_missing_docker_bin() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let plan = BuildPlan::new(&[Phase::build(Some("make".to_string()))], None);
+
+        let err = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                docker_bin: Some("/nonexistent/bin/docker".to_string()),
+                ..Default::default()
+            },
+        )
+        .create_image(
+            app_dir.path().to_str().unwrap(),
+            &plan,
+            &Environment::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Docker was not found at `/nonexistent/bin/docker`. Install it https://docs.docker.com/engine/install/ or set DOCKER_BIN to the path of the docker binary"
+        );
+        assert!(utils::find_docker_bin("nixpacks-nonexistent-docker").is_err());
+        assert!(utils::find_docker_bin("sh").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_multi_platform_build_needs_name() {
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let plan = BuildPlan::new(&[Phase::build(Some("make".to_string()))], None);
+
+        let err = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                docker_bin: Some("sh".to_string()),
+                platform: vec!["linux/amd64".to_string(), "linux/arm64".to_string()],
+                ..Default::default()
+            },
+        )
+        .create_image(
+            app_dir.path().to_str().unwrap(),
+            &plan,
+            &Environment::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Building for platforms linux/amd64, linux/arm64 pushes the image to a registry, pass --name with the repository to push to or --output-tar to write a tarball"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_nixpkgs_archive_sha256_mismatch_fails_build() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for the build, which fails like fetchTarball when the expected hash is not the one of the archive
+        let bin_dir = TempDir::new("nixpacks-bin").unwrap();
+        let docker_bin = bin_dir.path().join("docker");
+        fs::write(
+            &docker_bin,
+            indoc::indoc! {r#"
+                #!/bin/sh
+                expected=$(grep -ho 'sha256 = "[^"]*"' "$2"/.nixpacks/*.nix | cut -d '"' -f 2)
+                if [ -n "$expected" ] && [ "$expected" != "1fd4rp8m03xzyh3wcbcjv1r8pxvdynjv7skvgxxmlrgrb7vx8ixd" ]; then
+                    echo "error: hash mismatch in file downloaded, specified: sha256:$expected" >&2
+                    exit 102
+                fi
+            "#},
+        )
+        .unwrap();
+        fs::set_permissions(&docker_bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let build = |sha256: &str| {
+            let app_dir = TempDir::new("nixpacks-app").unwrap();
+            let mut setup = Phase::setup(Some(vec![Pkg::new("nodejs")]));
+            setup.nixpkgs_archive_sha256 = Some(sha256.to_string());
+            let plan = BuildPlan::new(&[setup], None);
+            let builder = DockerImageBuilder::new(
+                Logger::new(),
+                DockerBuilderOptions {
+                    docker_bin: Some(docker_bin.to_string_lossy().to_string()),
+                    quiet: true,
+                    ..Default::default()
+                },
+            );
+
+            async move {
+                builder
+                    .create_image(
+                        app_dir.path().to_str().unwrap(),
+                        &plan,
+                        &Environment::default(),
+                    )
+                    .await
+            }
+        };
+
+        build("1fd4rp8m03xzyh3wcbcjv1r8pxvdynjv7skvgxxmlrgrb7vx8ixd")
+            .await
+            .unwrap();
+        let err = build("0000000000000000000000000000000000000000000000000000")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Docker build failed");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_registry_login_before_incremental_cache_lookup() {
+        use crate::nixpacks::builder::docker::registry_auth::RegistryAuth;
+        use std::os::unix::fs::PermissionsExt;
+
+        // The fake Docker CLI records each command with its config directory
+        let app_dir = TempDir::new("nixpacks-app").unwrap();
+        let bin_dir = TempDir::new("nixpacks-docker-bin").unwrap();
+        let log = bin_dir.path().join("docker.log");
+        let docker_bin = bin_dir.path().join("docker");
+        fs::write(
+            &docker_bin,
+            format!(
+                "#!/bin/sh\necho \"$1 $2 $DOCKER_CONFIG\" >> {}\n[ \"$1\" = login ] && cat > /dev/null\nexit 0\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&docker_bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut build = Phase::build(Some("make".to_string()));
+        build.add_cache_directory("/root/.cache");
+        let plan = BuildPlan::new(&[build], None);
+        let builder = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                name: Some("app".to_string()),
+                docker_bin: Some(docker_bin.to_str().unwrap().to_string()),
+                no_git_labels: true,
+                quiet: true,
+                incremental_cache_image: Some("registry.example.com/cache:latest".to_string()),
+                no_incremental_cache_push: true,
+                registry_auth: vec![RegistryAuth {
+                    registry: "registry.example.com".to_string(),
+                    username: "deploy".to_string(),
+                    password: "s3cr3t-token".to_string(),
+                }],
+                ..Default::default()
+            },
+        );
+
+        builder
+            .create_image(
+                app_dir.path().to_str().unwrap(),
+                &plan,
+                &Environment::default(),
+            )
+            .await
+            .unwrap();
+
+        // The image is looked up and built with the credentials, which are removed after the build
+        let log = fs::read_to_string(&log).unwrap();
+        let commands = log.lines().collect::<Vec<_>>();
+        let config_dir = commands[0]
+            .strip_prefix("login registry.example.com ")
+            .unwrap();
+        assert!(commands.contains(&format!("manifest inspect {config_dir}").as_str()));
+        assert!(commands
+            .iter()
+            .any(|command| command.starts_with("build") && command.ends_with(config_dir)));
+        assert!(!Path::new(config_dir).exists());
+    }
+
+    #[test]
+    fn test_build_args_are_passed_to_docker() {
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+        let output = OutputDir::new(out_dir.path().to_path_buf(), false).unwrap();
+        let builder = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                build_args: BTreeMap::from([(
+                    "API_URL".to_string(),
+                    "https://api.example.com".to_string(),
+                )]),
+                no_git_labels: true,
+                ..Default::default()
+            },
+        );
+
+        let cmd = builder
+            .get_docker_build_cmd(
+                &BuildPlan::default(),
+                &Environment::default(),
+                ".",
+                "app",
+                &output,
+            )
+            .unwrap();
+        let args = cmd
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["--build-arg", "API_URL=https://api.example.com"]));
+    }
+
+    #[test]
+    fn test_labels_are_passed_to_docker() {
+        let out_dir = TempDir::new("nixpacks-out").unwrap();
+        let output = OutputDir::new(out_dir.path().to_path_buf(), false).unwrap();
+        let builder = DockerImageBuilder::new(
+            Logger::new(),
+            DockerBuilderOptions {
+                labels: vec![
+                    "com.example.team=platform".to_string(),
+                    "environment=staging".to_string(),
+                ],
+                no_git_labels: true,
+                ..Default::default()
+            },
+        );
+
+        let cmd = builder
+            .get_docker_build_cmd(
+                &BuildPlan::default(),
+                &Environment::default(),
+                ".",
+                "app",
+                &output,
+            )
+            .unwrap();
+        let labels = cmd
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .windows(2)
+            .filter(|pair| pair[0] == "--label")
+            .map(|pair| pair[1].clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            labels,
+            vec!["com.example.team=platform", "environment=staging"]
+        );
+    }
+}
@@ -1,27 +1,37 @@
 use super::{
-    file_server::FileServerConfig, incremental_cache::IncrementalCache, utils, DockerBuilderOptions,
+    file_server::FileServerConfig,
+    incremental_cache::{IncrementalCache, NIX_STORE_CACHE_DIRECTORIES},
+    utils, DockerBuilderOptions,
 };
 use crate::nixpacks::{
-    app,
+    app::{self, APP_DIR},
     environment::Environment,
-    images::DEFAULT_BASE_IMAGE,
-    nix::{create_nix_expressions_for_phases, nix_file_names_for_phases, setup_files_for_phases},
+    files,
+    images::{self, DEFAULT_BASE_IMAGE},
+    nix::{
+        create_nix_expressions_for_phases, local_overlay_files, nix_file_names_for_phases,
+        setup_files_for_phases, LocalNixpkgs,
+    },
     plan::{
-        phase::{Phase, StartPhase},
-        BuildPlan,
+        phase::{Entrypoint, Healthcheck, Phase, StartPhase},
+        AptSource, BuildPlan,
     },
 };
-use anyhow::{Context, Ok, Result};
+use anyhow::{bail, Context, Ok, Result};
 use indoc::formatdoc;
 use path_slash::PathBufExt;
 use std::{
+    collections::BTreeMap,
     fs::{self, File},
     io::Write,
     path::{Path, PathBuf},
 };
 
 const NIXPACKS_OUTPUT_DIR: &str = ".nixpacks";
-pub const APP_DIR: &str = "/app/";
+const DEFAULT_DOCKERFILE_NAME: &str = "Dockerfile";
+
+/// Where an entrypoint script from the app is copied to in the final image.
+const ENTRYPOINT_FILE_PATH: &str = "/usr/local/bin/nixpacks-entrypoint";
 
 /// Represents a directory into which project files and generated assets like Dockerfiles are written.
 #[derive(Debug, Clone)]
@@ -29,6 +39,8 @@ pub struct OutputDir {
     pub root: PathBuf,
     pub asset_root: PathBuf,
     pub is_temp: bool,
+    /// File name of the Dockerfile in the assets directory.
+    pub dockerfile_name: String,
 }
 
 impl OutputDir {
@@ -40,9 +52,20 @@ impl OutputDir {
             root,
             asset_root,
             is_temp,
+            dockerfile_name: DEFAULT_DOCKERFILE_NAME.to_string(),
         })
     }
 
+    /// Write the Dockerfile with another name, e.g. to keep several variants in the same output directory.
+    pub fn with_dockerfile_name(mut self, name: &str) -> Result<Self> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            bail!("Dockerfile name `{name}` must be a file name without a directory");
+        }
+
+        self.dockerfile_name = name.to_string();
+        Ok(self)
+    }
+
     pub fn from(root: &str, is_temp: bool) -> Result<Self> {
         Self::new(PathBuf::from(root), is_temp)
     }
@@ -72,6 +95,15 @@ impl OutputDir {
     pub fn get_absolute_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
         self.root.join(self.get_relative_path(path))
     }
+
+    pub fn get_dockerfile_path(&self) -> PathBuf {
+        self.get_absolute_path(&self.dockerfile_name)
+    }
+
+    /// BuildKit reads the ignore file named after the Dockerfile, instead of the `.dockerignore` of the context.
+    pub fn get_dockerignore_path(&self) -> PathBuf {
+        self.get_absolute_path(format!("{}.dockerignore", self.dockerfile_name))
+    }
 }
 
 impl Default for OutputDir {
@@ -89,6 +121,7 @@ pub trait DockerfileGenerator {
         env: &Environment,
         output: &OutputDir,
         _file_server_config: Option<FileServerConfig>,
+        _incremental_cache: &IncrementalCache,
     ) -> Result<String>;
     fn write_supporting_files(
         &self,
@@ -108,15 +141,53 @@ impl DockerfileGenerator for BuildPlan {
         env: &Environment,
         output: &OutputDir,
         file_server_config: Option<FileServerConfig>,
+        incremental_cache: &IncrementalCache,
     ) -> Result<String> {
-        let plan = self;
+        let plan = &self.expand_named_caches()?.resolve_arch_nix_pkgs(options)?;
+        let app_dir = plan.get_app_dir()?;
 
-        let setup_files = setup_files_for_phases(&plan.phases.clone().unwrap_or_default());
-        let setup_copy_cmds = utils::get_copy_commands(&setup_files, APP_DIR).join("\n");
+        let mut setup_files = setup_files_for_phases(&plan.phases.clone().unwrap_or_default());
+        setup_files.extend(local_overlay_files(
+            &plan.nix_overlays.clone().unwrap_or_default(),
+        ));
+        let setup_copy_cmds = utils::get_copy_commands(&setup_files, &app_dir).join("\n");
 
         let nix_file_names = nix_file_names_for_phases(&plan.phases.clone().unwrap_or_default());
 
+        // The Nix store is only cached when Nix packages are installed
+        let nix_store_cache_image = options
+            .incremental_cache_image
+            .as_ref()
+            .filter(|_| options.incremental_cache_nix_store && !nix_file_names.is_empty());
+
         let mut nix_install_cmds: Vec<String> = Vec::new();
+        if !nix_file_names.is_empty() {
+            if let Some(local_nixpkgs) = get_local_nixpkgs(options)? {
+                let local_nixpkgs_path = output
+                    .get_relative_path(local_nixpkgs.file_name())
+                    .to_slash()
+                    .context("Failed to convert nixpkgs path to slash path.")?
+                    .to_string();
+                nix_install_cmds.push(format!(
+                    "COPY {local_nixpkgs_path} {}",
+                    local_nixpkgs.image_path()
+                ));
+            }
+        }
+        if let Some(image) = nix_store_cache_image {
+            if incremental_cache.is_image_exists(image)? {
+                nix_install_cmds.extend(IncrementalCache::get_copy_to_image_command(
+                    &Some(
+                        NIX_STORE_CACHE_DIRECTORIES
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect(),
+                    ),
+                    image,
+                    utils::DEFAULT_HOME_DIR,
+                ));
+            }
+        }
         for name in nix_file_names {
             let nix_file = output.get_relative_path(name);
 
@@ -128,19 +199,47 @@ impl DockerfileGenerator for BuildPlan {
                 "COPY {nix_file_path} {nix_file_path}\nRUN nix-env -if {nix_file_path} && nix-collect-garbage -d"
             ));
         }
+        if nix_store_cache_image.is_some() {
+            nix_install_cmds.extend(
+                IncrementalCache::get_nix_store_copy_from_image_command(
+                    file_server_config.clone(),
+                    options.incremental_cache_compression,
+                )
+                .iter()
+                .map(|cmd| format!("RUN {cmd}")),
+            );
+        }
         let nix_install_cmds = nix_install_cmds.join("\n");
 
-        let apt_pkgs = self.all_apt_packages();
-        let apt_pkgs_str = if apt_pkgs.is_empty() {
-            String::new()
-        } else {
-            format!(
-                "RUN apt-get update && apt-get install -y --no-install-recommends {}",
-                apt_pkgs.join(" ")
-            )
-        };
-
-        let variables = plan.variables.clone().unwrap_or_default();
+        let apt_sources = plan.apt_sources.clone().unwrap_or_default();
+        let apt_sources_str = apt_sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                source
+                    .get_install_command(&format!("nixpacks-{i}"))
+                    .context("Adding apt source")
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join("\n");
+        let all_apt_pkgs = self.all_apt_packages();
+        let apt_mirror_cmd = plan.get_apt_mirror_command()?;
+        let apt_pkgs_str = [
+            if all_apt_pkgs.is_empty() {
+                String::new()
+            } else {
+                apt_mirror_cmd.clone()
+            },
+            utils::get_apt_install_command(&all_apt_pkgs),
+        ]
+        .into_iter()
+        .filter(|stmt| !stmt.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+        let secrets = plan.secrets.clone().unwrap_or_default();
+        let mut variables = plan.variables.clone().unwrap_or_default();
+        variables.retain(|name, _| !secrets.contains(name));
         let args_string = if variables.is_empty() {
             String::new()
         } else {
@@ -161,6 +260,28 @@ impl DockerfileGenerator for BuildPlan {
             )
         };
 
+        // Build args are only declared, so that they are available to the build phases without being set as variables of the image
+        if let Some(name) = options
+            .build_args
+            .keys()
+            .find(|name| variables.contains_key(*name) || secrets.contains(name))
+        {
+            bail!("`{name}` cannot be both a build argument and a plan variable");
+        }
+        let build_args_string = if options.build_args.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "ARG {}",
+                options
+                    .build_args
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        };
+
         let static_assets = plan.static_assets.clone().unwrap_or_default();
         let assets_copy_cmd = if static_assets.is_empty() {
             String::new()
@@ -172,13 +293,22 @@ impl DockerfileGenerator for BuildPlan {
             format!("COPY {rel_assets_slash_path} {}", app::ASSETS_DIR)
         };
 
+        let shell_instruction = plan.get_shell_instruction()?;
+
         let phases = plan.get_sorted_phases()?;
 
         let mut dockerfile_phases = phases
             .into_iter()
             .map(|phase| {
                 let phase_dockerfile = phase
-                    .generate_dockerfile(options, env, output, file_server_config.clone())
+                    .generate_phase_dockerfile(
+                        options,
+                        env,
+                        file_server_config.clone(),
+                        incremental_cache,
+                        &secrets,
+                        &app_dir,
+                    )
                     .context(format!(
                         "Generating Dockerfile for phase {}",
                         phase.get_name()
@@ -208,11 +338,21 @@ impl DockerfileGenerator for BuildPlan {
 
         let dockerfile_phases_str = dockerfile_phases.join("\n");
 
-        let start_phase_str = plan
-            .start_phase
-            .clone()
-            .unwrap_or_default()
-            .generate_dockerfile(options, env, output, file_server_config)?;
+        // Apt packages are only installed in the run image when it asks for them, so that it stays small
+        let mut start_phase = plan.start_phase.clone().unwrap_or_default();
+        start_phase.resolve_cmd_variables(&variables);
+        let runtime_apt_pkgs = if start_phase.install_apt_pkgs.unwrap_or(false) {
+            self.runtime_apt_packages()
+        } else {
+            Vec::new()
+        };
+        let start_phase_str = start_phase.generate_start_dockerfile(
+            &runtime_apt_pkgs,
+            &apt_sources,
+            &apt_mirror_cmd,
+            &args_string,
+            &app_dir,
+        )?;
 
         let base_image = plan
             .build_image
@@ -223,25 +363,31 @@ impl DockerfileGenerator for BuildPlan {
             FROM {base_image}
 
             ENTRYPOINT [\"/bin/bash\", \"-l\", \"-c\"]
-            WORKDIR {APP_DIR}
+            WORKDIR {app_dir}
 
             {setup_copy_cmds}
             {nix_install_cmds}
+            {apt_sources_str}
             {apt_pkgs_str}
             {assets_copy_cmd}
             {args_string}
+            {build_args_string}
+            {shell_instruction}
 
             {dockerfile_phases_str}
 
             {start_phase_str}
         ", 
         base_image=base_image,
-        APP_DIR=APP_DIR,
+        app_dir=app_dir,
         setup_copy_cmds=setup_copy_cmds,
         nix_install_cmds=nix_install_cmds,
+        apt_sources_str=apt_sources_str,
         apt_pkgs_str=apt_pkgs_str,
         assets_copy_cmd=assets_copy_cmd,
         args_string=args_string,
+        build_args_string=build_args_string,
+        shell_instruction=shell_instruction,
         dockerfile_phases_str=dockerfile_phases_str,
         start_phase_str=start_phase_str};
 
@@ -257,8 +403,30 @@ impl DockerfileGenerator for BuildPlan {
     ) -> Result<()> {
         self.write_assets(self, output).context("Writing assets")?;
 
-        let nix_expressions =
-            create_nix_expressions_for_phases(&self.phases.clone().unwrap_or_default());
+        let phases = self
+            .resolve_arch_nix_pkgs(options)?
+            .phases
+            .unwrap_or_default();
+        let local_nixpkgs = get_local_nixpkgs(options)?;
+        let nix_expressions = create_nix_expressions_for_phases(
+            &phases,
+            &self.nix_overlays.clone().unwrap_or_default(),
+            &self.get_app_dir()?,
+            local_nixpkgs.as_ref(),
+        );
+
+        // The local nixpkgs is copied into the build context, so that the image can use it without fetching
+        if let Some(local_nixpkgs) = local_nixpkgs.filter(|_| !nix_expressions.is_empty()) {
+            let dest = output.get_absolute_path(local_nixpkgs.file_name());
+            if local_nixpkgs.is_dir() {
+                files::recursive_copy_dir(local_nixpkgs.path(), dest)
+            } else {
+                fs::copy(local_nixpkgs.path(), dest)
+                    .map(|_| ())
+                    .map_err(anyhow::Error::from)
+            }
+            .context("Copying local nixpkgs")?;
+        }
 
         for (name, nix_expression) in nix_expressions {
             let nix_path = output.get_absolute_path(name);
@@ -279,6 +447,139 @@ impl DockerfileGenerator for BuildPlan {
 }
 
 impl BuildPlan {
+    /// The directory the app is copied to and built in, which is `/app/` unless the plan sets a `workdir`.
+    pub(crate) fn get_app_dir(&self) -> Result<String> {
+        let workdir = match &self.workdir {
+            Some(workdir) => workdir.trim_end_matches('/'),
+            None => return Ok(APP_DIR.to_string()),
+        };
+
+        if !workdir.starts_with('/') {
+            bail!("The workdir `{workdir}` must be an absolute path");
+        }
+        if workdir.chars().any(char::is_whitespace) {
+            bail!("The workdir `{workdir}` cannot contain whitespace");
+        }
+        if workdir.split('/').any(|segment| segment == "..") {
+            bail!("The workdir `{workdir}` cannot contain `..` segments");
+        }
+        if workdir.split('/').all(str::is_empty) {
+            bail!("The workdir cannot be the filesystem root");
+        }
+
+        Ok(format!("{workdir}/"))
+    }
+
+    /// Add the `archNixPkgs` of each phase for the architecture of the target platforms to its Nix packages.
+    ///
+    /// The Nix expressions are shared by every platform of the image, so the platforms must all have the same architecture.
+    /// Without platforms the image is built for the architecture of the Docker daemon, which is asked for it.
+    pub(crate) fn resolve_arch_nix_pkgs(
+        &self,
+        options: &DockerBuilderOptions,
+    ) -> Result<BuildPlan> {
+        let mut plan = self.clone();
+        let uses_arch_nix_pkgs = plan
+            .phases
+            .iter()
+            .flat_map(BTreeMap::values)
+            .any(|phase| phase.arch_nix_pkgs.is_some());
+        if !uses_arch_nix_pkgs {
+            return Ok(plan);
+        }
+
+        let platforms = if options.platform.is_empty() {
+            let docker_bin = utils::get_docker_bin(options.docker_bin.as_deref());
+            vec![utils::get_daemon_platform(&docker_bin).context(
+                "Architecture specific Nix packages need the architecture the image is built for, pass it with --platform",
+            )?]
+        } else {
+            options.platform.clone()
+        };
+        let mut archs = platforms
+            .iter()
+            // Platforms are `os/arch` with an optional variant, e.g. `linux/arm64/v8`
+            .map(|platform| platform.split('/').nth(1).unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        archs.sort();
+        archs.dedup();
+        let arch = match archs.as_slice() {
+            [arch] if !arch.is_empty() => arch.clone(),
+            _ => bail!(
+                "Architecture specific Nix packages can only be used when building for a single architecture, not {}",
+                platforms.join(", ")
+            ),
+        };
+
+        for phase in plan.phases.iter_mut().flat_map(BTreeMap::values_mut) {
+            let arch_pkgs = match phase
+                .arch_nix_pkgs
+                .take()
+                .and_then(|mut arch_nix_pkgs| arch_nix_pkgs.remove(&arch))
+            {
+                Some(arch_pkgs) if !arch_pkgs.is_empty() => arch_pkgs,
+                _ => continue,
+            };
+            let nix_pkgs = phase.nix_pkgs.get_or_insert_with(Vec::new);
+            for pkg in arch_pkgs {
+                if !nix_pkgs.contains(&pkg) {
+                    nix_pkgs.push(pkg);
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// The instruction that points the apt sources of the image at the `aptMirror` of the plan, if it sets one.
+    ///
+    /// The default Ubuntu and Debian archives are replaced in both the one-line and deb822 source files.
+    pub(crate) fn get_apt_mirror_command(&self) -> Result<String> {
+        let mirror = match &self.apt_mirror {
+            Some(mirror) => mirror.trim_end_matches('/'),
+            None => return Ok(String::new()),
+        };
+
+        let host = mirror
+            .strip_prefix("http://")
+            .or_else(|| mirror.strip_prefix("https://"))
+            .map(|rest| rest.split('/').next().unwrap_or_default());
+        match host {
+            None => bail!("The apt mirror `{mirror}` must be an http:// or https:// URL"),
+            Some("") => bail!("The apt mirror `{mirror}` must include a host"),
+            Some(_) => {}
+        }
+        if let Some(c) = mirror.chars().find(|c| {
+            c.is_whitespace() || ['\'', '"', '#', '\\', '&', ';', '|', '$', '`'].contains(c)
+        }) {
+            bail!("The apt mirror `{mirror}` cannot contain `{c}`");
+        }
+
+        let script = [
+            r"https\?://archive\.ubuntu\.com/ubuntu",
+            r"https\?://deb\.debian\.org/debian",
+        ]
+        .iter()
+        .map(|archive| format!(r"s#{archive}\([/ ]\|$\)#{mirror}\1#g"))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+        Ok(format!(
+            r"RUN find /etc/apt -maxdepth 2 -type f \( -name 'sources.list' -o -name '*.list' -o -name '*.sources' \) -exec sed -i '{script}' {{}} +"
+        ))
+    }
+
+    /// The `SHELL` instruction that the RUN instructions of the phases use, if the plan sets a `shell`.
+    pub(crate) fn get_shell_instruction(&self) -> Result<String> {
+        match &self.shell {
+            None => Ok(String::new()),
+            Some(shell) if shell.is_empty() || shell[0].trim().is_empty() => {
+                bail!("The shell must start with the program to run")
+            }
+            Some(shell) => Ok(format!("SHELL {}", serde_json::to_string(shell)?)),
+        }
+    }
+
     /// Copies the plan's static assets to the output directory.
     fn write_assets(&self, plan: &BuildPlan, output: &OutputDir) -> Result<()> {
         if let Some(assets) = &plan.static_assets {
@@ -304,6 +605,22 @@ impl BuildPlan {
 
     /// Returns a collection of apt packages required by all phases in the BuildPlan.
     fn all_apt_packages(&self) -> Vec<String> {
+        self.phases
+            .clone()
+            .unwrap_or_default()
+            .values()
+            .flat_map(|phase| {
+                [
+                    phase.apt_pkgs.clone().unwrap_or_default(),
+                    phase.build_apt_pkgs.clone().unwrap_or_default(),
+                ]
+                .concat()
+            })
+            .collect()
+    }
+
+    /// Returns the apt packages that are still needed when running the app.
+    fn runtime_apt_packages(&self) -> Vec<String> {
         self.phases
             .clone()
             .unwrap_or_default()
@@ -321,48 +638,449 @@ impl DockerfileGenerator for StartPhase {
         _env: &Environment,
         _output: &OutputDir,
         _file_server_config: Option<FileServerConfig>,
+        _incremental_cache: &IncrementalCache,
+    ) -> Result<String> {
+        self.generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+    }
+}
+
+impl StartPhase {
+    /// Write the StartPhase data to the Dockerfile.
+    ///
+    /// The run image gets the given apt packages, from the apt sources of the build image, and the variables declared by `args_string`.
+    /// The app is copied from `app_dir` of the build image to the same directory of the run image.
+    fn generate_start_dockerfile(
+        &self,
+        runtime_apt_pkgs: &[String],
+        apt_sources: &[AptSource],
+        apt_mirror_cmd: &str,
+        args_string: &str,
+        app_dir: &str,
     ) -> Result<String> {
-        let start_cmd = match &self.cmd {
-            Some(cmd) => utils::get_exec_command(cmd),
-            None => String::new(),
+        let shell_less = self
+            .run_image
+            .as_deref()
+            .is_some_and(images::is_shell_less_image);
+
+        // The start command is passed to the entrypoint as arguments, so it is run by a shell explicitly
+        let cmd = self.get_start_cmd()?;
+        let start_cmd = match (&cmd, &self.entrypoint) {
+            (_, Some(_)) if shell_less => bail!(
+                "Cannot use an entrypoint in {}, which has no shell",
+                self.run_image.clone().unwrap_or_default()
+            ),
+            (Some(cmd), Some(_)) => format!(
+                "CMD {}",
+                serde_json::to_string(&["/bin/bash", "-l", "-c", cmd])?
+            ),
+            (Some(_), None) if shell_less && cmd != self.cmd => bail!(
+                "Cannot select between processes in {}, which has no shell",
+                self.run_image.clone().unwrap_or_default()
+            ),
+            (Some(cmd), None) if shell_less => utils::get_exec_args_command(cmd)?,
+            (Some(cmd), None) => utils::get_exec_command(cmd),
+            (None, _) => String::new(),
+        };
+        let start_cmd = match &self.healthcheck {
+            Some(healthcheck) => format!("{}\n{start_cmd}", healthcheck.get_instruction()?),
+            None => start_cmd,
+        };
+        let start_cmd = [
+            self.get_volume_instructions(shell_less)?,
+            self.get_expose_instructions()?,
+            self.entrypoint
+                .as_ref()
+                .map(Entrypoint::get_instructions)
+                .transpose()?
+                .unwrap_or_default(),
+            self.user
+                .as_ref()
+                .map(|user| format!("USER {user}"))
+                .unwrap_or_default(),
+            start_cmd,
+            get_extra_instructions(&self.extra_instructions.clone().unwrap_or_default())?,
+        ]
+        .into_iter()
+        .filter(|stmt| !stmt.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+        let create_user = self.create_user.unwrap_or(false);
+        let user_setup_cmd = match &self.user {
+            Some(user) if !shell_less => {
+                utils::get_user_setup_command(user, create_user, app_dir, None, false)?
+            }
+            _ => String::new(),
+        };
+        let chown_copy_cmds = |copy_cmds: Vec<String>| match &self.user {
+            Some(user) => utils::chown_copy_commands(&copy_cmds.join("\n"), user),
+            None => copy_cmds.join("\n"),
         };
 
         let dockerfile: String = match &self.run_image {
+            Some(run_image) if shell_less => {
+                if !runtime_apt_pkgs.is_empty() {
+                    bail!("Cannot install apt packages in {run_image}, which has no shell");
+                }
+                if create_user {
+                    bail!("Cannot create a user in {run_image}, which has no shell");
+                }
+
+                let copy_cmds = chown_copy_cmds(utils::get_copy_from_commands(
+                    "0",
+                    &self.only_include_files.clone().unwrap_or_default(),
+                    app_dir,
+                ));
+
+                formatdoc! {"
+                  # start
+                  FROM {run_image}
+                  ENTRYPOINT []
+                  WORKDIR {app_dir}
+                  {args_string}
+                  {copy_cmds}
+                  {start_cmd}
+                ",
+                run_image=run_image,
+                app_dir=app_dir,
+                args_string=args_string,
+                copy_cmds=copy_cmds,
+                start_cmd=start_cmd,}
+            }
             Some(run_image) => {
-                let copy_cmds = utils::get_copy_from_commands(
+                let copy_cmds = chown_copy_cmds(utils::get_copy_from_commands(
                     "0",
                     &self.only_include_files.clone().unwrap_or_default(),
-                    APP_DIR,
-                );
+                    app_dir,
+                ));
+
+                let mut apt_pkgs_str = utils::get_apt_install_command(runtime_apt_pkgs);
+                if !runtime_apt_pkgs.is_empty() && !apt_mirror_cmd.is_empty() {
+                    apt_pkgs_str = format!("{apt_mirror_cmd}\n{apt_pkgs_str}");
+                }
+                if !runtime_apt_pkgs.is_empty() && !apt_sources.is_empty() {
+                    apt_pkgs_str = format!(
+                        "COPY --from=0 /etc/apt/keyrings /etc/apt/keyrings\nCOPY --from=0 /etc/apt/sources.list.d /etc/apt/sources.list.d\n{apt_pkgs_str}"
+                    );
+                }
 
                 // RUN true to prevent a Docker bug https://github.com/moby/moby/issues/37965#issuecomment-426853382
                 formatdoc! {"
                   # start
                   FROM {run_image}
                   ENTRYPOINT [\"/bin/bash\", \"-l\", \"-c\"]
-                  WORKDIR {APP_DIR}
+                  WORKDIR {app_dir}
                   COPY --from=0 /etc/ssl/certs /etc/ssl/certs
                   RUN true
+                  {apt_pkgs_str}
+                  {args_string}
+                  {user_setup_cmd}
                   {copy_cmds}
                   {start_cmd}
                 ",
                 run_image=run_image,
-                APP_DIR=APP_DIR,
-                copy_cmds=copy_cmds.join("\n"),
+                app_dir=app_dir,
+                apt_pkgs_str=apt_pkgs_str,
+                args_string=args_string,
+                user_setup_cmd=user_setup_cmd,
+                copy_cmds=copy_cmds,
                 start_cmd=start_cmd,}
             }
             None => {
+                let copy_cmds = [
+                    user_setup_cmd,
+                    chown_copy_cmds(vec![format!("COPY . {}", app_dir.trim_end_matches('/'))]),
+                ]
+                .into_iter()
+                .filter(|stmt| !stmt.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+
                 formatdoc! {"
                   # start
-                  COPY . /app
-                  {}
+                  {copy_cmds}
+                  {start_cmd}
                 ",
-                start_cmd}
+                copy_cmds=copy_cmds,
+                start_cmd=start_cmd}
             }
         };
 
         Ok(dockerfile)
     }
+
+    /// An `EXPOSE` instruction for each of the `exposePorts` of the start phase.
+    pub(crate) fn get_expose_instructions(&self) -> Result<String> {
+        let mut ports = self.expose_ports.clone().unwrap_or_default();
+        if ports.contains(&0) {
+            bail!("Exposed ports must be between 1 and 65535");
+        }
+        ports.sort_unstable();
+        ports.dedup();
+
+        Ok(ports
+            .iter()
+            .map(|port| format!("EXPOSE {port}"))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    /// The instructions that declare the `writablePaths` of the start phase as volumes, for running with a read-only root filesystem.
+    ///
+    /// Unless the image has no shell, the paths are created first and owned by the user of the start phase, so that the app can write to them.
+    pub(crate) fn get_volume_instructions(&self, shell_less: bool) -> Result<String> {
+        let paths = self.writable_paths.clone().unwrap_or_default();
+        if paths.is_empty() {
+            return Ok(String::new());
+        }
+
+        for path in &paths {
+            if !path.starts_with('/') {
+                bail!("The writable path `{path}` must be an absolute path");
+            }
+            if path.split('/').any(|segment| segment == "..") {
+                bail!("The writable path `{path}` cannot contain `..` segments");
+            }
+            if path.split('/').all(str::is_empty) {
+                bail!("The filesystem root cannot be a writable path");
+            }
+            if let Some(c) = path.chars().find(|c| {
+                c.is_whitespace() || ['\'', '"', '\\', '$', '`', ';', '&', '|'].contains(c)
+            }) {
+                bail!("The writable path `{path}` cannot contain `{c}`");
+            }
+        }
+
+        let volume = format!("VOLUME {}", serde_json::to_string(&paths)?);
+        if shell_less {
+            return Ok(volume);
+        }
+
+        let paths = paths.join(" ");
+        let create_paths = match &self.user {
+            Some(user) => format!("RUN mkdir -p {paths} && chown -R {user} {paths}"),
+            None => format!("RUN mkdir -p {paths}"),
+        };
+
+        Ok(format!("{create_paths}\n{volume}"))
+    }
+}
+
+/// The local copy of nixpkgs to install Nix packages from instead of fetching the archive of each phase.
+fn get_local_nixpkgs(options: &DockerBuilderOptions) -> Result<Option<LocalNixpkgs>> {
+    options
+        .nixpkgs_path
+        .as_deref()
+        .map(LocalNixpkgs::from_path)
+        .transpose()
+}
+
+/// Join the commands of a phase into a single RUN instruction to reduce the number of layers, unless `split_commands` is set.
+///
+/// Each command runs in its own subshell, so that a failing command fails the instruction and changes to the working directory do not carry over, as with separate instructions.
+fn get_phase_run_commands(cmds: &[String], split_commands: bool) -> Vec<String> {
+    if split_commands || cmds.len() <= 1 {
+        cmds.to_vec()
+    } else {
+        vec![cmds
+            .iter()
+            .map(|cmd| format!("({cmd})"))
+            .collect::<Vec<_>>()
+            .join(" && ")]
+    }
+}
+
+/// Only run the commands if all of the files exist when the image is built.
+///
+/// The check happens in the shell of each RUN instruction, rather than when generating the Dockerfile, so that files created by earlier phases are found.
+fn guard_with_files(cmds: Vec<String>, files: &[String]) -> Result<Vec<String>> {
+    if files.is_empty() {
+        return Ok(cmds);
+    }
+
+    let mut conditions = Vec::new();
+    for file in files {
+        if file.is_empty() || file.contains('\n') {
+            bail!("`{file}` is not a valid file name for onlyIfFiles");
+        }
+
+        // Single quote the file name, ending the quotes around any single quotes in it
+        conditions.push(format!("test -e '{}'", file.replace('\'', "'\\''")));
+    }
+    let condition = conditions.join(" && ");
+
+    Ok(cmds
+        .into_iter()
+        .map(|cmd| format!("if {condition}; then {cmd}; fi"))
+        .collect())
+}
+
+/// Join the extra Dockerfile instructions of a phase, which must not start a new stage.
+fn get_extra_instructions(instructions: &[String]) -> Result<String> {
+    for instruction in instructions {
+        let keyword = instruction.split_whitespace().next().unwrap_or_default();
+        if keyword.eq_ignore_ascii_case("FROM") {
+            bail!("Extra instruction `{instruction}` cannot start a new build stage");
+        }
+    }
+
+    Ok(instructions.join("\n"))
+}
+
+impl Entrypoint {
+    /// The instructions that copy in the entrypoint script, if it is a file, and set the `ENTRYPOINT`.
+    ///
+    /// Both kinds of entrypoint run in a login shell, so that scripts do not need to be executable.
+    fn get_instructions(&self) -> Result<String> {
+        match (&self.cmd, &self.file) {
+            (Some(cmd), None) => {
+                // `--` becomes `$0`, so the start command is all of `$@`
+                let script = format!("{cmd} && exec \"$@\"");
+                Ok(format!(
+                    "ENTRYPOINT {}",
+                    serde_json::to_string(&["/bin/bash", "-l", "-c", &script, "--"])?
+                ))
+            }
+            (None, Some(file)) => {
+                let path = Path::new(file);
+                if file.trim().is_empty()
+                    || path.is_absolute()
+                    || path
+                        .components()
+                        .any(|c| c == std::path::Component::ParentDir)
+                {
+                    bail!("The entrypoint file `{file}` must be a path within the app");
+                }
+
+                Ok(format!(
+                    "COPY {}\nENTRYPOINT {}",
+                    serde_json::to_string(&[file.as_str(), ENTRYPOINT_FILE_PATH])?,
+                    serde_json::to_string(&["/bin/bash", "-l", ENTRYPOINT_FILE_PATH])?
+                ))
+            }
+            _ => bail!("An entrypoint must have either a command or a file"),
+        }
+    }
+}
+
+impl Healthcheck {
+    /// The `HEALTHCHECK` instruction for this healthcheck.
+    fn get_instruction(&self) -> Result<String> {
+        let Some(cmd) = &self.cmd else {
+            bail!("A healthcheck must have a command");
+        };
+
+        let mut options = Vec::new();
+        for (option, duration) in [
+            ("interval", &self.interval),
+            ("timeout", &self.timeout),
+            ("start-period", &self.start_period),
+        ] {
+            if let Some(duration) = duration {
+                if !utils::is_valid_duration(duration) {
+                    bail!(
+                        "Invalid healthcheck {option} `{duration}`, expected a duration like `30s`"
+                    );
+                }
+                options.push(format!("--{option}={duration}"));
+            }
+        }
+        if let Some(retries) = self.retries {
+            options.push(format!("--retries={retries}"));
+        }
+
+        options.push(format!("CMD {cmd}"));
+        Ok(format!("HEALTHCHECK {}", options.join(" ")))
+    }
+}
+
+impl AptSource {
+    /// The `RUN` instruction that downloads the key of this source and adds it to the apt sources as `name`.
+    fn get_install_command(&self, name: &str) -> Result<String> {
+        let deb = self.deb.trim();
+        if deb.contains(['\'', '\n']) {
+            bail!("Apt source `{deb}` cannot contain quotes or newlines");
+        }
+
+        let (kind, rest) = deb.split_once(' ').unwrap_or((deb, ""));
+        if kind != "deb" && kind != "deb-src" {
+            bail!("Apt source `{deb}` must start with `deb` or `deb-src`");
+        }
+
+        let rest = rest.trim_start();
+        let (mut source_options, rest) = match rest.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((source_options, rest)) => (
+                    source_options
+                        .split_whitespace()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>(),
+                    rest,
+                ),
+                None => bail!("Apt source `{deb}` has unclosed options"),
+            },
+            None => (Vec::new(), rest),
+        };
+
+        let fields = rest.split_whitespace().collect::<Vec<_>>();
+        match fields.first() {
+            Some(uri) if is_http_url(uri) && fields.len() > 1 => {}
+            _ => bail!("Apt source `{deb}` must have an http(s) URL and a suite"),
+        }
+
+        let mut cmds = Vec::new();
+        if let Some(key_url) = &self.key_url {
+            if !is_http_url(key_url) || key_url.contains(['\'', '"', ' ']) {
+                bail!("Apt source key `{key_url}` must be an http(s) URL");
+            }
+            if source_options
+                .iter()
+                .any(|option| option.starts_with("signed-by="))
+            {
+                bail!("Apt source `{deb}` cannot set `signed-by` and a key URL");
+            }
+
+            // apt reads binary keys from `.gpg` files and ASCII armored keys from `.asc` files
+            let file_name = key_url
+                .split(['?', '#'])
+                .next()
+                .unwrap_or_default()
+                .rsplit('/')
+                .next()
+                .unwrap_or_default();
+            let extension = match file_name.rsplit_once('.') {
+                Some((_, "gpg")) => "gpg",
+                Some((_, "asc" | "key")) | None => "asc",
+                Some((_, extension)) => bail!(
+                    "Apt source key `{key_url}` has an unknown format `.{extension}`, expected a `.asc` or `.gpg` key"
+                ),
+            };
+
+            let key_path = format!("/etc/apt/keyrings/{name}.{extension}");
+            cmds.push(format!(
+                "mkdir -p /etc/apt/keyrings && curl -fsSL {key_url} -o {key_path}"
+            ));
+            source_options.push(format!("signed-by={key_path}"));
+        }
+
+        let entry = if source_options.is_empty() {
+            format!("{kind} {}", fields.join(" "))
+        } else {
+            format!("{kind} [{}] {}", source_options.join(" "), fields.join(" "))
+        };
+        cmds.push(format!(
+            "echo '{entry}' > /etc/apt/sources.list.d/{name}.list"
+        ));
+
+        Ok(format!("RUN {}", cmds.join(" && ")))
+    }
+}
+
+/// Whether the given string is an http or https URL.
+fn is_http_url(url: &str) -> bool {
+    url.strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .is_some_and(|rest| !rest.is_empty())
 }
 
 impl DockerfileGenerator for Phase {
@@ -373,6 +1091,40 @@ impl DockerfileGenerator for Phase {
         env: &Environment,
         _output: &OutputDir,
         file_server_config: Option<FileServerConfig>,
+        incremental_cache: &IncrementalCache,
+    ) -> Result<String> {
+        self.generate_phase_dockerfile(
+            options,
+            env,
+            file_server_config,
+            incremental_cache,
+            &[],
+            APP_DIR,
+        )
+    }
+
+    fn write_supporting_files(
+        &self,
+        _options: &DockerBuilderOptions,
+        _env: &Environment,
+        _output: &OutputDir,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Phase {
+    /// Write the Phase data to the Dockerfile, mounting the given BuildKit secrets into its commands.
+    ///
+    /// The app files are copied to `app_dir`, which relative cache directories are also resolved against.
+    fn generate_phase_dockerfile(
+        &self,
+        options: &DockerBuilderOptions,
+        env: &Environment,
+        file_server_config: Option<FileServerConfig>,
+        incremental_cache: &IncrementalCache,
+        secrets: &[String],
+        app_dir: &str,
     ) -> Result<String> {
         if !self.runs_docker_commands() {
             return Ok(format!("# {} phase\n# noop\n", self.get_name()));
@@ -399,50 +1151,146 @@ impl DockerfileGenerator for Phase {
             (_, Some(files)) => files.clone(),
             _ => vec![".".to_string()],
         };
-        let phase_copy_cmds = utils::get_copy_commands(&phase_files, APP_DIR);
+        let phase_copy_cmds = utils::get_copy_commands(&phase_files, app_dir);
+
+        // Cache directories under `~` belong to the user that the phase runs as
+        let user_home_dir = phase.user.as_deref().and_then(utils::get_user_home_dir);
+        let home_dir = user_home_dir
+            .clone()
+            .or_else(|| options.home_dir.clone())
+            .unwrap_or_else(|| utils::DEFAULT_HOME_DIR.to_string());
+        let caches_in_home = phase
+            .cache_directories
+            .iter()
+            .chain(phase.cache_mounts.iter())
+            .flatten()
+            .any(|dir| dir == "~" || dir.starts_with("~/"));
+
+        // Cache mounts are always BuildKit cache mounts, while the cache directories are uploaded when using an incremental cache
+        let create_user = phase.create_user.unwrap_or(false);
+        let owner_options =
+            utils::get_cache_mount_owner_options(phase.user.as_deref(), create_user);
+        let cache_mounts = phase.cache_mounts.clone().unwrap_or_default();
+        let cache_mount = if options.incremental_cache_image.is_some() {
+            let cache_directories = phase.cache_directories.clone().unwrap_or_default();
+            if let Some(dir) = cache_mounts
+                .iter()
+                .find(|dir| cache_directories.contains(dir))
+            {
+                bail!("`{dir}` cannot be both a cache mount and an incremental cache directory");
+            }
 
-        let cache_mount = utils::get_cache_mount(&cache_key, &phase.cache_directories);
+            utils::get_cache_mount(
+                &cache_key,
+                &phase.cache_mounts,
+                &home_dir,
+                app_dir,
+                &owner_options,
+            )
+        } else {
+            let mut dirs = phase.cache_directories.clone().unwrap_or_default();
+            dirs.extend(
+                cache_mounts
+                    .into_iter()
+                    .filter(|dir| !dirs.contains(dir))
+                    .collect::<Vec<_>>(),
+            );
+            utils::get_cache_mount(
+                &cache_key,
+                &Some(dirs).filter(|dirs| !dirs.is_empty()),
+                &home_dir,
+                app_dir,
+                &owner_options,
+            )
+        };
+        let env_prefix = utils::get_env_prefix(&phase.variables.clone().unwrap_or_default());
+        let secret_mounts = utils::get_secret_mounts(secrets);
+        let secret_env_prefix = utils::get_secret_env_prefix(secrets);
+        let phase_cmds = get_phase_run_commands(
+            &phase.cmds.clone().unwrap_or_default(),
+            options.split_phase_commands,
+        );
+        let phase_cmds =
+            guard_with_files(phase_cmds, &phase.only_if_files.clone().unwrap_or_default())?
+                .iter()
+                .map(|cmd| format!("{secret_mounts}{secret_env_prefix}{env_prefix}{cmd}"))
+                .collect::<Vec<_>>();
         let cmds_str = if options.incremental_cache_image.is_some() {
             let image = &options.incremental_cache_image.clone().unwrap();
-            let cache_copy_in_command = if IncrementalCache::is_image_exists(image)? {
-                IncrementalCache::get_copy_to_image_command(&phase.cache_directories, image)
-                    .join("\n")
+            let cache_copy_in_command = if incremental_cache.is_image_exists(image)? {
+                IncrementalCache::get_copy_to_image_command(
+                    &phase.cache_directories,
+                    image,
+                    &home_dir,
+                )
+                .join("\n")
             } else {
                 String::new()
             };
 
             let cache_copy_out_command = IncrementalCache::get_copy_from_image_command(
                 &phase.cache_directories,
+                &phase.cache_excludes.clone().unwrap_or_default(),
                 file_server_config,
-            );
+                &home_dir,
+                options.incremental_cache_compression,
+                options.incremental_cache_collapse_commands,
+            )?;
 
-            let run_commands = [
-                phase.cmds.clone().unwrap_or_default(),
-                cache_copy_out_command,
-            ]
-            .concat()
-            .iter()
-            .map(|s| format!("RUN {s}"))
-            .collect::<Vec<_>>()
-            .join("\n");
+            let phase_cmds = if cache_mount.is_empty() {
+                phase_cmds
+            } else {
+                phase_cmds
+                    .iter()
+                    .map(|cmd| format!("{cache_mount} {cmd}"))
+                    .collect()
+            };
+            let run_commands = [phase_cmds, cache_copy_out_command]
+                .concat()
+                .iter()
+                .map(|s| format!("RUN {s}"))
+                .collect::<Vec<_>>()
+                .join("\n");
 
             format!("{cache_copy_in_command}\n{run_commands}")
         } else {
-            phase
-                .cmds
-                .clone()
-                .unwrap_or_default()
+            phase_cmds
                 .iter()
                 .map(|s| format!("RUN {cache_mount} {s}"))
                 .collect::<Vec<_>>()
                 .join("\n")
         };
 
-        let dockerfile_stmts = vec![build_path, phase_copy_cmds.join("\n"), cmds_str]
-            .into_iter()
-            .filter(|stmt| !stmt.is_empty())
-            .collect::<Vec<_>>()
-            .join("\n");
+        let extra_instructions =
+            get_extra_instructions(&phase.extra_instructions.clone().unwrap_or_default())?;
+        let dockerfile_stmts = match &phase.user {
+            // Switch back to root afterwards so that the user does not carry over to later phases
+            Some(user) => vec![
+                build_path,
+                utils::get_user_setup_command(
+                    user,
+                    create_user,
+                    app_dir,
+                    user_home_dir.as_deref().filter(|_| caches_in_home),
+                    create_user && !cache_mount.is_empty(),
+                )?,
+                utils::chown_copy_commands(&phase_copy_cmds.join("\n"), user),
+                format!("USER {user}"),
+                utils::chown_copy_commands(&cmds_str, user),
+                extra_instructions,
+                "USER root".to_string(),
+            ],
+            None => vec![
+                build_path,
+                phase_copy_cmds.join("\n"),
+                cmds_str,
+                extra_instructions,
+            ],
+        }
+        .into_iter()
+        .filter(|stmt| !stmt.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
 
         let dockerfile = formatdoc! {"
             # {name} phase
@@ -454,20 +1302,16 @@ impl DockerfileGenerator for Phase {
 
         Ok(dockerfile)
     }
-
-    fn write_supporting_files(
-        &self,
-        _options: &DockerBuilderOptions,
-        _env: &Environment,
-        _output: &OutputDir,
-    ) -> Result<()> {
-        Ok(())
-    }
-}
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nixpacks::{
+        builder::docker::fake_cache_backend::FakeCacheBackend, environment::EnvironmentVariables,
+        nix::pkg::Pkg,
+    };
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_phase_generation() {
@@ -481,12 +1325,41 @@ mod tests {
                 &Environment::default(),
                 &OutputDir::default(),
                 Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
             )
             .unwrap();
 
         assert!(dockerfile.contains("echo test"));
     }
 
+    #[test]
+    fn test_lockfile_is_copied_before_install() {
+        let plan = crate::generate_build_plan(
+            "./examples/node",
+            Vec::new(),
+            &crate::nixpacks::plan::generator::GeneratePlanOptions::default(),
+        )
+        .unwrap();
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        let lockfile_copy = dockerfile
+            .find("COPY package-lock.json /app/package-lock.json")
+            .unwrap();
+        let install = dockerfile.find("npm ci").unwrap();
+        let app_copy = dockerfile.find("COPY . /app").unwrap();
+        assert!(lockfile_copy < install);
+        assert!(install < app_copy);
+    }
+
     #[test]
     fn test_plan_generation() {
         let mut plan = BuildPlan::default();
@@ -506,6 +1379,7 @@ mod tests {
                 &Environment::default(),
                 &OutputDir::default(),
                 Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
             )
             .unwrap();
 
@@ -514,4 +1388,1218 @@ mod tests {
         assert!(dockerfile.contains("apt-get update"));
         assert!(dockerfile.contains("wget"));
     }
+
+    #[test]
+    fn test_nix_file_per_phase_archive() {
+        let mut plan = BuildPlan::default();
+
+        let mut setup = Phase::setup(Some(vec![Pkg::new("nodejs")]));
+        setup.set_nix_archive("new-archive".to_string());
+        plan.add_phase(setup);
+
+        let mut toolchain = Phase::new("toolchain");
+        toolchain.add_nix_pkgs(&[Pkg::new("gcc")]);
+        toolchain.set_nix_archive("old-archive".to_string());
+        plan.add_phase(toolchain);
+
+        let dir = tempdir::TempDir::new("nixpacks-output").unwrap();
+        let output = OutputDir::new(dir.path().to_path_buf(), false).unwrap();
+        output.ensure_output_exists().unwrap();
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &output,
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+        plan.write_supporting_files(
+            &DockerBuilderOptions::default(),
+            &Environment::default(),
+            &output,
+        )
+        .unwrap();
+
+        for (archive, pkg) in [("new-archive", "nodejs"), ("old-archive", "gcc")] {
+            let nix_file = format!(".nixpacks/nixpkgs-{archive}.nix");
+            assert!(dockerfile.contains(&format!("RUN nix-env -if {nix_file}")));
+
+            let expression = fs::read_to_string(dir.path().join(nix_file)).unwrap();
+            assert!(expression.contains(&format!("nixpkgs/archive/{archive}.tar.gz")));
+            assert!(expression.contains(pkg));
+        }
+    }
+
+    #[test]
+    fn test_local_nixpkgs_is_copied_before_nix_install() {
+        let mut plan = BuildPlan::default();
+        plan.add_phase(Phase::setup(Some(vec![Pkg::new("nodejs")])));
+
+        let nixpkgs_dir = tempdir::TempDir::new("nixpacks-nixpkgs").unwrap();
+        let tarball = nixpkgs_dir.path().join("nixpkgs.tar.gz");
+        fs::write(&tarball, "tarball").unwrap();
+        let options = DockerBuilderOptions {
+            nixpkgs_path: Some(tarball.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+
+        let dir = tempdir::TempDir::new("nixpacks-output").unwrap();
+        let output = OutputDir::new(dir.path().to_path_buf(), false).unwrap();
+        output.ensure_output_exists().unwrap();
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &options,
+                &Environment::default(),
+                &output,
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+        plan.write_supporting_files(&options, &Environment::default(), &output)
+            .unwrap();
+
+        let copy = dockerfile
+            .find("COPY .nixpacks/nixpkgs.tar.gz /nixpacks/nixpkgs.tar.gz")
+            .unwrap();
+        assert!(
+            copy < dockerfile
+                .find("RUN nix-env -if .nixpacks/nixpkgs.nix")
+                .unwrap()
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join(".nixpacks/nixpkgs.tar.gz")).unwrap(),
+            "tarball"
+        );
+
+        let expression = fs::read_to_string(dir.path().join(".nixpacks/nixpkgs.nix")).unwrap();
+        assert!(expression.contains("fetchTarball \"file:///nixpacks/nixpkgs.tar.gz\""));
+
+        let missing = DockerBuilderOptions {
+            nixpkgs_path: Some("./does-not-exist".to_string()),
+            ..Default::default()
+        };
+        assert!(plan
+            .generate_dockerfile(
+                &missing,
+                &Environment::default(),
+                &output,
+                None,
+                &IncrementalCache::default(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_phase_variables_not_in_final_stage() {
+        let mut plan = BuildPlan::default();
+        plan.add_variables(EnvironmentVariables::from([(
+            "GLOBAL".to_string(),
+            "yes".to_string(),
+        )]));
+
+        let mut build = Phase::new("build");
+        build.add_cmd("make");
+        build.add_variable("BUILD_TOKEN", "secret");
+        plan.add_phase(build);
+
+        let mut start = StartPhase::new("./app");
+        start.run_image = Some("ubuntu:jammy".to_string());
+        plan.set_start_phase(start);
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        assert!(dockerfile.contains("ENV GLOBAL=$GLOBAL"));
+        assert!(dockerfile.contains("RUN  export BUILD_TOKEN='secret' && make"));
+        assert_eq!(dockerfile.matches("BUILD_TOKEN").count(), 1);
+
+        let (_, run_stage) = dockerfile.split_once("FROM ubuntu:jammy").unwrap();
+        assert!(!run_stage.contains("BUILD_TOKEN"));
+    }
+
+    #[test]
+    fn test_start_cmd_variables() {
+        let plan = BuildPlan::from_toml(
+            r#"
+            secrets = ["API_TOKEN"]
+
+            [variables]
+            PORT = "8080"
+            API_TOKEN = "s3cr3t"
+
+            [start]
+            cmd = "serve --port ${PORT} --host ${HOST} --token ${API_TOKEN} --default ${PORT:-3000} $PORT"
+            "#,
+        )
+        .unwrap();
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        // Only the start command of the image has the values, the plan keeps the variables
+        assert!(dockerfile.contains(
+            "serve --port 8080 --host ${HOST} --token ${API_TOKEN} --default ${PORT:-3000} $PORT"
+        ));
+        assert!(plan
+            .start_phase
+            .unwrap()
+            .cmd
+            .unwrap()
+            .starts_with("serve --port ${PORT}"));
+    }
+
+    #[test]
+    fn test_build_apt_pkgs_not_installed_in_run_image() {
+        let mut plan = BuildPlan::default();
+
+        let mut build = Phase::new("build");
+        build.add_cmd("make");
+        build.add_apt_pkgs(vec!["libpq5".to_owned()]);
+        build.add_build_apt_pkgs(vec!["build-essential".to_owned()]);
+        plan.add_phase(build);
+
+        let mut start = StartPhase::new("./app");
+        start.run_image = Some("ubuntu:jammy".to_string());
+        plan.set_start_phase(start.clone());
+
+        let generate = |plan: &BuildPlan| {
+            plan.generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
+            )
+            .unwrap()
+        };
+
+        // Apt packages are only installed in the build image by default
+        let dockerfile = generate(&plan);
+        let (build_stage, run_stage) = dockerfile.split_once("FROM ubuntu:jammy").unwrap();
+        assert!(build_stage
+            .contains("apt-get install -y --no-install-recommends libpq5 build-essential"));
+        assert!(!run_stage.contains("apt-get"));
+
+        start.install_apt_pkgs = Some(true);
+        plan.set_start_phase(start);
+        let dockerfile = generate(&plan);
+        let (_, run_stage) = dockerfile.split_once("FROM ubuntu:jammy").unwrap();
+        assert!(run_stage.contains("apt-get install -y --no-install-recommends libpq5\n"));
+        assert!(!run_stage.contains("build-essential"));
+    }
+
+    #[test]
+    fn test_apt_source_commands() {
+        let mut plan = BuildPlan {
+            apt_sources: Some(vec![AptSource {
+                deb: "deb [arch=amd64] https://repo.example.com/apt stable main".to_string(),
+                key_url: Some("https://repo.example.com/apt/key.gpg".to_string()),
+            }]),
+            ..Default::default()
+        };
+
+        let mut build = Phase::new("build");
+        build.add_cmd("make");
+        build.add_apt_pkgs(vec!["vendor-tool".to_owned()]);
+        plan.add_phase(build);
+
+        let mut start = StartPhase::new("./app");
+        start.run_image = Some("ubuntu:jammy".to_string());
+        start.install_apt_pkgs = Some(true);
+        plan.set_start_phase(start);
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        let (build_stage, run_stage) = dockerfile.split_once("FROM ubuntu:jammy").unwrap();
+        assert!(build_stage.contains(
+            "RUN mkdir -p /etc/apt/keyrings && curl -fsSL https://repo.example.com/apt/key.gpg -o /etc/apt/keyrings/nixpacks-0.gpg && echo 'deb [arch=amd64 signed-by=/etc/apt/keyrings/nixpacks-0.gpg] https://repo.example.com/apt stable main' > /etc/apt/sources.list.d/nixpacks-0.list\nRUN apt-get update && apt-get install -y --no-install-recommends vendor-tool"
+        ));
+        assert!(run_stage.contains(
+            "COPY --from=0 /etc/apt/sources.list.d /etc/apt/sources.list.d\nRUN apt-get update"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_apt_sources() {
+        for (deb, key_url) in [
+            ("https://repo.example.com/apt stable main", None),
+            ("deb ftp://repo.example.com/apt stable main", None),
+            ("deb https://repo.example.com/apt", None),
+            ("deb https://repo.example.com/apt stable main", Some("repo.example.com/key.gpg")),
+            ("deb https://repo.example.com/apt stable main", Some("https://repo.example.com/key.pem")),
+            (
+                "deb [signed-by=/usr/share/keyrings/repo.gpg] https://repo.example.com/apt stable main",
+                Some("https://repo.example.com/key.asc"),
+            ),
+        ] {
+            let source = AptSource {
+                deb: deb.to_string(),
+                key_url: key_url.map(ToString::to_string),
+            };
+            assert!(source.get_install_command("nixpacks-0").is_err(), "{deb}");
+        }
+
+        let source = AptSource {
+            deb: "deb https://repo.example.com/apt stable main".to_string(),
+            key_url: None,
+        };
+        assert_eq!(
+            source.get_install_command("nixpacks-0").unwrap(),
+            "RUN echo 'deb https://repo.example.com/apt stable main' > /etc/apt/sources.list.d/nixpacks-0.list"
+        );
+    }
+
+    #[test]
+    fn test_healthcheck_instruction() {
+        let mut start = StartPhase::new("./app");
+        start.healthcheck = Some(Healthcheck {
+            cmd: Some("curl -f http://localhost:3000/health".to_string()),
+            interval: Some("30s".to_string()),
+            timeout: Some("5s".to_string()),
+            retries: Some(3),
+            start_period: Some("1m".to_string()),
+        });
+
+        let dockerfile = start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .unwrap();
+        assert!(dockerfile.contains(
+            "HEALTHCHECK --interval=30s --timeout=5s --start-period=1m --retries=3 CMD curl -f http://localhost:3000/health\nCMD [\"./app\"]"
+        ));
+
+        start.healthcheck.as_mut().unwrap().timeout = Some("5".to_string());
+        assert!(start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .is_err());
+    }
+
+    #[test]
+    fn test_start_image_override() {
+        let mut plan = BuildPlan::default();
+
+        let mut build = Phase::new("build");
+        build.add_cmd("go build -o out");
+        plan.add_phase(build);
+        plan.add_variables(EnvironmentVariables::from([(
+            "CGO_ENABLED".to_string(),
+            "0".to_string(),
+        )]));
+
+        let mut start = StartPhase::new("./out --port 8080");
+        start.run_image = Some("gcr.io/distroless/static".to_string());
+        start.add_file_dependency("./out");
+        plan.set_start_phase(start);
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        let (_, run_stage) = dockerfile
+            .split_once("FROM gcr.io/distroless/static\n")
+            .unwrap();
+        assert!(run_stage.contains("ENTRYPOINT []"));
+        assert!(run_stage.contains("ARG CGO_ENABLED\nENV CGO_ENABLED=$CGO_ENABLED"));
+        assert!(run_stage.contains("COPY --from=0 /app/out /app/out"));
+        assert!(run_stage.contains("CMD [\"./out\", \"--port\", \"8080\"]"));
+        assert!(!run_stage.contains("RUN "));
+        assert!(!run_stage.contains("COPY . /app"));
+    }
+
+    #[test]
+    fn test_phases_rendered_in_dependency_order() {
+        let generate = |phases: &[Phase]| {
+            BuildPlan::new(phases, None).generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
+            )
+        };
+        let phase = |name: &str, deps: &[&str]| {
+            let mut phase = Phase::new(name);
+            phase.add_cmd(format!("echo {name}"));
+            phase.depends_on = Some(deps.iter().map(ToString::to_string).collect());
+            phase
+        };
+
+        // Declared in reverse, `top` depends on both `left` and `right`, which depend on `base`
+        let dockerfile = generate(&[
+            phase("top", &["left", "right"]),
+            phase("right", &["base"]),
+            phase("left", &["base"]),
+            phase("base", &[]),
+        ])
+        .unwrap();
+        let positions = ["base", "left", "right", "top"]
+            .iter()
+            .map(|name| dockerfile.find(&format!("# {name} phase")).unwrap())
+            .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+
+        let err = generate(&[phase("a", &["b"]), phase("b", &["a"])]).unwrap_err();
+        assert!(format!("{err:#}").contains("a -> b -> a"));
+    }
+
+    #[test]
+    fn test_extra_instructions() {
+        let mut plan = BuildPlan::default();
+
+        let mut build = Phase::new("build");
+        build.add_cmd("make");
+        build.extra_instructions = Some(vec!["LABEL stage=build".to_string()]);
+        plan.add_phase(build);
+
+        let mut start = StartPhase::new("./app");
+        start.run_image = Some("ubuntu:jammy".to_string());
+        start.extra_instructions = Some(vec![
+            "EXPOSE 8080".to_string(),
+            "LABEL org.opencontainers.image.source=https://example.com".to_string(),
+        ]);
+        plan.set_start_phase(start);
+
+        let generate = |plan: &BuildPlan| {
+            plan.generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
+            )
+        };
+
+        let dockerfile = generate(&plan).unwrap();
+        let (build_stage, run_stage) = dockerfile.split_once("FROM ubuntu:jammy").unwrap();
+        assert!(build_stage.contains("RUN  make\nLABEL stage=build"));
+        assert!(run_stage.trim_end().ends_with(
+            "CMD [\"./app\"]\nEXPOSE 8080\nLABEL org.opencontainers.image.source=https://example.com"
+        ));
+
+        let mut start = plan.start_phase.clone().unwrap();
+        start.extra_instructions = Some(vec!["from alpine".to_string()]);
+        plan.set_start_phase(start);
+        assert!(generate(&plan).is_err());
+    }
+
+    #[test]
+    fn test_expose_ports() {
+        let mut start = StartPhase::new("./app");
+        start.expose_ports = Some(vec![9090, 8080]);
+        let plan = BuildPlan::new(&[Phase::build(Some("make".to_string()))], Some(start));
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+        assert!(dockerfile.contains("EXPOSE 8080\nEXPOSE 9090\n"));
+        assert_eq!(dockerfile.matches("EXPOSE").count(), 2);
+
+        let start = StartPhase {
+            expose_ports: Some(vec![0]),
+            ..StartPhase::default()
+        };
+        assert!(start.get_expose_instructions().is_err());
+    }
+
+    #[test]
+    fn test_writable_paths() {
+        let mut start = StartPhase::new("./app");
+        start.run_image = Some("ubuntu:jammy".to_string());
+        start.user = Some("app".to_string());
+        start.writable_paths = Some(vec!["/tmp".to_string(), "/app/cache".to_string()]);
+        let plan = BuildPlan::new(&[Phase::build(Some("make".to_string()))], Some(start));
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+        let (_, run_stage) = dockerfile.split_once("FROM ubuntu:jammy").unwrap();
+
+        // The paths are owned by the user before it is switched to
+        let volume = run_stage
+            .find("RUN mkdir -p /tmp /app/cache && chown -R app /tmp /app/cache\nVOLUME [\"/tmp\",\"/app/cache\"]\nUSER app")
+            .unwrap();
+        assert!(run_stage.find("COPY --from=0").unwrap() < volume);
+
+        // Images without a shell only declare the volumes
+        let start = StartPhase {
+            writable_paths: Some(vec!["/tmp".to_string()]),
+            ..StartPhase::default()
+        };
+        assert_eq!(
+            start.get_volume_instructions(true).unwrap(),
+            "VOLUME [\"/tmp\"]"
+        );
+
+        for path in ["tmp", "/", "/app/../tmp", "/my cache", "/tmp;rm"] {
+            let start = StartPhase {
+                writable_paths: Some(vec![path.to_string()]),
+                ..StartPhase::default()
+            };
+            assert!(start.get_volume_instructions(false).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_secret_mounts() {
+        let mut plan = BuildPlan {
+            secrets: Some(vec!["NPM_TOKEN".to_string()]),
+            ..Default::default()
+        };
+        plan.add_variables(EnvironmentVariables::from([
+            ("NPM_TOKEN".to_string(), "secret".to_string()),
+            ("NODE_ENV".to_string(), "production".to_string()),
+        ]));
+
+        let mut install = Phase::new("install");
+        install.add_cmd("npm ci");
+        plan.add_phase(install);
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        assert!(dockerfile.contains(
+            "RUN  --mount=type=secret,id=NPM_TOKEN export NPM_TOKEN=\"$(cat /run/secrets/NPM_TOKEN)\" && npm ci"
+        ));
+        assert!(dockerfile.contains("ARG NODE_ENV\nENV NODE_ENV=$NODE_ENV"));
+        assert!(!dockerfile.contains("ARG NPM_TOKEN"));
+        assert!(!dockerfile.contains("secret\n"));
+    }
+
+    #[test]
+    fn test_cache_mounts() {
+        let mut phase = Phase::new("install");
+        phase.add_cmd("npm ci");
+        phase.add_cache_directory("/root/.npm");
+        phase.cache_mounts = Some(vec!["/root/.cache/Cypress".to_string()]);
+
+        let generate = |phase: &Phase, incremental_cache_image: Option<&str>| {
+            phase.generate_dockerfile(
+                &DockerBuilderOptions {
+                    cache_key: Some("key".to_string()),
+                    incremental_cache_image: incremental_cache_image.map(ToString::to_string),
+                    ..Default::default()
+                },
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::new(Box::<FakeCacheBackend>::default()),
+            )
+        };
+
+        // Both are BuildKit cache mounts without an incremental cache
+        let dockerfile = generate(&phase, None).unwrap();
+        assert!(dockerfile.contains(
+            "RUN --mount=type=cache,id=key-/root/npm,target=/root/.npm --mount=type=cache,id=key-/root/cache/Cypress,target=/root/.cache/Cypress npm ci"
+        ));
+
+        // Only the cache mounts are BuildKit cache mounts with an incremental cache
+        let dockerfile = generate(&phase, Some("registry.test/cache")).unwrap();
+        assert!(dockerfile.contains(
+            "RUN --mount=type=cache,id=key-/root/cache/Cypress,target=/root/.cache/Cypress npm ci"
+        ));
+
+        phase.cache_mounts = Some(vec!["/root/.npm".to_string()]);
+        assert!(generate(&phase, Some("registry.test/cache")).is_err());
+        assert!(generate(&phase, None).is_ok());
+    }
+
+    #[test]
+    fn test_phase_user() {
+        let mut phase = Phase::new("install");
+        phase.add_cmd("npm ci");
+        phase.add_cache_directory("~/.npm");
+        phase.user = Some("app".to_string());
+        phase.create_user = Some(true);
+
+        let dockerfile = phase
+            .generate_dockerfile(
+                &DockerBuilderOptions {
+                    cache_key: Some("key".to_string()),
+                    incremental_cache_image: Some("registry.test/cache".to_string()),
+                    ..Default::default()
+                },
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::new(Box::<FakeCacheBackend>::default()),
+            )
+            .unwrap();
+
+        assert!(dockerfile.contains(
+            "RUN (id -u app >/dev/null 2>&1 || useradd --create-home --home-dir /home/app --uid 10001 app) && chown -R app /app/ && { [ \"$(getent passwd app | cut -d: -f6)\" = /home/app ] || { echo 'The home directory of app is not /home/app, where the cache directories under ~ are mounted' >&2; exit 1; }; }\nCOPY --chown=app . /app/.\nUSER app\n"
+        ));
+        assert!(dockerfile.contains("\nRUN npm ci\n"));
+        assert!(dockerfile.contains("if [ -d \"/home/app/.npm\" ]"));
+        assert!(!dockerfile.contains("/root/.npm"));
+        assert!(dockerfile.trim_end().ends_with("USER root"));
+
+        let mut start = StartPhase::new("npm start");
+        start.user = Some("app".to_string());
+        start.run_image = Some("node:18-slim".to_string());
+        let dockerfile = start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .unwrap();
+        assert!(dockerfile.contains(
+            "RUN chown -R app /app/\nCOPY --chown=app --from=0 /app/ /app/\nUSER app\nCMD [\"npm start\"]"
+        ));
+    }
+
+    #[test]
+    fn test_phase_user_cache_mounts() {
+        let mut phase = Phase::new("install");
+        phase.add_cmd("npm ci");
+        phase.add_cache_directory("~/.npm");
+        phase.user = Some("app".to_string());
+        phase.create_user = Some(true);
+
+        let generate = |phase: &Phase| {
+            phase
+                .generate_dockerfile(
+                    &DockerBuilderOptions {
+                        cache_key: Some("key".to_string()),
+                        ..Default::default()
+                    },
+                    &Environment::default(),
+                    &OutputDir::default(),
+                    None,
+                    &IncrementalCache::default(),
+                )
+                .unwrap()
+        };
+
+        // The cache directories are mounted for the created user, which fails the build if it already existed with another ID
+        let dockerfile = generate(&phase);
+        assert!(dockerfile.contains(
+            "RUN --mount=type=cache,id=key-/home/app/npm,target=/home/app/.npm,uid=10001,gid=10001 npm ci\n"
+        ));
+        assert!(dockerfile.contains("useradd --create-home --home-dir /home/app --uid 10001 app"));
+        assert!(dockerfile.contains("[ \"$(id -u app)\" = 10001 ]"));
+
+        phase.user = Some("1000:1000".to_string());
+        phase.create_user = None;
+        assert!(generate(&phase).contains(
+            "RUN --mount=type=cache,id=key-/home/1000/npm,target=/home/1000/.npm,uid=1000,gid=1000 npm ci\n"
+        ));
+    }
+
+    #[test]
+    fn test_phase_commands_joined() {
+        let mut phase = Phase::new("build");
+        phase.add_cmd("cd frontend && npm run build");
+        phase.add_cmd("cargo build --release");
+        phase.add_cmd("cp target/release/app bin");
+
+        let generate = |split_phase_commands: bool| {
+            phase
+                .generate_dockerfile(
+                    &DockerBuilderOptions {
+                        split_phase_commands,
+                        ..Default::default()
+                    },
+                    &Environment::default(),
+                    &OutputDir::default(),
+                    None,
+                    &IncrementalCache::default(),
+                )
+                .unwrap()
+        };
+
+        let joined = generate(false);
+        assert!(joined.contains(
+            "RUN  (cd frontend && npm run build) && (cargo build --release) && (cp target/release/app bin)\n"
+        ));
+        assert_eq!(joined.matches("RUN ").count(), 1);
+
+        let split = generate(true);
+        assert!(split.contains(
+            "RUN  cd frontend && npm run build\nRUN  cargo build --release\nRUN  cp target/release/app bin\n"
+        ));
+        assert_eq!(split.matches("RUN ").count(), 3);
+    }
+
+    #[test]
+    fn test_named_caches() {
+        let mut install = Phase::install(Some("npm ci".to_string()));
+        install.caches = Some(vec!["npm".to_string()]);
+        let mut build = Phase::build(Some("npm run build".to_string()));
+        build.caches = Some(vec!["npm".to_string()]);
+
+        let mut plan = BuildPlan::new(&[install, build], None);
+        plan.caches = Some(BTreeMap::from([(
+            "npm".to_string(),
+            vec!["/root/.npm".to_string()],
+        )]));
+
+        let generate = |plan: &BuildPlan| {
+            plan.generate_dockerfile(
+                &DockerBuilderOptions {
+                    incremental_cache_image: Some("registry.test/cache".to_string()),
+                    ..Default::default()
+                },
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::new(Box::new(FakeCacheBackend {
+                    all_images_exist: true,
+                    ..Default::default()
+                })),
+            )
+        };
+
+        let dockerfile = generate(&plan).unwrap();
+        assert_eq!(
+            dockerfile
+                .matches("COPY --from=registry.test/cache root?/.npm? /root/.npm")
+                .count(),
+            2
+        );
+
+        plan.caches = None;
+        assert_eq!(
+            generate(&plan).unwrap_err().to_string(),
+            "Phase `build` uses the cache `npm`, which is not defined"
+        );
+    }
+
+    #[test]
+    fn test_incremental_cache_nix_store() {
+        let setup = Phase::setup(Some(vec![Pkg::new("nodejs")]));
+        let plan = BuildPlan::new(&[setup], None);
+
+        let generate = |incremental_cache_nix_store: bool| {
+            plan.generate_dockerfile(
+                &DockerBuilderOptions {
+                    incremental_cache_image: Some("registry.test/cache".to_string()),
+                    incremental_cache_nix_store,
+                    ..Default::default()
+                },
+                &Environment::default(),
+                &OutputDir::default(),
+                Some(FileServerConfig::default()),
+                &IncrementalCache::new(Box::new(FakeCacheBackend {
+                    all_images_exist: true,
+                    ..Default::default()
+                })),
+            )
+            .unwrap()
+        };
+
+        let dockerfile = generate(true);
+        let copy_to = dockerfile
+            .find("COPY --from=registry.test/cache nix?/store? /nix/store")
+            .unwrap();
+        assert!(dockerfile
+            .contains("COPY --from=registry.test/cache nix?/var?/nix?/db? /nix/var/nix/db"));
+        let install = dockerfile.find("RUN nix-env -if").unwrap();
+        let copy_from = dockerfile
+            .find("RUN if [ -d \"/nix/store\" ]; then tar")
+            .unwrap();
+        assert!(copy_to < install && install < copy_from);
+        assert!(!dockerfile.contains("rm -rf /nix/store"));
+
+        assert!(!generate(false).contains("/nix/store"));
+    }
+
+    #[test]
+    fn test_only_if_files() {
+        let mut phase = Phase::new("prisma");
+        phase.add_cmd("npx prisma generate");
+        phase.only_if_files = Some(vec!["prisma/schema.prisma".to_string()]);
+
+        let dockerfile = phase
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+        assert!(dockerfile
+            .contains("RUN  if test -e 'prisma/schema.prisma'; then npx prisma generate; fi\n"));
+
+        phase.only_if_files = Some(vec![String::new()]);
+        assert!(phase
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_only_if_files_guard_runs_in_shell() {
+        let dir = tempdir::TempDir::new("nixpacks-only-if-files").unwrap();
+        let cmds =
+            guard_with_files(vec!["echo ran".to_string()], &["it's here.txt".to_string()]).unwrap();
+        let run = || {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(&cmds[0])
+                .current_dir(dir.path())
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        // The phase is skipped without failing the build when the file is absent
+        assert_eq!(run(), "");
+
+        std::fs::write(dir.path().join("it's here.txt"), "").unwrap();
+        assert_eq!(run(), "ran\n");
+    }
+
+    #[test]
+    fn test_custom_workdir() {
+        let mut build = Phase::build(Some("make".to_string()));
+        build.add_cache_directory("node_modules/.cache");
+        build.only_include_files = Some(vec!["src".to_string()]);
+        let mut start = StartPhase::new("./start");
+        start.run_image = Some("ubuntu:jammy".to_string());
+        let plan = BuildPlan {
+            workdir: Some("/srv/app".to_string()),
+            ..BuildPlan::new(&[Phase::setup(None), build], Some(start))
+        };
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions {
+                    cache_key: Some("key".to_string()),
+                    ..Default::default()
+                },
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        assert!(dockerfile.contains("WORKDIR /srv/app/"));
+        assert!(!dockerfile.contains("WORKDIR /app/"));
+        assert!(dockerfile.contains("COPY src /srv/app/src"));
+        assert!(dockerfile.contains("target=/srv/app/node_modules/.cache"));
+        assert!(dockerfile.contains("COPY --from=0 /srv/app/ /srv/app/"));
+    }
+
+    #[test]
+    fn test_arch_nix_pkgs() {
+        let mut setup = Phase::setup(Some(vec![Pkg::new("nodejs")]));
+        setup.add_arch_nix_pkgs("amd64", &[Pkg::new("gcc_multi")]);
+        setup.add_arch_nix_pkgs("arm64", &[Pkg::new("qemu")]);
+        let plan = BuildPlan::new(&[setup], None);
+
+        let dir = tempdir::TempDir::new("nixpacks-output").unwrap();
+        let output = OutputDir::new(dir.path().to_path_buf(), false).unwrap();
+        output.ensure_output_exists().unwrap();
+        let options = DockerBuilderOptions {
+            platform: vec!["linux/arm64".to_string()],
+            ..Default::default()
+        };
+        plan.write_supporting_files(&options, &Environment::default(), &output)
+            .unwrap();
+
+        // The amd64 only package is left out of the Nix expression for arm64
+        let nix_files = fs::read_dir(dir.path().join(NIXPACKS_OUTPUT_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "nix"))
+            .collect::<Vec<_>>();
+        assert_eq!(nix_files.len(), 1);
+        let expression = fs::read_to_string(&nix_files[0]).unwrap();
+        assert!(expression.contains("nodejs qemu"));
+        assert!(!expression.contains("gcc_multi"));
+
+        let platforms = |platforms: &[&str]| DockerBuilderOptions {
+            platform: platforms.iter().map(ToString::to_string).collect(),
+            ..Default::default()
+        };
+        let resolved = plan
+            .resolve_arch_nix_pkgs(&platforms(&["linux/amd64"]))
+            .unwrap();
+        assert_eq!(
+            resolved.get_phase("setup").unwrap().nix_pkgs,
+            Some(vec!["nodejs".to_string(), "gcc_multi".to_string()])
+        );
+
+        // A phase without Nix packages keeps none when there are no packages for the architecture
+        let mut build = Phase::build(Some("make".to_string()));
+        build.add_arch_nix_pkgs("arm64", &[Pkg::new("qemu")]);
+        let resolved = BuildPlan::new(&[build], None)
+            .resolve_arch_nix_pkgs(&platforms(&["linux/amd64"]))
+            .unwrap();
+        assert_eq!(resolved.get_phase("build").unwrap().nix_pkgs, None);
+
+        // A single set of Nix expressions cannot serve several architectures
+        assert!(plan
+            .resolve_arch_nix_pkgs(&platforms(&["linux/amd64", "linux/arm64"]))
+            .is_err());
+        assert!(plan
+            .resolve_arch_nix_pkgs(&platforms(&["linux/arm64", "linux/arm64/v8"]))
+            .is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_arch_nix_pkgs_use_daemon_architecture() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut setup = Phase::setup(Some(vec![Pkg::new("nodejs")]));
+        setup.add_arch_nix_pkgs("arm64", &[Pkg::new("qemu")]);
+        let plan = BuildPlan::new(&[setup], None);
+
+        // The daemon can run on another machine than nixpacks, so its architecture is used without --platform
+        let bin_dir = tempdir::TempDir::new("nixpacks-docker-bin").unwrap();
+        let docker_bin = bin_dir.path().join("docker");
+        fs::write(&docker_bin, "#!/bin/sh\necho aarch64\n").unwrap();
+        fs::set_permissions(&docker_bin, fs::Permissions::from_mode(0o755)).unwrap();
+        let options = DockerBuilderOptions {
+            docker_bin: Some(docker_bin.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let resolved = plan.resolve_arch_nix_pkgs(&options).unwrap();
+        assert_eq!(
+            resolved.get_phase("setup").unwrap().nix_pkgs,
+            Some(vec!["nodejs".to_string(), "qemu".to_string()])
+        );
+
+        // Without a daemon to ask, the platform has to be given
+        let options = DockerBuilderOptions {
+            docker_bin: Some("/nonexistent/bin/docker".to_string()),
+            ..Default::default()
+        };
+        let error = plan.resolve_arch_nix_pkgs(&options).unwrap_err();
+        assert!(error.to_string().contains("--platform"));
+    }
+
+    #[test]
+    fn test_bash_shell() {
+        let mut plan = BuildPlan {
+            shell: Some(vec![
+                "/bin/bash".to_string(),
+                "-euo".to_string(),
+                "pipefail".to_string(),
+                "-c".to_string(),
+            ]),
+            ..BuildPlan::new(&[Phase::build(Some("make".to_string()))], None)
+        };
+        plan.pin(false);
+
+        assert_eq!(
+            plan.get_phase("setup").unwrap().nix_pkgs,
+            Some(vec!["bash".to_string()])
+        );
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        let shell = dockerfile
+            .find("SHELL [\"/bin/bash\",\"-euo\",\"pipefail\",\"-c\"]")
+            .unwrap();
+        assert!(shell < dockerfile.find("RUN  make").unwrap());
+
+        // The default shell is unchanged
+        let dockerfile = BuildPlan::new(&[Phase::build(Some("make".to_string()))], None)
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+        assert!(!dockerfile.contains("SHELL"));
+    }
+
+    #[test]
+    fn test_invalid_workdir() {
+        for workdir in ["app", "/", "/srv/../app", "/my app"] {
+            let plan = BuildPlan {
+                workdir: Some(workdir.to_string()),
+                ..BuildPlan::default()
+            };
+            assert!(plan.get_app_dir().is_err(), "{workdir}");
+        }
+    }
+
+    #[test]
+    fn test_apt_mirror() {
+        let mut setup = Phase::setup(None);
+        setup.add_apt_pkgs(vec!["curl".to_string()]);
+        let plan = BuildPlan {
+            apt_mirror: Some("https://mirror.example.com/ubuntu/".to_string()),
+            ..BuildPlan::new(&[setup], None)
+        };
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        let rewrite = dockerfile
+            .find(r"s#https\?://archive\.ubuntu\.com/ubuntu\([/ ]\|$\)#https://mirror.example.com/ubuntu\1#g")
+            .unwrap();
+        assert!(rewrite < dockerfile.find("apt-get update").unwrap());
+
+        // Without apt packages the sources are not rewritten
+        let plan = BuildPlan {
+            apt_mirror: Some("https://mirror.example.com/ubuntu".to_string()),
+            ..BuildPlan::new(&[Phase::build(Some("make".to_string()))], None)
+        };
+        let dockerfile = plan
+            .generate_dockerfile(
+                &DockerBuilderOptions::default(),
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+        assert!(!dockerfile.contains("mirror.example.com"));
+    }
+
+    #[test]
+    fn test_invalid_apt_mirror() {
+        for mirror in [
+            "mirror.example.com/ubuntu",
+            "ftp://mirror.example.com/ubuntu",
+            "https:///ubuntu",
+            "https://mirror.example.com/ubuntu#g; s#x",
+            "https://mirror.example.com/$(id)",
+        ] {
+            let plan = BuildPlan {
+                apt_mirror: Some(mirror.to_string()),
+                ..BuildPlan::default()
+            };
+            assert!(plan.get_apt_mirror_command().is_err(), "{mirror}");
+        }
+    }
+
+    #[test]
+    fn test_build_args() {
+        let mut plan = BuildPlan::new(
+            &[Phase::build(Some("echo $API_URL".to_string()))],
+            Some(StartPhase::new("./start")),
+        );
+        plan.add_variables(EnvironmentVariables::from([(
+            "NODE_ENV".to_string(),
+            "production".to_string(),
+        )]));
+        let options = DockerBuilderOptions {
+            build_args: BTreeMap::from([(
+                "API_URL".to_string(),
+                "https://api.example.com".to_string(),
+            )]),
+            ..Default::default()
+        };
+
+        let dockerfile = plan
+            .generate_dockerfile(
+                &options,
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .unwrap();
+
+        assert!(dockerfile.contains("ARG NODE_ENV\nENV NODE_ENV=$NODE_ENV\nARG API_URL\n"));
+        assert!(!dockerfile.contains("API_URL=$API_URL"));
+
+        let options = DockerBuilderOptions {
+            build_args: BTreeMap::from([("NODE_ENV".to_string(), "test".to_string())]),
+            ..Default::default()
+        };
+        assert!(plan
+            .generate_dockerfile(
+                &options,
+                &Environment::default(),
+                &OutputDir::default(),
+                None,
+                &IncrementalCache::default(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_entrypoint_file() {
+        let mut start = StartPhase::new("npm start");
+        start.entrypoint = Some(Entrypoint {
+            file: Some("bin/entrypoint.sh".to_string()),
+            ..Default::default()
+        });
+
+        let dockerfile = start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .unwrap();
+
+        assert!(dockerfile.contains(
+            "COPY [\"bin/entrypoint.sh\",\"/usr/local/bin/nixpacks-entrypoint\"]\nENTRYPOINT [\"/bin/bash\",\"-l\",\"/usr/local/bin/nixpacks-entrypoint\"]\nCMD [\"/bin/bash\",\"-l\",\"-c\",\"npm start\"]"
+        ));
+    }
+
+    #[test]
+    fn test_entrypoint_cmd() {
+        let mut start = StartPhase::new("npm start");
+        start.run_image = Some("ubuntu:jammy".to_string());
+        start.entrypoint = Some(Entrypoint {
+            cmd: Some("npm run migrate".to_string()),
+            ..Default::default()
+        });
+
+        let dockerfile = start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .unwrap();
+
+        assert!(dockerfile.contains(
+            "ENTRYPOINT [\"/bin/bash\",\"-l\",\"-c\",\"npm run migrate && exec \\\"$@\\\"\",\"--\"]\nCMD [\"/bin/bash\",\"-l\",\"-c\",\"npm start\"]"
+        ));
+        assert!(!dockerfile.contains("COPY [\""));
+    }
+
+    #[test]
+    fn test_invalid_entrypoint() {
+        for entrypoint in [
+            Entrypoint::default(),
+            Entrypoint {
+                cmd: Some("migrate".to_string()),
+                file: Some("entrypoint.sh".to_string()),
+            },
+            Entrypoint {
+                file: Some("../entrypoint.sh".to_string()),
+                ..Default::default()
+            },
+        ] {
+            let mut start = StartPhase::new("./start");
+            start.entrypoint = Some(entrypoint);
+            assert!(start
+                .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+                .is_err());
+        }
+
+        let mut start = StartPhase::new("./start");
+        start.run_image = Some("gcr.io/distroless/static".to_string());
+        start.entrypoint = Some(Entrypoint {
+            cmd: Some("migrate".to_string()),
+            ..Default::default()
+        });
+        assert!(start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .is_err());
+    }
+
+    #[test]
+    fn test_start_processes() {
+        let mut start = StartPhase::new("npm start");
+        start.processes = Some(BTreeMap::from([
+            ("web".to_string(), "npm start".to_string()),
+            ("worker".to_string(), "npm run worker".to_string()),
+        ]));
+
+        let dockerfile = start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .unwrap();
+
+        assert!(dockerfile.contains(
+            "CMD [\"case \\\"${NIXPACKS_PROCESS:-}\\\" in '') eval 'npm start' ;; web) eval 'npm start' ;; worker) eval 'npm run worker' ;; *) echo \\\"Unknown process $NIXPACKS_PROCESS\\\" >&2; exit 1 ;; esac\"]"
+        ));
+
+        // Commands with quotes or case syntax in them run as they are
+        start.processes = Some(BTreeMap::from([
+            ("web".to_string(), "echo 'web;; esac'".to_string()),
+            ("worker".to_string(), "echo worker; echo done".to_string()),
+        ]));
+        start.cmd = Some("echo \"it's the default\"".to_string());
+        let cmd = start.get_start_cmd().unwrap().unwrap();
+        let run = |process: &str| {
+            let output = std::process::Command::new("sh")
+                .args(["-c", &cmd])
+                .env("NIXPACKS_PROCESS", process)
+                .output()
+                .unwrap();
+            String::from_utf8_lossy(&output.stdout).to_string()
+        };
+        assert_eq!(run("web"), "web;; esac\n");
+        assert_eq!(run("worker"), "worker\ndone\n");
+        assert_eq!(run(""), "it's the default\n");
+        assert_eq!(run("other"), "");
+
+        start.run_image = Some("gcr.io/distroless/static".to_string());
+        assert!(start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .is_err());
+
+        start.processes = Some(BTreeMap::from([(
+            "web; rm".to_string(),
+            "npm start".to_string(),
+        )]));
+        start.run_image = None;
+        assert!(start
+            .generate_start_dockerfile(&[], &[], "", "", APP_DIR)
+            .is_err());
+    }
 }
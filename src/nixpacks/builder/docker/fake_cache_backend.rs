@@ -0,0 +1,75 @@
+use super::incremental_cache::CacheBackend;
+use anyhow::{bail, Result};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// A cache backend for tests that records its calls instead of running Docker.
+#[derive(Clone, Default)]
+pub struct FakeCacheBackend {
+    /// Whether every image exists, instead of only the tags in `existing_images`.
+    pub all_images_exist: bool,
+    /// The tags of the images that exist.
+    pub existing_images: Vec<String>,
+    /// Pushing a tag, or importing a file whose name, starts with one of these prefixes fails.
+    pub failing_prefixes: Vec<String>,
+    /// Every call that was made, such as `push myuser/cache` or `create_image a.tar`, shared by the clones of the backend.
+    pub calls: Arc<Mutex<Vec<String>>>,
+    /// How long each image import takes.
+    pub import_delay: Duration,
+    /// The number of imports that are running, and the most that ran at the same time.
+    pub imports: Arc<Mutex<(usize, usize)>>,
+}
+
+impl FakeCacheBackend {
+    /// The calls that were made, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The most image imports that ran at the same time.
+    pub fn max_concurrent_imports(&self) -> usize {
+        self.imports.lock().unwrap().1
+    }
+
+    fn record(&self, call: String) -> Result<()> {
+        let name = call.split_once(' ').map_or("", |(_, name)| name);
+        let fails = self
+            .failing_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix.as_str()));
+        if fails {
+            bail!("{call} failed");
+        }
+
+        self.calls.lock().unwrap().push(call);
+        Ok(())
+    }
+}
+
+impl CacheBackend for FakeCacheBackend {
+    fn create_image(&self, file: &Path, _tag: &str) -> Result<()> {
+        {
+            let mut imports = self.imports.lock().unwrap();
+            imports.0 += 1;
+            imports.1 = imports.1.max(imports.0);
+        }
+        thread::sleep(self.import_delay);
+        self.imports.lock().unwrap().0 -= 1;
+
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        self.record(format!("create_image {name}"))
+    }
+
+    fn image_exists(&self, tag: &str) -> Result<bool> {
+        self.record(format!("image_exists {tag}"))?;
+        Ok(self.all_images_exist || self.existing_images.iter().any(|image| image == tag))
+    }
+
+    fn push(&self, tag: &str) -> Result<()> {
+        self.record(format!("push {tag}"))
+    }
+}
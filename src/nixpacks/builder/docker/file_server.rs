@@ -1,16 +1,21 @@
 // use actix_multipart::Multipart;
 use actix_web::http::header::HeaderValue;
+use actix_web::web::Bytes;
 use actix_web::{
     middleware, rt, web, App as ActixApp, Error as ActixError, HttpRequest, HttpResponse,
     HttpServer,
 };
-use anyhow::Result;
-use futures_util::stream::StreamExt;
-use portpicker::pick_unused_port;
+use anyhow::{Context, Result};
+use futures_util::stream::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::net::TcpListener;
 use std::path::PathBuf;
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 
 use super::incremental_cache::IncrementalCacheDirs;
 use uuid::Uuid;
@@ -18,6 +23,12 @@ use uuid::Uuid;
 const NIXPACKS_SERVER_HOST: &str = "host.docker.internal";
 const NIXPACKS_SERVER_LISTEN_TO_IP: &str = "0.0.0.0";
 
+/// How long a signed upload token is accepted for.
+const UPLOAD_TOKEN_TTL_SECS: u64 = 6 * 60 * 60;
+
+/// Size of the buffer uploads are written to disk through.
+const UPLOAD_BUFFER_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug, Clone)]
 pub struct FileServer {}
 
@@ -27,51 +38,145 @@ pub struct FileServerConfig {
     pub listen_to_ip: String,
     pub port: u16,
     pub access_token: String,
+    /// Unix timestamp after which uploads are rejected. When set, uploads must use a token signed with `access_token` rather than `access_token` itself.
+    pub token_expires_at: Option<u64>,
+    /// Identifier of the build that the uploads belong to, which signed tokens are bound to.
+    pub build_id: String,
     pub upload_url: String,
     pub files_dir: PathBuf,
+    pub upload_options: UploadOptions,
+    /// Most connections that are accepted at once, each holding a file descriptor. Further connections wait in the listen backlog until some close. Unlimited if not set, and at least 2.
+    pub max_connections: Option<usize>,
+}
+
+/// Controls how uploads to the file server are retried by the generated `curl` commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadOptions {
+    /// Number of times a failed upload is retried. Setting this to 0 disables retries entirely.
+    pub retries: u32,
+    /// Seconds to wait between retries (`--retry-delay`).
+    pub retry_delay: Option<u32>,
+    /// Maximum number of seconds to spend retrying (`--retry-max-time`).
+    pub retry_max_time: Option<u32>,
+    /// Proxy that uploads go through (`--proxy`). Without it curl uses the `http_proxy` and `no_proxy` variables of the build.
+    pub proxy: Option<String>,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            retry_delay: None,
+            retry_max_time: None,
+            proxy: None,
+        }
+    }
+}
+
+impl UploadOptions {
+    /// Produce the `curl` flags for these retry settings.
+    pub fn get_curl_retry_flags(&self) -> String {
+        if self.retries == 0 {
+            return String::new();
+        }
+
+        let mut flags = vec![format!("--retry {} --retry-all-errors", self.retries)];
+        if let Some(delay) = self.retry_delay {
+            flags.push(format!("--retry-delay {delay}"));
+        }
+        if let Some(max_time) = self.retry_max_time {
+            flags.push(format!("--retry-max-time {max_time}"));
+        }
+
+        flags.join(" ")
+    }
+
+    /// Produce the `curl` flag for the proxy, if one is set.
+    pub fn get_curl_proxy_flags(&self) -> String {
+        match &self.proxy {
+            Some(proxy) if !proxy.is_empty() => {
+                format!("--proxy '{}'", proxy.replace('\'', "'\\''"))
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+impl FileServerConfig {
+    /// The token sent by uploads in the `t` header.
+    pub fn get_upload_token(&self) -> String {
+        match self.token_expires_at {
+            Some(expires_at) => sign_token(&self.access_token, &self.build_id, expires_at),
+            None => self.access_token.clone(),
+        }
+    }
+}
+
+/// Sign the build and an expiry timestamp with the access token, producing `<expiry>.<hmac-sha256 hex>`.
+fn sign_token(secret: &str, build_id: &str, expires_at: u64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{build_id}.{expires_at}").as_bytes());
+
+    format!("{expires_at}.{:x}", mac.finalize().into_bytes())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 impl FileServer {
     /// Launch the file server using the default settings and the incremental cache storage directories.
-    pub fn start(self, incremental_cache_dirs: &IncrementalCacheDirs) -> FileServerConfig {
-        let port = self.get_free_port();
-
-        let config = FileServerConfig {
+    ///
+    /// Uploads must use an expiring token signed for the build unless `plain_token` is set.
+    pub fn start(
+        self,
+        incremental_cache_dirs: &IncrementalCacheDirs,
+        plain_token: bool,
+        build_id: &str,
+    ) -> Result<FileServerConfig> {
+        self.start_with_config(FileServerConfig {
             files_dir: incremental_cache_dirs.uploads_dir.clone(),
             access_token: Uuid::new_v4().to_string(),
+            token_expires_at: (!plain_token).then(|| now_secs() + UPLOAD_TOKEN_TTL_SECS),
+            build_id: build_id.to_string(),
             listen_to_ip: NIXPACKS_SERVER_LISTEN_TO_IP.to_string(),
-            port,
-            upload_url: format!("http://{NIXPACKS_SERVER_HOST}:{port}/upload/"),
-        };
+            port: 0,
+            upload_url: String::new(),
+            upload_options: UploadOptions::default(),
+            max_connections: None,
+        })
+    }
+
+    /// Launch the file server with the provided config.
+    ///
+    /// A `port` of 0 binds an ephemeral port. The returned config holds the port that was actually bound and the upload URL for it.
+    pub fn start_with_config(self, mut config: FileServerConfig) -> Result<FileServerConfig> {
+        let listener = TcpListener::bind((config.listen_to_ip.as_str(), config.port))
+            .with_context(|| format!("Binding file server to port {}", config.port))?;
+
+        let port = listener.local_addr()?.port();
+        config.port = port;
+        config.upload_url = format!("http://{NIXPACKS_SERVER_HOST}:{port}/upload/");
 
         let server_config = config.clone();
         thread::spawn(move || {
-            let server_future = FileServer::run_app(server_config);
+            let server_future = FileServer::run_app(server_config, listener);
             if let Err(e) = rt::System::new().block_on(server_future) {
                 println!("File server error: {e}");
             }
         });
 
-        config
-    }
-
-    /// Try to find an unused port.
-    fn get_free_port(&self) -> u16 {
-        for _ in 1..3 {
-            // try 2 times
-            if let Some(port) = pick_unused_port() {
-                return port;
-            }
-        }
-
-        // last try to get free port then fail if no ports available
-        pick_unused_port().expect("No ports available")
+        Ok(config)
     }
 
     /// Using the provided config, launch a new file server.
-    async fn run_app(data: FileServerConfig) -> std::io::Result<()> {
+    async fn run_app(data: FileServerConfig, listener: TcpListener) -> std::io::Result<()> {
         let server_config = web::Data::new(data.clone());
-        let server = HttpServer::new(move || {
+        let mut server = HttpServer::new(move || {
             ActixApp::new()
                 .app_data(server_config.clone())
                 .wrap(middleware::Logger::default())
@@ -82,25 +187,68 @@ impl FileServer {
                 .service(
                     web::resource("/upload/{filename}").route(web::put().to(FileServer::upload)),
                 )
-        })
-        .bind((data.listen_to_ip, data.port))?
-        .run();
+        });
 
-        server.await
+        // The limit is per worker, so a single worker keeps the whole server within it.
+        // actix-server only resumes accepting once the connections drop two below the limit, so a limit of 1 would never resume.
+        if let Some(max_connections) = data.max_connections {
+            server = server.workers(1).max_connections(max_connections.max(2));
+        }
+
+        server.listen(listener)?.run().await
     }
 
-    /// Check if the provided access_token matches the one in the server response header.
-    fn has_valid_access_token(token: Option<&HeaderValue>, access_token: &str) -> bool {
-        if let Some(header) = token {
-            match header.to_str() {
-                Ok(value) => value == access_token,
-                _ => false,
+    /// Check if the token in the upload request header is valid for the config.
+    ///
+    /// Signed tokens must have a signature for the build and an expiry that has not passed, and must not expire later than the config allows.
+    /// Tokens are compared in constant time, so that the time taken does not reveal how much of a token matched.
+    fn has_valid_access_token(
+        token: Option<&HeaderValue>,
+        config: &FileServerConfig,
+        now: u64,
+    ) -> bool {
+        let Some(Ok(token)) = token.map(HeaderValue::to_str) else {
+            return false;
+        };
+
+        match config.token_expires_at {
+            Some(max_expires_at) => {
+                let Some(Ok(expires_at)) = token.split_once('.').map(|(e, _)| e.parse::<u64>())
+                else {
+                    return false;
+                };
+
+                let expected = sign_token(&config.access_token, &config.build_id, expires_at);
+                expires_at >= now
+                    && expires_at <= max_expires_at
+                    && bool::from(token.as_bytes().ct_eq(expected.as_bytes()))
             }
-        } else {
-            false
+            None => bool::from(token.as_bytes().ct_eq(config.access_token.as_bytes())),
         }
     }
 
+    /// Write the chunks of `stream` to the file at `path` as they arrive.
+    ///
+    /// Only a bounded buffer is held in memory, so large uploads are never read into memory whole.
+    async fn write_stream<S, E>(stream: &mut S, path: PathBuf) -> Result<(), ActixError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: Into<ActixError>,
+    {
+        let mut f = web::block(|| {
+            File::create(path).map(|f| BufWriter::with_capacity(UPLOAD_BUFFER_SIZE, f))
+        })
+        .await??;
+
+        while let Some(chunk) = stream.next().await {
+            let data = chunk.map_err(Into::into)?;
+            f = web::block(move || f.write_all(&data).map(|_| f)).await??;
+        }
+        web::block(move || f.flush()).await??;
+
+        Ok(())
+    }
+
     #[allow(dead_code)]
     async fn upload(
         mut payload: web::Payload,
@@ -108,22 +256,222 @@ impl FileServer {
         req: HttpRequest,
         data: web::Data<FileServerConfig>,
     ) -> Result<HttpResponse, ActixError> {
-        if !FileServer::has_valid_access_token(req.headers().get("t"), &data.access_token) {
+        if !FileServer::has_valid_access_token(req.headers().get("t"), &data, now_secs()) {
             return Ok(HttpResponse::Unauthorized().into());
         }
 
         let filename = path.into_inner();
         let filepath = data.files_dir.join(sanitize_filename::sanitize(&filename));
 
-        let in_path = PathBuf::from(&filepath);
-        let mut f: File = web::block(|| File::create(in_path)).await??;
-
-        while let Some(chunk) = payload.next().await {
-            let data = chunk?;
-            f = web::block(move || f.write_all(&data).map(|_| f)).await??;
-        }
-        web::block(move || f.flush()).await??;
+        FileServer::write_stream(&mut payload, filepath).await?;
 
         Ok(HttpResponse::Ok().into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{ErrorKind, Read},
+        net::TcpStream,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_curl_retry_flags() {
+        assert_eq!(
+            UploadOptions::default().get_curl_retry_flags(),
+            "--retry 3 --retry-all-errors"
+        );
+        assert_eq!(
+            UploadOptions {
+                retries: 5,
+                retry_delay: Some(2),
+                retry_max_time: Some(60),
+                ..Default::default()
+            }
+            .get_curl_retry_flags(),
+            "--retry 5 --retry-all-errors --retry-delay 2 --retry-max-time 60"
+        );
+        assert_eq!(
+            UploadOptions {
+                retries: 0,
+                retry_delay: Some(2),
+                retry_max_time: None,
+                ..Default::default()
+            }
+            .get_curl_retry_flags(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_start_on_ephemeral_port() {
+        let files_dir = tempdir::TempDir::new("nixpacks-uploads").unwrap();
+        let config = FileServer {}
+            .start_with_config(FileServerConfig {
+                listen_to_ip: "127.0.0.1".to_string(),
+                port: 0,
+                files_dir: files_dir.path().to_path_buf(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert_ne!(config.port, 0);
+        assert_eq!(
+            config.upload_url,
+            format!("http://{NIXPACKS_SERVER_HOST}:{}/upload/", config.port)
+        );
+        assert!(TcpStream::connect(("127.0.0.1", config.port)).is_ok());
+    }
+
+    #[test]
+    fn test_connections_over_max_connections_wait() {
+        let files_dir = tempdir::TempDir::new("nixpacks-uploads").unwrap();
+        let config = FileServer {}
+            .start_with_config(FileServerConfig {
+                listen_to_ip: "127.0.0.1".to_string(),
+                access_token: "secret".to_string(),
+                files_dir: files_dir.path().to_path_buf(),
+                max_connections: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        let read_response = |stream: &mut TcpStream| {
+            let mut response = vec![0; 1024];
+            let len = stream.read(&mut response)?;
+            std::io::Result::Ok(String::from_utf8_lossy(&response[..len]).to_string())
+        };
+
+        // A response on a kept alive connection shows that it was accepted and still holds a slot
+        let mut held = (0..2)
+            .map(|_| {
+                let mut stream = TcpStream::connect(("127.0.0.1", config.port)).unwrap();
+                write!(stream, "GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+                assert!(read_response(&mut stream)
+                    .unwrap()
+                    .starts_with("HTTP/1.1 200"));
+                stream
+            })
+            .collect::<Vec<_>>();
+
+        // The upload is queued rather than rejected, so it is never answered while the other connections are open
+        let mut upload = TcpStream::connect(("127.0.0.1", config.port)).unwrap();
+        write!(
+            upload,
+            "PUT /upload/queued HTTP/1.1\r\nHost: localhost\r\nt: secret\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123456789"
+        )
+        .unwrap();
+        upload
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let err = read_response(&mut upload).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+
+        held.clear();
+        upload
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        assert!(read_response(&mut upload)
+            .unwrap()
+            .starts_with("HTTP/1.1 200"));
+        assert_eq!(
+            std::fs::read_to_string(files_dir.path().join("queued")).unwrap(),
+            "0123456789"
+        );
+    }
+
+    #[test]
+    fn test_sign_token() {
+        // HMAC-SHA256 of `build.1000` with the key `secret`
+        assert_eq!(
+            sign_token("secret", "build", 1000),
+            "1000.f6637309b62b4bb51569bc8e423a91e6bb4dd5e1cf414a2ed9a340539055bf04"
+        );
+    }
+
+    #[test]
+    fn test_signed_tokens() {
+        let config = FileServerConfig {
+            access_token: "secret".to_string(),
+            token_expires_at: Some(1000),
+            build_id: "build".to_string(),
+            ..Default::default()
+        };
+        let is_valid = |token: &str, now: u64| {
+            FileServer::has_valid_access_token(
+                Some(&HeaderValue::from_str(token).unwrap()),
+                &config,
+                now,
+            )
+        };
+
+        let token = config.get_upload_token();
+        assert!(is_valid(&token, 500));
+        assert!(is_valid(&token, 1000));
+
+        // Expired
+        assert!(!is_valid(&token, 1001));
+
+        // Tampered signature or expiry
+        let (_, signature) = token.split_once('.').unwrap();
+        assert!(!is_valid(&format!("{token}0"), 500));
+        assert!(!is_valid(&format!("999.{signature}"), 500));
+        assert!(!is_valid(&sign_token("other-secret", "build", 1000), 500));
+
+        // Tokens are bound to the build they were signed for
+        assert!(!is_valid(&sign_token("secret", "other-build", 1000), 500));
+
+        // Extending the expiry beyond the configured one is rejected even when signed
+        assert!(!is_valid(&sign_token("secret", "build", 2000), 500));
+
+        // The plain token is not accepted when signing is enabled
+        assert!(!is_valid("secret", 500));
+        assert!(!FileServer::has_valid_access_token(None, &config, 500));
+    }
+
+    #[test]
+    fn test_plain_tokens() {
+        let config = FileServerConfig {
+            access_token: "secret".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config.get_upload_token(), "secret");
+        assert!(FileServer::has_valid_access_token(
+            Some(&HeaderValue::from_static("secret")),
+            &config,
+            u64::MAX,
+        ));
+        assert!(!FileServer::has_valid_access_token(
+            Some(&HeaderValue::from_static("other")),
+            &config,
+            0,
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_write_stream_in_chunks() {
+        let files_dir = tempdir::TempDir::new("nixpacks-uploads").unwrap();
+        let path = files_dir.path().join("upload.tar");
+
+        // More data than fits in the write buffer, sent in uneven chunks
+        let chunks = (0..64u8)
+            .map(|i| Ok::<_, ActixError>(Bytes::from(vec![i; 100_000 + i as usize])))
+            .collect::<Vec<_>>();
+        let expected = chunks
+            .iter()
+            .flat_map(|chunk| chunk.as_ref().unwrap().to_vec())
+            .collect::<Vec<_>>();
+        assert!(expected.len() > UPLOAD_BUFFER_SIZE);
+
+        FileServer::write_stream(&mut futures_util::stream::iter(chunks), path.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), expected);
+    }
+}
@@ -1,19 +1,303 @@
 use std::{
+    collections::BTreeMap,
     fs::{self},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Command,
+    thread,
+    time::{Duration, SystemTime},
 };
 
-use super::{dockerfile_generation::OutputDir, file_server::FileServerConfig};
+use super::{
+    dockerfile_generation::OutputDir,
+    file_server::FileServerConfig,
+    registry_auth::DOCKER_CONFIG_ENV_VAR,
+    utils::{expand_home_dir, DEFAULT_DOCKER_BIN},
+};
 use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
 use std::process::Stdio;
 
 const INCREMENTAL_CACHE_DIR: &str = "incremental-cache";
 const INCREMENTAL_CACHE_UPLOADS_DIR: &str = "uploads";
 const INCREMENTAL_CACHE_IMAGE_DIR: &str = "image";
 
-#[derive(Default)]
-pub struct IncrementalCache {}
+/// The Nix store and the database that registers its paths, which are cached together so that Nix trusts the cached paths.
+pub const NIX_STORE_CACHE_DIRECTORIES: &[&str] = &["/nix/store", "/nix/var/nix/db"];
+
+/// Files whose contents decide whether a previous incremental cache image can be reused.
+pub const INCREMENTAL_CACHE_INPUT_FILES: &[&str] = &[
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "bun.lockb",
+    "Cargo.lock",
+    "go.sum",
+    "Gemfile.lock",
+    "composer.lock",
+    "poetry.lock",
+    "Pipfile.lock",
+    "requirements.txt",
+    "mix.lock",
+    "pubspec.lock",
+];
+
+/// Compression applied to the tarballs uploaded to the incremental cache.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CacheCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl CacheCompression {
+    /// The extension of tarballs created with this compression.
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            CacheCompression::None => "tar",
+            CacheCompression::Gzip => "tar.gz",
+            CacheCompression::Zstd => "tar.zst",
+        }
+    }
+
+    /// The `tar` flags used to create a tarball with this compression.
+    pub fn tar_flags(self) -> &'static str {
+        match self {
+            CacheCompression::None => "-cf",
+            CacheCompression::Gzip => "-czf",
+            CacheCompression::Zstd => "--zstd -cf",
+        }
+    }
+
+    /// Determine the compression of a tarball from its file name.
+    pub fn from_path(path: &Path) -> Self {
+        let file_name = path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if file_name.ends_with(".tar.gz") {
+            CacheCompression::Gzip
+        } else if file_name.ends_with(".tar.zst") {
+            CacheCompression::Zstd
+        } else {
+            CacheCompression::None
+        }
+    }
+
+    /// Decompress the given tarball in place, returning the path to the plain tarball.
+    fn decompress(self, path: &Path) -> Result<PathBuf> {
+        let mut decompress_cmd = match self {
+            CacheCompression::None => return Ok(path.to_path_buf()),
+            CacheCompression::Gzip => Command::new("gzip"),
+            CacheCompression::Zstd => {
+                let mut cmd = Command::new("zstd");
+                cmd.arg("--rm");
+                cmd
+            }
+        };
+        decompress_cmd.arg("-d").arg("-f").arg(path);
+
+        let result = decompress_cmd
+            .spawn()?
+            .wait()
+            .context("Decompress incremental cache tarball")?;
+
+        if !result.success() {
+            bail!(
+                "Decompressing incremental cache tarball {} failed",
+                path.display()
+            )
+        }
+
+        // Both gzip and zstd strip the compression extension from the file name
+        Ok(path.with_extension(""))
+    }
+}
+
+/// Types that impl this trait can store and look up the incremental cache image.
+pub trait CacheBackend: Send + Sync {
+    /// Create (or update) the image `tag` from a single filesystem tarball.
+    fn create_image(&self, file: &Path, tag: &str) -> Result<()>;
+
+    /// Check whether the image `tag` already exists.
+    fn image_exists(&self, tag: &str) -> Result<bool>;
+
+    /// Push the image `tag` to its registry.
+    fn push(&self, tag: &str) -> Result<()>;
+}
+
+/// Stores the incremental cache image using the Docker CLI.
+pub struct DockerCacheBackend {
+    /// Log which command was used to look up the cache image.
+    pub verbose: bool,
+    /// The Docker CLI to run, as a path or a name that is looked up in `PATH`.
+    pub docker_bin: String,
+    /// The Docker config directory with the credentials of the build, instead of the one of the user.
+    pub docker_config: Option<PathBuf>,
+}
+
+impl Default for DockerCacheBackend {
+    fn default() -> Self {
+        DockerCacheBackend {
+            verbose: false,
+            docker_bin: DEFAULT_DOCKER_BIN.to_string(),
+            docker_config: None,
+        }
+    }
+}
+
+/// The Docker CLI commands that can look up an image in its registry, in the order they are tried.
+///
+/// `docker manifest` needs the experimental CLI features on older versions of Docker, in which case buildx is used instead.
+const IMAGE_LOOKUP_COMMANDS: &[&[&str]] = &[
+    &["manifest", "inspect"],
+    &["buildx", "imagetools", "inspect"],
+];
+
+/// The result of looking up an image with one of the [`IMAGE_LOOKUP_COMMANDS`].
+#[derive(Debug, PartialEq, Eq)]
+enum ImageLookup {
+    Found,
+    Absent,
+    /// The command is not available in this Docker CLI, so it says nothing about the image.
+    Unsupported,
+}
+
+impl ImageLookup {
+    /// Classify the exit status and stderr of a lookup command.
+    fn from_output(success: bool, stderr: &str) -> Self {
+        let stderr = stderr.to_lowercase();
+        if success {
+            ImageLookup::Found
+        } else if stderr.contains("experimental")
+            || stderr.contains("is not a docker command")
+            || stderr.contains("unknown command")
+        {
+            ImageLookup::Unsupported
+        } else {
+            ImageLookup::Absent
+        }
+    }
+}
+
+impl DockerCacheBackend {
+    /// Look up the image with each of the lookup commands until one is supported, running them with `run`.
+    ///
+    /// `run` returns whether the command succeeded and its stderr. The image is assumed to be absent if no command is supported.
+    fn lookup_image<F>(&self, tag: &str, mut run: F) -> Result<bool>
+    where
+        F: FnMut(&[&str]) -> Result<(bool, String)>,
+    {
+        for lookup_cmd in IMAGE_LOOKUP_COMMANDS {
+            let args = [lookup_cmd, &[tag][..]].concat();
+            let (success, stderr) = run(&args)?;
+            let command = format!("docker {}", lookup_cmd.join(" "));
+
+            match ImageLookup::from_output(success, &stderr) {
+                ImageLookup::Found => {
+                    self.debug(&format!(
+                        "Found incremental cache image {tag} with {command}"
+                    ));
+                    return Ok(true);
+                }
+                ImageLookup::Absent => {
+                    self.debug(&format!(
+                        "Incremental cache image {tag} not found with {command}"
+                    ));
+                    return Ok(false);
+                }
+                ImageLookup::Unsupported => {
+                    self.debug(&format!("{command} is not supported: {}", stderr.trim()));
+                }
+            }
+        }
+
+        println!(
+            "Could not check whether incremental cache image {tag} exists, building without it"
+        );
+        Ok(false)
+    }
+
+    fn debug(&self, msg: &str) {
+        if self.verbose {
+            println!("{msg}");
+        }
+    }
+
+    fn docker_command(&self) -> Command {
+        let mut cmd = Command::new(&self.docker_bin);
+        if let Some(docker_config) = &self.docker_config {
+            cmd.env(DOCKER_CONFIG_ENV_VAR, docker_config);
+        }
+        cmd
+    }
+}
+
+impl CacheBackend for DockerCacheBackend {
+    fn create_image(&self, file: &Path, tag: &str) -> Result<()> {
+        let mut docker_import_cmd = self.docker_command();
+        docker_import_cmd.arg("import").arg(file).arg(tag);
+
+        let result = docker_import_cmd
+            .spawn()?
+            .wait()
+            .context("Create incremental cache image")?;
+
+        if !result.success() {
+            bail!("Creating incremental cache image failed")
+        }
+
+        Ok(())
+    }
+
+    fn image_exists(&self, tag: &str) -> Result<bool> {
+        self.lookup_image(tag, |args| {
+            let output = self
+                .docker_command()
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .output()
+                .context("Check incremental cache image exists in registry")?;
+
+            Ok((
+                output.status.success(),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ))
+        })
+    }
+
+    fn push(&self, tag: &str) -> Result<()> {
+        let mut docker_push_cmd = self.docker_command();
+        docker_push_cmd.arg("push").arg(tag);
+
+        let result = docker_push_cmd
+            .spawn()?
+            .wait()
+            .context("Push incremental cache image")?;
+
+        if !result.success() {
+            bail!("Pushing incremental cache image failed")
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates and looks up the incremental cache image through a pluggable CacheBackend.
+pub struct IncrementalCache {
+    backend: Box<dyn CacheBackend>,
+    max_parallel_imports: usize,
+}
+
+impl Default for IncrementalCache {
+    /// The incremental cache defaults to storing images with Docker.
+    fn default() -> Self {
+        Self::new(Box::new(DockerCacheBackend::default()))
+    }
+}
 
 /// Directories in which to cache Docker image layers.
 #[derive(Default)]
@@ -36,8 +320,17 @@ impl IncrementalCacheDirs {
         }
     }
 
-    /// Makes the incremental cache directories.
+    /// Makes the incremental cache directories, keeping the contents of any that already exist.
     pub fn create(&self) -> Result<()> {
+        fs::create_dir_all(&self.image_dir).context("Create incremental cache image dir")?;
+        fs::create_dir_all(&self.uploads_dir)
+            .context("Creating incremental-cache uploads directory")?;
+
+        Ok(())
+    }
+
+    /// Removes the incremental cache directories and everything in them, then makes them again.
+    pub fn reset(&self) -> Result<()> {
         let incremental_cache_root = self.out_dir.get_absolute_path(INCREMENTAL_CACHE_DIR);
 
         if fs::metadata(&incremental_cache_root)
@@ -47,81 +340,345 @@ impl IncrementalCacheDirs {
             fs::remove_dir_all(&incremental_cache_root)?;
         }
 
-        if fs::metadata(&self.image_dir)
-            .context("Check if incremental cache image dir exists")
-            .is_ok()
-        {
-            fs::remove_dir_all(&self.image_dir)?;
-        }
+        self.create()
+    }
 
-        if fs::metadata(&self.uploads_dir)
-            .context("Check if incremental cache uploads dir exists")
-            .is_ok()
-        {
-            fs::remove_dir_all(&self.uploads_dir)?;
+    /// Removes the tarballs uploaded by a previous build, so that only those of the next build are imported into the cache image.
+    pub fn clear_uploads(&self) -> Result<()> {
+        if self.uploads_dir.is_dir() {
+            fs::remove_dir_all(&self.uploads_dir)
+                .context("Removing incremental-cache uploads directory")?;
         }
-
-        fs::create_dir_all(&self.image_dir).context("Create incremental cache image dir")?;
         fs::create_dir_all(&self.uploads_dir)
             .context("Creating incremental-cache uploads directory")?;
 
         Ok(())
     }
+
+    /// The incremental cache directories of an output directory saved with `--out`.
+    pub fn from_output_path(path: &str) -> Result<Self> {
+        Ok(IncrementalCacheDirs::new(&OutputDir::from(path, false)?))
+    }
+
+    /// Removes uploaded tarballs that have not been written for longer than `max_age`, and then the least recently used ones until the rest fit in `max_size` bytes.
+    pub fn prune(&self, max_age: Option<Duration>, max_size: Option<u64>) -> Result<PruneSummary> {
+        let mut entries = Vec::new();
+        if self.uploads_dir.is_dir() {
+            for entry in fs::read_dir(&self.uploads_dir)
+                .context("Reading incremental-cache uploads directory")?
+            {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    entries.push(CacheEntry {
+                        path: entry.path(),
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                    });
+                }
+            }
+        }
+
+        let evicted = select_evictions(entries.clone(), SystemTime::now(), max_age, max_size);
+        let mut summary = PruneSummary {
+            remaining_bytes: entries.iter().map(|entry| entry.size).sum(),
+            ..PruneSummary::default()
+        };
+        for entry in evicted {
+            fs::remove_file(&entry.path)
+                .with_context(|| format!("Removing {}", entry.path.display()))?;
+            summary.freed_bytes += entry.size;
+            summary.remaining_bytes -= entry.size;
+            summary.removed.push(entry.path);
+        }
+
+        Ok(summary)
+    }
+}
+
+/// A tarball in the incremental cache uploads directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// The tarballs removed by [`IncrementalCacheDirs::prune`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PruneSummary {
+    pub removed: Vec<PathBuf>,
+    pub freed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+/// Pick the entries to remove, oldest first, so that none are older than `max_age` and the rest fit in `max_size`.
+fn select_evictions(
+    mut entries: Vec<CacheEntry>,
+    now: SystemTime,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+) -> Vec<CacheEntry> {
+    // Ties are broken by path so that the same files are always removed
+    entries.sort_by(|a, b| a.modified.cmp(&b.modified).then(a.path.cmp(&b.path)));
+
+    let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut evicted = Vec::new();
+    for entry in entries {
+        let expired = max_age.is_some_and(|max_age| {
+            now.duration_since(entry.modified)
+                .is_ok_and(|age| age > max_age)
+        });
+        let over_budget = max_size.is_some_and(|max_size| total_size > max_size);
+        if !expired && !over_budget {
+            break;
+        }
+
+        total_size -= entry.size;
+        evicted.push(entry);
+    }
+
+    evicted
+}
+
+/// Parse a duration such as `30m`, `12h` or `7d`. A number without a unit is in seconds.
+pub fn parse_max_age(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration `{value}`"))?;
+    let seconds = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        unit => bail!("Invalid duration unit `{unit}`, expected one of s, m, h, d or w"),
+    };
+
+    let seconds = number
+        .checked_mul(seconds)
+        .with_context(|| format!("The duration `{value}` is too long"))?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parse a size such as `500MB` or `10G`. A number without a unit is in bytes.
+pub fn parse_max_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid size `{value}`"))?;
+    let bytes: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => bail!(
+            "Invalid size unit `{}`, expected one of B, KB, MB, GB or TB",
+            unit.trim()
+        ),
+    };
+
+    number
+        .checked_mul(bytes)
+        .with_context(|| format!("The size `{value}` is too large"))
 }
 
 impl IncrementalCache {
+    pub fn new(backend: Box<dyn CacheBackend>) -> Self {
+        let max_parallel_imports = thread::available_parallelism().map_or(1, usize::from);
+
+        Self {
+            backend,
+            max_parallel_imports,
+        }
+    }
+
+    /// Limit how many tarballs are decompressed and imported at the same time. Defaults to the number of available CPUs.
+    #[must_use]
+    pub fn with_max_parallel_imports(mut self, max_parallel_imports: usize) -> Self {
+        self.max_parallel_imports = max_parallel_imports.max(1);
+        self
+    }
+
     /// Create a filesystem image for each of the files in the incremental cache uploads directory, then upload these to the Docker cache.
+    ///
+    /// Returns whether an image was created. Nothing is created when no directories were uploaded during the build.
     pub fn create_image(
         &self,
         incremental_cache_dirs: &IncrementalCacheDirs,
         tag: &str,
-    ) -> Result<()> {
-        let files = fs::read_dir(&incremental_cache_dirs.uploads_dir)?;
+    ) -> Result<bool> {
+        let mut files = fs::read_dir(&incremental_cache_dirs.uploads_dir)?
+            .map(|f| f.map(|f| f.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        if files.is_empty() {
+            println!("No incremental cache directories were uploaded, skipping image creation");
+            return Ok(false);
+        }
+
+        // Sort the files so that they are imported, and failures are reported, in the same order on every build
+        files.sort();
 
         // There are three options to create a filesystem image that contains only tar files
         // #1 Use a Rust crate to create the image: 30+ seconds in a sample test, Also no clear winner Crate for creating OCI image
         // #2 Create minimal Dockerfile: 6 seconds in a sample test
         // #3 Use Docker import: Provide 3 seconds in a sample test
-        for f in files {
-            let mut docker_import_cmd = Command::new("docker");
-            docker_import_cmd.arg("import").arg(&f?.path()).arg(tag);
+        // Every import updates the same tag, so the last file is imported once the others are, for the tag to point to the same import on every build
+        let import = |path: &Path| {
+            CacheCompression::from_path(path)
+                .decompress(path)
+                .and_then(|tarball| self.backend.create_image(&tarball, tag))
+                .with_context(|| format!("Importing incremental cache file {}", path.display()))
+        };
+        // There is at least one file, as nothing is created otherwise
+        let (last, rest) = files.split_last().unwrap();
+        for batch in rest.chunks(self.max_parallel_imports) {
+            thread::scope(|scope| {
+                let handles = batch
+                    .iter()
+                    .map(|path| scope.spawn(|| import(path)))
+                    .collect::<Vec<_>>();
 
-            let result = docker_import_cmd
-                .spawn()?
-                .wait()
-                .context("Create incremental cache image")?;
+                // Failures are reported in the order of the files rather than the order they finish in
+                for (path, handle) in batch.iter().zip(handles) {
+                    let Ok(result) = handle.join() else {
+                        bail!(
+                            "Importing incremental cache file {} panicked",
+                            path.display()
+                        );
+                    };
+                    result?;
+                }
 
-            if !result.success() {
-                bail!("Creating incremental cache image failed")
-            }
+                Ok(())
+            })?;
         }
+        import(last)?;
 
         println!("Incremental cache image created: {}", &tag);
-        Ok(())
+        Ok(true)
     }
 
-    /// Check if the provided image_tag matches a tag in the incremental Docker image cache.
-    pub fn is_image_exists(image_tag: &str) -> Result<bool> {
-        let mut docker_inspect_cmd = Command::new("docker");
-        docker_inspect_cmd
-            .arg("manifest")
-            .arg("inspect")
-            .arg(image_tag)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null());
-
-        let result = docker_inspect_cmd
-            .spawn()?
-            .wait()
-            .context("Check incremental cache image exists in registry")?;
+    /// Read the incremental cache input files, and the extra ones contributed by providers, that exist in the app source directory.
+    pub fn read_cache_inputs(
+        app_src: &Path,
+        extra_files: &[String],
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut names = INCREMENTAL_CACHE_INPUT_FILES
+            .iter()
+            .map(ToString::to_string)
+            .chain(extra_files.iter().cloned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let path = app_src.join(&name);
+                (name, path)
+            })
+            .filter(|(_, path)| path.is_file())
+            .map(|(name, path)| {
+                let contents = fs::read(&path)
+                    .with_context(|| format!("Reading incremental cache input {name}"))?;
+                Ok((name, contents))
+            })
+            .collect()
+    }
+
+    /// Compute a stable sha256 hash over the named cache inputs, independent of the order they are given in.
+    pub fn compute_inputs_hash(inputs: &[(String, Vec<u8>)]) -> String {
+        let mut inputs = inputs.to_vec();
+        inputs.sort();
+
+        let mut hasher = Sha256::new();
+        for (name, contents) in inputs {
+            hasher.update(name.as_bytes());
+            hasher.update((contents.len() as u64).to_be_bytes());
+            hasher.update(contents);
+        }
 
-        Ok(result.success())
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Fold the hash of the cache inputs into the tag of the incremental cache image.
+    pub fn get_image_tag(incremental_cache_image: &str, inputs_hash: &str) -> String {
+        let short_hash = &inputs_hash[..inputs_hash.len().min(12)];
+        let name_start = incremental_cache_image.rfind('/').map_or(0, |i| i + 1);
+
+        if incremental_cache_image[name_start..].contains(':') {
+            format!("{incremental_cache_image}-{short_hash}")
+        } else {
+            format!("{incremental_cache_image}:{short_hash}")
+        }
+    }
+
+    /// The registry of an image reference, which Docker takes to be the first path component if it has a `.` or `:` or is `localhost`.
+    pub fn get_registry(image: &str) -> Option<&str> {
+        let (first, _) = image.split_once('/')?;
+        if first.contains('.') || first.contains(':') || first == "localhost" {
+            Some(first)
+        } else {
+            None
+        }
+    }
+
+    /// Lowercase the repository of an image reference, as Docker requires, keeping the case of its registry and tag.
+    pub fn normalize_image_tag(image: &str) -> String {
+        let (registry, rest) = match IncrementalCache::get_registry(image) {
+            Some(registry) => (format!("{registry}/"), &image[registry.len() + 1..]),
+            None => (String::new(), image),
+        };
+
+        let (repository, reference) = match rest.find([':', '@']) {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+
+        format!("{registry}{}{reference}", repository.to_lowercase())
+    }
+
+    /// Push the incremental cache image to its registry, which is Docker Hub for images without one, so that other machines can build with it.
+    ///
+    /// Returns whether the image was pushed. A failed push only prints a warning, since the image itself was built.
+    pub fn push_image(&self, tag: &str) -> bool {
+        match self.backend.push(tag) {
+            Ok(()) => {
+                println!("Incremental cache image pushed: {tag}");
+                true
+            }
+            Err(err) => {
+                println!("Warning: pushing incremental cache image {tag} failed: {err:#}");
+                false
+            }
+        }
+    }
+
+    /// Check if the provided image_tag matches a tag in the incremental Docker image cache.
+    pub fn is_image_exists(&self, image_tag: &str) -> Result<bool> {
+        self.backend.image_exists(image_tag)
     }
 
     /// Produce Dockerfile line(s) copying cached files from the incremental cache to the final build image.
     pub fn get_copy_to_image_command(
         cache_directories: &Option<Vec<String>>,
         incremental_cache_image: &str,
+        home_dir: &str,
     ) -> Vec<String> {
         let dirs = &cache_directories.clone().unwrap_or_default();
         if dirs.is_empty() {
@@ -130,7 +687,7 @@ impl IncrementalCache {
 
         dirs.iter()
             .flat_map(|dir| {
-                let target_cache_dir = dir.replace('~', "/root");
+                let target_cache_dir = expand_home_dir(dir, home_dir);
                 let target_cache_dir_optional = target_cache_dir
                     .split('/')
                     .filter(|c| !c.is_empty())
@@ -146,46 +703,195 @@ impl IncrementalCache {
     }
 
     /// Produce Dockerfile line(s) copying files from the build image into the incremental cache.
+    ///
+    /// Each directory is tarred, uploaded, and removed by three separately guarded commands.
+    /// Passing `collapse_commands` joins the three steps into a single command guarded by one directory check instead.
+    /// Files matching the `cache_excludes` of a directory are left out of its tarball with `tar --exclude`.
     pub fn get_copy_from_image_command(
         cache_directories: &Option<Vec<String>>,
+        cache_excludes: &BTreeMap<String, Vec<String>>,
         file_server_config: Option<FileServerConfig>,
-    ) -> Vec<String> {
+        home_dir: &str,
+        compression: CacheCompression,
+        collapse_commands: bool,
+    ) -> Result<Vec<String>> {
         let container_dirs = cache_directories.clone().unwrap_or_default();
         if container_dirs.is_empty() || file_server_config.is_none() {
-            return vec![];
+            return Ok(vec![]);
         }
 
         let server_config = file_server_config.unwrap();
-        container_dirs
+        let mut cmds = Vec::new();
+        for dir in &container_dirs {
+            let sanitized_dir = IncrementalCache::validate_cache_directory(dir, home_dir)?;
+            let excludes = cache_excludes
+                .iter()
+                .filter(|(excluded_dir, _)| {
+                    expand_home_dir(excluded_dir, home_dir) == sanitized_dir
+                })
+                .flat_map(|(_, patterns)| patterns.clone())
+                .collect::<Vec<_>>();
+            let steps = IncrementalCache::get_cache_upload_steps(
+                &sanitized_dir,
+                &excludes,
+                &server_config,
+                compression,
+            );
+
+            if collapse_commands {
+                cmds.push(IncrementalCache::get_collapsed_cache_upload_command(
+                    &sanitized_dir,
+                    &steps,
+                ));
+            } else {
+                let [tar_cmd, upload_cmd, remove_cmd] = steps;
+                cmds.extend([
+                    format!("if [ -d \"{sanitized_dir}\" ]; then {tar_cmd}; fi;"),
+                    format!("if [ -d \"{sanitized_dir}\" ]; then {upload_cmd}; fi;"),
+                    format!("if [ -d \"{sanitized_dir}\" ]; then {remove_cmd}; fi"),
+                ]);
+            }
+        }
+
+        Ok(cmds)
+    }
+
+    /// Produce Dockerfile line(s) uploading the Nix store to the incremental cache after the Nix packages are installed.
+    ///
+    /// Unlike the cache directories of phases the Nix store is needed in the image, so only the uploaded tarballs are removed.
+    pub fn get_nix_store_copy_from_image_command(
+        file_server_config: Option<FileServerConfig>,
+        compression: CacheCompression,
+    ) -> Vec<String> {
+        let Some(server_config) = file_server_config else {
+            return vec![];
+        };
+
+        NIX_STORE_CACHE_DIRECTORIES
             .iter()
-            .flat_map(|dir| {
-                let sanitized_dir = dir.replace('~', "/root");
-                let compressed_file_name = format!("{}.tar", sanitized_dir.replace('/', "%2f"));
-                vec![
-                    format!("if [ -d \"{sanitized_dir}\" ]; then tar -cf {compressed_file_name} {sanitized_dir}; fi;"),
-                    format!(
-                        "if [ -d \"{sanitized_dir}\" ]; then curl -v -T {} {} --header \"t:{}\" --retry 3 --retry-all-errors; fi;",
-                        compressed_file_name, server_config.upload_url, server_config.access_token,
-                    ),
-                    format!("if [ -d \"{sanitized_dir}\" ]; then rm -rf {sanitized_dir}; fi"),
-                ]
+            .map(|dir| {
+                let [tar_cmd, upload_cmd, _] =
+                    IncrementalCache::get_cache_upload_steps(dir, &[], &server_config, compression);
+                IncrementalCache::get_collapsed_cache_upload_command(
+                    dir,
+                    &[
+                        tar_cmd,
+                        upload_cmd,
+                        format!(
+                            "rm -f {}",
+                            IncrementalCache::get_cache_file_name(dir, compression)
+                        ),
+                    ],
+                )
             })
-            .collect::<Vec<String>>()
+            .collect()
+    }
+
+    /// The name of the tarball that a directory is uploaded as, which encodes the path of the directory.
+    fn get_cache_file_name(sanitized_dir: &str, compression: CacheCompression) -> String {
+        format!(
+            "{}.{}",
+            sanitized_dir.replace('/', "%2f"),
+            compression.file_extension()
+        )
+    }
+
+    /// Returns the tar, upload, and remove commands used to move a directory into the incremental cache.
+    fn get_cache_upload_steps(
+        sanitized_dir: &str,
+        excludes: &[String],
+        server_config: &FileServerConfig,
+        compression: CacheCompression,
+    ) -> [String; 3] {
+        let compressed_file_name =
+            IncrementalCache::get_cache_file_name(sanitized_dir, compression);
+
+        let tar_flags = excludes
+            .iter()
+            .map(|pattern| format!("--exclude='{}'", pattern.replace('\'', "'\\''")))
+            .chain([compression.tar_flags().to_string()])
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let retry_flags = server_config.upload_options.get_curl_retry_flags();
+        let proxy_flags = server_config.upload_options.get_curl_proxy_flags();
+        let upload_cmd = [
+            format!(
+                "curl -v -T {} {} --header \"t:{}\"",
+                compressed_file_name,
+                server_config.upload_url,
+                server_config.get_upload_token()
+            ),
+            retry_flags,
+            proxy_flags,
+        ]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+        [
+            format!("tar {tar_flags} {compressed_file_name} {sanitized_dir}"),
+            upload_cmd,
+            format!("rm -rf {sanitized_dir}"),
+        ]
+    }
+
+    /// Joins the steps for a cache directory into one `&&`-chained command behind a single directory check.
+    pub fn get_collapsed_cache_upload_command(sanitized_dir: &str, steps: &[String]) -> String {
+        format!(
+            "if [ -d \"{sanitized_dir}\" ]; then {}; fi",
+            steps.join(" && ")
+        )
+    }
+
+    /// Expand `~` in a cache directory and make sure it is safe to tar up and remove.
+    ///
+    /// Empty paths, the filesystem root, and paths escaping through `..` are rejected.
+    fn validate_cache_directory(dir: &str, home_dir: &str) -> Result<String> {
+        let sanitized_dir = expand_home_dir(dir, home_dir);
+
+        if sanitized_dir.trim().is_empty() {
+            bail!("Invalid cache directory \"{}\": path is empty", dir);
+        }
+        if sanitized_dir.split('/').all(str::is_empty) {
+            bail!(
+                "Invalid cache directory \"{}\": cannot cache the filesystem root",
+                dir
+            );
+        }
+        if sanitized_dir.split('/').any(|segment| segment == "..") {
+            bail!(
+                "Invalid cache directory \"{}\": path cannot contain `..` segments",
+                dir
+            );
+        }
+
+        Ok(sanitized_dir)
     }
 }
 
+#[cfg(test)]
+use super::{fake_cache_backend::FakeCacheBackend, utils::DEFAULT_HOME_DIR};
+
 #[test]
 fn test_get_copy_from_image_command() {
     let cmds = IncrementalCache::get_copy_from_image_command(
         &Some(vec!["./parent_dir/child_dir".to_string()]),
+        &BTreeMap::new(),
         Some(FileServerConfig {
             listen_to_ip: "0.0.0.0".to_string(),
             port: 1234,
             access_token: "test_access_token".to_string(),
             upload_url: "http://test.com/upload".to_string(),
             files_dir: PathBuf::from("./source_dir".to_string()),
+            ..Default::default()
         }),
-    );
+        DEFAULT_HOME_DIR,
+        CacheCompression::None,
+        false,
+    )
+    .unwrap();
 
     assert_eq!(cmds.len(), 3);
     assert_eq!(cmds[0], "if [ -d \"./parent_dir/child_dir\" ]; then tar -cf .%2fparent_dir%2fchild_dir.tar ./parent_dir/child_dir; fi;".to_string());
@@ -196,11 +902,222 @@ fn test_get_copy_from_image_command() {
     );
 }
 
+#[test]
+fn test_get_copy_from_image_command_excludes() {
+    let cmds = IncrementalCache::get_copy_from_image_command(
+        &Some(vec!["node_modules".to_string(), "~/.npm".to_string()]),
+        &BTreeMap::from([(
+            "node_modules".to_string(),
+            vec!["node_modules/@esbuild/*".to_string(), "*.node".to_string()],
+        )]),
+        Some(FileServerConfig {
+            access_token: "test_access_token".to_string(),
+            upload_url: "http://test.com/upload".to_string(),
+            ..Default::default()
+        }),
+        DEFAULT_HOME_DIR,
+        CacheCompression::None,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(
+        cmds[0],
+        "if [ -d \"node_modules\" ]; then tar --exclude='node_modules/@esbuild/*' --exclude='*.node' -cf node_modules.tar node_modules; fi;"
+    );
+    assert_eq!(
+        cmds[3],
+        "if [ -d \"/root/.npm\" ]; then tar -cf %2froot%2f.npm.tar /root/.npm; fi;"
+    );
+}
+
+#[test]
+fn test_get_copy_from_image_command_collapsed() {
+    let cmds = IncrementalCache::get_copy_from_image_command(
+        &Some(vec!["./parent_dir/child_dir".to_string()]),
+        &BTreeMap::new(),
+        Some(FileServerConfig {
+            upload_url: "http://test.com/upload".to_string(),
+            access_token: "test_access_token".to_string(),
+            ..Default::default()
+        }),
+        DEFAULT_HOME_DIR,
+        CacheCompression::None,
+        true,
+    )
+    .unwrap();
+
+    assert_eq!(cmds.len(), 1);
+    assert_eq!(cmds[0].matches("-d \"").count(), 1);
+    assert_eq!(
+        cmds[0],
+        "if [ -d \"./parent_dir/child_dir\" ]; then tar -cf .%2fparent_dir%2fchild_dir.tar ./parent_dir/child_dir && curl -v -T .%2fparent_dir%2fchild_dir.tar http://test.com/upload --header \"t:test_access_token\" --retry 3 --retry-all-errors && rm -rf ./parent_dir/child_dir; fi".to_string()
+    );
+}
+
+#[test]
+fn test_copy_commands_use_home_dir() {
+    let cache_directories = Some(vec!["~/.npm".to_string()]);
+
+    let cmds = IncrementalCache::get_copy_to_image_command(
+        &cache_directories,
+        "docker.io/library/test-image",
+        "/home/app",
+    );
+    assert_eq!(
+        cmds[0],
+        "COPY --from=docker.io/library/test-image home?/app?/.npm? /home/app/.npm".to_string()
+    );
+
+    let cmds = IncrementalCache::get_copy_from_image_command(
+        &cache_directories,
+        &BTreeMap::new(),
+        Some(FileServerConfig::default()),
+        "/home/app",
+        CacheCompression::None,
+        true,
+    )
+    .unwrap();
+    assert!(cmds[0].starts_with(
+        "if [ -d \"/home/app/.npm\" ]; then tar -cf %2fhome%2fapp%2f.npm.tar /home/app/.npm"
+    ));
+}
+
+#[test]
+fn test_get_copy_from_image_command_compressed() {
+    let server_config = FileServerConfig {
+        listen_to_ip: "0.0.0.0".to_string(),
+        port: 1234,
+        access_token: "test_access_token".to_string(),
+        upload_url: "http://test.com/upload".to_string(),
+        files_dir: PathBuf::from("./source_dir".to_string()),
+        ..Default::default()
+    };
+
+    let cmds = IncrementalCache::get_copy_from_image_command(
+        &Some(vec!["./parent_dir/child_dir".to_string()]),
+        &BTreeMap::new(),
+        Some(server_config.clone()),
+        DEFAULT_HOME_DIR,
+        CacheCompression::Gzip,
+        false,
+    )
+    .unwrap();
+    assert_eq!(cmds[0], "if [ -d \"./parent_dir/child_dir\" ]; then tar -czf .%2fparent_dir%2fchild_dir.tar.gz ./parent_dir/child_dir; fi;".to_string());
+    assert!(cmds[1].contains("curl -v -T .%2fparent_dir%2fchild_dir.tar.gz "));
+
+    let cmds = IncrementalCache::get_copy_from_image_command(
+        &Some(vec!["./parent_dir/child_dir".to_string()]),
+        &BTreeMap::new(),
+        Some(server_config),
+        DEFAULT_HOME_DIR,
+        CacheCompression::Zstd,
+        false,
+    )
+    .unwrap();
+    assert_eq!(cmds[0], "if [ -d \"./parent_dir/child_dir\" ]; then tar --zstd -cf .%2fparent_dir%2fchild_dir.tar.zst ./parent_dir/child_dir; fi;".to_string());
+    assert!(cmds[1].contains("curl -v -T .%2fparent_dir%2fchild_dir.tar.zst "));
+}
+
+#[test]
+fn test_get_copy_from_image_command_retries() {
+    use super::file_server::UploadOptions;
+
+    let cmds = IncrementalCache::get_copy_from_image_command(
+        &Some(vec!["./parent_dir/child_dir".to_string()]),
+        &BTreeMap::new(),
+        Some(FileServerConfig {
+            upload_url: "http://test.com/upload".to_string(),
+            access_token: "test_access_token".to_string(),
+            upload_options: UploadOptions {
+                retries: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+        DEFAULT_HOME_DIR,
+        CacheCompression::None,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(cmds[1], "if [ -d \"./parent_dir/child_dir\" ]; then curl -v -T .%2fparent_dir%2fchild_dir.tar http://test.com/upload --header \"t:test_access_token\"; fi;".to_string());
+}
+
+#[test]
+fn test_get_copy_from_image_command_proxy() {
+    use super::file_server::UploadOptions;
+
+    let cmds = IncrementalCache::get_copy_from_image_command(
+        &Some(vec!["./parent_dir/child_dir".to_string()]),
+        &BTreeMap::new(),
+        Some(FileServerConfig {
+            upload_url: "http://test.com/upload".to_string(),
+            access_token: "test_access_token".to_string(),
+            upload_options: UploadOptions {
+                proxy: Some("http://proxy.corp.example:3128".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+        DEFAULT_HOME_DIR,
+        CacheCompression::None,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(cmds[1], "if [ -d \"./parent_dir/child_dir\" ]; then curl -v -T .%2fparent_dir%2fchild_dir.tar http://test.com/upload --header \"t:test_access_token\" --retry 3 --retry-all-errors --proxy 'http://proxy.corp.example:3128'; fi;".to_string());
+}
+
+#[test]
+fn test_validate_cache_directory() {
+    assert_eq!(
+        IncrementalCache::validate_cache_directory("~", DEFAULT_HOME_DIR).unwrap(),
+        "/root"
+    );
+    assert_eq!(
+        IncrementalCache::validate_cache_directory("./foo", DEFAULT_HOME_DIR).unwrap(),
+        "./foo"
+    );
+
+    for dir in ["/", "", "../escape", "~/../escape"] {
+        let err = IncrementalCache::validate_cache_directory(dir, DEFAULT_HOME_DIR).unwrap_err();
+        assert!(err.to_string().contains(&format!("\"{dir}\"")));
+    }
+
+    assert!(IncrementalCache::get_copy_from_image_command(
+        &Some(vec!["/".to_string()]),
+        &BTreeMap::new(),
+        Some(FileServerConfig::default()),
+        DEFAULT_HOME_DIR,
+        CacheCompression::None,
+        true,
+    )
+    .is_err());
+}
+
+#[test]
+fn test_cache_compression_from_path() {
+    assert_eq!(
+        CacheCompression::from_path(Path::new("/uploads/%2fapp%2fnode_modules.tar")),
+        CacheCompression::None
+    );
+    assert_eq!(
+        CacheCompression::from_path(Path::new("/uploads/%2fapp%2fnode_modules.tar.gz")),
+        CacheCompression::Gzip
+    );
+    assert_eq!(
+        CacheCompression::from_path(Path::new("/uploads/%2fapp%2fnode_modules.tar.zst")),
+        CacheCompression::Zstd
+    );
+}
+
 #[test]
 fn test_get_copy_to_image_command() {
     let cmds = IncrementalCache::get_copy_to_image_command(
         &Some(vec!["./parent_dir/child_dir".to_string()]),
         "docker.io/library/test-image",
+        DEFAULT_HOME_DIR,
     );
 
     assert_eq!(cmds.len(), 1);
@@ -210,3 +1127,460 @@ fn test_get_copy_to_image_command() {
             .to_string()
     );
 }
+
+#[test]
+fn test_nix_store_copy_commands() {
+    let copy_to = IncrementalCache::get_copy_to_image_command(
+        &Some(
+            NIX_STORE_CACHE_DIRECTORIES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        ),
+        "registry.test/cache",
+        DEFAULT_HOME_DIR,
+    );
+    assert_eq!(
+        copy_to,
+        vec![
+            "COPY --from=registry.test/cache nix?/store? /nix/store",
+            "COPY --from=registry.test/cache nix?/var?/nix?/db? /nix/var/nix/db",
+        ]
+    );
+
+    let config = FileServerConfig {
+        upload_url: "http://localhost:8080/upload".to_string(),
+        access_token: "token".to_string(),
+        ..Default::default()
+    };
+    let copy_from = IncrementalCache::get_nix_store_copy_from_image_command(
+        Some(config),
+        CacheCompression::Gzip,
+    );
+    assert_eq!(copy_from.len(), 2);
+    assert!(copy_from[0].starts_with(
+        "if [ -d \"/nix/store\" ]; then tar -czf %2fnix%2fstore.tar.gz /nix/store && curl -v -T %2fnix%2fstore.tar.gz"
+    ));
+    assert!(copy_from[0].ends_with("&& rm -f %2fnix%2fstore.tar.gz; fi"));
+    assert!(!copy_from[0].contains("rm -rf /nix/store"));
+    assert!(copy_from[1].contains("%2fnix%2fvar%2fnix%2fdb.tar.gz"));
+
+    assert!(
+        IncrementalCache::get_nix_store_copy_from_image_command(None, CacheCompression::Gzip)
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_image_tag_includes_inputs_hash() {
+    let lockfile_v1 = vec![("package-lock.json".to_string(), b"v1".to_vec())];
+    let lockfile_v2 = vec![("package-lock.json".to_string(), b"v2".to_vec())];
+
+    let tag_v1 = IncrementalCache::get_image_tag(
+        "registry.test/cache",
+        &IncrementalCache::compute_inputs_hash(&lockfile_v1),
+    );
+    let tag_v2 = IncrementalCache::get_image_tag(
+        "registry.test/cache",
+        &IncrementalCache::compute_inputs_hash(&lockfile_v2),
+    );
+
+    assert_ne!(tag_v1, tag_v2);
+    assert!(tag_v1.starts_with("registry.test/cache:"));
+    assert_eq!(
+        tag_v1,
+        IncrementalCache::get_image_tag(
+            "registry.test/cache",
+            &IncrementalCache::compute_inputs_hash(&lockfile_v1),
+        )
+    );
+
+    let tag = IncrementalCache::get_image_tag("localhost:5000/cache:latest", "0123456789abcdef");
+    assert_eq!(tag, "localhost:5000/cache:latest-0123456789ab");
+}
+
+#[test]
+fn test_read_cache_inputs() {
+    let dir = tempdir::TempDir::new("nixpacks-cache-inputs").unwrap();
+    fs::write(dir.path().join("package-lock.json"), "{}").unwrap();
+    fs::write(dir.path().join("uv.lock"), "version = 1").unwrap();
+
+    let names = |extra_files: &[String]| {
+        IncrementalCache::read_cache_inputs(dir.path(), extra_files)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&[]), vec!["package-lock.json"]);
+    assert_eq!(
+        names(&[
+            "uv.lock".to_string(),
+            "package-lock.json".to_string(),
+            "missing.lock".to_string()
+        ]),
+        vec!["package-lock.json", "uv.lock"]
+    );
+}
+
+#[test]
+fn test_inputs_hash_is_order_independent() {
+    let a = ("a.lock".to_string(), b"a".to_vec());
+    let b = ("b.lock".to_string(), b"b".to_vec());
+
+    assert_eq!(
+        IncrementalCache::compute_inputs_hash(&[a.clone(), b.clone()]),
+        IncrementalCache::compute_inputs_hash(&[b, a])
+    );
+}
+
+#[test]
+fn test_custom_cache_backend() {
+    let incremental_cache = IncrementalCache::new(Box::new(FakeCacheBackend {
+        existing_images: vec!["registry.test/cache:existing".to_string()],
+        ..Default::default()
+    }));
+
+    assert!(incremental_cache
+        .is_image_exists("registry.test/cache:existing")
+        .unwrap());
+    assert!(!incremental_cache
+        .is_image_exists("registry.test/cache:missing")
+        .unwrap());
+}
+
+#[test]
+fn test_normalize_image_tag() {
+    assert_eq!(
+        IncrementalCache::normalize_image_tag("Registry.Example.com:5000/Team/Cache"),
+        "Registry.Example.com:5000/team/cache"
+    );
+    assert_eq!(
+        IncrementalCache::normalize_image_tag("ghcr.io/team/cache:Main-0123"),
+        "ghcr.io/team/cache:Main-0123"
+    );
+    assert_eq!(
+        IncrementalCache::normalize_image_tag("localhost:5000/cache@sha256:abc"),
+        "localhost:5000/cache@sha256:abc"
+    );
+    assert_eq!(
+        IncrementalCache::normalize_image_tag("MyUser/Cache"),
+        "myuser/cache"
+    );
+}
+
+#[test]
+fn test_push_image_to_registry() {
+    let backend = FakeCacheBackend {
+        failing_prefixes: vec!["denied/".to_string()],
+        ..Default::default()
+    };
+    let incremental_cache = IncrementalCache::new(Box::new(backend.clone()));
+
+    // Images without a registry are Docker Hub repositories, and a failed push does not fail the build
+    let tag = IncrementalCache::normalize_image_tag("registry.example.com:5000/Team/Cache");
+    assert!(incremental_cache.push_image(&tag));
+    assert!(incremental_cache.push_image("myuser/cache"));
+    assert!(!incremental_cache.push_image("denied/cache"));
+    assert_eq!(
+        backend.calls(),
+        vec![
+            "push registry.example.com:5000/team/cache",
+            "push myuser/cache"
+        ]
+    );
+    assert_eq!(
+        IncrementalCache::get_registry(&tag),
+        Some("registry.example.com:5000")
+    );
+}
+
+#[test]
+fn test_create_image_reports_failed_file() {
+    let tmp = tempdir::TempDir::new("nixpacks-incremental-cache").unwrap();
+    let output = OutputDir::new(tmp.path().to_path_buf(), true).unwrap();
+    let dirs = IncrementalCacheDirs::new(&output);
+    dirs.create().unwrap();
+    for name in ["a.tar", "bad-2.tar", "bad-1.tar", "c.tar"] {
+        fs::write(dirs.uploads_dir.join(name), "").unwrap();
+    }
+
+    let incremental_cache = IncrementalCache::new(Box::new(FakeCacheBackend {
+        failing_prefixes: vec!["bad".to_string()],
+        ..Default::default()
+    }))
+    .with_max_parallel_imports(4);
+    let err = incremental_cache
+        .create_image(&dirs, "registry.test/cache")
+        .unwrap_err();
+
+    assert!(err.to_string().contains("bad-1.tar"));
+}
+
+#[test]
+fn test_create_image_imports_in_parallel() {
+    let tmp = tempdir::TempDir::new("nixpacks-incremental-cache").unwrap();
+    let output = OutputDir::new(tmp.path().to_path_buf(), true).unwrap();
+    let dirs = IncrementalCacheDirs::new(&output);
+    dirs.create().unwrap();
+    for name in ["d.tar", "b.tar", "a.tar", "c.tar", "e.tar"] {
+        fs::write(dirs.uploads_dir.join(name), "").unwrap();
+    }
+
+    // The files are imported at the same time, except for the last one, which decides what the tag points to on every build
+    let backend = FakeCacheBackend {
+        import_delay: Duration::from_millis(100),
+        ..Default::default()
+    };
+    IncrementalCache::new(Box::new(backend.clone()))
+        .with_max_parallel_imports(4)
+        .create_image(&dirs, "registry.test/cache")
+        .unwrap();
+
+    let mut calls = backend.calls();
+    assert_eq!(calls.pop().unwrap(), "create_image e.tar");
+    calls.sort();
+    assert_eq!(
+        calls,
+        vec![
+            "create_image a.tar",
+            "create_image b.tar",
+            "create_image c.tar",
+            "create_image d.tar",
+        ]
+    );
+    assert_eq!(backend.max_concurrent_imports(), 4);
+}
+
+#[test]
+fn test_incremental_cache_dirs_create_keeps_contents() {
+    let tmp = tempdir::TempDir::new("nixpacks-incremental-cache").unwrap();
+    let output = OutputDir::new(tmp.path().to_path_buf(), true).unwrap();
+    let dirs = IncrementalCacheDirs::new(&output);
+
+    dirs.create().unwrap();
+    let cached_file = dirs.uploads_dir.join("%2froot%2f.npm.tar");
+    fs::write(&cached_file, "cached").unwrap();
+
+    dirs.create().unwrap();
+    assert!(cached_file.is_file());
+    assert!(dirs.image_dir.is_dir());
+}
+
+#[test]
+fn test_incremental_cache_dirs_clear_uploads() {
+    let tmp = tempdir::TempDir::new("nixpacks-incremental-cache").unwrap();
+    let output = OutputDir::new(tmp.path().to_path_buf(), true).unwrap();
+    let dirs = IncrementalCacheDirs::new(&output);
+
+    dirs.create().unwrap();
+    let stale_file = dirs.uploads_dir.join("%2froot%2f.npm.tar");
+    fs::write(&stale_file, "stale").unwrap();
+    let image_file = dirs.image_dir.join("Dockerfile");
+    fs::write(&image_file, "FROM scratch").unwrap();
+
+    dirs.clear_uploads().unwrap();
+    assert!(!stale_file.exists());
+    assert!(dirs.uploads_dir.is_dir());
+    assert!(image_file.is_file());
+}
+
+#[test]
+fn test_incremental_cache_dirs_reset() {
+    let tmp = tempdir::TempDir::new("nixpacks-incremental-cache").unwrap();
+    let output = OutputDir::new(tmp.path().to_path_buf(), true).unwrap();
+    let dirs = IncrementalCacheDirs::new(&output);
+
+    dirs.create().unwrap();
+    let cached_file = dirs.uploads_dir.join("%2froot%2f.npm.tar");
+    fs::write(&cached_file, "cached").unwrap();
+
+    dirs.reset().unwrap();
+    assert!(!cached_file.exists());
+    assert!(dirs.uploads_dir.is_dir());
+    assert!(dirs.image_dir.is_dir());
+}
+
+#[test]
+fn test_create_image_with_empty_uploads_dir() {
+    let tmp = tempdir::TempDir::new("nixpacks-incremental-cache").unwrap();
+    let output = OutputDir::new(tmp.path().to_path_buf(), true).unwrap();
+    let dirs = IncrementalCacheDirs::new(&output);
+    dirs.create().unwrap();
+
+    let backend = FakeCacheBackend::default();
+    let incremental_cache = IncrementalCache::new(Box::new(backend.clone()));
+    assert!(!incremental_cache
+        .create_image(&dirs, "registry.test/cache")
+        .unwrap());
+    assert!(backend.calls().is_empty());
+}
+
+#[cfg(test)]
+fn cache_entry(name: &str, size: u64, age_secs: u64, now: SystemTime) -> CacheEntry {
+    CacheEntry {
+        path: PathBuf::from(name),
+        size,
+        modified: now - Duration::from_secs(age_secs),
+    }
+}
+
+#[test]
+fn test_select_evictions_removes_least_recently_used_first() {
+    let now = SystemTime::now();
+    let entries = vec![
+        cache_entry("new.tar", 100, 60, now),
+        cache_entry("oldest.tar", 100, 3600, now),
+        cache_entry("old.tar", 100, 600, now),
+    ];
+
+    let names = |evicted: Vec<CacheEntry>| {
+        evicted
+            .into_iter()
+            .map(|entry| entry.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert!(select_evictions(entries.clone(), now, None, None).is_empty());
+    assert_eq!(
+        names(select_evictions(entries.clone(), now, None, Some(150))),
+        vec!["oldest.tar", "old.tar"]
+    );
+    assert_eq!(
+        names(select_evictions(
+            entries.clone(),
+            now,
+            Some(Duration::from_secs(300)),
+            None
+        )),
+        vec!["oldest.tar", "old.tar"]
+    );
+    assert_eq!(
+        names(select_evictions(
+            entries,
+            now,
+            Some(Duration::from_secs(1800)),
+            Some(250)
+        )),
+        vec!["oldest.tar"]
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_prune_uses_modification_times() {
+    let tmp = tempdir::TempDir::new("nixpacks-incremental-cache").unwrap();
+    let dirs = IncrementalCacheDirs::from_output_path(tmp.path().to_str().unwrap()).unwrap();
+    dirs.create().unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    for (name, age) in [("a.tar", 3 * 86400), ("b.tar", 2 * 86400), ("c.tar", 60)] {
+        let path = dirs.uploads_dir.join(name);
+        fs::write(&path, [0u8; 10]).unwrap();
+        let status = Command::new("touch")
+            .arg("-m")
+            .arg("-d")
+            .arg(format!("@{}", now - age))
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    let summary = dirs
+        .prune(Some(Duration::from_secs(5 * 86400)), Some(15))
+        .unwrap();
+    assert_eq!(
+        summary,
+        PruneSummary {
+            removed: vec![
+                dirs.uploads_dir.join("a.tar"),
+                dirs.uploads_dir.join("b.tar")
+            ],
+            freed_bytes: 20,
+            remaining_bytes: 10,
+        }
+    );
+    assert!(dirs.uploads_dir.join("c.tar").is_file());
+
+    let summary = dirs.prune(Some(Duration::from_secs(30)), None).unwrap();
+    assert_eq!(summary.removed, vec![dirs.uploads_dir.join("c.tar")]);
+}
+
+#[test]
+fn test_parse_prune_limits() {
+    assert_eq!(parse_max_age("90").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_max_age("12h").unwrap(), Duration::from_secs(43200));
+    assert_eq!(parse_max_age("7d").unwrap(), Duration::from_secs(604_800));
+    assert!(parse_max_age("7 days").is_err());
+    assert!(parse_max_age("d").is_err());
+    assert!(parse_max_age(&format!("{}w", u64::MAX / 2)).is_err());
+
+    assert_eq!(parse_max_size("512").unwrap(), 512);
+    assert_eq!(parse_max_size("500MB").unwrap(), 500 * 1024 * 1024);
+    assert_eq!(parse_max_size("10g").unwrap(), 10 * 1024 * 1024 * 1024);
+    assert!(parse_max_size("10 parsecs").is_err());
+    assert!(parse_max_size("20000000TB").is_err());
+}
+
+#[test]
+fn test_image_lookup_falls_back_when_manifest_is_experimental() {
+    let backend = DockerCacheBackend::default();
+    let mut calls = Vec::new();
+    let exists = backend
+        .lookup_image("registry.test/cache", |args| {
+            calls.push(args.join(" "));
+            match args[0] {
+                "manifest" => Ok((
+                    false,
+                    "docker manifest inspect is only supported on a Docker cli with experimental cli features enabled".to_string(),
+                )),
+                _ => Ok((true, String::new())),
+            }
+        })
+        .unwrap();
+
+    assert!(exists);
+    assert_eq!(
+        calls,
+        vec![
+            "manifest inspect registry.test/cache",
+            "buildx imagetools inspect registry.test/cache"
+        ]
+    );
+}
+
+#[test]
+fn test_image_lookup_distinguishes_absent_from_unsupported() {
+    let backend = DockerCacheBackend::default();
+
+    // A missing image is reported by the first command, without falling back
+    let mut calls = 0;
+    let exists = backend
+        .lookup_image("registry.test/cache", |_| {
+            calls += 1;
+            Ok((
+                false,
+                "no such manifest: registry.test/cache:latest".to_string(),
+            ))
+        })
+        .unwrap();
+    assert!(!exists);
+    assert_eq!(calls, 1);
+
+    let mut calls = 0;
+    let exists = backend
+        .lookup_image("registry.test/cache", |args| {
+            calls += 1;
+            Ok((
+                false,
+                format!("docker: '{}' is not a docker command.", args[0]),
+            ))
+        })
+        .unwrap();
+    assert!(!exists);
+    assert_eq!(calls, IMAGE_LOOKUP_COMMANDS.len());
+}
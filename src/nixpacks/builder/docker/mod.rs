@@ -1,4 +1,9 @@
-use super::ImageBuilder;
+use self::{
+    file_server::UploadOptions, incremental_cache::CacheCompression, registry_auth::RegistryAuth,
+};
+use super::{BuildEvent, ImageBuilder};
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::UnboundedSender;
 
 /// Holds options for generating a Docker image.
 #[derive(Clone, Default, Debug)]
@@ -6,9 +11,18 @@ use super::ImageBuilder;
 pub struct DockerBuilderOptions {
     pub name: Option<String>,
     pub out_dir: Option<String>,
+    /// File name of the generated Dockerfile in the output directory, instead of `Dockerfile`.
+    pub dockerfile_name: Option<String>,
+    /// Write the built image to this tarball instead of loading it into the Docker daemon.
+    pub output_tar: Option<String>,
     pub print_dockerfile: bool,
+    pub dry_run: bool,
     pub tags: Vec<String>,
     pub labels: Vec<String>,
+    /// Values for `ARG`s that are declared in the build stage, which unlike plan variables are not set as environment variables of the image.
+    pub build_args: BTreeMap<String, String>,
+    /// Do not label the image with the git commit, branch, and repository of the app.
+    pub no_git_labels: bool,
     pub quiet: bool,
     pub cache_key: Option<String>,
     pub no_cache: bool,
@@ -16,14 +30,53 @@ pub struct DockerBuilderOptions {
     pub cache_from: Option<String>,
     pub platform: Vec<String>,
     pub current_dir: bool,
+    /// Shell command run on the host in the app directory before the image is built, e.g. to generate code with tools that are not in the image.
+    ///
+    /// This is only an option of the builder, so that the config of an app can never run commands on the host.
+    pub prebuild: Option<String>,
     pub no_error_without_start: bool,
     pub incremental_cache_image: Option<String>,
+    /// Build without the incremental cache, even if an incremental cache image is given.
+    pub no_incremental_cache: bool,
+    /// Keep the created incremental cache image in the local Docker daemon instead of pushing it.
+    pub no_incremental_cache_push: bool,
+    pub incremental_cache_key: Option<String>,
+    pub incremental_cache_compression: CacheCompression,
+    pub incremental_cache_upload_options: UploadOptions,
+    pub incremental_cache_collapse_commands: bool,
+    /// Emit each command of a phase as its own RUN instruction instead of joining them into one.
+    pub split_phase_commands: bool,
+    pub incremental_cache_checksum: bool,
+    /// Files of the app that key the incremental cache with `incremental_cache_checksum`, in addition to the common lockfiles.
+    pub incremental_cache_inputs: Vec<String>,
+    /// Also store the Nix store in the incremental cache, so that Nix packages are not fetched again.
+    pub incremental_cache_nix_store: bool,
+    pub incremental_cache_reset: bool,
+    /// Authenticate incremental cache uploads with a plain token instead of an expiring signed one.
+    pub incremental_cache_plain_token: bool,
+    pub home_dir: Option<String>,
+    /// A nixpkgs tarball or directory to install Nix packages from, so that nixpkgs is not fetched during the build.
+    pub nixpkgs_path: Option<String>,
     pub verbose: bool,
+    /// Registries to log in to before building, so that private base images can be pulled and images pushed.
+    pub registry_auth: Vec<RegistryAuth>,
+    /// The Docker CLI to build with, as a path or a name that is looked up in `PATH`, instead of `DOCKER_BIN` or `docker`.
+    pub docker_bin: Option<String>,
+    /// Stop the docker build if it runs for longer than this many seconds.
+    pub build_timeout: Option<u64>,
+    /// Receives progress events during the build.
+    pub progress_sender: Option<UnboundedSender<BuildEvent>>,
 }
 
 mod cache;
 pub mod docker_image_builder;
 mod dockerfile_generation;
+#[cfg(test)]
+mod fake_cache_backend;
 pub mod file_server;
+pub(crate) mod git_labels;
 pub mod incremental_cache;
+mod phase_progress;
+mod process;
+pub mod registry_auth;
 pub mod utils;
@@ -0,0 +1,174 @@
+use crate::nixpacks::builder::BuildEvent;
+
+/// Follows the plain progress output of a BuildKit build, to tell when the phases of the plan start and finish.
+///
+/// BuildKit prints each step of the build as `#<id> [<stage> <n>/<total>] <instruction>` when it starts.
+/// The instructions are looked up in the generated Dockerfile, where each phase is a `# <name> phase` section.
+/// Phases without any instructions that run as steps, such as noop phases, are skipped.
+pub struct PhaseProgress {
+    /// Every instruction of the Dockerfile in order, with the phase it belongs to.
+    instructions: Vec<(Option<String>, String)>,
+    /// The index of the instruction of the last step.
+    cursor: Option<usize>,
+    last_step_id: Option<String>,
+    current_phase: Option<String>,
+}
+
+impl PhaseProgress {
+    pub fn new(dockerfile: &str, phases: &[String]) -> PhaseProgress {
+        let mut instructions = Vec::new();
+        let mut phase: Option<String> = None;
+        let mut instruction = String::new();
+        for line in dockerfile.lines() {
+            let line = line.trim();
+
+            // The section of a phase ends at the blank line after it
+            if line.is_empty() {
+                phase = None;
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                let name = comment.trim().strip_suffix(" phase");
+                if let Some(name) = name.filter(|name| phases.iter().any(|phase| phase == name)) {
+                    phase = Some(name.to_string());
+                }
+                continue;
+            }
+
+            // Instructions can continue on the next line
+            instruction.push_str(line.trim_end_matches('\\'));
+            if line.ends_with('\\') {
+                instruction.push(' ');
+                continue;
+            }
+            instructions.push((phase.clone(), normalize(&instruction)));
+            instruction.clear();
+        }
+
+        PhaseProgress {
+            instructions,
+            cursor: None,
+            last_step_id: None,
+            current_phase: None,
+        }
+    }
+
+    /// The events for a line of the build output.
+    pub fn on_line(&mut self, line: &str) -> Vec<BuildEvent> {
+        let Some((step_id, instruction)) = parse_step(line) else {
+            return Vec::new();
+        };
+
+        // BuildKit prints a step again when its output continues after that of another step
+        if self.last_step_id.as_deref() == Some(step_id) {
+            return Vec::new();
+        }
+        self.last_step_id = Some(step_id.to_string());
+
+        let instruction = normalize(instruction);
+        let start = self.cursor.map_or(0, |cursor| cursor + 1);
+        let Some(index) = self.instructions[start.min(self.instructions.len())..]
+            .iter()
+            .position(|(_, known)| *known == instruction)
+            .map(|position| start + position)
+        else {
+            return Vec::new();
+        };
+        self.cursor = Some(index);
+
+        let phase = self.instructions[index].0.clone();
+        if phase == self.current_phase {
+            return Vec::new();
+        }
+
+        let mut events = self.finish();
+        if let Some(phase) = phase {
+            events.push(BuildEvent::PhaseStarted {
+                phase: phase.clone(),
+            });
+            self.current_phase = Some(phase);
+        }
+        events
+    }
+
+    /// The event for the phase that is running, once the build succeeded.
+    pub fn finish(&mut self) -> Vec<BuildEvent> {
+        self.current_phase
+            .take()
+            .map(|phase| BuildEvent::PhaseFinished { phase })
+            .into_iter()
+            .collect()
+    }
+}
+
+/// The id and instruction of a line of BuildKit output that starts a step, e.g. `#8 [stage-0 4/9] RUN npm ci`.
+fn parse_step(line: &str) -> Option<(&str, &str)> {
+    let (step_id, rest) = line.strip_prefix('#')?.split_once(' ')?;
+    let (vertex, instruction) = rest.strip_prefix('[')?.split_once("] ")?;
+
+    // Steps of the Dockerfile are numbered, unlike internal ones such as `[internal] load metadata`
+    let (_, position) = vertex.rsplit_once(' ')?;
+    position.contains('/').then_some((step_id, instruction))
+}
+
+fn normalize(instruction: &str) -> String {
+    instruction.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_progress() {
+        let dockerfile = "FROM ubuntu\nRUN nix-env -if nixpkgs.nix\n\n# setup phase\n# noop\n\n# install phase\nENV NIXPACKS_PATH /app/node_modules/.bin:$NIXPACKS_PATH\nCOPY . /app/.\nRUN --mount=type=cache,id=npm,target=/root/.npm npm ci\n\n# build phase\nCOPY . /app/.\nRUN npm run \\\n  build\n\n# start\nCOPY . /app\n";
+        let phases = ["setup", "install", "build"].map(ToString::to_string);
+        let mut progress = PhaseProgress::new(dockerfile, &phases);
+
+        let output = [
+            "#1 [internal] load build definition from Dockerfile",
+            "#5 [stage-0 2/8] RUN nix-env -if nixpkgs.nix",
+            "#5 CACHED",
+            "#6 [stage-0 3/8] COPY . /app/.",
+            "#7 [stage-0 4/8] RUN --mount=type=cache,id=npm,target=/root/.npm npm ci",
+            "#7 1.234 added 12 packages",
+            "#7 [stage-0 4/8] RUN --mount=type=cache,id=npm,target=/root/.npm npm ci",
+            "#8 [stage-0 5/8] COPY . /app/.",
+            "#9 [stage-0 6/8] RUN npm run build",
+        ];
+        let mut events = output
+            .iter()
+            .flat_map(|line| progress.on_line(line))
+            .collect::<Vec<_>>();
+        events.extend(progress.finish());
+
+        assert_eq!(
+            events,
+            vec![
+                BuildEvent::PhaseStarted {
+                    phase: "install".to_string()
+                },
+                BuildEvent::PhaseFinished {
+                    phase: "install".to_string()
+                },
+                BuildEvent::PhaseStarted {
+                    phase: "build".to_string()
+                },
+                BuildEvent::PhaseFinished {
+                    phase: "build".to_string()
+                },
+            ]
+        );
+
+        // A step of the start of the image finishes the last phase
+        let mut progress = PhaseProgress::new(dockerfile, &phases);
+        progress.on_line("#9 [stage-0 6/8] RUN npm run build");
+        assert_eq!(
+            progress.on_line("#10 [stage-0 7/8] COPY . /app"),
+            vec![BuildEvent::PhaseFinished {
+                phase: "build".to_string()
+            }]
+        );
+        assert!(progress.finish().is_empty());
+    }
+}
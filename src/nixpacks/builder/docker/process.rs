@@ -0,0 +1,355 @@
+use anyhow::{bail, Result};
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::Duration,
+};
+use wait_timeout::ChildExt;
+
+/// How long a timed out command is given to exit after being asked to terminate.
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Run a command to completion, terminating it and every process it started if it runs longer than the timeout.
+///
+/// With a timeout the command runs in its own process group, so that its children are not left running.
+/// That group is not in the foreground of the terminal, so a Ctrl-C or SIGTERM sent to nixpacks while it runs is forwarded to the group.
+pub fn run_with_timeout(cmd: &mut Command, timeout: Option<Duration>) -> Result<ExitStatus> {
+    run(cmd, timeout, |_| {})
+}
+
+/// Run a command like [`run_with_timeout`], passing each line it writes to stderr to `on_line` after it is written to the stderr of nixpacks.
+pub fn run_with_timeout_reading_stderr<F>(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    mut on_line: F,
+) -> Result<ExitStatus>
+where
+    F: FnMut(&str) + Send + 'static,
+{
+    let mut reader = None;
+    let result = run(cmd.stderr(Stdio::piped()), timeout, |child| {
+        if let Some(stderr) = child.stderr.take() {
+            reader = Some(thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    eprintln!("{line}");
+                    on_line(&line);
+                }
+            }));
+        }
+    });
+
+    // The pipe is closed once the command and its children exited
+    if let Some(reader) = reader {
+        let _ = reader.join();
+    }
+
+    result
+}
+
+fn run(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    on_spawn: impl FnOnce(&mut Child),
+) -> Result<ExitStatus> {
+    let Some(timeout) = timeout else {
+        let mut child = cmd.spawn()?;
+        on_spawn(&mut child);
+        return Ok(child.wait()?);
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        // SAFETY: setpgid is async-signal-safe and this closure does not allocate
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 {
+                    std::result::Result::Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    let forwarder = signals::Forwarder::install();
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            forwarder.restore();
+            return Err(err.into());
+        }
+    };
+    forwarder.forward_to(&child);
+    on_spawn(&mut child);
+
+    let result = child.wait_timeout(timeout);
+    let interrupted = forwarder.restore();
+    if let Some(signal) = interrupted {
+        terminate(&mut child)?;
+        bail!("Interrupted by signal {signal}");
+    }
+    if let Some(status) = result? {
+        return Ok(status);
+    }
+
+    terminate(&mut child)?;
+    bail!("Timed out after {}s", timeout.as_secs())
+}
+
+/// Forwards SIGINT and SIGTERM to the process group of a command while it runs.
+#[cfg(unix)]
+mod signals {
+    use std::{
+        process::Child,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+    /// The process group that signals are forwarded to, or 0 before the command is spawned.
+    static PGID: AtomicI32 = AtomicI32::new(0);
+
+    /// The last signal received while the command ran, or 0.
+    static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn forward(signal: libc::c_int) {
+        RECEIVED.store(signal, Ordering::SeqCst);
+        let pgid = PGID.load(Ordering::SeqCst);
+        if pgid > 0 {
+            // SAFETY: killpg is async-signal-safe
+            unsafe {
+                libc::killpg(pgid, signal);
+            }
+        }
+    }
+
+    pub struct Forwarder {
+        previous: Vec<(libc::c_int, libc::sigaction)>,
+    }
+
+    impl Forwarder {
+        /// Handle the signals in nixpacks until the forwarder is restored.
+        pub fn install() -> Forwarder {
+            PGID.store(0, Ordering::SeqCst);
+            RECEIVED.store(0, Ordering::SeqCst);
+
+            let previous = SIGNALS
+                .iter()
+                .map(|&signal| {
+                    // SAFETY: the handler only touches atomics and calls killpg
+                    unsafe {
+                        let mut action: libc::sigaction = std::mem::zeroed();
+                        action.sa_sigaction = forward as extern "C" fn(libc::c_int) as usize;
+                        libc::sigemptyset(&mut action.sa_mask);
+                        let mut previous: libc::sigaction = std::mem::zeroed();
+                        libc::sigaction(signal, &action, &mut previous);
+                        (signal, previous)
+                    }
+                })
+                .collect();
+
+            Forwarder { previous }
+        }
+
+        /// Forward signals to the process group of the child, including one received while it was spawned.
+        pub fn forward_to(&self, child: &Child) {
+            let pgid = child.id() as libc::pid_t;
+            PGID.store(pgid, Ordering::SeqCst);
+
+            let signal = RECEIVED.load(Ordering::SeqCst);
+            if signal != 0 {
+                // SAFETY: the child is the leader of its own process group, which is not reaped yet
+                unsafe {
+                    libc::killpg(pgid, signal);
+                }
+            }
+        }
+
+        /// Restore the previous handlers, returning the signal that was forwarded, if any.
+        pub fn restore(self) -> Option<libc::c_int> {
+            for (signal, previous) in &self.previous {
+                // SAFETY: the previous action was returned by sigaction
+                unsafe {
+                    libc::sigaction(*signal, previous, std::ptr::null_mut());
+                }
+            }
+            PGID.store(0, Ordering::SeqCst);
+
+            match RECEIVED.swap(0, Ordering::SeqCst) {
+                0 => None,
+                signal => Some(signal),
+            }
+        }
+    }
+}
+
+/// Signals reach the command directly on other platforms.
+#[cfg(not(unix))]
+mod signals {
+    use std::process::Child;
+
+    pub struct Forwarder {}
+
+    impl Forwarder {
+        pub fn install() -> Forwarder {
+            Forwarder {}
+        }
+
+        pub fn forward_to(&self, _child: &Child) {}
+
+        pub fn restore(self) -> Option<i32> {
+            None
+        }
+    }
+}
+
+/// Ask the process group of the child to terminate, and kill it if it is still running after the grace period.
+#[cfg(unix)]
+fn terminate(child: &mut Child) -> Result<()> {
+    let pgid = child.id() as libc::pid_t;
+
+    // SAFETY: the child is the leader of its own process group, which is not reaped until the wait below
+    unsafe {
+        libc::killpg(pgid, libc::SIGTERM);
+    }
+    child.wait_timeout(TERMINATE_GRACE_PERIOD)?;
+
+    // Children that outlive the group leader are killed as well
+    unsafe {
+        libc::killpg(pgid, libc::SIGKILL);
+    }
+    child.wait()?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) -> Result<()> {
+    child.kill()?;
+    child.wait()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::Instant};
+
+    /// Whether a process exists and has not exited, since orphaned zombies may not be reaped in containers.
+    #[cfg(target_os = "linux")]
+    fn is_running(pid: &str) -> bool {
+        fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+            stat.rsplit_once(") ")
+                .is_some_and(|(_, fields)| !fields.starts_with('Z'))
+        })
+    }
+
+    /// The signal handlers are shared by the process, so the commands of these tests do not run at the same time.
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn test_run_without_timeout() {
+        let _lock = LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        let status = run_with_timeout(Command::new("sh").args(["-c", "exit 3"]), None).unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let status = run_with_timeout(
+            Command::new("sh").args(["-c", "true"]),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_run_reading_stderr() {
+        let _lock = LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        for timeout in [None, Some(Duration::from_secs(10))] {
+            let lines = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let collected = lines.clone();
+            let status = run_with_timeout_reading_stderr(
+                Command::new("sh").args(["-c", "echo one >&2; echo out; echo two >&2; exit 2"]),
+                timeout,
+                move |line| collected.lock().unwrap().push(line.to_string()),
+            )
+            .unwrap();
+
+            assert_eq!(status.code(), Some(2));
+            assert_eq!(*lines.lock().unwrap(), vec!["one", "two"]);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_run_with_timeout_kills_process_group() {
+        let _lock = LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let dir = tempdir::TempDir::new("nixpacks-timeout").unwrap();
+        let pid_file = dir.path().join("pid");
+
+        // The shell starts a child that would outlive it, and exits itself on SIGTERM
+        let start = Instant::now();
+        let err = run_with_timeout(
+            Command::new("sh").args([
+                "-c",
+                &format!("sleep 60 & echo $! > {}; wait", pid_file.display()),
+            ]),
+            Some(Duration::from_secs(1)),
+        )
+        .unwrap_err();
+
+        assert_eq!(err.to_string(), "Timed out after 1s");
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        let pid = fs::read_to_string(pid_file).unwrap();
+        assert!(!is_running(pid.trim()));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_run_with_timeout_forwards_signals() {
+        let _lock = LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let dir = tempdir::TempDir::new("nixpacks-signal").unwrap();
+        let pid_file = dir.path().join("pid");
+
+        // The shell sends SIGTERM to nixpacks, as a Ctrl-C or `docker stop` would, which is forwarded to its group
+        let start = Instant::now();
+        let err = run_with_timeout(
+            Command::new("sh").args([
+                "-c",
+                &format!(
+                    "sleep 60 & echo $! > {}; kill -TERM $PPID; wait",
+                    pid_file.display()
+                ),
+            ]),
+            Some(Duration::from_secs(60)),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!("Interrupted by signal {}", libc::SIGTERM)
+        );
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        // The killed child exits asynchronously, once it is scheduled again
+        let pid = fs::read_to_string(pid_file).unwrap();
+        while is_running(pid.trim()) {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            std::thread::yield_now();
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use tempdir::TempDir;
+
+/// Replaces credentials in anything that is logged.
+const REDACTED: &str = "********";
+
+/// The environment variable that points the Docker CLI at another config directory than `~/.docker`.
+pub const DOCKER_CONFIG_ENV_VAR: &str = "DOCKER_CONFIG";
+
+/// Credentials for a Docker registry, used to `docker login` before the image is built and pushed.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+pub struct RegistryAuth {
+    #[serde(skip)]
+    pub registry: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for RegistryAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryAuth")
+            .field("registry", &self.registry)
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
+impl RegistryAuth {
+    /// Read the credentials of each registry from a JSON file, e.g. `{ "ghcr.io": { "username": "...", "password": "..." } }`.
+    pub fn from_file(path: &str) -> Result<Vec<RegistryAuth>> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Reading registry credentials from {path}"))?;
+        let registries: BTreeMap<String, RegistryAuth> = serde_json::from_str(&contents)
+            .with_context(|| format!("Parsing registry credentials from {path}"))?;
+
+        Ok(registries
+            .into_iter()
+            .map(|(registry, auth)| RegistryAuth { registry, ..auth })
+            .collect())
+    }
+
+    /// The `docker login` arguments, which read the password from stdin so it never appears in the process list.
+    pub fn get_login_args(&self) -> Vec<String> {
+        vec![
+            "login".to_string(),
+            self.registry.clone(),
+            "--username".to_string(),
+            self.username.clone(),
+            "--password-stdin".to_string(),
+        ]
+    }
+
+    /// Log in to the registry with the Docker CLI, storing the credentials in the given Docker config directory.
+    pub fn login(&self, docker_bin: &str, docker_config: &Path) -> Result<()> {
+        let mut child = Command::new(docker_bin)
+            .args(self.get_login_args())
+            .env(DOCKER_CONFIG_ENV_VAR, docker_config)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Running docker login")?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(self.password.as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "Logging in to {} failed: {}",
+                self.registry,
+                self.redact(String::from_utf8_lossy(&output.stderr).trim())
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Remove the password from output of the Docker CLI before it is logged.
+    pub fn redact(&self, output: &str) -> String {
+        if self.password.is_empty() {
+            return output.to_string();
+        }
+
+        output.replace(&self.password, REDACTED)
+    }
+}
+
+/// The registries that were logged in to for a build.
+///
+/// `docker login` stores the credentials in the Docker config, so the build logs in with a Docker config directory of its own, which is removed when this is dropped, even if the build fails.
+/// The Docker config of the user, and other builds that use it, are left alone.
+pub struct RegistryLogins {
+    config_dir: Option<TempDir>,
+}
+
+impl RegistryLogins {
+    /// Log in to each registry with a new Docker config directory, which is only made if there are registries to log in to.
+    pub fn login(auths: &[RegistryAuth], docker_bin: &str) -> Result<RegistryLogins> {
+        if auths.is_empty() {
+            return Ok(RegistryLogins { config_dir: None });
+        }
+
+        let config_dir = TempDir::new("nixpacks-docker-config")
+            .context("Creating a Docker config directory for the build")?;
+        if let Some(user_config_dir) = get_user_docker_config_dir() {
+            init_docker_config(&user_config_dir, config_dir.path(), auths)
+                .context("Copying the Docker config")?;
+        }
+
+        for auth in auths {
+            println!("Logging in to {} as {}", auth.registry, auth.username);
+            auth.login(docker_bin, config_dir.path())?;
+        }
+
+        Ok(RegistryLogins {
+            config_dir: Some(config_dir),
+        })
+    }
+
+    /// The Docker config directory with the credentials of the registries, if any were logged in to.
+    pub fn docker_config(&self) -> Option<&Path> {
+        self.config_dir.as_ref().map(TempDir::path)
+    }
+
+    /// Let a Docker CLI command use the credentials of the registries that were logged in to.
+    pub fn configure<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        if let Some(config_dir) = self.docker_config() {
+            cmd.env(DOCKER_CONFIG_ENV_VAR, config_dir);
+        }
+        cmd
+    }
+}
+
+/// The Docker config directory of the user, from `DOCKER_CONFIG` or `~/.docker`.
+fn get_user_docker_config_dir() -> Option<PathBuf> {
+    env::var_os(DOCKER_CONFIG_ENV_VAR)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker")))
+}
+
+/// Start the Docker config of the build from the one of the user, so that the other registries, buildx builders, contexts and CLI plugins keep working.
+///
+/// The credential store is left out, as `docker login` would save the credentials of the build in it, as are the credential helpers of the registries that are logged in to.
+fn init_docker_config(
+    user_config_dir: &Path,
+    config_dir: &Path,
+    auths: &[RegistryAuth],
+) -> Result<()> {
+    let Ok(entries) = fs::read_dir(user_config_dir) else {
+        return Ok(());
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name() == "config.json" {
+            let mut config: Value = serde_json::from_str(&fs::read_to_string(entry.path())?)
+                .with_context(|| format!("Parsing {}", entry.path().display()))?;
+            if let Some(config) = config.as_object_mut() {
+                config.remove("credsStore");
+                if let Some(helpers) = config.get_mut("credHelpers").and_then(Value::as_object_mut)
+                {
+                    for auth in auths {
+                        helpers.remove(&auth.registry);
+                    }
+                }
+            }
+            fs::write(
+                config_dir.join("config.json"),
+                serde_json::to_string_pretty(&config)?,
+            )?;
+        } else {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(entry.path(), config_dir.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn auth() -> RegistryAuth {
+        RegistryAuth {
+            registry: "registry.example.com:5000".to_string(),
+            username: "deploy".to_string(),
+            password: "s3cr3t-token".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_login_args() {
+        let args = auth().get_login_args();
+
+        assert_eq!(
+            args,
+            vec![
+                "login",
+                "registry.example.com:5000",
+                "--username",
+                "deploy",
+                "--password-stdin"
+            ]
+        );
+        assert!(!args.iter().any(|arg| arg.contains("s3cr3t-token")));
+    }
+
+    #[test]
+    fn test_credentials_are_redacted() {
+        let auth = auth();
+
+        assert_eq!(
+            auth.redact("Error response from daemon: bad token s3cr3t-token"),
+            "Error response from daemon: bad token ********"
+        );
+        assert!(!format!("{auth:?}").contains("s3cr3t-token"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_logins_use_own_docker_config() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The fake Docker CLI records its arguments and config directory, and only accepts the password of the first registry
+        let dir = TempDir::new("nixpacks-registry-auth").unwrap();
+        let log = dir.path().join("docker.log");
+        let docker_bin = dir.path().join("docker");
+        fs::write(
+            &docker_bin,
+            format!(
+                "#!/bin/sh
+echo \"$1 $2 $DOCKER_CONFIG\" >> {}
+[ \"$(cat)\" = s3cr3t-token ]
+",
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&docker_bin, fs::Permissions::from_mode(0o755)).unwrap();
+        let docker_bin = docker_bin.to_str().unwrap();
+        let read_log = || fs::read_to_string(&log).unwrap();
+
+        let logins = RegistryLogins::login(&[auth()], docker_bin).unwrap();
+        let config_dir = logins.docker_config().unwrap().to_path_buf();
+        assert_eq!(
+            read_log(),
+            format!("login registry.example.com:5000 {}\n", config_dir.display())
+        );
+
+        let mut cmd = Command::new("sh");
+        logins.configure(cmd.args(["-c", "echo $DOCKER_CONFIG"]));
+        assert_eq!(
+            String::from_utf8(cmd.output().unwrap().stdout)
+                .unwrap()
+                .trim(),
+            config_dir.to_str().unwrap()
+        );
+
+        // The config directory is removed instead of logging out, and a failed login removes it as well
+        drop(logins);
+        assert!(!config_dir.exists());
+        let other = RegistryAuth {
+            registry: "ghcr.io".to_string(),
+            password: "wrong".to_string(),
+            ..auth()
+        };
+        assert!(RegistryLogins::login(&[auth(), other], docker_bin).is_err());
+        assert!(!read_log().contains("logout"));
+
+        // Nothing is made without registries
+        assert!(RegistryLogins::login(&[], docker_bin)
+            .unwrap()
+            .docker_config()
+            .is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_init_docker_config() {
+        let user_config_dir = TempDir::new("nixpacks-user-docker-config").unwrap();
+        let config_dir = TempDir::new("nixpacks-docker-config").unwrap();
+        let user_config = r#"{ "credsStore": "desktop", "credHelpers": { "registry.example.com:5000": "ecr-login", "gcr.io": "gcloud" }, "auths": { "docker.io": {} } }"#;
+        fs::write(user_config_dir.path().join("config.json"), user_config).unwrap();
+        fs::create_dir(user_config_dir.path().join("buildx")).unwrap();
+
+        init_docker_config(user_config_dir.path(), config_dir.path(), &[auth()]).unwrap();
+
+        let config: Value = serde_json::from_str(
+            &fs::read_to_string(config_dir.path().join("config.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({ "credHelpers": { "gcr.io": "gcloud" }, "auths": { "docker.io": {} } })
+        );
+        assert!(config_dir.path().join("buildx").is_dir());
+
+        // The config of the user is not changed
+        assert_eq!(
+            fs::read_to_string(user_config_dir.path().join("config.json")).unwrap(),
+            user_config
+        );
+    }
+
+    #[test]
+    fn test_from_file() {
+        let dir = TempDir::new("nixpacks-registry-auth").unwrap();
+        let path = dir.path().join("credentials.json");
+        fs::write(
+            &path,
+            r#"{ "registry.example.com:5000": { "username": "deploy", "password": "s3cr3t-token" } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            RegistryAuth::from_file(path.to_str().unwrap()).unwrap(),
+            vec![auth()]
+        );
+    }
+}
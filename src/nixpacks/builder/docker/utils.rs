@@ -1,22 +1,94 @@
-use std::path::Path;
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 use super::cache::sanitize_cache_key;
+use crate::nixpacks::environment::EnvironmentVariables;
+
+/// The home directory of the user that runs the build steps, unless configured otherwise.
+pub const DEFAULT_HOME_DIR: &str = "/root";
+
+/// The user and group ID that users are created with, so that their cache directories can be mounted for them.
+pub const CREATED_USER_ID: &str = "10001";
+
+/// The Docker CLI that is run, unless `DOCKER_BIN` or `--docker-bin` are set.
+pub const DEFAULT_DOCKER_BIN: &str = "docker";
+
+/// The Docker CLI to run, which is the configured one, else `DOCKER_BIN`, else `docker`.
+pub fn get_docker_bin(docker_bin: Option<&str>) -> String {
+    docker_bin
+        .map(ToString::to_string)
+        .or_else(|| env::var("DOCKER_BIN").ok())
+        .filter(|bin| !bin.is_empty())
+        .unwrap_or_else(|| DEFAULT_DOCKER_BIN.to_string())
+}
+
+/// Find the Docker CLI, which is either a path or a name that is looked up in `PATH`.
+pub fn find_docker_bin(docker_bin: &str) -> Result<PathBuf> {
+    let path = Path::new(docker_bin);
+    let found = if path.components().count() > 1 {
+        Some(path.to_path_buf()).filter(|path| path.is_file())
+    } else {
+        env::var_os("PATH").and_then(|paths| {
+            env::split_paths(&paths)
+                .flat_map(|dir| {
+                    let bin = dir.join(docker_bin);
+                    let exe = bin.with_extension("exe");
+                    if cfg!(windows) {
+                        vec![bin, exe]
+                    } else {
+                        vec![bin]
+                    }
+                })
+                .find(|bin| bin.is_file())
+        })
+    };
+
+    match found {
+        Some(path) => Ok(path),
+        None => bail!(
+            "Docker was not found at `{docker_bin}`. Install it https://docs.docker.com/engine/install/ or set DOCKER_BIN to the path of the docker binary"
+        ),
+    }
+}
+
+/// Expand a leading `~` in the given directory to the provided home directory.
+pub fn expand_home_dir(dir: &str, home_dir: &str) -> String {
+    match dir.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{rest}", home_dir.trim_end_matches('/'))
+        }
+        _ => dir.to_string(),
+    }
+}
 
 /// Using the provided cache_key and cache_directories, produce a string of Docker command flags mounting the cache.
+///
+/// The `owner_options`, from [`get_cache_mount_owner_options`], are added to every mount.
 pub fn get_cache_mount(
     cache_key: &Option<String>,
     cache_directories: &Option<Vec<String>>,
+    home_dir: &str,
+    app_dir: &str,
+    owner_options: &str,
 ) -> String {
     match (cache_key, cache_directories) {
         (Some(cache_key), Some(cache_directories)) => cache_directories
             .iter()
             .map(|dir| {
-                let mut sanitized_dir = dir.replace('~', "/root");
+                let mut sanitized_dir = expand_home_dir(dir, home_dir);
                 let sanitized_key = sanitize_cache_key(&format!("{cache_key}-{sanitized_dir}"));
                 if !sanitized_dir.starts_with('/') {
-                    sanitized_dir = format!("/app/{sanitized_dir}");
+                    sanitized_dir = format!("{}/{sanitized_dir}", app_dir.trim_end_matches('/'));
                 }
-                format!("--mount=type=cache,id={sanitized_key},target={sanitized_dir}")
+                format!(
+                    "--mount=type=cache,id={sanitized_key},target={sanitized_dir}{owner_options}"
+                )
             })
             .collect::<Vec<String>>()
             .join(" "),
@@ -62,6 +134,217 @@ pub fn get_copy_from_commands(from: &str, files: &[String], app_dir: &str) -> Ve
     }
 }
 
+/// Produce the Dockerfile line that installs the given apt packages.
+///
+/// Packages pinned to a version, e.g. `libpq-dev=13.*`, are kept as they are and quoted if the shell would expand them.
+pub fn get_apt_install_command(apt_pkgs: &[String]) -> String {
+    if apt_pkgs.is_empty() {
+        String::new()
+    } else {
+        let apt_pkgs = apt_pkgs
+            .iter()
+            .map(|pkg| {
+                if pkg.contains(['*', '?', '[']) {
+                    format!("'{pkg}'")
+                } else {
+                    pkg.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        format!(
+            "RUN apt-get update && apt-get install -y --no-install-recommends {}",
+            apt_pkgs.join(" ")
+        )
+    }
+}
+
+/// Returns a prefix for a RUN command that exports the given variables for that command only.
+pub fn get_env_prefix(variables: &EnvironmentVariables) -> String {
+    if variables.is_empty() {
+        String::new()
+    } else {
+        let exports = variables
+            .iter()
+            .map(|(name, value)| format!("{name}='{}'", value.replace('\'', "'\\''")))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!("export {exports} && ")
+    }
+}
+
+/// Produce the flags mounting the BuildKit secrets into a RUN instruction, followed by a space.
+pub fn get_secret_mounts(secrets: &[String]) -> String {
+    secrets
+        .iter()
+        .map(|secret| format!("--mount=type=secret,id={secret} "))
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+/// Fail if BuildKit was disabled with the `DOCKER_BUILDKIT` variable of the environment, since only BuildKit can mount secrets.
+pub fn check_buildkit_for_secrets(docker_buildkit: Option<&str>, secrets: &[String]) -> Result<()> {
+    let disabled =
+        docker_buildkit.is_some_and(|value| value == "0" || value.eq_ignore_ascii_case("false"));
+    if disabled && !secrets.is_empty() {
+        bail!(
+            "The secrets {} are mounted with BuildKit, which is disabled by DOCKER_BUILDKIT={}. Unset DOCKER_BUILDKIT to build the image",
+            secrets.join(", "),
+            docker_buildkit.unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+/// Produce a prefix for shell commands that exports each mounted secret as an environment variable.
+pub fn get_secret_env_prefix(secrets: &[String]) -> String {
+    if secrets.is_empty() {
+        String::new()
+    } else {
+        let exports = secrets
+            .iter()
+            .map(|secret| format!("{secret}=\"$(cat /run/secrets/{secret})\""))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!("export {exports} && ")
+    }
+}
+
+/// Returns the Docker platform of the machine nixpacks is running on, e.g. `linux/amd64`.
+pub fn get_native_platform() -> String {
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        arch => arch,
+    };
+
+    format!("linux/{arch}")
+}
+
+/// Returns the Docker platform of the Docker daemon, which builds the image for its own architecture unless a platform is given.
+pub fn get_daemon_platform(docker_bin: &str) -> Result<String> {
+    let output = Command::new(docker_bin)
+        .args(["info", "--format", "{{.Architecture}}"])
+        .output()
+        .with_context(|| format!("Running `{docker_bin} info`"))?;
+    if !output.status.success() {
+        bail!(
+            "Asking the Docker daemon for its architecture failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // The daemon reports the architecture of its kernel, e.g. `x86_64` or `aarch64`
+    let arch = match String::from_utf8_lossy(&output.stdout).trim() {
+        "x86_64" => "amd64".to_string(),
+        "aarch64" => "arm64".to_string(),
+        arch if arch.starts_with("arm") => "arm".to_string(),
+        "" => bail!("The Docker daemon did not report its architecture"),
+        arch => arch.to_string(),
+    };
+
+    Ok(format!("linux/{arch}"))
+}
+
+/// Whether building for the given platforms needs `docker buildx` instead of a plain `docker build`.
+pub fn requires_buildx(platforms: &[String]) -> bool {
+    let native_platform = get_native_platform();
+    platforms.len() > 1 || platforms.iter().any(|p| *p != native_platform)
+}
+
+/// The buildx output flags that write the image to a tarball instead of loading it into the daemon.
+///
+/// A docker archive can only hold a single platform, so an OCI archive is written for multiple platforms.
+pub fn get_output_tar_args(output_tar: &str, platforms: &[String]) -> Vec<String> {
+    let format = if platforms.len() > 1 { "oci" } else { "docker" };
+    vec![
+        "--output".to_string(),
+        format!("type={format},dest={output_tar}"),
+    ]
+}
+
+/// The buildx flags that choose where the built image is written.
+///
+/// The Docker daemon can not load an image for multiple platforms, so unless it is written to a tarball such an image is pushed to the registry of its name.
+pub fn get_buildx_output_args(output_tar: Option<&str>, platforms: &[String]) -> Vec<String> {
+    match output_tar {
+        Some(output_tar) => get_output_tar_args(output_tar, platforms),
+        None if platforms.len() > 1 => vec!["--push".to_string()],
+        None => vec!["--load".to_string()],
+    }
+}
+
+/// The `docker save` arguments that write an image from the daemon to a tarball.
+pub fn get_save_args(name: &str, output_tar: &str) -> Vec<String> {
+    vec![
+        "save".to_string(),
+        "-o".to_string(),
+        output_tar.to_string(),
+        name.to_string(),
+    ]
+}
+
+/// The `docker run` command suggested after a build, which publishes the exposed ports of the app on the same ports of the host.
+pub fn get_run_command(name: &str, ports: &[u16]) -> String {
+    let mut args = vec!["docker run -it".to_string()];
+    args.extend(ports.iter().map(|port| format!("-p {port}:{port}")));
+    args.push(name.to_string());
+    args.join(" ")
+}
+
+/// Parse a `KEY=VALUE` build argument passed on the command line.
+pub fn parse_build_arg(arg: &str) -> Result<(String, String)> {
+    let Some((name, value)) = arg.split_once('=') else {
+        bail!("Build argument `{arg}` must be in the form KEY=VALUE");
+    };
+
+    let is_valid_name = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_valid_name {
+        bail!("Invalid build argument name `{name}`");
+    }
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parse a `key=value` image label passed on the command line.
+///
+/// Keys are made of alphanumerics, `.`, `-`, `_`, and `/`, and start and end with an alphanumeric, e.g. `com.example.team`.
+pub fn parse_label(label: &str) -> Result<String> {
+    let Some((key, _)) = label.split_once('=') else {
+        bail!("Label `{label}` must be in the form key=value");
+    };
+
+    let is_valid_key = key
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric())
+        && key.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/'));
+    if !is_valid_key {
+        bail!("Invalid label key `{key}`");
+    }
+
+    Ok(label.to_string())
+}
+
+/// The `docker build` flags that pass each build argument.
+pub fn get_build_arg_args(build_args: &BTreeMap<String, String>) -> Vec<String> {
+    build_args
+        .iter()
+        .flat_map(|(name, value)| ["--build-arg".to_string(), format!("{name}={value}")])
+        .collect()
+}
+
 /// Produce the Dockerfile line containing the CMD instruction which executes the application.
 pub fn get_exec_command(command: &str) -> String {
     let params = command.replace('\"', "\\\"");
@@ -69,17 +352,321 @@ pub fn get_exec_command(command: &str) -> String {
     format!("CMD [\"{params}\"]")
 }
 
+/// Produce the CMD instruction for an image without a shell, splitting the command into its arguments.
+pub fn get_exec_args_command(command: &str) -> Result<String> {
+    let args = split_shell_words(command)?
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(format!("CMD [{}]", args.join(", ")))
+}
+
+/// Split a command into its arguments the way a POSIX shell does, keeping quoted and escaped whitespace in an argument.
+pub fn split_shell_words(command: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Command `{command}` has an unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Only these characters can be escaped in double quotes
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('$' | '`' | '"' | '\\')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => bail!("Command `{command}` has an unterminated double quote"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("Command `{command}` has an unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => bail!("Command `{command}` ends with an escape"),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+/// Whether `duration` is a duration Docker accepts, e.g. `30s`, `1m30s` or `500ms`.
+pub fn is_valid_duration(duration: &str) -> bool {
+    let mut rest = duration;
+    if rest.is_empty() {
+        return false;
+    }
+
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        if rest[..number_len].parse::<f64>().is_err() {
+            return false;
+        }
+        rest = &rest[number_len..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        if !["ns", "us", "µs", "ms", "s", "m", "h"].contains(&&rest[..unit_len]) {
+            return false;
+        }
+        rest = &rest[unit_len..];
+    }
+
+    true
+}
+
+/// Returns the user and group ID that the cache directories of a phase that runs as `user` are mounted for.
+///
+/// These are only known for user IDs, in the group that is given as an ID or else the group with the same ID, and for users that are created.
+pub fn get_user_ids(user: &str, create_user: bool) -> Option<(String, String)> {
+    let (name, group) = user.split_once(':').unwrap_or((user, ""));
+    let is_id = |id: &str| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit());
+    if is_id(name) {
+        let group = if is_id(group) { group } else { name };
+        Some((name.to_string(), group.to_string()))
+    } else if create_user {
+        Some((CREATED_USER_ID.to_string(), CREATED_USER_ID.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Produce the options of the cache mounts of a phase that runs as `user`, which BuildKit would otherwise make only writable by root.
+///
+/// The mounts are owned by the user when its IDs are known, and writable by everyone otherwise.
+pub fn get_cache_mount_owner_options(user: Option<&str>, create_user: bool) -> String {
+    let Some(user) = user.filter(|user| get_user_home_dir(user).is_some()) else {
+        return String::new();
+    };
+
+    match get_user_ids(user, create_user) {
+        Some((uid, gid)) => format!(",uid={uid},gid={gid}"),
+        None => ",mode=0777".to_string(),
+    }
+}
+
+/// Returns the home directory of the given `user` or `user:group`, which users are created with.
+///
+/// Returns `None` for root, whose home directory is configured with the builder options.
+pub fn get_user_home_dir(user: &str) -> Option<String> {
+    let name = user.split(':').next().unwrap_or_default();
+    if name == "root" || name == "0" {
+        None
+    } else {
+        Some(format!("/home/{name}"))
+    }
+}
+
+/// Produce the `RUN` instruction that gives `user` ownership of the app directory, creating the user first if requested.
+///
+/// When cache directories are mounted in `home_dir`, the home directory of the user is looked up with `getent` during the build
+/// and the build fails if it is another one, instead of caching directories that the user does not use.
+/// Users are created with [`CREATED_USER_ID`], and when `check_user_id` is set the build fails if a user that already existed has another ID,
+/// instead of mounting cache directories that the user cannot write to.
+pub fn get_user_setup_command(
+    user: &str,
+    create_user: bool,
+    app_dir: &str,
+    home_dir: Option<&str>,
+    check_user_id: bool,
+) -> Result<String> {
+    let (name, group) = user.split_once(':').unwrap_or((user, ""));
+    let is_valid_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    };
+    if !is_valid_name(name) || (user.contains(':') && !is_valid_name(group)) {
+        bail!("Invalid user `{user}`, expected a name or a `user:group` pair");
+    }
+
+    // Files that earlier phases copied to the app directory are owned by root
+    let mut setup = format!("chown -R {user} {app_dir}");
+    if let Some(home_dir) = home_dir {
+        write!(
+            setup,
+            " && {{ [ \"$(getent passwd {name} | cut -d: -f6)\" = {home_dir} ] || {{ echo 'The home directory of {name} is not {home_dir}, where the cache directories under ~ are mounted' >&2; exit 1; }}; }}"
+        )?;
+    }
+    if !create_user {
+        return Ok(format!("RUN {setup}"));
+    }
+    if name.chars().all(|c| c.is_ascii_digit()) {
+        bail!("Cannot create user `{name}`, which is a user ID instead of a name");
+    }
+
+    if check_user_id {
+        write!(
+            setup,
+            " && {{ [ \"$(id -u {name})\" = {CREATED_USER_ID} ] || {{ echo 'The user ID of {name} is not {CREATED_USER_ID}, which the cache directories are mounted for' >&2; exit 1; }}; }}"
+        )?;
+    }
+
+    let home_dir = get_user_home_dir(user).unwrap_or_default();
+    Ok(format!(
+        "RUN (id -u {name} >/dev/null 2>&1 || useradd --create-home --home-dir {home_dir} --uid {CREATED_USER_ID} {name}) && {setup}"
+    ))
+}
+
+/// Make the `COPY` instructions in the given Dockerfile lines give ownership of the copied files to `user`.
+pub fn chown_copy_commands(lines: &str, user: &str) -> String {
+    lines
+        .split('\n')
+        .map(|line| match line.strip_prefix("COPY ") {
+            Some(rest) => format!("COPY --chown={user} {rest}"),
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_split_shell_words() {
+        assert_eq!(
+            split_shell_words("./out --port 8080").unwrap(),
+            vec!["./out", "--port", "8080"]
+        );
+        assert_eq!(
+            split_shell_words(r#"./out --name 'my app' --greeting "say \"hi\"" a\ b ''"#).unwrap(),
+            vec![
+                "./out",
+                "--name",
+                "my app",
+                "--greeting",
+                "say \"hi\"",
+                "a b",
+                ""
+            ]
+        );
+        assert!(split_shell_words("./out 'my app").is_err());
+        assert!(split_shell_words("./out \"my app").is_err());
+
+        assert_eq!(
+            get_exec_args_command("./out --name 'my app'").unwrap(),
+            "CMD [\"./out\", \"--name\", \"my app\"]"
+        );
+    }
+
+    #[test]
+    fn test_secrets() {
+        let secrets = vec!["NPM_TOKEN".to_string(), "SENTRY_AUTH_TOKEN".to_string()];
+
+        assert_eq!(get_secret_mounts(&[]), "");
+        assert_eq!(
+            get_secret_mounts(&secrets),
+            "--mount=type=secret,id=NPM_TOKEN --mount=type=secret,id=SENTRY_AUTH_TOKEN "
+        );
+        assert_eq!(get_secret_env_prefix(&[]), "");
+        assert_eq!(
+            get_secret_env_prefix(&secrets),
+            "export NPM_TOKEN=\"$(cat /run/secrets/NPM_TOKEN)\" SENTRY_AUTH_TOKEN=\"$(cat /run/secrets/SENTRY_AUTH_TOKEN)\" && "
+        );
+    }
+
+    #[test]
+    fn test_buildkit_for_secrets() {
+        let secrets = vec!["NPM_TOKEN".to_string()];
+
+        assert!(check_buildkit_for_secrets(None, &secrets).is_ok());
+        assert!(check_buildkit_for_secrets(Some("1"), &secrets).is_ok());
+        assert!(check_buildkit_for_secrets(Some("0"), &[]).is_ok());
+        let error = check_buildkit_for_secrets(Some("false"), &secrets).unwrap_err();
+        assert!(error.to_string().contains("NPM_TOKEN"));
+        assert!(check_buildkit_for_secrets(Some("0"), &secrets).is_err());
+    }
+
+    #[test]
+    fn test_user_setup() {
+        assert_eq!(get_user_home_dir("app"), Some("/home/app".to_string()));
+        assert_eq!(
+            get_user_home_dir("app:staff"),
+            Some("/home/app".to_string())
+        );
+        assert_eq!(get_user_home_dir("root"), None);
+
+        assert_eq!(
+            get_user_setup_command("app", true, "/app/", None, false).unwrap(),
+            "RUN (id -u app >/dev/null 2>&1 || useradd --create-home --home-dir /home/app --uid 10001 app) && chown -R app /app/"
+        );
+        assert_eq!(
+            get_user_setup_command("app", true, "/app/", None, true).unwrap(),
+            "RUN (id -u app >/dev/null 2>&1 || useradd --create-home --home-dir /home/app --uid 10001 app) && chown -R app /app/ && { [ \"$(id -u app)\" = 10001 ] || { echo 'The user ID of app is not 10001, which the cache directories are mounted for' >&2; exit 1; }; }"
+        );
+        assert_eq!(
+            get_user_setup_command("1000:1000", false, "/app/", None, false).unwrap(),
+            "RUN chown -R 1000:1000 /app/"
+        );
+        assert_eq!(
+            get_user_setup_command("app", false, "/app/", Some("/home/app"), false).unwrap(),
+            "RUN chown -R app /app/ && { [ \"$(getent passwd app | cut -d: -f6)\" = /home/app ] || { echo 'The home directory of app is not /home/app, where the cache directories under ~ are mounted' >&2; exit 1; }; }"
+        );
+        assert!(get_user_setup_command("1000", true, "/app/", None, false).is_err());
+        assert!(get_user_setup_command("app; rm -rf /", false, "/app/", None, false).is_err());
+        assert!(get_user_setup_command("app:", false, "/app/", None, false).is_err());
+
+        assert_eq!(
+            chown_copy_commands("COPY . /app/.\nRUN make", "app"),
+            "COPY --chown=app . /app/.\nRUN make"
+        );
+    }
+
+    #[test]
+    fn test_is_valid_duration() {
+        assert!(is_valid_duration("30s"));
+        assert!(is_valid_duration("1m30s"));
+        assert!(is_valid_duration("500ms"));
+        assert!(is_valid_duration("1.5h"));
+
+        assert!(!is_valid_duration(""));
+        assert!(!is_valid_duration("30"));
+        assert!(!is_valid_duration("s"));
+        assert!(!is_valid_duration("10 seconds"));
+    }
+
     #[test]
     fn test_get_cache_mount() {
         let cache_key = Some("cache_key".to_string());
         let cache_directories = Some(vec!["dir1".to_string(), "dir2".to_string()]);
 
         let expected = "--mount=type=cache,id=cache_key-dir1,target=/app/dir1 --mount=type=cache,id=cache_key-dir2,target=/app/dir2";
-        let actual = get_cache_mount(&cache_key, &cache_directories);
+        let actual = get_cache_mount(
+            &cache_key,
+            &cache_directories,
+            DEFAULT_HOME_DIR,
+            "/app/",
+            "",
+        );
 
         assert_eq!(expected, actual);
     }
@@ -90,11 +677,105 @@ mod tests {
         let cache_directories = Some(vec!["dir1".to_string(), "dir2".to_string()]);
 
         let expected = "--mount=type=cache,id=my-cache-key-dir1,target=/app/dir1 --mount=type=cache,id=my-cache-key-dir2,target=/app/dir2";
-        let actual = get_cache_mount(&cache_key, &cache_directories);
+        let actual = get_cache_mount(
+            &cache_key,
+            &cache_directories,
+            DEFAULT_HOME_DIR,
+            "/app/",
+            "",
+        );
 
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_cache_mount_owner_options() {
+        assert_eq!(get_cache_mount_owner_options(None, false), "");
+        assert_eq!(get_cache_mount_owner_options(Some("root"), true), "");
+        assert_eq!(
+            get_cache_mount_owner_options(Some("app"), true),
+            ",uid=10001,gid=10001"
+        );
+        assert_eq!(
+            get_cache_mount_owner_options(Some("1000:2000"), false),
+            ",uid=1000,gid=2000"
+        );
+        assert_eq!(
+            get_cache_mount_owner_options(Some("1000:staff"), false),
+            ",uid=1000,gid=1000"
+        );
+        assert_eq!(
+            get_cache_mount_owner_options(Some("node"), false),
+            ",mode=0777"
+        );
+
+        let actual = get_cache_mount(
+            &Some("key".to_string()),
+            &Some(vec!["~/.npm".to_string()]),
+            "/home/app",
+            "/app/",
+            ",uid=10001,gid=10001",
+        );
+        assert_eq!(
+            actual,
+            "--mount=type=cache,id=key-/home/app/npm,target=/home/app/.npm,uid=10001,gid=10001"
+        );
+    }
+
+    #[test]
+    fn test_expand_home_dir() {
+        assert_eq!(expand_home_dir("~", DEFAULT_HOME_DIR), "/root");
+        assert_eq!(expand_home_dir("~/foo", DEFAULT_HOME_DIR), "/root/foo");
+        assert_eq!(expand_home_dir("~/.npm", "/home/app"), "/home/app/.npm");
+        assert_eq!(expand_home_dir("~", "/home/app/"), "/home/app");
+        assert_eq!(expand_home_dir("./foo~bar", "/home/app"), "./foo~bar");
+        assert_eq!(expand_home_dir("~app/foo", "/home/app"), "~app/foo");
+    }
+
+    #[test]
+    fn test_requires_buildx() {
+        let native_platform = get_native_platform();
+        let other_platform = if native_platform == "linux/arm64" {
+            "linux/amd64"
+        } else {
+            "linux/arm64"
+        };
+
+        assert!(!requires_buildx(&[]));
+        assert!(!requires_buildx(std::slice::from_ref(&native_platform)));
+        assert!(requires_buildx(&[other_platform.to_string()]));
+        assert!(requires_buildx(&[
+            native_platform,
+            other_platform.to_string()
+        ]));
+    }
+
+    #[test]
+    fn test_get_apt_install_command() {
+        assert_eq!(
+            get_apt_install_command(&[
+                "libpq-dev=13.*".to_string(),
+                "curl".to_string(),
+                "git/bookworm-backports".to_string(),
+                "wget=1.21.3-1+b2".to_string(),
+            ]),
+            "RUN apt-get update && apt-get install -y --no-install-recommends 'libpq-dev=13.*' curl git/bookworm-backports wget=1.21.3-1+b2"
+        );
+        assert_eq!(get_apt_install_command(&[]), "");
+    }
+
+    #[test]
+    fn test_get_env_prefix() {
+        assert_eq!(get_env_prefix(&EnvironmentVariables::new()), "");
+        assert_eq!(
+            get_env_prefix(&EnvironmentVariables::from([
+                ("FOO".to_string(), "bar".to_string()),
+                ("QUOTED".to_string(), "it's".to_string()),
+            ])),
+            "export FOO='bar' QUOTED='it'\\''s' && "
+        );
+    }
+
     #[cfg(not(target_os = "windows"))]
     #[test]
     fn test_get_copy_commands() {
@@ -146,6 +827,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_build_arg() {
+        assert_eq!(
+            parse_build_arg("API_URL=https://api.example.com?a=b").unwrap(),
+            (
+                "API_URL".to_string(),
+                "https://api.example.com?a=b".to_string()
+            )
+        );
+        assert_eq!(
+            parse_build_arg("EMPTY=").unwrap(),
+            ("EMPTY".to_string(), String::new())
+        );
+        assert!(parse_build_arg("API_URL").is_err());
+        assert!(parse_build_arg("1API=x").is_err());
+        assert!(parse_build_arg("API URL=x").is_err());
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(
+            parse_label("com.example.team=platform").unwrap(),
+            "com.example.team=platform"
+        );
+        assert_eq!(parse_label("cost-center=a=b").unwrap(), "cost-center=a=b");
+        assert_eq!(parse_label("environment=").unwrap(), "environment=");
+        assert_eq!(
+            parse_label("team").unwrap_err().to_string(),
+            "Label `team` must be in the form key=value"
+        );
+        assert_eq!(
+            parse_label("=platform").unwrap_err().to_string(),
+            "Invalid label key ``"
+        );
+        assert!(parse_label("com.example.=x").is_err());
+        assert!(parse_label("my team=x").is_err());
+    }
+
+    #[test]
+    fn test_get_output_tar_args() {
+        assert_eq!(
+            get_output_tar_args("image.tar", &[]),
+            vec!["--output", "type=docker,dest=image.tar"]
+        );
+        assert_eq!(
+            get_output_tar_args("image.tar", &["linux/arm64".to_string()]),
+            vec!["--output", "type=docker,dest=image.tar"]
+        );
+        assert_eq!(
+            get_output_tar_args(
+                "image.tar",
+                &["linux/amd64".to_string(), "linux/arm64".to_string()]
+            ),
+            vec!["--output", "type=oci,dest=image.tar"]
+        );
+        assert_eq!(
+            get_buildx_output_args(Some("image.tar"), &["linux/arm64".to_string()]),
+            vec!["--output", "type=docker,dest=image.tar"]
+        );
+        assert_eq!(
+            get_buildx_output_args(None, &["linux/arm64".to_string()]),
+            vec!["--load"]
+        );
+        assert_eq!(
+            get_buildx_output_args(
+                None,
+                &["linux/amd64".to_string(), "linux/arm64".to_string()]
+            ),
+            vec!["--push"]
+        );
+        assert_eq!(
+            get_save_args("my-app", "image.tar"),
+            vec!["save", "-o", "image.tar", "my-app"]
+        );
+    }
+
+    #[test]
+    fn test_get_run_command() {
+        assert_eq!(get_run_command("my-app", &[]), "docker run -it my-app");
+        assert_eq!(
+            get_run_command("my-app", &[8080, 9090]),
+            "docker run -it -p 8080:8080 -p 9090:9090 my-app"
+        );
+    }
+
     #[test]
     fn test_get_exec_cmd() {
         assert_eq!(
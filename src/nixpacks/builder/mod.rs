@@ -1,9 +1,37 @@
 use super::{environment::Environment, plan::BuildPlan};
 use anyhow::Result;
 use async_trait::async_trait;
+use std::path::PathBuf;
 
 pub mod docker;
 
+/// Progress of an image build, for reporting by tools that embed nixpacks.
+///
+/// The phases themselves run inside `docker build`, so their progress is only available from the Docker output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildEvent {
+    /// The phases of the plan, in the order they will run.
+    PhasesPlanned { phases: Vec<String> },
+    /// The Dockerfile and supporting files were written to the output directory.
+    DockerfileGenerated { path: PathBuf },
+    /// `docker build` was started for the image.
+    BuildStarted { image: String },
+    /// The first instruction of a phase started, which is only known from the BuildKit output of the build.
+    PhaseStarted { phase: String },
+    /// The instructions of a phase succeeded.
+    PhaseFinished { phase: String },
+    /// `docker build` exited.
+    BuildFinished { image: String, success: bool },
+    /// The image was pushed to the registry of its name, as images for multiple platforms are.
+    ImagePushed { image: String },
+    /// The incremental cache image was created from the uploaded cache directories.
+    IncrementalCacheImageCreated { tag: String },
+    /// The incremental cache image was pushed to its registry.
+    IncrementalCacheImagePushed { tag: String },
+    /// The output directory was kept instead of building an image.
+    OutputSaved { path: PathBuf },
+}
+
 /// Types that impl this trait can produce Docker images.
 #[async_trait]
 pub trait ImageBuilder {
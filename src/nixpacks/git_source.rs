@@ -0,0 +1,258 @@
+use crate::nixpacks::builder::docker::git_labels::strip_credentials;
+use anyhow::{bail, Context, Result};
+use std::{
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+use tempdir::TempDir;
+
+/// Schemes of app sources that are cloned with git instead of being read from a directory.
+const GIT_URL_PREFIXES: &[&str] = &["https://", "http://", "git://", "ssh://", "file://", "git@"];
+
+/// A git repository to build, given as `<url>#<ref>:<subdir>` where the ref and subdirectory are optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitSource {
+    pub url: String,
+    /// Branch or tag to clone instead of the default branch.
+    pub reference: Option<String>,
+    /// Directory of the app within the repository.
+    pub subdir: Option<String>,
+}
+
+/// A shallow clone of a [`GitSource`] in a temporary directory, which is removed when it is dropped.
+///
+/// Only `git clone` runs on the host, nothing from the repository itself.
+pub struct GitCheckout {
+    /// Removes the clone when the checkout is dropped.
+    #[allow(dead_code)]
+    dir: TempDir,
+    path: PathBuf,
+}
+
+impl GitSource {
+    /// Recognize an app source that is a git URL, such as `https://github.com/org/repo#main:apps/web`.
+    pub fn parse(source: &str) -> Option<GitSource> {
+        if !GIT_URL_PREFIXES
+            .iter()
+            .any(|prefix| source.starts_with(prefix))
+        {
+            return None;
+        }
+
+        let (url, fragment) = match source.rsplit_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (source, None),
+        };
+        // Refs cannot contain `:`, so the first one separates the subdirectory
+        let (reference, subdir) = match fragment {
+            Some(fragment) => match fragment.split_once(':') {
+                Some((reference, subdir)) => (reference, Some(subdir)),
+                None => (fragment, None),
+            },
+            None => ("", None),
+        };
+
+        Some(GitSource {
+            url: url.to_string(),
+            reference: Some(reference.to_string()).filter(|reference| !reference.is_empty()),
+            subdir: subdir
+                .map(|subdir| subdir.trim_matches('/').to_string())
+                .filter(|subdir| !subdir.is_empty()),
+        })
+    }
+
+    /// Shallow clone the repository into a temporary directory.
+    pub fn checkout(&self) -> Result<GitCheckout> {
+        if let Some(subdir) = &self.subdir {
+            let is_relative = Path::new(subdir)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            if !is_relative {
+                bail!("The subdirectory `{subdir}` of a git source must be a relative path inside the repository");
+            }
+        }
+
+        let dir = TempDir::new("nixpacks-git").context("Creating a temp directory")?;
+        let url = strip_credentials(&self.url);
+        // Printed to stderr so that plans and detected providers can still be piped
+        match &self.reference {
+            Some(reference) => eprintln!("Cloning {url} at {reference}"),
+            None => eprintln!("Cloning {url}"),
+        }
+
+        let mut git_clone_cmd = Command::new("git");
+        git_clone_cmd.args(["clone", "--quiet", "--depth", "1", "--single-branch"]);
+        if let Some(reference) = &self.reference {
+            git_clone_cmd.arg("--branch").arg(reference);
+        }
+        let status = git_clone_cmd
+            .arg("--")
+            .arg(&self.url)
+            .arg(dir.path())
+            .status()
+            .context("Running git clone, is git installed?")?;
+        if !status.success() {
+            bail!("Cloning {url} failed");
+        }
+
+        // The subdirectory can be a symlink in the repository, which must not lead anywhere else on the host
+        let root = dir.path().canonicalize()?;
+        let path = match &self.subdir {
+            Some(subdir) => {
+                let path = root
+                    .join(subdir)
+                    .canonicalize()
+                    .ok()
+                    .filter(|path| path.is_dir())
+                    .with_context(|| {
+                        format!("The subdirectory `{subdir}` does not exist in {url}")
+                    })?;
+                if !path.starts_with(&root) {
+                    bail!("The subdirectory `{subdir}` of a git source must be inside the repository, but it links to {}", path.display());
+                }
+                path
+            }
+            None => root,
+        };
+
+        Ok(GitCheckout { dir, path })
+    }
+}
+
+impl GitCheckout {
+    /// The directory of the app in the clone.
+    pub fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_build_plan, nixpacks::plan::generator::GeneratePlanOptions};
+    use std::fs;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args([
+                "-c",
+                "user.name=nixpacks",
+                "-c",
+                "user.email=nixpacks@example.com",
+            ])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(GitSource::parse("./examples/node"), None);
+        assert_eq!(GitSource::parse("/home/app"), None);
+        assert_eq!(
+            GitSource::parse("https://github.com/org/repo"),
+            Some(GitSource {
+                url: "https://github.com/org/repo".to_string(),
+                reference: None,
+                subdir: None,
+            })
+        );
+        assert_eq!(
+            GitSource::parse("https://github.com/org/repo#feature/login"),
+            Some(GitSource {
+                url: "https://github.com/org/repo".to_string(),
+                reference: Some("feature/login".to_string()),
+                subdir: None,
+            })
+        );
+        assert_eq!(
+            GitSource::parse("git@github.com:org/repo.git#v1.2.0:apps/web/"),
+            Some(GitSource {
+                url: "git@github.com:org/repo.git".to_string(),
+                reference: Some("v1.2.0".to_string()),
+                subdir: Some("apps/web".to_string()),
+            })
+        );
+        assert_eq!(
+            GitSource::parse("ssh://git@example.com/repo#:api"),
+            Some(GitSource {
+                url: "ssh://git@example.com/repo".to_string(),
+                reference: None,
+                subdir: Some("api".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_checkout_local_bare_repo() {
+        let tmp = TempDir::new("nixpacks-git-source").unwrap();
+        let work = tmp.path().join("work");
+        fs::create_dir_all(work.join("apps/web")).unwrap();
+        fs::write(
+            work.join("apps/web/package.json"),
+            r#"{ "name": "web", "scripts": { "start": "node index.js" } }"#,
+        )
+        .unwrap();
+        fs::write(work.join("apps/web/index.js"), "console.log('hello')").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(tmp.path(), work.join("escape")).unwrap();
+
+        git(&work, &["init", "--quiet"]);
+        git(&work, &["checkout", "--quiet", "-b", "release"]);
+        git(&work, &["add", "."]);
+        git(&work, &["commit", "--quiet", "-m", "Add web app"]);
+        let bare = tmp.path().join("repo.git");
+        git(
+            tmp.path(),
+            &["clone", "--quiet", "--bare", "work", bare.to_str().unwrap()],
+        );
+
+        let url = format!("file://{}", bare.display());
+        let checkout = GitSource::parse(&format!("{url}#release:apps/web"))
+            .unwrap()
+            .checkout()
+            .unwrap();
+        let path = checkout.path();
+        assert!(path.join("package.json").is_file());
+
+        let plan = generate_build_plan(
+            path.to_str().unwrap(),
+            Vec::new(),
+            &GeneratePlanOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            plan.start_phase.unwrap().cmd,
+            Some("npm run start".to_string())
+        );
+
+        // The clone is removed with the checkout
+        let root = checkout.dir.path().to_path_buf();
+        drop(checkout);
+        assert!(!root.exists());
+
+        assert!(GitSource::parse(&format!("{url}#release:missing"))
+            .unwrap()
+            .checkout()
+            .is_err());
+        assert!(GitSource::parse(&format!("{url}#release:../work"))
+            .unwrap()
+            .checkout()
+            .is_err());
+        #[cfg(unix)]
+        assert!(GitSource::parse(&format!("{url}#release:escape"))
+            .unwrap()
+            .checkout()
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("must be inside the repository"));
+        assert!(GitSource::parse(&format!("{url}#no-such-branch"))
+            .unwrap()
+            .checkout()
+            .is_err());
+    }
+}
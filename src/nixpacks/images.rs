@@ -3,3 +3,38 @@ pub const UBUNTU_BASE_IMAGE: &str = "ghcr.io/railwayapp/nixpacks:ubuntu-16902434
 pub const DEFAULT_BASE_IMAGE: &str = UBUNTU_BASE_IMAGE;
 
 pub const STANDALONE_IMAGE: &str = "ubuntu:jammy";
+
+/// Images that do not ship a shell, so commands cannot be run in them.
+const SHELL_LESS_IMAGES: &[&str] = &["gcr.io/distroless/", "scratch"];
+
+/// Whether the image is known to ship without a shell.
+///
+/// The `debug` tags of the distroless images, e.g. `gcr.io/distroless/static:debug-nonroot`, ship a busybox shell.
+pub fn is_shell_less_image(image: &str) -> bool {
+    let name = image.rsplit('/').next().unwrap_or_default();
+    let is_debug = name
+        .split_once(':')
+        .is_some_and(|(_, tag)| tag.starts_with("debug"));
+
+    !is_debug
+        && SHELL_LESS_IMAGES
+            .iter()
+            .any(|prefix| image.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_less_images() {
+        assert!(is_shell_less_image("gcr.io/distroless/static"));
+        assert!(is_shell_less_image("gcr.io/distroless/cc-debian12:nonroot"));
+        assert!(is_shell_less_image("scratch"));
+        assert!(!is_shell_less_image("gcr.io/distroless/static:debug"));
+        assert!(!is_shell_less_image(
+            "gcr.io/distroless/base-debian12:debug-nonroot"
+        ));
+        assert!(!is_shell_less_image("ubuntu:jammy"));
+    }
+}
@@ -2,6 +2,7 @@ pub mod app;
 pub mod builder;
 pub mod environment;
 mod files;
+pub mod git_source;
 pub mod images;
 pub mod logger;
 pub mod nix;
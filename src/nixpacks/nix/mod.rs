@@ -1,5 +1,9 @@
+use anyhow::{bail, Result};
 use indoc::formatdoc;
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
 
 use crate::nixpacks::plan::phase::{Phase, Phases};
 
@@ -13,10 +17,70 @@ pub const NIXPKGS_ARCHIVE: &str = "293a28df6d7ff3dec1e61e37cc4ee6e6c0fb0847";
 // Version of the Nix archive that uses OpenSSL 1.1
 pub const NIXPACKS_ARCHIVE_LEGACY_OPENSSL: &str = "a0b7e70db7a55088d3de0cc370a59f9fbcc906c3";
 
+/// Directory in the image that a local copy of nixpkgs is copied to.
+const LOCAL_NIXPKGS_DIR: &str = "/nixpacks";
+
+/// A nixpkgs tarball or directory on the host, which is used instead of fetching nixpkgs so that Nix packages can be installed offline.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct LocalNixpkgs {
+    path: PathBuf,
+    is_dir: bool,
+}
+
+impl LocalNixpkgs {
+    pub fn from_path(path: &str) -> Result<LocalNixpkgs> {
+        let Ok(path) = Path::new(path).canonicalize() else {
+            bail!("Nixpkgs path `{path}` does not exist");
+        };
+        if path.file_name().is_none() {
+            bail!(
+                "Nixpkgs path `{}` must be a tarball or directory",
+                path.display()
+            );
+        }
+
+        Ok(LocalNixpkgs {
+            is_dir: path.is_dir(),
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Name of the tarball or directory, which it keeps in the output directory and the image.
+    pub fn file_name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Where nixpkgs is copied to in the image.
+    pub fn image_path(&self) -> String {
+        format!("{LOCAL_NIXPKGS_DIR}/{}", self.file_name())
+    }
+
+    /// Tarballs are unpacked by Nix from the file, while directories are imported directly.
+    fn import(&self) -> String {
+        if self.is_dir {
+            format!("import {}", self.image_path())
+        } else {
+            format!("import (fetchTarball \"file://{}\")", self.image_path())
+        }
+    }
+}
+
 /// Contains all the data needed to generate a Nix expression file for installing Nix dependencies.
 #[derive(Eq, PartialEq, Default, Debug, Clone)]
 struct NixGroup {
     archive: Option<String>,
+    archive_sha256: Option<String>,
     pkgs: Vec<String>,
     libs: Vec<String>,
     overlays: Vec<String>,
@@ -32,6 +96,7 @@ fn group_nix_packages_by_archive(phases: &[Phase]) -> Vec<NixGroup> {
         .filter(|phase| phase.uses_nix())
         .map(|phase| NixGroup {
             archive: phase.nixpkgs_archive.clone(),
+            archive_sha256: phase.nixpkgs_archive_sha256.clone(),
             pkgs: phase.nix_pkgs.clone().unwrap_or_default(),
             libs: phase.nix_libs.clone().unwrap_or_default(),
             overlays: phase.nix_overlays.clone().unwrap_or_default(),
@@ -41,6 +106,7 @@ fn group_nix_packages_by_archive(phases: &[Phase]) -> Vec<NixGroup> {
     for g in groups {
         match archive_to_packages.get_mut(&g.archive) {
             Some(group) => {
+                group.archive_sha256 = group.archive_sha256.take().or(g.archive_sha256);
                 group.pkgs.extend(g.pkgs);
                 group.libs.extend(g.libs);
                 group.overlays.extend(g.overlays);
@@ -59,7 +125,15 @@ fn group_nix_packages_by_archive(phases: &[Phase]) -> Vec<NixGroup> {
 }
 
 /// Turn the Nix dependencies for each phase into a Nix expression that installs them.
-pub fn create_nix_expressions_for_phases(phases: &Phases) -> BTreeMap<String, String> {
+///
+/// The given overlays are applied to every expression, in addition to the overlays of each phase, and local overlays are imported from `app_dir`.
+/// If a local nixpkgs is given, every expression imports it instead of fetching the archive of the phase.
+pub fn create_nix_expressions_for_phases(
+    phases: &Phases,
+    overlays: &[String],
+    app_dir: &str,
+    local_nixpkgs: Option<&LocalNixpkgs>,
+) -> BTreeMap<String, String> {
     let archive_to_packages = group_nix_packages_by_archive(
         &phases
             .values()
@@ -70,11 +144,48 @@ pub fn create_nix_expressions_for_phases(phases: &Phases) -> BTreeMap<String, St
     archive_to_packages
         .iter()
         .fold(BTreeMap::new(), |mut acc, g| {
-            acc.insert(nix_file_name(&g.archive), nix_expression_for_group(g));
+            let mut g = g.clone();
+            for overlay in overlays {
+                if !g.overlays.contains(overlay) {
+                    g.overlays.push(overlay.clone());
+                }
+            }
+
+            acc.insert(
+                nix_file_name(&g.archive),
+                nix_expression_for_group(&g, app_dir, local_nixpkgs),
+            );
             acc
         })
 }
 
+/// Returns the overlay files from the app source that need to be copied before installing Nix packages.
+pub fn local_overlay_files(overlays: &[String]) -> Vec<String> {
+    overlays
+        .iter()
+        .filter(|overlay| !is_remote_overlay(overlay) && !overlay.starts_with('/'))
+        .cloned()
+        .collect()
+}
+
+fn is_remote_overlay(overlay: &str) -> bool {
+    overlay.starts_with("https://") || overlay.starts_with("http://")
+}
+
+/// Generates the Nix expression that imports an overlay from a tarball URL or a file path.
+///
+/// Relative paths are resolved against the app directory, where they are copied before the install.
+fn overlay_import(overlay: &str, app_dir: &str) -> String {
+    if is_remote_overlay(overlay) {
+        format!("(import (builtins.fetchTarball \"{overlay}\"))")
+    } else if overlay.starts_with('/') {
+        format!("(import {overlay})")
+    } else {
+        let path = overlay.trim_start_matches("./");
+        format!("(import {}/{path})", app_dir.trim_end_matches('/'))
+    }
+}
+
 /// Generates the filenames for all the Nix expressions used to install Nix dependencies for each phase.
 pub fn nix_file_names_for_phases(phases: &Phases) -> Vec<String> {
     let archives = phases
@@ -100,6 +211,29 @@ pub fn setup_files_for_phases(phases: &Phases) -> Vec<String> {
     })
 }
 
+/// Whether `hash` is a sha256 in one of the formats Nix accepts: hex, Nix base32, base64, or SRI (`sha256-<base64>`).
+pub fn is_valid_sha256(hash: &str) -> bool {
+    let is_base64 = |hash: &str| {
+        hash.len() == 44
+            && hash.ends_with('=')
+            && hash[..43]
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+    };
+
+    if let Some(base64) = hash.strip_prefix("sha256-") {
+        return is_base64(base64);
+    }
+
+    let hash = hash.strip_prefix("sha256:").unwrap_or(hash);
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        || (hash.len() == 52
+            && hash
+                .chars()
+                .all(|c| "0123456789abcdfghijklmnpqrsvwxyz".contains(c)))
+        || is_base64(hash)
+}
+
 /// Generates the filename for each Nix expression file.
 fn nix_file_name(archive: &Option<String>) -> String {
     match archive {
@@ -109,7 +243,11 @@ fn nix_file_name(archive: &Option<String>) -> String {
 }
 
 /// Generates an expression that installs Nix packages in the container environment and makes them available in PATH.
-fn nix_expression_for_group(group: &NixGroup) -> String {
+fn nix_expression_for_group(
+    group: &NixGroup,
+    app_dir: &str,
+    local_nixpkgs: Option<&LocalNixpkgs>,
+) -> String {
     let archive = group
         .archive
         .clone()
@@ -126,13 +264,19 @@ fn nix_expression_for_group(group: &NixGroup) -> String {
     let overlays_string = group
         .overlays
         .iter()
-        .map(|url| format!("(import (builtins.fetchTarball \"{url}\"))"))
+        .map(|overlay| overlay_import(overlay, app_dir))
         .collect::<Vec<String>>()
         .join("\n");
 
-    let pkg_import = format!(
-        "import (fetchTarball \"https://github.com/NixOS/nixpkgs/archive/{archive}.tar.gz\")"
-    );
+    let url = format!("https://github.com/NixOS/nixpkgs/archive/{archive}.tar.gz");
+    let pkg_import = match (local_nixpkgs, &group.archive_sha256) {
+        (Some(local_nixpkgs), _) => local_nixpkgs.import(),
+        // Nix fails the install with a hash mismatch if the download does not match
+        (None, Some(sha256)) => {
+            format!("import (fetchTarball {{ url = \"{url}\"; sha256 = \"{sha256}\"; }})")
+        }
+        (None, None) => format!("import (fetchTarball \"{url}\")"),
+    };
 
     // If the openssl library is added, set the OPENSSL_DIR and OPENSSL_LIB_DIR environment variables
     // In the future, we will probably want a generic way for providers to set variables based off Nix package locations
@@ -202,6 +346,7 @@ mod tests {
             groups[0],
             NixGroup {
                 archive: None,
+                archive_sha256: None,
                 pkgs: vec!["foo".to_string(), "bar".to_string(), "baz".to_string()],
                 libs: vec!["lib1".to_string()],
                 overlays: vec![],
@@ -212,6 +357,7 @@ mod tests {
             groups[1],
             NixGroup {
                 archive: Some("archive2".to_string()),
+                archive_sha256: None,
                 pkgs: vec!["hello".to_string(), "world".to_string()],
                 libs: vec![],
                 overlays: vec![],
@@ -219,4 +365,127 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_plan_overlays_in_nix_expressions() {
+        let mut setup = Phase::setup(Some(vec![Pkg::new("foo")]));
+        setup.nix_overlays = Some(vec!["https://example.com/phase.tar.gz".to_string()]);
+        let mut other = Phase::setup(Some(vec![Pkg::new("bar")]));
+        other.set_name("other");
+        other.nixpkgs_archive = Some("archive2".to_string());
+        let phases = Phases::from([("setup".to_string(), setup), ("other".to_string(), other)]);
+
+        let overlays = [
+            "https://example.com/overlay.tar.gz".to_string(),
+            "./nix/overlay.nix".to_string(),
+            "/etc/nix/overlay.nix".to_string(),
+        ];
+        let expressions = create_nix_expressions_for_phases(&phases, &overlays, "/app/", None);
+        assert_eq!(expressions.len(), 2);
+
+        for expression in expressions.values() {
+            assert!(expression.contains(
+                "(import (builtins.fetchTarball \"https://example.com/overlay.tar.gz\"))"
+            ));
+            assert!(expression.contains("(import /app/nix/overlay.nix)"));
+            assert!(expression.contains("(import /etc/nix/overlay.nix)"));
+        }
+        assert!(expressions["nixpkgs.nix"].contains("https://example.com/phase.tar.gz"));
+        assert!(!expressions["nixpkgs-archive2.nix"].contains("https://example.com/phase.tar.gz"));
+
+        // Local overlays are copied to the workdir of the plan
+        let expressions = create_nix_expressions_for_phases(&phases, &overlays, "/srv/app/", None);
+        for expression in expressions.values() {
+            assert!(expression.contains("(import /srv/app/nix/overlay.nix)"));
+            assert!(expression.contains("(import /etc/nix/overlay.nix)"));
+        }
+    }
+
+    #[test]
+    fn test_local_overlay_files() {
+        assert_eq!(
+            local_overlay_files(&[
+                "https://example.com/overlay.tar.gz".to_string(),
+                "./nix/overlay.nix".to_string(),
+                "/etc/nix/overlay.nix".to_string(),
+            ]),
+            vec!["./nix/overlay.nix".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_local_nixpkgs_in_nix_expressions() {
+        let dir = tempdir::TempDir::new("nixpacks-nixpkgs").unwrap();
+        let tarball = dir.path().join("nixpkgs-unstable.tar.gz");
+        std::fs::write(&tarball, "").unwrap();
+
+        let mut setup = Phase::setup(Some(vec![Pkg::new("foo")]));
+        setup.nixpkgs_archive = Some("archive2".to_string());
+        let phases = Phases::from([("setup".to_string(), setup)]);
+
+        let local_nixpkgs = LocalNixpkgs::from_path(tarball.to_str().unwrap()).unwrap();
+        assert_eq!(
+            local_nixpkgs.image_path(),
+            "/nixpacks/nixpkgs-unstable.tar.gz"
+        );
+        let expressions =
+            create_nix_expressions_for_phases(&phases, &[], "/app/", Some(&local_nixpkgs));
+        let expression = &expressions["nixpkgs-archive2.nix"];
+        assert!(expression
+            .contains("import (fetchTarball \"file:///nixpacks/nixpkgs-unstable.tar.gz\")"));
+        assert!(!expression.contains("github.com/NixOS/nixpkgs"));
+
+        let local_nixpkgs = LocalNixpkgs::from_path(dir.path().to_str().unwrap()).unwrap();
+        let expressions =
+            create_nix_expressions_for_phases(&phases, &[], "/app/", Some(&local_nixpkgs));
+        assert!(expressions["nixpkgs-archive2.nix"].contains(&format!(
+            "import /nixpacks/{} {{",
+            local_nixpkgs.file_name()
+        )));
+    }
+
+    #[test]
+    fn test_local_nixpkgs_must_exist() {
+        assert_eq!(
+            LocalNixpkgs::from_path("./does-not-exist/nixpkgs.tar.gz")
+                .unwrap_err()
+                .to_string(),
+            "Nixpkgs path `./does-not-exist/nixpkgs.tar.gz` does not exist"
+        );
+    }
+
+    #[test]
+    fn test_archive_sha256_in_nix_expressions() {
+        let mut setup = Phase::setup(Some(vec![Pkg::new("foo")]));
+        setup.nixpkgs_archive = Some("archive2".to_string());
+        setup.nixpkgs_archive_sha256 =
+            Some("0mbqa1p1hhh4frdbc9z8axxiv1q6sp4ymgzj8y7aarg0zhqvh3pl".to_string());
+        let phases = Phases::from([("setup".to_string(), setup)]);
+
+        let expressions = create_nix_expressions_for_phases(&phases, &[], "/app/", None);
+        assert!(expressions["nixpkgs-archive2.nix"].contains(
+            "import (fetchTarball { url = \"https://github.com/NixOS/nixpkgs/archive/archive2.tar.gz\"; sha256 = \"0mbqa1p1hhh4frdbc9z8axxiv1q6sp4ymgzj8y7aarg0zhqvh3pl\"; })"
+        ));
+    }
+
+    #[test]
+    fn test_is_valid_sha256() {
+        for hash in [
+            "0mbqa1p1hhh4frdbc9z8axxiv1q6sp4ymgzj8y7aarg0zhqvh3pl",
+            "sha256:0mbqa1p1hhh4frdbc9z8axxiv1q6sp4ymgzj8y7aarg0zhqvh3pl",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+        ] {
+            assert!(is_valid_sha256(hash), "{hash}");
+        }
+
+        for hash in [
+            "",
+            "0mbqa1p1hhh4frdbc9z8axxiv1q6sp4ymgzj8y7aarg0zhqvh3p",
+            "0mbqa1p1hhh4frdbc9z8axxiv1q6sp4ymgzj8y7aarg0zhqvh3pe",
+            "sha512-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+        ] {
+            assert!(!is_valid_sha256(hash), "{hash}");
+        }
+    }
 }
@@ -0,0 +1,216 @@
+use super::BuildPlan;
+use anyhow::Result;
+use serde_json::Value;
+use std::{collections::BTreeSet, fmt};
+
+/// A difference between two build plans, at a `.` separated path of the plan such as `phases.setup.nixPkgs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanChange {
+    /// A value that only the new plan has, or an item added to a list.
+    Added { path: String, value: Value },
+    /// A value that only the old plan has, or an item removed from a list.
+    Removed { path: String, value: Value },
+    /// A value that is different in the new plan, or a list that has the same items in another order.
+    Changed {
+        path: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl fmt::Display for PlanChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanChange::Added { path, value } => write!(f, "+ {path}: {value}"),
+            PlanChange::Removed { path, value } => write!(f, "- {path}: {value}"),
+            PlanChange::Changed { path, old, new } => write!(f, "~ {path}: {old} -> {new}"),
+        }
+    }
+}
+
+impl BuildPlan {
+    /// The changes from this plan to another plan, in the order of their paths.
+    ///
+    /// Both plans are normalized as for the [digest](BuildPlan::digest) first, so the order of fields and empty values are not reported as changes.
+    pub fn diff(&self, other: &BuildPlan) -> Result<Vec<PlanChange>> {
+        let mut changes = Vec::new();
+        diff_values(
+            "",
+            &self.normalized_value()?,
+            &other.normalized_value()?,
+            &mut changes,
+        );
+
+        Ok(changes)
+    }
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<PlanChange>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                match (old.get(key), new.get(key)) {
+                    (Some(old), Some(new)) => diff_values(&path, old, new, changes),
+                    (Some(old), None) => diff_values(&path, old, &Value::Null, changes),
+                    (None, Some(new)) => diff_values(&path, &Value::Null, new, changes),
+                    (None, None) => {}
+                }
+            }
+        }
+        // Lists report the items that were added or removed, such as a single Nix package
+        (Value::Array(old_items), Value::Array(new_items)) => {
+            let removed = old_items
+                .iter()
+                .filter(|item| !new_items.contains(item))
+                .collect::<Vec<_>>();
+            let added = new_items
+                .iter()
+                .filter(|item| !old_items.contains(item))
+                .collect::<Vec<_>>();
+
+            if removed.is_empty() && added.is_empty() {
+                if old_items != new_items {
+                    changes.push(PlanChange::Changed {
+                        path: path.to_string(),
+                        old: old.clone(),
+                        new: new.clone(),
+                    });
+                }
+                return;
+            }
+
+            changes.extend(removed.into_iter().map(|value| PlanChange::Removed {
+                path: path.to_string(),
+                value: value.clone(),
+            }));
+            changes.extend(added.into_iter().map(|value| PlanChange::Added {
+                path: path.to_string(),
+                value: value.clone(),
+            }));
+        }
+        (old, Value::Null) => changes.push(PlanChange::Removed {
+            path: path.to_string(),
+            value: old.clone(),
+        }),
+        (Value::Null, new) => changes.push(PlanChange::Added {
+            path: path.to_string(),
+            value: new.clone(),
+        }),
+        (old, new) if old != new => changes.push(PlanChange::Changed {
+            path: path.to_string(),
+            old: old.clone(),
+            new: new.clone(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plan(toml: &str) -> BuildPlan {
+        BuildPlan::from_toml(toml).unwrap()
+    }
+
+    #[test]
+    fn test_added_nix_package() {
+        let old = plan(
+            r#"
+            [phases.setup]
+            nixPkgs = ["nodejs"]
+
+            [start]
+            cmd = "npm start"
+            "#,
+        );
+        let new = plan(
+            r#"
+            [phases.setup]
+            nixPkgs = ["nodejs", "ffmpeg"]
+
+            [start]
+            cmd = "npm start"
+            "#,
+        );
+
+        let changes = old.diff(&new).unwrap();
+        assert_eq!(
+            changes,
+            vec![PlanChange::Added {
+                path: "phases.setup.nixPkgs".to_string(),
+                value: json!("ffmpeg"),
+            }]
+        );
+        assert_eq!(changes[0].to_string(), "+ phases.setup.nixPkgs: \"ffmpeg\"");
+        assert_eq!(
+            new.diff(&old).unwrap()[0].to_string(),
+            "- phases.setup.nixPkgs: \"ffmpeg\""
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = plan(
+            r#"
+            secrets = ["NPM_TOKEN"]
+
+            [variables]
+            NODE_ENV = "production"
+            NPM_TOKEN = "old-token"
+
+            [phases.build]
+            cmds = ["npm run lint", "npm run build"]
+
+            [start]
+            cmd = "npm start"
+            "#,
+        );
+        let new = plan(
+            r#"
+            secrets = ["NPM_TOKEN"]
+            workdir = "/srv/app"
+
+            [variables]
+            NPM_TOKEN = "new-token"
+
+            [phases.build]
+            cmds = ["npm run build", "npm run lint"]
+            aptPkgs = []
+
+            [phases.migrate]
+            cmds = ["npm run migrate"]
+
+            [start]
+            cmd = "node server.js"
+            "#,
+        );
+
+        // Secret values and empty lists are not reported
+        let changes = old
+            .diff(&new)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                "~ phases.build.cmds: [\"npm run lint\",\"npm run build\"] -> [\"npm run build\",\"npm run lint\"]",
+                "+ phases.migrate: {\"cmds\":[\"npm run migrate\"],\"name\":\"migrate\"}",
+                "~ start.cmd: \"npm start\" -> \"node server.js\"",
+                "- variables.NODE_ENV: \"production\"",
+                "+ workdir: \"/srv/app\"",
+            ]
+        );
+
+        assert!(old.diff(&old.clone()).unwrap().is_empty());
+    }
+}
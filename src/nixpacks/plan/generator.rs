@@ -6,7 +6,7 @@ use crate::{
         environment::{Environment, EnvironmentVariables},
         plan::{BuildPlan, PlanGenerator},
     },
-    providers::{procfile::ProcfileProvider, Provider},
+    providers::{procfile::ProcfileProvider, AppMetadata, Provider},
 };
 use anyhow::{bail, Context, Ok, Result};
 use colored::Colorize;
@@ -22,7 +22,10 @@ const NIXPACKS_METADATA: &str = "NIXPACKS_METADATA";
 #[derive(Clone, Default, Debug)]
 pub struct GeneratePlanOptions {
     pub plan: Option<BuildPlan>,
-    pub config_file: Option<String>,
+    /// Config files merged in order, with later files taking priority.
+    pub config_files: Vec<String>,
+    /// Fail instead of producing a plan without any provider when none is detected or specified.
+    pub require_provider: bool,
 }
 
 /// Holds plan options and providers for a build.
@@ -43,7 +46,12 @@ impl<'a> PlanGenerator for NixpacksBuildPlanGenerator<'a> {
     /// Combine detected providers with providers specified in config files, environment variables, and CLI arguments.
     fn get_plan_providers(&self, app: &App, env: &Environment) -> Result<Vec<String>> {
         let plan_before_providers = self.get_plan_before_providers(app, env)?;
-        let providers = self.get_all_providers(app, env, plan_before_providers.providers)?;
+        let providers = self.get_all_providers(
+            app,
+            env,
+            plan_before_providers.providers,
+            &plan_before_providers.exclude_providers.unwrap_or_default(),
+        )?;
 
         Ok(providers)
     }
@@ -61,14 +69,28 @@ impl NixpacksBuildPlanGenerator<'_> {
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<(BuildPlan, App)> {
         let plan_before_providers = self.get_plan_before_providers(app, env)?;
 
+        // Providers resolve paths in the container against the workdir, an invalid one is reported by the validation
+        let app = &match plan_before_providers.get_app_dir().ok() {
+            Some(app_dir) => app.clone().with_app_dir(app_dir),
+            None => app.clone(),
+        };
+
         // Add the variables from the nixpacks.toml to environment
         let new_env = &Environment::append_variables(
             env,
             plan_before_providers.variables.clone().unwrap_or_default(),
         );
 
-        let provider_plan =
-            self.get_plan_from_providers(app, new_env, plan_before_providers.providers.clone())?;
+        let provider_names = self.get_all_providers(
+            app,
+            new_env,
+            plan_before_providers.providers.clone(),
+            &plan_before_providers
+                .exclude_providers
+                .clone()
+                .unwrap_or_default(),
+        )?;
+        let provider_plan = self.get_plan_from_providers(app, new_env, &provider_names)?;
 
         let procfile_plan = (ProcfileProvider {})
             .get_build_plan(app, new_env)?
@@ -84,18 +106,82 @@ impl NixpacksBuildPlanGenerator<'_> {
         plan.pin(new_env.is_config_variable_truthy("DEBIAN"));
         if plan.clone().phases.unwrap_or_default().is_empty() {
             // try again in a subdir
-            let dir_count = app.paths.clone().iter().filter(|p| p.is_dir()).count();
-            if dir_count == 1 {
+            let dirs = app
+                .paths
+                .iter()
+                .filter(|p| app.is_dir(p))
+                .collect::<Vec<_>>();
+            if dirs.len() == 1 {
                 // there is 1 sub dir, try and generate a plan from that
-                let paths = app.paths.clone();
-                let new_dir = paths.iter().find(|p| p.is_dir()).unwrap();
-                return self
-                    .get_build_plan(&App::new(new_dir.display().to_string().as_str())?, env);
+                return self.get_build_plan(&app.subdirectory(dirs[0])?, env);
             }
         }
+
+        if self.config.require_provider && provider_names.is_empty() {
+            bail!(
+                "No provider was detected for the app at {}. Set `providers` in the config to choose one",
+                app.source.display()
+            );
+        }
+
         Ok((plan, app.clone()))
     }
 
+    /// Describe the app with the languages, frameworks and package managers that the providers used for it detect.
+    pub fn get_app_metadata(&self, app: &App, env: &Environment) -> Result<AppMetadata> {
+        let plan_before_providers = self.get_plan_before_providers(app, env)?;
+        let new_env = &Environment::append_variables(
+            env,
+            plan_before_providers.variables.unwrap_or_default(),
+        );
+
+        let mut metadata = AppMetadata::default();
+        let provider_names = self.get_all_providers(
+            app,
+            new_env,
+            plan_before_providers.providers,
+            &plan_before_providers.exclude_providers.unwrap_or_default(),
+        )?;
+        for name in provider_names {
+            if let Some(provider) = self.providers.iter().find(|p| p.name() == name) {
+                metadata.extend(AppMetadata {
+                    providers: vec![provider.name().to_string()],
+                    languages: vec![provider.language().to_string()],
+                    ..AppMetadata::default()
+                });
+                metadata.extend(provider.app_metadata(app, new_env)?);
+            } else if name != "..." && name != "@auto" {
+                bail!("Provider {} not found", name);
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// The files that the providers used for the app key the incremental cache with.
+    pub fn get_cache_inputs(&self, app: &App, env: &Environment) -> Result<Vec<String>> {
+        let plan_before_providers = self.get_plan_before_providers(app, env)?;
+        let new_env = &Environment::append_variables(
+            env,
+            plan_before_providers.variables.unwrap_or_default(),
+        );
+
+        let provider_names = self.get_all_providers(
+            app,
+            new_env,
+            plan_before_providers.providers,
+            &plan_before_providers.exclude_providers.unwrap_or_default(),
+        )?;
+        let mut inputs = Vec::new();
+        for name in provider_names {
+            if let Some(provider) = self.providers.iter().find(|p| p.name() == name) {
+                inputs.extend(provider.get_cache_inputs(app, new_env)?);
+            }
+        }
+
+        Ok(inputs)
+    }
+
     /// Generate a build plan based on config files, environment variables, and CLI arguments.
     fn get_plan_before_providers(&self, app: &App, env: &Environment) -> Result<BuildPlan> {
         let file_plan = self.read_file_plan(app, env)?;
@@ -107,11 +193,25 @@ impl NixpacksBuildPlanGenerator<'_> {
     }
 
     /// Use each provider's detect method to determine which providers are needed for the build.
-    fn get_detected_providers(&self, app: &App, env: &Environment) -> Result<Vec<String>> {
+    ///
+    /// Excluded providers are skipped, so that the next provider that matches the app is detected instead.
+    fn get_detected_providers(
+        &self,
+        app: &App,
+        env: &Environment,
+        excluded_providers: &[String],
+    ) -> Result<Vec<String>> {
         let mut providers = Vec::new();
 
         for provider in self.providers {
-            if provider.detect(app, env)? {
+            if excluded_providers
+                .iter()
+                .any(|name| name == provider.name())
+            {
+                continue;
+            }
+
+            if provider.detect(app, env)?.matched {
                 providers.push(provider.name().to_string());
 
                 // Only match a single provider... for now
@@ -123,20 +223,30 @@ impl NixpacksBuildPlanGenerator<'_> {
     }
 
     /// Get a list of providers that will be used to create the plan.
+    ///
+    /// Providers that are specified without `...` are used without running detection, unless they are excluded.
     pub fn get_all_providers(
         &self,
         app: &App,
         env: &Environment,
         manual_providers: Option<Vec<String>>,
+        excluded_providers: &[String],
     ) -> Result<Vec<String>> {
-        let detected_providers = self.get_detected_providers(app, env)?;
+        let manual_providers = manual_providers.unwrap_or_else(|| vec!["...".to_string()]);
+        let detected_providers = if manual_providers
+            .iter()
+            .any(|name| name == "..." || name == "@auto")
+        {
+            self.get_detected_providers(app, env, excluded_providers)?
+        } else {
+            Vec::new()
+        };
         let provider_names = remove_autos_from_vec(
-            fill_auto_in_vec(
-                Some(detected_providers),
-                Some(manual_providers.unwrap_or_else(|| vec!["...".to_string()])),
-            )
-            .unwrap_or_default(),
-        );
+            fill_auto_in_vec(Some(detected_providers), Some(manual_providers)).unwrap_or_default(),
+        )
+        .into_iter()
+        .filter(|name| !excluded_providers.contains(name))
+        .collect();
 
         Ok(provider_names)
     }
@@ -146,10 +256,8 @@ impl NixpacksBuildPlanGenerator<'_> {
         &self,
         app: &App,
         env: &Environment,
-        manual_providers: Option<Vec<String>>,
+        provider_names: &[String],
     ) -> Result<BuildPlan> {
-        let provider_names = self.get_all_providers(app, env, manual_providers)?;
-
         if provider_names.len() > 1 {
             println!(
                 "{}",
@@ -166,6 +274,8 @@ impl NixpacksBuildPlanGenerator<'_> {
             let provider = self.providers.iter().find(|p| p.name() == name);
             if let Some(provider) = provider {
                 if let Some(mut provider_plan) = provider.get_build_plan(app, env)? {
+                    provider_plan.add_cache_directories(provider.get_cache_dirs(app, env)?);
+
                     // All but the first provider have their phases prefixed with their name
                     if count > 0 {
                         provider_plan.prefix_phases(provider.name());
@@ -196,54 +306,58 @@ impl NixpacksBuildPlanGenerator<'_> {
     }
 
     /// If a supported config file exists, use it to generate a build plan.
+    ///
+    /// When multiple config files are specified, their plans are merged in order.
     fn read_file_plan(&self, app: &App, env: &Environment) -> Result<BuildPlan> {
-        let file_path = if let Some(file_path) = &self.config.config_file {
-            Some(file_path.clone())
+        let file_paths = if !self.config.config_files.is_empty() {
+            self.config.config_files.clone()
         } else if let Some(env_config_file) = env.get_config_variable("CONFIG_FILE") {
             if !app.includes_file(&env_config_file) {
                 bail!("Config file {} does not exist", env_config_file);
             }
 
-            Some(env_config_file)
+            vec![env_config_file]
         } else if app.includes_file("nixpacks.toml") {
-            Some("nixpacks.toml".to_owned())
+            vec!["nixpacks.toml".to_owned()]
         } else if app.includes_file("nixpacks.json") {
-            Some("nixpacks.json".to_owned())
+            vec!["nixpacks.json".to_owned()]
         } else {
-            None
+            Vec::new()
         };
 
-        let plan =
-            if let Some(file_path) = file_path {
-                let filename = Path::new(&file_path);
-                let ext = filename.extension().unwrap_or_default();
-
-                let contents = app.read_file(file_path.as_str()).with_context(|| {
-                    format!("Failed to read Nixpacks config file `{file_path}`")
-                })?;
-                let plan = if ext == "toml" {
-                    BuildPlan::from_toml(&contents)
-                } else if ext == "json" {
-                    BuildPlan::from_json(&contents)
-                } else {
-                    bail!("Unknown file type: {}", file_path)
-                };
-
-                Some(plan.with_context(|| {
-                    format!("Failed to parse Nixpacks config file `{file_path}`")
-                })?)
-            } else {
-                None
-            };
-
-        if plan.is_some() {
-            println!(
-                "{}",
-                "\n Nixpacks file based configuration is experimental and may change\n"
-                    .bright_yellow()
-            );
+        if file_paths.is_empty() {
+            return Ok(BuildPlan::default());
         }
 
-        Ok(plan.unwrap_or_default())
+        let plans = file_paths
+            .iter()
+            .map(|file_path| NixpacksBuildPlanGenerator::read_plan_file(app, file_path))
+            .collect::<Result<Vec<_>>>()?;
+
+        println!(
+            "{}",
+            "\n Nixpacks file based configuration is experimental and may change\n".bright_yellow()
+        );
+
+        BuildPlan::merge_config_files(&plans)
+    }
+
+    /// Parse a single toml or json config file into a build plan.
+    fn read_plan_file(app: &App, file_path: &str) -> Result<BuildPlan> {
+        let filename = Path::new(file_path);
+        let ext = filename.extension().unwrap_or_default();
+
+        let contents = app
+            .read_file(file_path)
+            .with_context(|| format!("Failed to read Nixpacks config file `{file_path}`"))?;
+        let plan = if ext == "toml" {
+            BuildPlan::from_toml(&contents)
+        } else if ext == "json" {
+            BuildPlan::from_json(&contents)
+        } else {
+            bail!("Unknown file type: {}", file_path)
+        };
+
+        plan.with_context(|| format!("Failed to parse Nixpacks config file `{file_path}`"))
     }
 }
@@ -3,6 +3,14 @@ use super::{
     utils::fill_auto_in_vec,
     BuildPlan,
 };
+use anyhow::Result;
+use serde_json::Value;
+
+/// First item of a list in a config file that replaces the list of the config files before it, instead of being appended to it.
+pub const REPLACE_MARKER: &str = "!replace";
+
+/// Lists that are always replaced when config files are merged, since their items only make sense together.
+const REPLACED_CONFIG_LISTS: &[&str] = &["cmds", "shell"];
 
 /// Types that impl this trait can be pairwise combined.
 pub trait Mergeable {
@@ -16,7 +24,19 @@ impl Mergeable for BuildPlan {
         let plan2 = c2.clone();
 
         new_plan.providers = fill_auto_in_vec(new_plan.providers.clone(), plan2.providers.clone());
+        new_plan.exclude_providers = fill_auto_in_vec(
+            new_plan.exclude_providers.clone(),
+            plan2.exclude_providers.clone(),
+        );
         new_plan.build_image = plan2.build_image.or(new_plan.build_image);
+        new_plan.nix_overlays =
+            fill_auto_in_vec(new_plan.nix_overlays.clone(), plan2.nix_overlays.clone());
+        new_plan.exclude_files =
+            fill_auto_in_vec(new_plan.exclude_files.clone(), plan2.exclude_files.clone());
+        new_plan.secrets = fill_auto_in_vec(new_plan.secrets.clone(), plan2.secrets.clone());
+        new_plan.workdir = plan2.workdir.or(new_plan.workdir);
+        new_plan.shell = plan2.shell.or(new_plan.shell);
+        new_plan.apt_mirror = plan2.apt_mirror.or(new_plan.apt_mirror);
 
         new_plan.static_assets = match (new_plan.static_assets, plan2.static_assets) {
             (None, assets) | (assets, None) => assets,
@@ -27,6 +47,37 @@ impl Mergeable for BuildPlan {
             }
         };
 
+        new_plan.apt_sources = match (new_plan.apt_sources, plan2.apt_sources) {
+            (None, sources) | (sources, None) => sources,
+            (Some(sources1), Some(sources2)) => {
+                let mut sources = sources1;
+                for source in sources2 {
+                    if !sources.contains(&source) {
+                        sources.push(source);
+                    }
+                }
+                Some(sources)
+            }
+        };
+
+        new_plan.nix_overrides = match (new_plan.nix_overrides, plan2.nix_overrides) {
+            (None, overrides) | (overrides, None) => overrides,
+            (Some(overrides1), Some(overrides2)) => {
+                let mut overrides = overrides1;
+                overrides.extend(overrides2);
+                Some(overrides)
+            }
+        };
+
+        new_plan.caches = match (new_plan.caches, plan2.caches) {
+            (None, caches) | (caches, None) => caches,
+            (Some(caches1), Some(caches2)) => {
+                let mut caches = caches1;
+                caches.extend(caches2);
+                Some(caches)
+            }
+        };
+
         new_plan.variables = match (new_plan.variables, plan2.variables) {
             (None, vars) | (vars, None) => vars,
             (Some(vars1), Some(vars2)) => {
@@ -72,19 +123,66 @@ impl Mergeable for Phase {
     fn merge(c1: &Phase, c2: &Phase) -> Phase {
         let mut phase = c1.clone();
         let c2 = c2.clone();
+        // The hash belongs to the archive, so it is replaced along with it
+        if c2.nixpkgs_archive.is_some() || c2.nixpkgs_archive_sha256.is_some() {
+            phase.nixpkgs_archive_sha256 = c2.nixpkgs_archive_sha256;
+        }
         phase.nixpkgs_archive = c2.nixpkgs_archive.or_else(|| phase.nixpkgs_archive.clone());
 
         phase.cmds = fill_auto_in_vec(phase.cmds.clone(), c2.cmds);
+        phase.cmd_patches = match (phase.cmd_patches.clone(), c2.cmd_patches) {
+            (None, patches) | (patches, None) => patches,
+            (Some(patches1), Some(patches2)) => {
+                let mut patches = patches1;
+                patches.extend(patches2);
+                Some(patches)
+            }
+        };
         phase.depends_on = fill_auto_in_vec(phase.depends_on.clone(), c2.depends_on);
         phase.nix_pkgs = fill_auto_in_vec(phase.nix_pkgs.clone(), c2.nix_pkgs);
         phase.nix_libs = fill_auto_in_vec(phase.nix_libs.clone(), c2.nix_libs);
         phase.apt_pkgs = fill_auto_in_vec(phase.apt_pkgs.clone(), c2.apt_pkgs);
+        phase.build_apt_pkgs = fill_auto_in_vec(phase.build_apt_pkgs.clone(), c2.build_apt_pkgs);
         phase.nix_overlays = fill_auto_in_vec(phase.nix_overlays.clone(), c2.nix_overlays);
         phase.only_include_files =
             fill_auto_in_vec(phase.only_include_files.clone(), c2.only_include_files);
+        phase.only_if_files = fill_auto_in_vec(phase.only_if_files.clone(), c2.only_if_files);
         phase.cache_directories =
             fill_auto_in_vec(phase.cache_directories.clone(), c2.cache_directories);
+        phase.caches = fill_auto_in_vec(phase.caches.clone(), c2.caches);
+        phase.cache_mounts = fill_auto_in_vec(phase.cache_mounts.clone(), c2.cache_mounts);
         phase.paths = fill_auto_in_vec(phase.paths.clone(), c2.paths);
+        phase.extra_instructions =
+            fill_auto_in_vec(phase.extra_instructions.clone(), c2.extra_instructions);
+        phase.user = c2.user.or_else(|| phase.user.clone());
+        phase.create_user = c2.create_user.or(phase.create_user);
+        phase.variables = match (phase.variables.clone(), c2.variables) {
+            (None, vars) | (vars, None) => vars,
+            (Some(vars1), Some(vars2)) => {
+                let mut vars = vars1;
+                vars.extend(vars2);
+                Some(vars)
+            }
+        };
+        phase.arch_nix_pkgs = match (phase.arch_nix_pkgs.clone(), c2.arch_nix_pkgs) {
+            (None, pkgs) | (pkgs, None) => pkgs,
+            (Some(pkgs1), Some(pkgs2)) => {
+                let mut pkgs = pkgs1;
+                for (arch, arch_pkgs) in pkgs2 {
+                    let merged = fill_auto_in_vec(pkgs.remove(&arch), Some(arch_pkgs));
+                    pkgs.insert(arch, merged.unwrap_or_default());
+                }
+                Some(pkgs)
+            }
+        };
+        phase.cache_excludes = match (phase.cache_excludes.clone(), c2.cache_excludes) {
+            (None, excludes) | (excludes, None) => excludes,
+            (Some(excludes1), Some(excludes2)) => {
+                let mut excludes = excludes1;
+                excludes.extend(excludes2);
+                Some(excludes)
+            }
+        };
 
         phase
     }
@@ -101,10 +199,81 @@ impl Mergeable for StartPhase {
             start_phase.only_include_files.clone(),
             c2.only_include_files,
         );
+        start_phase.install_apt_pkgs = c2.install_apt_pkgs.or(start_phase.install_apt_pkgs);
+        start_phase.healthcheck = c2.healthcheck.or_else(|| start_phase.healthcheck.clone());
+        start_phase.extra_instructions = fill_auto_in_vec(
+            start_phase.extra_instructions.clone(),
+            c2.extra_instructions,
+        );
+        start_phase.user = c2.user.or_else(|| start_phase.user.clone());
+        start_phase.create_user = c2.create_user.or(start_phase.create_user);
+        start_phase.writable_paths =
+            fill_auto_in_vec(start_phase.writable_paths.clone(), c2.writable_paths);
+        start_phase.expose_ports = c2.expose_ports.or_else(|| start_phase.expose_ports.clone());
+        start_phase.entrypoint = c2.entrypoint.or_else(|| start_phase.entrypoint.clone());
+        start_phase.processes = c2.processes.or_else(|| start_phase.processes.clone());
         start_phase
     }
 }
 
+impl BuildPlan {
+    /// Combine the plans of config files, with later files taking priority.
+    ///
+    /// Unlike [`BuildPlan::merge_plans`], lists of strings such as `nixPkgs` are appended to the lists of the files before, unless they contain `"..."` or start with [`REPLACE_MARKER`]. Commands and the shell are always replaced.
+    pub fn merge_config_files(plans: &[BuildPlan]) -> Result<BuildPlan> {
+        let mut merged = Value::Object(serde_json::Map::new());
+        for plan in plans {
+            merged = merge_config_values(None, merged, serde_json::to_value(plan)?);
+        }
+
+        let mut plan: BuildPlan = serde_json::from_value(merged)?;
+        plan.resolve_phase_names();
+        Ok(plan)
+    }
+}
+
+/// Merge the value of a later config file into the value of the files before it, for the field `key`.
+fn merge_config_values(key: Option<&str>, base: Value, value: Value) -> Value {
+    match (base, value) {
+        (Value::Object(mut base), Value::Object(value)) => {
+            for (key, value) in value {
+                let merged = match base.remove(&key) {
+                    Some(base) => merge_config_values(Some(&key), base, value),
+                    None => merge_config_values(Some(&key), Value::Null, value),
+                };
+                base.insert(key, merged);
+            }
+            Value::Object(base)
+        }
+        (base, Value::Array(items)) if items.iter().all(Value::is_string) => {
+            let items = items
+                .into_iter()
+                .filter_map(|item| item.as_str().map(ToString::to_string))
+                .collect::<Vec<_>>();
+            let base = match base {
+                Value::Array(base) => Some(
+                    base.into_iter()
+                        .filter_map(|item| item.as_str().map(ToString::to_string))
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            };
+
+            let merged = if items.first().map(String::as_str) == Some(REPLACE_MARKER) {
+                items[1..].to_vec()
+            } else if items.iter().any(|item| item == "...")
+                || matches!(key, Some(key) if REPLACED_CONFIG_LISTS.contains(&key))
+            {
+                fill_auto_in_vec(base, Some(items)).unwrap_or_default()
+            } else {
+                [base.unwrap_or_default(), items].concat()
+            };
+            Value::from(merged)
+        }
+        (_, value) => value,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -208,4 +377,53 @@ mod test {
             merged
         );
     }
+
+    #[test]
+    fn test_merge_config_files() {
+        let merged = BuildPlan::merge_config_files(&[
+            BuildPlan::from_toml(
+                r#"
+                [phases.setup]
+                nixPkgs = ["...", "nodejs"]
+                aptPkgs = ["wget"]
+                cmds = ["echo base"]
+
+                [variables]
+                NODE_ENV = "production"
+                "#,
+            )
+            .unwrap(),
+            BuildPlan::from_toml(
+                r#"
+                [phases.setup]
+                nixPkgs = ["cowsay"]
+                aptPkgs = ["!replace", "curl"]
+                cmds = ["echo service"]
+
+                [variables]
+                PORT = "8080"
+                "#,
+            )
+            .unwrap(),
+        ])
+        .unwrap();
+
+        // The hole of the first file is kept, so the provider's packages are still filled in
+        assert_eq!(
+            merged,
+            BuildPlan::from_toml(
+                r#"
+                [phases.setup]
+                nixPkgs = ["...", "nodejs", "cowsay"]
+                aptPkgs = ["curl"]
+                cmds = ["echo service"]
+
+                [variables]
+                NODE_ENV = "production"
+                PORT = "8080"
+                "#,
+            )
+            .unwrap()
+        );
+    }
 }
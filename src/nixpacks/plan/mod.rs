@@ -7,18 +7,24 @@ use super::images::{DEBIAN_BASE_IMAGE, UBUNTU_BASE_IMAGE};
 use crate::nixpacks::{
     app::{App, StaticAssets},
     environment::{Environment, EnvironmentVariables},
+    nix::pkg::Pkg,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 
 // pub mod config;
+pub mod diff;
 pub mod generator;
 pub mod merge;
 pub mod phase;
 pub mod pretty_print;
+pub mod schema;
 mod topological_sort;
 mod utils;
+pub mod validate;
 
 /// Types that impl this trait can generate build plans.
 pub trait PlanGenerator {
@@ -36,20 +42,69 @@ pub trait PlanGenerator {
 pub struct BuildPlan {
     pub providers: Option<Vec<String>>,
 
+    /// Providers that are never detected or used, even when they match the app.
+    #[serde(rename = "excludeProviders")]
+    pub exclude_providers: Option<Vec<String>>,
+
     #[serde(rename = "buildImage")]
     pub build_image: Option<String>,
 
+    /// Nix overlays applied on top of the nixpkgs archive of every phase.
+    ///
+    /// Declared before any table fields so that plans can be serialized to toml.
+    #[serde(rename = "nixOverlays")]
+    pub nix_overlays: Option<Vec<String>>,
+
+    /// `.dockerignore` patterns for files that are left out of the Docker build context.
+    #[serde(rename = "excludeFiles")]
+    pub exclude_files: Option<Vec<String>>,
+
+    /// Names of variables passed to the build as BuildKit secrets instead of build args, so they are not stored in the image.
+    pub secrets: Option<Vec<String>>,
+
+    /// Absolute path of the directory the app is copied to and built in, instead of `/app`.
+    pub workdir: Option<String>,
+
+    /// Shell that the commands of the phases run in, as the arguments of a Dockerfile `SHELL` instruction.
+    pub shell: Option<Vec<String>>,
+
+    /// Mirror of the Ubuntu or Debian archive that apt packages are installed from, instead of the default archive.
+    #[serde(rename = "aptMirror")]
+    pub apt_mirror: Option<String>,
+
     pub variables: Option<EnvironmentVariables>,
 
+    /// Nix packages chosen by providers to replace with another package, by name.
+    pub nix_overrides: Option<BTreeMap<String, String>>,
+
     #[serde(rename = "staticAssets")]
     pub static_assets: Option<StaticAssets>,
 
+    /// Extra apt repositories that apt packages can be installed from.
+    #[serde(rename = "aptSources")]
+    pub apt_sources: Option<Vec<AptSource>>,
+
+    /// Cache directories by name, which phases can use with `caches` instead of repeating the directories.
+    pub caches: Option<BTreeMap<String, Vec<String>>>,
+
     pub phases: Option<Phases>,
 
     #[serde(rename = "start")]
     pub start_phase: Option<StartPhase>,
 }
 
+/// An apt repository, added to the sources of the image before any apt packages are installed.
+#[serde_with::skip_serializing_none]
+#[derive(PartialEq, Eq, Default, Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AptSource {
+    /// A one-line-style source entry, e.g. `deb https://example.com/apt stable main`.
+    pub deb: String,
+
+    /// URL of the GPG key the repository is signed with.
+    pub key_url: Option<String>,
+}
+
 impl BuildPlan {
     /// Used by providers to create language-specific build plans.
     pub fn new(phases: &[Phase], start_phase: Option<StartPhase>) -> Self {
@@ -60,6 +115,30 @@ impl BuildPlan {
         }
     }
 
+    /// Add the directories of the named caches that each phase uses to its cache directories.
+    pub fn expand_named_caches(&self) -> Result<BuildPlan> {
+        let mut plan = self.clone();
+        let caches = plan.caches.clone().unwrap_or_default();
+
+        for phase in plan.phases.iter_mut().flat_map(BTreeMap::values_mut) {
+            for name in phase.caches.take().unwrap_or_default() {
+                match caches.get(&name) {
+                    Some(dirs) => {
+                        for dir in dirs {
+                            phase.add_cache_directory(dir);
+                        }
+                    }
+                    None => bail!(
+                        "Phase `{}` uses the cache `{name}`, which is not defined",
+                        phase.get_name()
+                    ),
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+
     /// Create a BuildPlan from a toml config file.
     pub fn from_toml<S: Into<String>>(toml: S) -> Result<Self> {
         let mut plan: BuildPlan = toml::from_str(&toml.into())?;
@@ -127,6 +206,21 @@ impl BuildPlan {
         }
     }
 
+    /// Cache the given directories in the install phase, or in the build phase if dependencies are installed while building.
+    pub fn add_cache_directories(&mut self, dirs: Vec<String>) {
+        let phase_name = if self.get_phase("install").is_some() {
+            "install"
+        } else {
+            "build"
+        };
+
+        if let Some(phase) = self.get_phase_mut(phase_name) {
+            for dir in dirs {
+                phase.add_cache_directory(dir);
+            }
+        }
+    }
+
     /// Returns the Phase of this BuildPlan with the given name.
     pub fn get_phase(&self, name: &str) -> Option<&Phase> {
         match self.phases {
@@ -259,9 +353,16 @@ impl BuildPlan {
             phases.push(setup);
         }
 
+        let nix_overlays = env.get_config_variable("NIX_OVERLAYS").map(|overlays| {
+            let mut overlays = split_env_string(overlays.as_str());
+            overlays.push("...".to_string());
+            overlays
+        });
+
         // Install
         if let Some(cmd_string) = env.get_config_variable("INSTALL_CMD") {
-            let mut install = Phase::install(Some(cmd_string));
+            let mut install = Phase::install(None);
+            install.cmds = Some(get_override_cmds(cmd_string));
 
             if let Some(cache_dirs) = env.get_config_variable("INSTALL_CACHE_DIRS") {
                 split_env_string(cache_dirs.as_str())
@@ -274,7 +375,8 @@ impl BuildPlan {
 
         // Build
         if let Some(cmd_string) = env.get_config_variable("BUILD_CMD") {
-            let mut build = Phase::build(Some(cmd_string));
+            let mut build = Phase::build(None);
+            build.cmds = Some(get_override_cmds(cmd_string));
 
             if let Some(cache_dirs) = env.get_config_variable("BUILD_CACHE_DIRS") {
                 split_env_string(cache_dirs.as_str())
@@ -288,12 +390,16 @@ impl BuildPlan {
         // Start
         let start = env.get_config_variable("START_CMD").map(StartPhase::new);
 
-        BuildPlan::new(&phases, start)
+        let mut plan = BuildPlan::new(&phases, start);
+        plan.nix_overlays = nix_overlays;
+        plan.apt_mirror = env.get_config_variable("APT_MIRROR");
+        plan
     }
 
     /// Store the base image and phase dependencies in this BuildPlan, for later reproducibility.
     pub fn pin(&mut self, use_debian: bool) {
         self.providers = Some(Vec::new());
+        self.exclude_providers = None;
         if self.build_image.is_none() {
             let base_image = if use_debian {
                 DEBIAN_BASE_IMAGE
@@ -303,7 +409,22 @@ impl BuildPlan {
             self.build_image = Some(base_image.to_string());
         }
 
+        self.nix_overlays = self.nix_overlays.clone().map(utils::remove_autos_from_vec);
+        self.exclude_files = self.exclude_files.clone().map(utils::remove_autos_from_vec);
+        self.secrets = self.secrets.clone().map(utils::remove_autos_from_vec);
+
         self.resolve_phase_names();
+        if self.uses_bash_shell() {
+            match self.get_phase_mut("setup") {
+                Some(setup) => {
+                    if !setup.nix_pkgs.iter().flatten().any(|pkg| pkg == "bash") {
+                        setup.add_nix_pkgs(&[Pkg::new("bash")]);
+                    }
+                }
+                None => self.add_phase(Phase::setup(Some(vec![Pkg::new("bash")]))),
+            }
+        }
+
         let phases = self.phases.get_or_insert(Phases::default());
         for (_, phase) in phases.iter_mut() {
             phase.pin(use_debian);
@@ -312,6 +433,37 @@ impl BuildPlan {
         if let Some(start) = &mut self.start_phase {
             start.pin();
         }
+
+        self.apply_nix_overrides();
+    }
+
+    /// Whether the phases run in bash, which is installed with Nix so that it is available in any build image.
+    fn uses_bash_shell(&self) -> bool {
+        self.shell
+            .as_ref()
+            .and_then(|shell| shell.first())
+            .is_some_and(|program| program.rsplit('/').next() == Some("bash"))
+    }
+
+    /// Replace the Nix packages and libraries of every phase that have an override.
+    fn apply_nix_overrides(&mut self) {
+        let overrides = self.nix_overrides.clone().unwrap_or_default();
+        if overrides.is_empty() {
+            return;
+        }
+
+        let replace = |pkgs: &mut Option<Vec<String>>| {
+            for pkg in pkgs.iter_mut().flatten() {
+                if let Some(replacement) = overrides.get(pkg) {
+                    *pkg = replacement.clone();
+                }
+            }
+        };
+
+        for phase in self.phases.iter_mut().flat_map(BTreeMap::values_mut) {
+            replace(&mut phase.nix_pkgs);
+            replace(&mut phase.nix_libs);
+        }
     }
 
     /// Prefix each phase name with the name of the provider that generated the phase, in the case of multiple providers.
@@ -331,6 +483,31 @@ impl BuildPlan {
         }
     }
 
+    /// A stable SHA-256 hash of the plan, so that tools can skip rebuilding when the plan of an app has not changed.
+    ///
+    /// The digest does not depend on the order of fields, or on whether empty values are left out of the plan.
+    /// Only the names of secret variables are hashed, so that rotating a secret does not change the digest.
+    pub fn digest(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_string(&self.normalized_value()?)?);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// The plan as JSON with sorted keys and without empty values or the values of secret variables, which the digest and diffs of plans are made from.
+    fn normalized_value(&self) -> Result<Value> {
+        let mut plan = self.clone();
+        plan.resolve_phase_names();
+        if let (Some(secrets), Some(variables)) = (&plan.secrets, &mut plan.variables) {
+            for name in secrets {
+                if let Some(value) = variables.get_mut(name) {
+                    value.clear();
+                }
+            }
+        }
+
+        Ok(normalize_for_digest(serde_json::to_value(&plan)?).unwrap_or(Value::Null))
+    }
+
     /// Combine plans from multiple sources (environment variables, config files, providers) into a single plan.
     pub fn merge_plans(plans: &[BuildPlan]) -> BuildPlan {
         plans.iter().fold(BuildPlan::default(), |acc, plan| {
@@ -354,6 +531,36 @@ impl topological_sort::TopItem for (String, Phase) {
     }
 }
 
+/// Sort the keys of objects and leave out nulls, empty arrays, and empty objects, so equivalent plans hash the same.
+fn normalize_for_digest(value: Value) -> Option<Value> {
+    match value {
+        Value::Null => None,
+        Value::Array(values) => {
+            let values = values
+                .into_iter()
+                .filter_map(normalize_for_digest)
+                .collect::<Vec<_>>();
+            if values.is_empty() {
+                None
+            } else {
+                Some(Value::Array(values))
+            }
+        }
+        Value::Object(entries) => {
+            let entries = entries
+                .into_iter()
+                .filter_map(|(key, value)| normalize_for_digest(value).map(|value| (key, value)))
+                .collect::<BTreeMap<_, _>>();
+            if entries.is_empty() {
+                None
+            } else {
+                Some(Value::Object(entries.into_iter().collect::<Map<_, _>>()))
+            }
+        }
+        value => Some(value),
+    }
+}
+
 /// Splits a string taken from an environment variable into a vector of packages, libraries, or directories.
 fn split_env_string(s: &str) -> Vec<String> {
     s.split([' ', ','])
@@ -362,9 +569,19 @@ fn split_env_string(s: &str) -> Vec<String> {
         .collect::<Vec<_>>()
 }
 
+/// An empty command override skips the phase by leaving it without any commands.
+fn get_override_cmds(cmd: String) -> Vec<String> {
+    if cmd.trim().is_empty() {
+        Vec::new()
+    } else {
+        vec![cmd]
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::nixpacks::nix::pkg::Pkg;
 
     #[test]
     fn get_plan_from_environment() {
@@ -407,6 +624,153 @@ mod test {
         assert_eq!(result, env_plan);
     }
 
+    #[test]
+    fn test_empty_cmd_overrides_skip_phases() {
+        // A variable passed without a value is pulled from the calling environment, where it can be empty
+        let env = Environment::new(EnvironmentVariables::from([
+            ("NIXPACKS_INSTALL_CMD".to_string(), String::new()),
+            ("NIXPACKS_BUILD_CMD".to_string(), " ".to_string()),
+            ("NIXPACKS_START_CMD".to_string(), " ".to_string()),
+        ]));
+        let env_plan = BuildPlan::from_environment(&env);
+
+        let provider_plan = BuildPlan::new(
+            &[
+                Phase::install(Some("npm ci".to_string())),
+                Phase::build(Some("npm run build".to_string())),
+            ],
+            Some(StartPhase::new("npm run start")),
+        );
+
+        let mut plan = BuildPlan::merge_plans(&[provider_plan, env_plan]);
+        plan.pin(false);
+
+        assert_eq!(Some(vec![]), plan.get_phase("install").unwrap().cmds);
+        assert_eq!(Some(vec![]), plan.get_phase("build").unwrap().cmds);
+        assert!(!plan.get_phase("install").unwrap().runs_docker_commands());
+        assert!(!plan.get_phase("build").unwrap().runs_docker_commands());
+        assert_eq!(None, plan.start_phase.unwrap().cmd);
+    }
+
+    #[test]
+    fn test_cmd_overrides_replace_provider_cmds() {
+        let env = Environment::from_envs(vec![
+            "NIXPACKS_INSTALL_CMD=yarn install",
+            "NIXPACKS_BUILD_CMD=yarn build",
+            "NIXPACKS_START_CMD=yarn start",
+        ])
+        .unwrap();
+        let env_plan = BuildPlan::from_environment(&env);
+
+        let provider_plan = BuildPlan::new(
+            &[
+                Phase::install(Some("npm ci".to_string())),
+                Phase::build(Some("npm run build".to_string())),
+            ],
+            Some(StartPhase::new("npm run start")),
+        );
+
+        let mut plan = BuildPlan::merge_plans(&[provider_plan, env_plan]);
+        plan.pin(false);
+
+        assert_eq!(
+            Some(vec!["yarn install".to_string()]),
+            plan.get_phase("install").unwrap().cmds
+        );
+        assert_eq!(
+            Some(vec!["yarn build".to_string()]),
+            plan.get_phase("build").unwrap().cmds
+        );
+        assert_eq!(
+            Some("yarn start".to_string()),
+            plan.start_phase.unwrap().cmd
+        );
+    }
+
+    #[test]
+    fn test_cmd_patches() {
+        let config_plan = BuildPlan::from_toml(
+            r#"
+            [[phases.install.cmdPatches]]
+            cmd = "npm ci"
+            prefix = "npm config set fund false"
+
+            [[phases.build.cmdPatches]]
+            cmd = "npm run build"
+            replace = "npm run build:prod"
+            suffix = "npm run sentry"
+
+            [[phases.build.cmdPatches]]
+            cmd = "npm run lint"
+            prefix = "echo lint"
+            "#,
+        )
+        .unwrap();
+
+        let provider_plan = BuildPlan::new(
+            &[
+                Phase::install(Some("npm ci --omit=dev".to_string())),
+                Phase::build(Some("npm run build".to_string())),
+            ],
+            Some(StartPhase::new("npm run start")),
+        );
+
+        let mut plan = BuildPlan::merge_plans(&[provider_plan, config_plan]);
+        plan.pin(false);
+
+        let install = plan.get_phase("install").unwrap();
+        assert_eq!(
+            install.cmds,
+            Some(vec![
+                "npm config set fund false && npm ci --omit=dev".to_string()
+            ])
+        );
+        assert_eq!(install.cmd_patches, None);
+
+        let build = plan.get_phase("build").unwrap();
+        assert_eq!(
+            build.cmds,
+            Some(vec!["npm run build:prod && npm run sentry".to_string()])
+        );
+        assert_eq!(
+            plan.validate(),
+            vec!["Phase `build` patches the command `npm run lint`, which it does not run"]
+        );
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let mut setup = Phase::setup(Some(vec![Pkg::new("nodejs"), Pkg::new("yarn")]));
+        setup.add_apt_pkgs(vec!["wget".to_string()]);
+        setup.add_pkgs_libs(vec!["zlib".to_string()]);
+        setup.set_nix_archive("archive".to_string());
+
+        let mut build = Phase::build(Some("yarn run build".to_string()));
+        build.add_cache_directory("node_modules/.cache");
+        build.add_path("/app/node_modules/.bin".to_string());
+        build.add_file_dependency("package.json");
+        build.add_variable("BUILD_TOKEN", "secret");
+
+        let mut start = StartPhase::new("yarn run start");
+        start.run_image = Some("ubuntu:jammy".to_string());
+
+        let mut plan = BuildPlan::new(&[setup, build], Some(start));
+        plan.providers = Some(vec!["node".to_string()]);
+        plan.build_image = Some("ghcr.io/railwayapp/nixpacks:latest".to_string());
+        plan.nix_overlays = Some(vec!["./overlay.nix".to_string()]);
+        plan.add_variables(EnvironmentVariables::from([(
+            "NODE_ENV".to_string(),
+            "production".to_string(),
+        )]));
+        plan.static_assets = Some(StaticAssets::from([(
+            "file".to_string(),
+            "contents".to_string(),
+        )]));
+
+        let toml = plan.to_toml().unwrap();
+        assert_eq!(BuildPlan::from_toml(toml).unwrap(), plan);
+    }
+
     #[test]
     fn test_get_phases_with_dependencies() {
         let setup = Phase::new("setup");
@@ -452,6 +816,26 @@ mod test {
         assert!(plan.get_phase("setup").unwrap().nixpkgs_archive.is_some());
     }
 
+    #[test]
+    fn test_nix_overrides() {
+        let mut plan = BuildPlan::from_toml(
+            r#"
+            [nixOverrides]
+            nodejs_18 = "nodejs-slim_18"
+
+            [phases.setup]
+            nixPkgs = ["nodejs_18", "yarn"]
+            "#,
+        )
+        .unwrap();
+
+        plan.pin(false);
+        assert_eq!(
+            plan.get_phase("setup").unwrap().nix_pkgs,
+            Some(vec!["nodejs-slim_18".to_string(), "yarn".to_string()])
+        );
+    }
+
     #[test]
     fn test_split_env_string() {
         assert_eq!(
@@ -463,4 +847,72 @@ mod test {
             vec!["nodejs".to_string(), "yarn".to_string()]
         );
     }
+
+    #[test]
+    fn test_digest() {
+        let plan = BuildPlan::from_toml(
+            r#"
+            [variables]
+            NODE_ENV = "production"
+            NPM_CONFIG_PRODUCTION = "false"
+
+            [phases.setup]
+            nixPkgs = ["nodejs", "yarn"]
+
+            [phases.build]
+            cmds = ["yarn run build"]
+            dependsOn = ["install"]
+            aptPkgs = []
+
+            [start]
+            cmd = "yarn run start"
+            "#,
+        )
+        .unwrap();
+
+        let mut same_plan = BuildPlan::new(
+            &[
+                Phase::build(Some("yarn run build".to_string())),
+                Phase::setup(Some(vec![Pkg::new("nodejs"), Pkg::new("yarn")])),
+            ],
+            Some(StartPhase::new("yarn run start".to_string())),
+        );
+        same_plan.add_variables(EnvironmentVariables::from([
+            ("NPM_CONFIG_PRODUCTION".to_string(), "false".to_string()),
+            ("NODE_ENV".to_string(), "production".to_string()),
+        ]));
+        same_plan.static_assets = Some(StaticAssets::new());
+
+        assert_eq!(plan.digest().unwrap(), same_plan.digest().unwrap());
+        assert_eq!(
+            plan.digest().unwrap(),
+            BuildPlan::from_json(plan.to_json().unwrap())
+                .unwrap()
+                .digest()
+                .unwrap()
+        );
+
+        let mut changed_plan = plan.clone();
+        changed_plan.get_phase_mut("build").unwrap().cmds =
+            Some(vec!["yarn run build:production".to_string()]);
+        assert_ne!(plan.digest().unwrap(), changed_plan.digest().unwrap());
+
+        // Rotating a secret does not change the digest, but adding one does
+        let mut secret_plan = plan.clone();
+        secret_plan.secrets = Some(vec!["API_TOKEN".to_string()]);
+        secret_plan.add_variables(EnvironmentVariables::from([(
+            "API_TOKEN".to_string(),
+            "old".to_string(),
+        )]));
+        let mut rotated_plan = secret_plan.clone();
+        rotated_plan.add_variables(EnvironmentVariables::from([(
+            "API_TOKEN".to_string(),
+            "new".to_string(),
+        )]));
+        assert_eq!(
+            secret_plan.digest().unwrap(),
+            rotated_plan.digest().unwrap()
+        );
+        assert_ne!(plan.digest().unwrap(), secret_plan.digest().unwrap());
+    }
 }
@@ -1,7 +1,9 @@
 use crate::nixpacks::{
+    environment::EnvironmentVariables,
     images::{DEFAULT_BASE_IMAGE, STANDALONE_IMAGE},
     nix::{pkg::Pkg, NIXPACKS_ARCHIVE_LEGACY_OPENSSL, NIXPKGS_ARCHIVE},
 };
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashSet};
 use std::hash::Hash;
@@ -30,20 +32,111 @@ pub struct Phase {
 
     pub nixpkgs_archive: Option<String>,
 
+    /// Expected sha256 of the unpacked nixpkgs archive, which Nix verifies when fetching it.
+    ///
+    /// This is the NAR hash that `fetchTarball` checks, as printed by `nix-prefetch-url --unpack`, not the hash of the tarball.
+    pub nixpkgs_archive_sha256: Option<String>,
+
     #[serde(alias = "aptPackages")]
     pub apt_pkgs: Option<Vec<String>>,
 
+    #[serde(alias = "buildAptPackages")]
+    pub build_apt_pkgs: Option<Vec<String>>,
+
     #[serde(alias = "commands")]
     pub cmds: Option<Vec<String>>,
 
+    /// Changes to single commands of the phase, applied when the plan is pinned.
+    pub cmd_patches: Option<Vec<CmdPatch>>,
+
     #[serde(rename = "onlyIncludeFiles")]
     pub only_include_files: Option<Vec<String>>,
 
+    /// Files that must all exist when the phase runs for its commands to run, as they may be created by earlier phases.
+    pub only_if_files: Option<Vec<String>>,
+
     #[serde(rename = "cacheDirectories")]
     pub cache_directories: Option<Vec<String>>,
 
+    /// Names of caches defined in the `caches` of the plan whose directories are cached in this phase.
+    pub caches: Option<Vec<String>>,
+
+    /// Directories that are always BuildKit cache mounts, and are never uploaded to the incremental cache.
+    pub cache_mounts: Option<Vec<String>>,
+
     #[serde(alias = "envPaths")]
     pub paths: Option<Vec<String>>,
+
+    /// Variables that are only available to the commands of this phase.
+    #[serde(alias = "env")]
+    pub variables: Option<EnvironmentVariables>,
+
+    /// Dockerfile instructions added verbatim at the end of this phase.
+    pub extra_instructions: Option<Vec<String>>,
+
+    /// User, optionally with a group as `user:group`, that the commands of this phase run as.
+    pub user: Option<String>,
+
+    /// Create the user with a home directory before running the phase, if it does not exist.
+    pub create_user: Option<bool>,
+
+    /// Nix packages that are only installed when building for an architecture, by its Docker name such as `amd64` or `arm64`.
+    pub arch_nix_pkgs: Option<BTreeMap<String, Vec<String>>>,
+
+    /// Patterns left out of the incremental cache upload of each cache directory, by directory.
+    ///
+    /// Declared last as it is a table in toml.
+    pub cache_excludes: Option<BTreeMap<String, Vec<String>>>,
+}
+
+/// Changes one command of a phase while keeping the others, e.g. to configure npm before the install command of the Node provider.
+///
+/// The command is replaced first, and then the prefix and suffix are run before and after it with `&&`.
+#[serde_with::skip_serializing_none]
+#[derive(PartialEq, Eq, Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CmdPatch {
+    /// The command that is patched, which also matches it with more arguments, e.g. `npm ci` matches `npm ci --omit=dev`.
+    ///
+    /// Every command of the phase is patched if not given.
+    pub cmd: Option<String>,
+
+    /// Command that replaces the patched command.
+    pub replace: Option<String>,
+
+    /// Command run before the patched command.
+    pub prefix: Option<String>,
+
+    /// Command run after the patched command.
+    pub suffix: Option<String>,
+}
+
+impl CmdPatch {
+    fn matches(&self, cmd: &str) -> bool {
+        match &self.cmd {
+            Some(target) => cmd == target || cmd.starts_with(&format!("{target} ")),
+            None => true,
+        }
+    }
+
+    /// Patch the matching commands, returning whether there were any.
+    fn apply(&self, cmds: &mut [String]) -> bool {
+        let mut matched = false;
+        for cmd in cmds.iter_mut().filter(|cmd| self.matches(cmd)) {
+            let mut patched = self.replace.clone().unwrap_or_else(|| cmd.clone());
+            if let Some(prefix) = &self.prefix {
+                patched = format!("{prefix} && {patched}");
+            }
+            if let Some(suffix) = &self.suffix {
+                patched = format!("{patched} && {suffix}");
+            }
+
+            *cmd = patched;
+            matched = true;
+        }
+
+        matched
+    }
 }
 
 /// Represents the final step of a container image, contains the startup command, any necessary files, and the final image that gets run by Docker.
@@ -54,6 +147,61 @@ pub struct StartPhase {
     pub cmd: Option<String>,
     pub run_image: Option<String>,
     pub only_include_files: Option<Vec<String>>,
+
+    /// Also install the apt packages of the phases, except their build apt packages, in the run image.
+    pub install_apt_pkgs: Option<bool>,
+
+    pub healthcheck: Option<Healthcheck>,
+
+    /// Dockerfile instructions added verbatim at the end of the final image.
+    pub extra_instructions: Option<Vec<String>>,
+
+    /// User, optionally with a group as `user:group`, that the app runs as.
+    pub user: Option<String>,
+
+    /// Create the user with a home directory in the final image, if it does not exist.
+    pub create_user: Option<bool>,
+
+    /// Absolute paths that the app writes to, declared as volumes so the image can run with a read-only root filesystem.
+    pub writable_paths: Option<Vec<String>>,
+
+    /// Ports that the app listens on, which are declared with `EXPOSE` in the final image.
+    pub expose_ports: Option<Vec<u16>>,
+
+    /// Runs before the start command, which is passed to it as arguments.
+    pub entrypoint: Option<Entrypoint>,
+
+    /// Commands of the processes of the app by name, e.g. `web` and `worker` from a Procfile.
+    ///
+    /// The `NIXPACKS_PROCESS` variable selects which one runs when the container starts, and `cmd` runs if it is unset.
+    pub processes: Option<BTreeMap<String, String>>,
+}
+
+/// A wrapper around the start command, either an inline shell command or a script from the app.
+///
+/// The entrypoint is given the start command as `/bin/bash -l -c <cmd>`, which scripts should run with `exec "$@"`.
+#[serde_with::skip_serializing_none]
+#[derive(PartialEq, Eq, Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Entrypoint {
+    /// Shell command run before the start command, e.g. to run migrations.
+    pub cmd: Option<String>,
+
+    /// Path of a script in the app, relative to its root, that is copied into the image.
+    pub file: Option<String>,
+}
+
+/// A command Docker runs inside the container to check that the app is healthy.
+#[serde_with::skip_serializing_none]
+#[derive(PartialEq, Eq, Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Healthcheck {
+    #[serde(alias = "command")]
+    pub cmd: Option<String>,
+    pub interval: Option<String>,
+    pub timeout: Option<String>,
+    pub retries: Option<u32>,
+    pub start_period: Option<String>,
 }
 
 impl Phase {
@@ -133,12 +281,22 @@ impl Phase {
     pub fn uses_nix(&self) -> bool {
         !self.nix_pkgs.clone().unwrap_or_default().is_empty()
             || !self.nix_libs.clone().unwrap_or_default().is_empty()
+            || self
+                .arch_nix_pkgs
+                .iter()
+                .flat_map(BTreeMap::values)
+                .any(|pkgs| !pkgs.is_empty())
     }
 
     /// Whether or not the phase runs any docker commands
     pub fn runs_docker_commands(&self) -> bool {
         !self.cmds.clone().unwrap_or_default().is_empty()
             || !self.paths.clone().unwrap_or_default().is_empty()
+            || !self
+                .extra_instructions
+                .clone()
+                .unwrap_or_default()
+                .is_empty()
     }
 
     /// Add a phase name as a dependency of this phase.
@@ -161,6 +319,24 @@ impl Phase {
         ));
     }
 
+    /// Add Nix packages that are only installed when building for the given architecture, e.g. `arm64`.
+    pub fn add_arch_nix_pkgs(&mut self, arch: &str, new_pkgs: &[Pkg]) {
+        self.nix_overlays = Some(add_multiple_to_option_vec(
+            self.nix_overlays.clone(),
+            new_pkgs
+                .iter()
+                .filter_map(|pkg| pkg.overlay.clone())
+                .collect::<Vec<_>>(),
+        ));
+        let mut arch_nix_pkgs = self.arch_nix_pkgs.clone().unwrap_or_default();
+        let pkgs = arch_nix_pkgs.remove(arch);
+        arch_nix_pkgs.insert(
+            arch.to_string(),
+            add_multiple_to_option_vec(pkgs, new_pkgs.iter().map(Pkg::to_nix_string).collect()),
+        );
+        self.arch_nix_pkgs = Some(arch_nix_pkgs);
+    }
+
     /// Add a collection of libraries to install with Nix in this phase.
     pub fn add_pkgs_libs(&mut self, new_libraries: Vec<String>) {
         self.nix_libs = Some(add_multiple_to_option_vec(
@@ -174,6 +350,16 @@ impl Phase {
         self.apt_pkgs = Some(add_multiple_to_option_vec(self.apt_pkgs.clone(), new_pkgs));
     }
 
+    /// Add a collection of packages to install with apt that are only needed while building.
+    ///
+    /// These are left out of the final image when a separate run image is used.
+    pub fn add_build_apt_pkgs(&mut self, new_pkgs: Vec<String>) {
+        self.build_apt_pkgs = Some(add_multiple_to_option_vec(
+            self.build_apt_pkgs.clone(),
+            new_pkgs,
+        ));
+    }
+
     /// Add a command to execute in this phase.
     pub fn add_cmd<S: Into<String>>(&mut self, cmd: S) {
         self.cmds = Some(add_to_option_vec(self.cmds.clone(), cmd.into()));
@@ -202,6 +388,13 @@ impl Phase {
         self.paths = Some(add_to_option_vec(self.paths.clone(), path));
     }
 
+    /// Add a variable that is only available to the commands of this phase.
+    pub fn add_variable<S: Into<String>>(&mut self, name: S, value: S) {
+        self.variables
+            .get_or_insert_with(EnvironmentVariables::new)
+            .insert(name.into(), value.into());
+    }
+
     /// Set the nixpkgs revision used by this phase.
     pub fn set_nix_archive(&mut self, archive: String) {
         self.nixpkgs_archive = Some(archive);
@@ -218,14 +411,33 @@ impl Phase {
         }
 
         self.cmds = pin_option_vec(&self.cmds);
+        self.apply_cmd_patches();
         self.depends_on = pin_option_vec(&self.depends_on);
         self.nix_pkgs = pin_option_vec(&self.nix_pkgs);
         self.nix_libs = pin_option_vec(&self.nix_libs);
         self.apt_pkgs = pin_option_vec(&self.apt_pkgs);
+        self.build_apt_pkgs = pin_option_vec(&self.build_apt_pkgs);
         self.nix_overlays = pin_option_vec(&self.nix_overlays);
         self.only_include_files = pin_option_vec(&self.only_include_files);
+        self.only_if_files = pin_option_vec(&self.only_if_files);
         self.cache_directories = pin_option_vec(&self.cache_directories);
+        self.caches = pin_option_vec(&self.caches);
+        self.cache_mounts = pin_option_vec(&self.cache_mounts);
         self.paths = pin_option_vec(&self.paths);
+        self.extra_instructions = pin_option_vec(&self.extra_instructions);
+    }
+
+    /// Apply the command patches to the commands, keeping only patches that matched none of them.
+    fn apply_cmd_patches(&mut self) {
+        if let (Some(cmds), Some(patches)) = (&mut self.cmds, self.cmd_patches.take()) {
+            let unmatched = patches
+                .into_iter()
+                .filter(|patch| !patch.apply(cmds))
+                .collect::<Vec<_>>();
+            if !unmatched.is_empty() {
+                self.cmd_patches = Some(unmatched);
+            }
+        }
     }
 }
 
@@ -262,8 +474,86 @@ impl StartPhase {
 
     /// Store the list of files to include in this phase for later reproducibility.
     pub fn pin(&mut self) {
+        // An empty start command (e.g. `NIXPACKS_START_CMD=""`) means no command is run
+        self.cmd = self.cmd.take().filter(|cmd| !cmd.trim().is_empty());
         self.only_include_files = pin_option_vec(&self.only_include_files);
+        self.extra_instructions = pin_option_vec(&self.extra_instructions);
+    }
+
+    /// Replace `${NAME}` in the start command with the value of the variable, if it is given.
+    ///
+    /// Unknown variables, `$NAME`, and `${NAME:-default}` are left for the shell to expand at runtime.
+    pub fn resolve_cmd_variables(&mut self, variables: &EnvironmentVariables) {
+        if let Some(cmd) = &self.cmd {
+            self.cmd = Some(resolve_variables(cmd, variables));
+        }
+        if let Some(processes) = &mut self.processes {
+            for cmd in processes.values_mut() {
+                *cmd = resolve_variables(cmd, variables);
+            }
+        }
+    }
+
+    /// The command run when the container starts, which selects one of the processes with `NIXPACKS_PROCESS`.
+    pub fn get_start_cmd(&self) -> Result<Option<String>> {
+        let processes = match &self.processes {
+            Some(processes) if !processes.is_empty() => processes,
+            _ => return Ok(self.cmd.clone()),
+        };
+
+        // Each command is quoted and evaluated, so that `;;` or `esac` in a command cannot end its case
+        let eval = |cmd: &str| format!("eval '{}'", cmd.replace('\'', "'\\''"));
+        let mut cases = vec![format!(
+            "'') {} ;;",
+            self.cmd.as_deref().map_or_else(
+                || "echo \"NIXPACKS_PROCESS is not set\" >&2; exit 1".to_string(),
+                eval
+            )
+        )];
+        for (name, cmd) in processes {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                bail!("Process name `{name}` can only contain letters, numbers, `_` and `-`");
+            }
+            cases.push(format!("{name}) {} ;;", eval(cmd)));
+        }
+        cases.push("*) echo \"Unknown process $NIXPACKS_PROCESS\" >&2; exit 1 ;;".to_string());
+
+        Ok(Some(format!(
+            "case \"${{NIXPACKS_PROCESS:-}}\" in {} esac",
+            cases.join(" ")
+        )))
+    }
+}
+
+fn resolve_variables(cmd: &str, variables: &EnvironmentVariables) -> String {
+    let mut resolved = String::new();
+    let mut rest = cmd;
+
+    while let Some(start) = rest.find("${") {
+        let (before, template) = rest.split_at(start);
+        let Some(end) = template.find('}') else {
+            break;
+        };
+
+        let name = &template[2..end];
+        let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        resolved.push_str(before);
+        match variables.get(name) {
+            Some(value) if is_name => resolved.push_str(value),
+            _ => resolved.push_str(&template[..=end]),
+        }
+
+        rest = &template[end + 1..];
     }
+
+    resolved.push_str(rest);
+    resolved
 }
 
 /// Store the list of options for this phase for later reproducibility.
@@ -18,7 +18,12 @@ impl BuildPlan {
         let phase_contents = self
             .get_sorted_phases()?
             .iter()
-            .filter(|phase| phase.uses_nix() || phase.apt_pkgs.is_some() || phase.cmds.is_some())
+            .filter(|phase| {
+                phase.uses_nix()
+                    || phase.apt_pkgs.is_some()
+                    || phase.build_apt_pkgs.is_some()
+                    || phase.cmds.is_some()
+            })
             .map(|phase| (phase.get_name(), self.get_phase_content(phase).unwrap()))
             .collect::<Vec<_>>();
 
@@ -154,8 +159,9 @@ impl BuildPlan {
 
         let nix_pkgs = phase.nix_pkgs.clone().unwrap_or_default();
         let apt_pkgs = phase.apt_pkgs.clone().unwrap_or_default();
+        let build_apt_pkgs = phase.build_apt_pkgs.clone().unwrap_or_default();
         let cmds = phase.cmds.clone().unwrap_or_default();
-        let pkgs = [nix_pkgs, apt_pkgs].concat();
+        let pkgs = [nix_pkgs, apt_pkgs, build_apt_pkgs].concat();
 
         let show_label = !pkgs.is_empty() && !cmds.is_empty();
 
@@ -0,0 +1,610 @@
+use serde_json::{json, Map, Value};
+
+const DURATION_PATTERN: &str = r"^([0-9]+(\.[0-9]+)?(ns|us|µs|ms|s|m|h))+$";
+
+/// The fields of a struct, described by their (camelCase) name in the plan and their schema.
+type Properties = Vec<(&'static str, Value)>;
+
+/// Describes a JSON Schema for build plans and `nixpacks.{toml,json}` config files.
+///
+/// Every field of a plan is optional, so the schema only marks the fields that must be set when their parent is set.
+/// The names of the given providers are the allowed values of `providers`.
+pub fn build_plan_schema(provider_names: &[&str]) -> Value {
+    let mut providers = provider_names
+        .iter()
+        .map(|name| (*name).to_string())
+        .collect::<Vec<_>>();
+    providers.push("...".to_string());
+    providers.push("@auto".to_string());
+
+    let mut schema = object_schema(
+        "Contains all information needed to build a project.",
+        vec![
+            (
+                "providers",
+                json!({
+                    "description": "Providers used to build the app. `...` is replaced by the detected providers.",
+                    "type": "array",
+                    "items": { "type": "string", "enum": providers },
+                }),
+            ),
+            (
+                "excludeProviders",
+                json!({
+                    "description": "Providers that are never detected or used, even when they match the app.",
+                    "type": "array",
+                    "items": { "type": "string", "enum": providers },
+                }),
+            ),
+            (
+                "buildImage",
+                string("Base image that the build phases run in."),
+            ),
+            (
+                "nixOverlays",
+                string_array("Nix overlays applied on top of the nixpkgs archive of every phase."),
+            ),
+            (
+                "excludeFiles",
+                string_array(
+                    "`.dockerignore` patterns for files that are left out of the Docker build context.",
+                ),
+            ),
+            (
+                "secrets",
+                string_array("Names of variables passed to the build as BuildKit secrets."),
+            ),
+            (
+                "workdir",
+                string("Absolute path of the directory the app is copied to and built in. Defaults to `/app`."),
+            ),
+            (
+                "shell",
+                string_array("Shell that the commands of the phases run in, as the arguments of a Dockerfile `SHELL` instruction, e.g. `[\"/bin/bash\", \"-euo\", \"pipefail\", \"-c\"]`."),
+            ),
+            (
+                "variables",
+                string_map("Environment variables available during the build and at runtime."),
+            ),
+            (
+                "nixOverrides",
+                string_map("Nix packages chosen by providers to replace with another package, by name."),
+            ),
+            (
+                "staticAssets",
+                string_map("Files, by path, that are written into the `assets` directory of the image."),
+            ),
+            (
+                "aptMirror",
+                string("Mirror of the Ubuntu or Debian archive that apt packages are installed from."),
+            ),
+            (
+                "aptSources",
+                json!({
+                    "description": "Extra apt repositories that apt packages can be installed from.",
+                    "type": "array",
+                    "items": { "$ref": "#/definitions/AptSource" },
+                }),
+            ),
+            (
+                "caches",
+                json!({
+                    "description": "Cache directories by name, which phases can use with `caches`.",
+                    "type": "object",
+                    "additionalProperties": { "type": "array", "items": { "type": "string" } },
+                }),
+            ),
+            (
+                "phases",
+                json!({
+                    "description": "Phases of the build, by name.",
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/definitions/Phase" },
+                }),
+            ),
+            ("start", json!({ "$ref": "#/definitions/StartPhase" })),
+        ],
+    );
+
+    let schema_object = schema.as_object_mut().unwrap();
+    schema_object.insert(
+        "$schema".to_string(),
+        json!("http://json-schema.org/draft-07/schema#"),
+    );
+    schema_object.insert("title".to_string(), json!("BuildPlan"));
+    schema_object.insert(
+        "definitions".to_string(),
+        json!({
+            "Phase": phase_schema(),
+            "StartPhase": start_phase_schema(),
+            "Healthcheck": healthcheck_schema(),
+            "Entrypoint": entrypoint_schema(),
+            "CmdPatch": cmd_patch_schema(),
+            "AptSource": apt_source_schema(),
+        }),
+    );
+
+    schema
+}
+
+fn phase_schema() -> Value {
+    object_schema(
+        "Holds the packages, commands, and directories needed for part of a build.",
+        vec![
+            ("name", string("Name of the phase.")),
+            (
+                "dependsOn",
+                string_array("Names of the phases that run before this phase."),
+            ),
+            (
+                "nixPkgs",
+                string_array("Nix packages installed in this phase."),
+            ),
+            ("nixPackages", string_array("Alias of `nixPkgs`.")),
+            (
+                "nixLibs",
+                string_array("Nix libraries made available in this phase."),
+            ),
+            ("nixLibraries", string_array("Alias of `nixLibs`.")),
+            (
+                "nixOverlays",
+                string_array("Nix overlays applied to the packages of this phase."),
+            ),
+            (
+                "nixpkgsArchive",
+                string("Commit of nixpkgs that the Nix packages are installed from."),
+            ),
+            (
+                "nixpkgsArchiveSha256",
+                string(
+                    "Expected NAR hash (sha256) of the unpacked nixpkgs archive, verified by Nix.",
+                ),
+            ),
+            (
+                "aptPkgs",
+                string_array("Apt packages installed in this phase."),
+            ),
+            ("aptPackages", string_array("Alias of `aptPkgs`.")),
+            (
+                "buildAptPkgs",
+                string_array("Apt packages that are only installed in the build image."),
+            ),
+            ("buildAptPackages", string_array("Alias of `buildAptPkgs`.")),
+            ("cmds", string_array("Commands run in this phase.")),
+            ("commands", string_array("Alias of `cmds`.")),
+            (
+                "cmdPatches",
+                json!({
+                    "description": "Changes to single commands of this phase.",
+                    "type": "array",
+                    "items": { "$ref": "#/definitions/CmdPatch" },
+                }),
+            ),
+            (
+                "onlyIncludeFiles",
+                string_array(
+                    "Files copied into the image before this phase, instead of the whole app.",
+                ),
+            ),
+            (
+                "onlyIfFiles",
+                string_array("Files that must all exist for the commands of this phase to run."),
+            ),
+            (
+                "cacheDirectories",
+                string_array("Directories cached between builds."),
+            ),
+            (
+                "caches",
+                string_array("Names of caches from the `caches` of the plan used in this phase."),
+            ),
+            (
+                "cacheMounts",
+                string_array("Directories that are always BuildKit cache mounts."),
+            ),
+            ("paths", string_array("Directories added to the `PATH`.")),
+            ("envPaths", string_array("Alias of `paths`.")),
+            (
+                "variables",
+                string_map("Variables that are only available to the commands of this phase."),
+            ),
+            ("env", string_map("Alias of `variables`.")),
+            (
+                "extraInstructions",
+                string_array("Dockerfile instructions added verbatim at the end of this phase."),
+            ),
+            (
+                "user",
+                string("User, optionally as `user:group`, that the commands of this phase run as."),
+            ),
+            (
+                "createUser",
+                boolean("Create the user before running the phase, if it does not exist."),
+            ),
+            (
+                "archNixPkgs",
+                json!({
+                    "description": "Nix packages that are only installed when building for an architecture, by its Docker name such as `amd64` or `arm64`.",
+                    "type": "object",
+                    "additionalProperties": { "type": "array", "items": { "type": "string" } },
+                }),
+            ),
+            (
+                "cacheExcludes",
+                json!({
+                    "description": "Patterns left out of the incremental cache upload of each cache directory, by directory.",
+                    "type": "object",
+                    "additionalProperties": { "type": "array", "items": { "type": "string" } },
+                }),
+            ),
+        ],
+    )
+}
+
+fn start_phase_schema() -> Value {
+    object_schema(
+        "The startup command, files, and image of the final container image.",
+        vec![
+            ("cmd", string("Command run when the container starts.")),
+            (
+                "runImage",
+                string("Image that the app is copied into to run, instead of the build image."),
+            ),
+            (
+                "onlyIncludeFiles",
+                string_array("Files copied from the build image into the run image."),
+            ),
+            (
+                "installAptPkgs",
+                boolean("Also install the apt packages of the phases, except their build apt packages, in the run image."),
+            ),
+            (
+                "healthcheck",
+                json!({ "$ref": "#/definitions/Healthcheck" }),
+            ),
+            (
+                "extraInstructions",
+                string_array(
+                    "Dockerfile instructions added verbatim at the end of the final image.",
+                ),
+            ),
+            (
+                "user",
+                string("User, optionally as `user:group`, that the app runs as."),
+            ),
+            (
+                "createUser",
+                boolean("Create the user in the final image, if it does not exist."),
+            ),
+            (
+                "writablePaths",
+                string_array("Absolute paths that the app writes to, declared as volumes so the image can run with a read-only root filesystem."),
+            ),
+            (
+                "exposePorts",
+                json!({
+                    "description": "Ports that the app listens on, which are declared with `EXPOSE` in the final image.",
+                    "type": "array",
+                    "items": { "type": "integer", "minimum": 1, "maximum": 65535 },
+                }),
+            ),
+            ("entrypoint", json!({ "$ref": "#/definitions/Entrypoint" })),
+            (
+                "processes",
+                string_map("Commands of the processes of the app by name, selected with `NIXPACKS_PROCESS`."),
+            ),
+        ],
+    )
+}
+
+fn entrypoint_schema() -> Value {
+    let mut schema = object_schema(
+        "Runs before the start command, which is passed to it as arguments.",
+        vec![
+            ("cmd", string("Shell command run before the start command.")),
+            (
+                "file",
+                string("Path of a script in the app that is copied into the image."),
+            ),
+        ],
+    );
+
+    schema.as_object_mut().unwrap().insert(
+        "oneOf".to_string(),
+        json!([{ "required": ["cmd"] }, { "required": ["file"] }]),
+    );
+
+    schema
+}
+
+fn cmd_patch_schema() -> Value {
+    object_schema(
+        "Changes one command of a phase while keeping the others.",
+        vec![
+            (
+                "cmd",
+                string("The command that is patched, or every command if not given."),
+            ),
+            (
+                "replace",
+                string("Command that replaces the patched command."),
+            ),
+            ("prefix", string("Command run before the patched command.")),
+            ("suffix", string("Command run after the patched command.")),
+        ],
+    )
+}
+
+fn healthcheck_schema() -> Value {
+    let mut schema = object_schema(
+        "A command Docker runs inside the container to check that the app is healthy.",
+        vec![
+            ("cmd", string("Command that checks the app is healthy.")),
+            ("command", string("Alias of `cmd`.")),
+            ("interval", duration("Time between checks.")),
+            (
+                "timeout",
+                duration("Time a check may take before it fails."),
+            ),
+            (
+                "retries",
+                json!({
+                    "description": "Consecutive failed checks before the container is unhealthy.",
+                    "type": "integer",
+                    "minimum": 0,
+                }),
+            ),
+            (
+                "startPeriod",
+                duration("Time the app is given to start before failed checks count."),
+            ),
+        ],
+    );
+
+    schema.as_object_mut().unwrap().insert(
+        "anyOf".to_string(),
+        json!([{ "required": ["cmd"] }, { "required": ["command"] }]),
+    );
+
+    schema
+}
+
+fn apt_source_schema() -> Value {
+    let mut schema = object_schema(
+        "An apt repository, added to the sources of the image before any apt packages are installed.",
+        vec![
+            (
+                "deb",
+                json!({
+                    "description": "A one-line-style source entry, e.g. `deb https://example.com/apt stable main`.",
+                    "type": "string",
+                    "pattern": "^deb(-src)? ",
+                }),
+            ),
+            (
+                "keyUrl",
+                json!({
+                    "description": "URL of the GPG key the repository is signed with.",
+                    "type": "string",
+                    "pattern": "^https?://",
+                }),
+            ),
+        ],
+    );
+
+    schema
+        .as_object_mut()
+        .unwrap()
+        .insert("required".to_string(), json!(["deb"]));
+
+    schema
+}
+
+fn object_schema(description: &str, properties: Properties) -> Value {
+    json!({
+        "description": description,
+        "type": "object",
+        "properties": properties
+            .into_iter()
+            .map(|(name, schema)| (name.to_string(), schema))
+            .collect::<Map<_, _>>(),
+        "additionalProperties": false,
+    })
+}
+
+fn string(description: &str) -> Value {
+    json!({ "description": description, "type": "string" })
+}
+
+fn boolean(description: &str) -> Value {
+    json!({ "description": description, "type": "boolean" })
+}
+
+fn string_array(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "array",
+        "items": { "type": "string" },
+    })
+}
+
+fn string_map(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "object",
+        "additionalProperties": { "type": "string" },
+    })
+}
+
+fn duration(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "string",
+        "pattern": DURATION_PATTERN,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::plan::BuildPlan;
+    use regex::Regex;
+
+    /// Check a value against the subset of JSON Schema used by the build plan schema.
+    fn schema_errors(value: &Value, schema: &Value, root: &Value, path: &str) -> Vec<String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/definitions/");
+            return schema_errors(value, &root["definitions"][name], root, path);
+        }
+
+        let mut errors = Vec::new();
+        let type_matches = match schema["type"].as_str() {
+            Some("object") => value.is_object(),
+            Some("array") => value.is_array(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_u64() || value.is_i64(),
+            Some("boolean") => value.is_boolean(),
+            _ => true,
+        };
+        if !type_matches {
+            return vec![format!("{path} is not a {}", schema["type"])];
+        }
+
+        if let Some(values) = schema["enum"].as_array() {
+            if !values.contains(value) {
+                errors.push(format!("{path} is not one of the allowed values"));
+            }
+        }
+        if let (Some(pattern), Some(s)) = (schema["pattern"].as_str(), value.as_str()) {
+            if !Regex::new(pattern).unwrap().is_match(s) {
+                errors.push(format!("{path} does not match {pattern}"));
+            }
+        }
+        if let (Some(minimum), Some(n)) = (schema["minimum"].as_i64(), value.as_i64()) {
+            if n < minimum {
+                errors.push(format!("{path} is less than {minimum}"));
+            }
+        }
+        if let Some(items) = value.as_array() {
+            for (i, item) in items.iter().enumerate() {
+                errors.extend(schema_errors(
+                    item,
+                    &schema["items"],
+                    root,
+                    &format!("{path}[{i}]"),
+                ));
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for (key, field) in object {
+                let field_path = format!("{path}.{key}");
+                match (&schema["properties"][key], &schema["additionalProperties"]) {
+                    (Value::Null, Value::Bool(false)) => {
+                        errors.push(format!("{field_path} is not allowed"));
+                    }
+                    (Value::Null, Value::Null) => {}
+                    (Value::Null, additional) => {
+                        errors.extend(schema_errors(field, additional, root, &field_path));
+                    }
+                    (property, _) => {
+                        errors.extend(schema_errors(field, property, root, &field_path));
+                    }
+                }
+            }
+        }
+        if let Some(required) = schema["required"].as_array() {
+            for key in required {
+                if value.get(key.as_str().unwrap()).is_none() {
+                    errors.push(format!("{path} is missing {key}"));
+                }
+            }
+        }
+        if let Some(any_of) = schema["anyOf"].as_array() {
+            let matches_any = any_of.iter().any(|option| {
+                option["required"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .all(|key| value.get(key.as_str().unwrap()).is_some())
+            });
+            if !matches_any {
+                errors.push(format!("{path} is missing a required field"));
+            }
+        }
+
+        errors
+    }
+
+    #[test]
+    fn test_schema_has_plan_keys() {
+        let schema = build_plan_schema(&["node"]);
+
+        assert!(schema["properties"]["phases"].is_object());
+        assert!(schema["properties"]["start"].is_object());
+        assert_eq!(
+            schema["properties"]["providers"]["items"]["enum"],
+            json!(["node", "...", "@auto"])
+        );
+        assert_eq!(
+            schema["definitions"]["Healthcheck"]["properties"]["retries"]["type"],
+            "integer"
+        );
+    }
+
+    #[test]
+    fn test_schema_validates_sample_plan() {
+        let schema = build_plan_schema(&["node", "python"]);
+        let plan = BuildPlan::from_toml(
+            r#"
+            providers = ["...", "python"]
+            buildImage = "ghcr.io/railwayapp/nixpacks:ubuntu"
+
+            [variables]
+            NODE_ENV = "production"
+
+            [[aptSources]]
+            deb = "deb https://repo.example.com/apt stable main"
+            keyUrl = "https://repo.example.com/apt/key.gpg"
+
+            [phases.setup]
+            nixPkgs = ["...", "cowsay"]
+
+            [phases.build]
+            cmds = ["npm run build"]
+            dependsOn = ["setup"]
+            cacheDirectories = ["node_modules/.cache"]
+
+            [start]
+            cmd = "npm run start"
+
+            [start.healthcheck]
+            cmd = "curl -f http://localhost:3000"
+            interval = "30s"
+            retries = 3
+            "#,
+        )
+        .unwrap();
+        let mut plan = serde_json::to_value(plan).unwrap();
+
+        assert_eq!(
+            schema_errors(&plan, &schema, &schema, "plan"),
+            Vec::<String>::new()
+        );
+
+        plan["staticAssets"] = json!(["not", "a", "map"]);
+        plan["phases"]["build"]["cachDirectories"] = json!(["typo"]);
+        plan["start"]["healthcheck"]["interval"] = json!("soon");
+        plan["providers"] = json!(["cobol"]);
+        plan["aptSources"][0] = json!({ "keyUrl": "https://repo.example.com/apt/key.gpg" });
+        assert_eq!(
+            schema_errors(&plan, &schema, &schema, "plan"),
+            vec![
+                "plan.aptSources[0] is missing \"deb\"",
+                "plan.phases.build.cachDirectories is not allowed",
+                "plan.providers[0] is not one of the allowed values",
+                "plan.start.healthcheck.interval does not match ^([0-9]+(\\.[0-9]+)?(ns|us|µs|ms|s|m|h))+$",
+                "plan.staticAssets is not a \"object\"",
+            ]
+        );
+    }
+}
@@ -64,7 +64,10 @@ where
 
         // Circular dependency
         if no_deps.is_empty() {
-            bail!("Circular dependency detected");
+            bail!(
+                "Circular dependency detected: {}",
+                find_cycle(&new_indegree, &lookup).join(" -> ")
+            );
         }
 
         indegree = new_indegree;
@@ -92,6 +95,35 @@ where
     Ok(result)
 }
 
+/// Find a cycle among the remaining items, which all have unresolved dependencies.
+///
+/// Returns the names in the cycle, starting and ending with the same name.
+fn find_cycle<T: TopItem>(
+    remaining: &BTreeMap<String, usize>,
+    lookup: &BTreeMap<String, T>,
+) -> Vec<String> {
+    let mut path: Vec<String> = Vec::new();
+    let mut current = remaining.keys().next().cloned();
+
+    while let Some(name) = current {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle = path.split_off(start);
+            cycle.push(name);
+            return cycle;
+        }
+
+        current = lookup.get(&name).and_then(|item| {
+            item.get_dependencies()
+                .iter()
+                .find(|dep| remaining.contains_key(*dep))
+                .cloned()
+        });
+        path.push(name);
+    }
+
+    path
+}
+
 #[cfg(test)]
 mod tests {
     use crate::nixpacks::plan::topological_sort;
@@ -149,6 +181,43 @@ mod tests {
             TestItem::new("b", vec!["a".to_string()]),
         ];
 
-        assert!(topological_sort(items).is_err());
+        assert_eq!(
+            topological_sort(items).unwrap_err().to_string(),
+            "Circular dependency detected: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn test_reports_cycle_behind_dependency() {
+        let items = vec![
+            TestItem::new("a", vec!["b".to_string()]),
+            TestItem::new("b", vec!["c".to_string()]),
+            TestItem::new("c", vec!["d".to_string()]),
+            TestItem::new("d", vec!["b".to_string()]),
+        ];
+
+        assert_eq!(
+            topological_sort(items).unwrap_err().to_string(),
+            "Circular dependency detected: b -> c -> d -> b"
+        );
+    }
+
+    #[test]
+    fn test_sorts_diamond() {
+        let items = vec![
+            TestItem::new("d", vec!["b".to_string(), "c".to_string()]),
+            TestItem::new("c", vec!["a".to_string()]),
+            TestItem::new("b", vec!["a".to_string()]),
+            TestItem::new("a", vec![]),
+        ];
+
+        assert_eq!(
+            topological_sort(items)
+                .unwrap()
+                .iter()
+                .map(topological_sort::TopItem::get_name)
+                .collect::<Vec<_>>(),
+            vec!["a", "b", "c", "d"]
+        );
     }
 }
@@ -0,0 +1,294 @@
+use super::BuildPlan;
+use crate::nixpacks::nix::is_valid_sha256;
+use std::collections::BTreeMap;
+
+/// Phases that are depended on by default but that providers can leave out.
+const OPTIONAL_PHASES: &[&str] = &["setup", "install", "build"];
+
+/// Whether an apt package is a package name, optionally pinned to a version with `=` or to a release with `/`.
+///
+/// Versions can use the wildcards of apt, e.g. `libpq-dev=13.*`.
+fn is_valid_apt_package(pkg: &str) -> bool {
+    let (name, pin) = match pkg.find(['=', '/']) {
+        Some(i) => (&pkg[..i], Some(&pkg[i + 1..])),
+        None => (pkg, None),
+    };
+    // Packages for another architecture are named `name:arch`
+    let (name, arch) = match name.split_once(':') {
+        Some((name, arch)) => (name, Some(arch)),
+        None => (name, None),
+    };
+
+    let is_valid_name = name.len() > 1
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '+' || c == '-' || c == '.'
+        });
+    let is_valid_arch = arch.map_or(true, |arch| {
+        !arch.is_empty() && arch.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    let is_valid_pin = pin.map_or(true, |pin| {
+        !pin.is_empty()
+            && pin.chars().all(|c| {
+                c.is_ascii_alphanumeric() || ['.', '+', '-', '~', ':', '*', '?'].contains(&c)
+            })
+    });
+
+    is_valid_name && is_valid_arch && is_valid_pin
+}
+
+/// Whether a phase named `name` not existing is expected, including `provider:phase` names from multi-provider plans.
+fn is_optional_phase(name: &str) -> bool {
+    let name = name.rsplit(':').next().unwrap_or(name);
+    OPTIONAL_PHASES.contains(&name)
+}
+
+impl BuildPlan {
+    /// Check the plan for mistakes that would otherwise only show up during the Docker build.
+    ///
+    /// Returns a readable description of each problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let phases = self.phases.clone().unwrap_or_default();
+        let mut archive_hashes: BTreeMap<Option<String>, (&str, &str)> = BTreeMap::new();
+
+        for (name, phase) in &phases {
+            for dep in phase.depends_on.clone().unwrap_or_default() {
+                if dep != "..." && !phases.contains_key(&dep) && !is_optional_phase(&dep) {
+                    problems.push(format!(
+                        "Phase `{name}` depends on `{dep}`, which does not exist"
+                    ));
+                }
+            }
+
+            if phase
+                .nix_pkgs
+                .clone()
+                .unwrap_or_default()
+                .iter()
+                .any(|pkg| pkg.trim().is_empty())
+            {
+                problems.push(format!("Phase `{name}` has an empty Nix package name"));
+            }
+
+            // Patches that matched a command were applied and removed when the plan was pinned
+            for patch in phase.cmd_patches.clone().unwrap_or_default() {
+                problems.push(match patch.cmd {
+                    Some(cmd) => {
+                        format!("Phase `{name}` patches the command `{cmd}`, which it does not run")
+                    }
+                    None => format!("Phase `{name}` patches its commands, but has none"),
+                });
+            }
+
+            for pkg in [
+                phase.apt_pkgs.clone().unwrap_or_default(),
+                phase.build_apt_pkgs.clone().unwrap_or_default(),
+            ]
+            .concat()
+            {
+                if !is_valid_apt_package(&pkg) {
+                    problems.push(format!(
+                        "Phase `{name}` has an invalid apt package `{pkg}`, which must be a name optionally pinned as `name=version` or `name/release`"
+                    ));
+                }
+            }
+
+            if let Some(sha256) = &phase.nixpkgs_archive_sha256 {
+                if !is_valid_sha256(sha256) {
+                    problems.push(format!(
+                        "Phase `{name}` has an invalid nixpkgs archive sha256 `{sha256}`"
+                    ));
+                }
+
+                // Phases that share an archive are installed from a single download
+                match archive_hashes.get(&phase.nixpkgs_archive) {
+                    Some((other, other_sha256)) if other_sha256 != sha256 => {
+                        problems.push(format!(
+                            "Phases `{other}` and `{name}` expect different sha256 hashes of the same nixpkgs archive"
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        archive_hashes.insert(phase.nixpkgs_archive.clone(), (name, sha256));
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = self.get_sorted_phases() {
+            problems.push(e.to_string());
+        }
+
+        if let Err(e) = self.expand_named_caches() {
+            problems.push(e.to_string());
+        }
+
+        if let Err(e) = self.get_app_dir() {
+            problems.push(e.to_string());
+        }
+
+        if let Err(e) = self.get_shell_instruction() {
+            problems.push(e.to_string());
+        }
+
+        if let Err(e) = self.get_apt_mirror_command() {
+            problems.push(e.to_string());
+        }
+
+        if let Some(start) = &self.start_phase {
+            if let Err(e) = start.get_volume_instructions(false) {
+                problems.push(e.to_string());
+            }
+            if let Err(e) = start.get_expose_instructions() {
+                problems.push(e.to_string());
+            }
+        }
+
+        let has_start_cmd = self
+            .start_phase
+            .as_ref()
+            .is_some_and(|start| start.cmd.is_some());
+        if !has_start_cmd {
+            problems.push("No start command could be found".to_string());
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_apt_package;
+    use crate::nixpacks::plan::{
+        phase::{Phase, StartPhase},
+        BuildPlan,
+    };
+
+    fn plan_with_phases(phases: &[Phase]) -> BuildPlan {
+        BuildPlan::new(phases, Some(StartPhase::new("./start")))
+    }
+
+    #[test]
+    fn test_valid_plan() {
+        // `build` depends on `install` by default, which may be left out
+        let plan = plan_with_phases(&[Phase::setup(None), Phase::build(Some("make".to_string()))]);
+
+        assert!(plan.validate().is_empty());
+    }
+
+    #[test]
+    fn test_dangling_dependency() {
+        let mut build = Phase::build(Some("make".to_string()));
+        build.depends_on = Some(vec!["instal".to_string()]);
+        let plan = plan_with_phases(&[build]);
+
+        assert_eq!(
+            plan.validate(),
+            vec!["Phase `build` depends on `instal`, which does not exist"]
+        );
+    }
+
+    #[test]
+    fn test_dependency_cycle() {
+        let mut a = Phase::new("a");
+        a.depends_on_phase("b");
+        let mut b = Phase::new("b");
+        b.depends_on_phase("a");
+        let plan = plan_with_phases(&[a, b]);
+
+        let problems = plan.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("Circular dependency"));
+    }
+
+    #[test]
+    fn test_missing_start_and_empty_package() {
+        let mut setup = Phase::setup(None);
+        setup.nix_pkgs = Some(vec!["nodejs".to_string(), String::new()]);
+        let plan = BuildPlan::new(&[setup], None);
+
+        assert_eq!(
+            plan.validate(),
+            vec![
+                "Phase `setup` has an empty Nix package name",
+                "No start command could be found"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undefined_named_cache() {
+        let mut build = Phase::build(Some("make".to_string()));
+        build.caches = Some(vec!["ccache".to_string()]);
+        let plan = plan_with_phases(&[build]);
+
+        assert_eq!(
+            plan.validate(),
+            vec!["Phase `build` uses the cache `ccache`, which is not defined"]
+        );
+    }
+
+    #[test]
+    fn test_relative_workdir() {
+        let mut plan = plan_with_phases(&[Phase::build(Some("make".to_string()))]);
+        plan.workdir = Some("srv/app".to_string());
+
+        assert_eq!(
+            plan.validate(),
+            vec!["The workdir `srv/app` must be an absolute path"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_archive_sha256() {
+        let mut setup = Phase::setup(None);
+        setup.nixpkgs_archive = Some("archive".to_string());
+        setup.nixpkgs_archive_sha256 = Some("not-a-hash".to_string());
+        let mut toolchain = Phase::new("toolchain");
+        toolchain.nixpkgs_archive = Some("archive".to_string());
+        toolchain.nixpkgs_archive_sha256 =
+            Some("0mbqa1p1hhh4frdbc9z8axxiv1q6sp4ymgzj8y7aarg0zhqvh3pl".to_string());
+        let plan = plan_with_phases(&[setup, toolchain]);
+
+        assert_eq!(
+            plan.validate(),
+            vec![
+                "Phase `setup` has an invalid nixpkgs archive sha256 `not-a-hash`",
+                "Phases `setup` and `toolchain` expect different sha256 hashes of the same nixpkgs archive"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apt_package_pins() {
+        for pkg in [
+            "libpq-dev",
+            "libpq-dev=13.*",
+            "wget=1.21.3-1+b2",
+            "libc6:amd64=2.36-9",
+            "git/bookworm-backports",
+            "g++",
+        ] {
+            assert!(is_valid_apt_package(pkg), "{pkg}");
+        }
+        for pkg in [
+            "",
+            "libpq-dev=",
+            "libpq-dev==13",
+            "libpq-dev=13 && rm",
+            "LibPQ",
+            "libc6:",
+            "=13",
+        ] {
+            assert!(!is_valid_apt_package(pkg), "{pkg}");
+        }
+
+        let mut setup = Phase::setup(None);
+        setup.add_apt_pkgs(vec!["libpq-dev=13.*".to_string(), "curl=".to_string()]);
+        assert_eq!(
+            plan_with_phases(&[setup]).validate(),
+            vec!["Phase `setup` has an invalid apt package `curl=`, which must be a name optionally pinned as `name=version` or `name/release`"]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use super::{
+    node::{NodeProvider, PackageJson, NODE_OVERLAY},
+    Provider,
+};
+use crate::nixpacks::{
+    app::App,
+    environment::Environment,
+    nix::pkg::Pkg,
+    plan::{
+        phase::{Phase, StartPhase},
+        BuildPlan,
+    },
+};
+use anyhow::Result;
+
+const BUN_CACHE_DIR: &str = "~/.bun/install/cache";
+const NODE_MODULES_CACHE_DIR: &str = "node_modules/.cache";
+
+pub struct BunProvider {}
+
+impl Provider for BunProvider {
+    fn name(&self) -> &str {
+        "bun"
+    }
+
+    fn language(&self) -> &'static str {
+        "JavaScript"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["bun.lockb", "bunfig.toml"]
+    }
+
+    fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
+        let setup = Phase::setup(Some(vec![Pkg::new("bun").from_overlay(NODE_OVERLAY)]));
+
+        let mut install = Phase::install(Some(BunProvider::get_install_cmd(app)));
+        install.add_path(app.app_path("node_modules/.bin"));
+
+        let mut build = Phase::build(BunProvider::get_build_cmd(app)?);
+        build.add_cache_directory(NODE_MODULES_CACHE_DIR.to_string());
+
+        let start = BunProvider::get_start_cmd(app)?.map(StartPhase::new);
+
+        let mut plan = BuildPlan::new(&[setup, install, build], start);
+        plan.add_variables(NodeProvider::get_node_environment_variables());
+
+        Ok(Some(plan))
+    }
+
+    fn get_cache_dirs(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(vec![BUN_CACHE_DIR.to_string()])
+    }
+}
+
+impl BunProvider {
+    fn get_install_cmd(app: &App) -> String {
+        if app.includes_file("bun.lockb") {
+            "bun install --frozen-lockfile".to_string()
+        } else {
+            "bun install".to_string()
+        }
+    }
+
+    fn get_build_cmd(app: &App) -> Result<Option<String>> {
+        if NodeProvider::has_script(app, "build")? {
+            Ok(Some("bun run build".to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_start_cmd(app: &App) -> Result<Option<String>> {
+        if NodeProvider::has_script(app, "start")? {
+            return Ok(Some("bun run start".to_string()));
+        }
+
+        let package_json: PackageJson = app.read_json("package.json").unwrap_or_default();
+        if let Some(main) = package_json.main {
+            if app.includes_file(&main) {
+                return Ok(Some(format!("bun {main}")));
+            }
+        }
+
+        for file in ["index.ts", "index.js"] {
+            if app.includes_file(file) {
+                return Ok(Some(format!("bun {file}")));
+            }
+        }
+
+        Ok(None)
+    }
+}
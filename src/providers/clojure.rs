@@ -20,8 +20,12 @@ impl Provider for ClojureProvider {
         "clojure"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(self.is_using_lein(app) || self.is_using_tools_build(app))
+    fn language(&self) -> &'static str {
+        "Clojure"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["project.clj", "build.clj"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -43,11 +47,14 @@ impl Provider for ClojureProvider {
         };
 
         // based on project config, uberjar can be created under ./target/uberjar or ./target, This ensure file will be found on the same place whatevery the project config is
-        let move_file_cmd = "if [ -f /app/target/default+uberjar/*standalone.jar ]; then mv /app/target/default+uberjar/*standalone.jar /app/target/*standalone.jar; fi";
+        let target_dir = app.app_path("target");
+        let move_file_cmd = format!("if [ -f {target_dir}/default+uberjar/*standalone.jar ]; then mv {target_dir}/default+uberjar/*standalone.jar {target_dir}/*standalone.jar; fi");
         let mut build = Phase::build(Some(format!("{build_cmd}; {move_file_cmd}")));
         build.depends_on_phase("setup");
 
-        let start = StartPhase::new("bash -c \"java $JAVA_OPTS -jar /app/target/*standalone.jar\"");
+        let start = StartPhase::new(format!(
+            "bash -c \"java $JAVA_OPTS -jar {target_dir}/*standalone.jar\""
+        ));
 
         let plan = BuildPlan::new(&vec![setup, build], Some(start));
         Ok(Some(plan))
@@ -10,7 +10,6 @@ use crate::nixpacks::{
         BuildPlan,
     },
 };
-use anyhow::Result;
 use path_slash::PathBufExt;
 
 const COBOL_COMPILE_ARGS: &str = "COBOL_COMPILE_ARGS";
@@ -24,8 +23,12 @@ impl Provider for CobolProvider {
         "cobol"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.has_match("*.cbl"))
+    fn language(&self) -> &'static str {
+        "COBOL"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["*.cbl"]
     }
 
     fn get_build_plan(
@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 use super::Provider;
 use crate::nixpacks::{
     app::App,
@@ -10,14 +8,31 @@ use crate::nixpacks::{
         BuildPlan,
     },
 };
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
 
+/// Where `shards install` caches the repositories of dependencies.
+const SHARDS_CACHE_DIR: &str = "/root/.cache/shards";
+
+/// Environment variable that selects which target of `shard.yml` is built and started.
+const CRYSTAL_TARGET: &str = "CRYSTAL_TARGET";
+
 // https://github.com/crystal-lang/shards/blob/master/docs/shard.yml.adoc
 #[derive(Deserialize, Debug)]
 pub struct ShardYaml {
     pub name: String,
-    pub targets: HashMap<String, HashMap<String, String>>,
+    /// A mapping keeps the targets in the order they are written in.
+    #[serde(default)]
+    pub targets: serde_yaml::Mapping,
+}
+
+impl ShardYaml {
+    pub fn target_names(&self) -> Vec<String> {
+        self.targets
+            .keys()
+            .filter_map(|name| name.as_str().map(ToString::to_string))
+            .collect()
+    }
 }
 
 pub struct CrystalProvider {}
@@ -27,23 +42,28 @@ impl Provider for CrystalProvider {
         "crystal"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("shard.yml"))
+    fn language(&self) -> &'static str {
+        "Crystal"
     }
 
-    fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["shard.yml"]
+    }
+
+    fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
         let setup = Phase::setup(Some(vec![Pkg::new("crystal"), Pkg::new("shards")]));
-        let install = Phase::install(Some("shards install".to_string()));
-        let build = Phase::build(Some("shards build".to_string()));
+
+        let mut install = Phase::install(Some("shards install".to_string()));
+        install.add_file_dependency("shard.yml".to_string());
+        if app.includes_file("shard.lock") {
+            install.add_file_dependency("shard.lock".to_string());
+        }
+        install.add_cache_directory(SHARDS_CACHE_DIR);
 
         let config = CrystalProvider::get_config(app)?;
-        let target_names = config.targets.keys().cloned().collect::<Vec<_>>();
-        let start = StartPhase::new(format!(
-            "./bin/{}",
-            target_names
-                .get(0)
-                .ok_or_else(|| anyhow::anyhow!("Unable to get executable name"))?
-        ));
+        let target = CrystalProvider::get_target(&config, env)?;
+        let build = Phase::build(Some(format!("shards build {target} --release")));
+        let start = StartPhase::new(format!("./bin/{target}"));
 
         let plan = BuildPlan::new(&vec![setup, install, build], Some(start));
         Ok(Some(plan))
@@ -55,4 +75,74 @@ impl CrystalProvider {
         app.read_yaml::<ShardYaml>("shard.yml")
             .context("Reading shard.yml")
     }
+
+    /// The target that is built and started, which is `NIXPACKS_CRYSTAL_TARGET`, the target named after the shard, or the first one.
+    fn get_target(config: &ShardYaml, env: &Environment) -> Result<String> {
+        let targets = config.target_names();
+
+        if let Some(target) = env.get_config_variable(CRYSTAL_TARGET) {
+            if !targets.contains(&target) {
+                bail!(
+                    "The target `{target}` is not one of the targets in shard.yml: {}",
+                    targets.join(", ")
+                );
+            }
+            return Ok(target);
+        }
+
+        if targets.contains(&config.name) {
+            return Ok(config.name.clone());
+        }
+
+        targets
+            .into_iter()
+            .next()
+            .context("shard.yml does not have any targets to build")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_from_shard_yml() -> Result<()> {
+        let provider = CrystalProvider {};
+        let app = App::new("./examples/crystal-targets")?;
+
+        // The target named after the shard is chosen over the first one
+        let plan = provider
+            .get_build_plan(&app, &Environment::default())?
+            .unwrap();
+        assert_eq!(
+            plan.get_phase("build").unwrap().cmds,
+            Some(vec!["shards build server --release".to_string()])
+        );
+        assert_eq!(
+            plan.get_phase("install").unwrap().cache_directories,
+            Some(vec![SHARDS_CACHE_DIR.to_string()])
+        );
+        assert_eq!(
+            plan.start_phase.unwrap().cmd,
+            Some("./bin/server".to_string())
+        );
+
+        let env = Environment::from_envs(vec!["NIXPACKS_CRYSTAL_TARGET=cli"])?;
+        let plan = provider.get_build_plan(&app, &env)?.unwrap();
+        assert_eq!(plan.start_phase.unwrap().cmd, Some("./bin/cli".to_string()));
+
+        let env = Environment::from_envs(vec!["NIXPACKS_CRYSTAL_TARGET=worker"])?;
+        assert!(provider.get_build_plan(&app, &env).is_err());
+
+        // Otherwise the first target is chosen
+        let plan = provider
+            .get_build_plan(&App::new("./examples/crystal")?, &Environment::default())?
+            .unwrap();
+        assert_eq!(
+            plan.start_phase.unwrap().cmd,
+            Some("./bin/crystal".to_string())
+        );
+
+        Ok(())
+    }
 }
@@ -30,8 +30,12 @@ impl Provider for CSharpProvider {
         "c#"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(!app.find_files("*.csproj")?.is_empty())
+    fn language(&self) -> &'static str {
+        "C#"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["*.csproj"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -1,4 +1,4 @@
-use super::Provider;
+use super::{staticfile::StaticfileProvider, Provider};
 use crate::nixpacks::{
     app::App,
     environment::Environment,
@@ -10,13 +10,31 @@ use crate::nixpacks::{
 };
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 
 pub const DEFAULT_DART_PKG_NAME: &str = "dart";
+pub const FLUTTER_PKG_NAME: &str = "flutter";
+
+/// Where `dart pub get` downloads packages to.
+const PUB_CACHE_DIR: &str = "/root/.pub-cache";
+
+/// Where `flutter build web` writes the files of the app.
+const FLUTTER_WEB_OUTPUT_DIR: &str = "build/web";
 
 #[derive(Deserialize, Debug)]
 pub struct DartPubspec {
     pub name: String,
     pub version: String,
+    pub dependencies: Option<BTreeMap<String, serde_yaml::Value>>,
+}
+
+impl DartPubspec {
+    /// Flutter apps depend on the `flutter` SDK package.
+    pub fn is_flutter(&self) -> bool {
+        self.dependencies
+            .as_ref()
+            .is_some_and(|dependencies| dependencies.contains_key("flutter"))
+    }
 }
 
 pub struct DartProvider {}
@@ -26,21 +44,26 @@ impl Provider for DartProvider {
         "dart"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("pubspec.yaml"))
+    fn language(&self) -> &'static str {
+        "Dart"
     }
 
-    fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
-        let setup = Phase::setup(Some(vec![Pkg::new(DEFAULT_DART_PKG_NAME)]));
-
-        let mut install = Phase::install(Some("dart pub get".to_string()));
-        install.add_file_dependency("pubspec.yaml".to_string());
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["pubspec.yaml"]
+    }
 
+    fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
         let pubspec = DartProvider::get_pubspec(app)?;
-        let build = Phase::build(Some(format!("dart compile exe bin/{}.dart", pubspec.name)));
+        if pubspec.is_flutter() {
+            return Ok(Some(DartProvider::get_flutter_web_plan(app, env)?));
+        }
 
-        let pubspec = DartProvider::get_pubspec(app)?;
-        let start = StartPhase::new(format!("./bin/{}.exe", pubspec.name));
+        let setup = Phase::setup(Some(vec![Pkg::new(DEFAULT_DART_PKG_NAME)]));
+        let install = DartProvider::get_install_phase(app, "dart pub get");
+
+        let entrypoint = DartProvider::get_entrypoint(app, &pubspec);
+        let build = Phase::build(Some(format!("dart compile exe bin/{entrypoint}.dart")));
+        let start = StartPhase::new(format!("./bin/{entrypoint}.exe"));
 
         let plan = BuildPlan::new(&vec![setup, install, build], Some(start));
         Ok(Some(plan))
@@ -52,11 +75,49 @@ impl DartProvider {
         app.read_yaml::<DartPubspec>("pubspec.yaml")
             .context("Reading pubspec.yaml")
     }
+
+    fn get_install_phase(app: &App, cmd: &str) -> Phase {
+        let mut install = Phase::install(Some(cmd.to_string()));
+        install.add_file_dependency("pubspec.yaml".to_string());
+        if app.includes_file("pubspec.lock") {
+            install.add_file_dependency("pubspec.lock".to_string());
+        }
+        install.add_cache_directory(PUB_CACHE_DIR);
+
+        install
+    }
+
+    /// The script in `bin` that is compiled, which is named after the package or is the `server.dart` of the server templates.
+    fn get_entrypoint(app: &App, pubspec: &DartPubspec) -> String {
+        if !app.includes_file(&format!("bin/{}.dart", pubspec.name))
+            && app.includes_file("bin/server.dart")
+        {
+            return "server".to_string();
+        }
+
+        pubspec.name.clone()
+    }
+
+    /// Flutter web apps are built to static files, which are served with nginx.
+    fn get_flutter_web_plan(app: &App, env: &Environment) -> Result<BuildPlan> {
+        let mut plan = StaticfileProvider::get_serve_plan(app, env, Some(FLUTTER_WEB_OUTPUT_DIR))?;
+        if let Some(setup) = plan.get_phase_mut("setup") {
+            setup.add_nix_pkgs(&[Pkg::new(FLUTTER_PKG_NAME)]);
+        }
+
+        plan.add_phase(DartProvider::get_install_phase(app, "flutter pub get"));
+        plan.add_phase(Phase::build(Some(
+            "flutter build web --release".to_string(),
+        )));
+
+        Ok(plan)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_get_pubspec() -> Result<()> {
@@ -66,4 +127,40 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_flutter_web() -> Result<()> {
+        let app = App::from_files(HashMap::from([
+            (
+                "pubspec.yaml".into(),
+                b"name: flutter_app\nversion: 1.0.0\ndependencies:\n  flutter:\n    sdk: flutter\n"
+                    .to_vec(),
+            ),
+            ("lib/main.dart".into(), b"void main() {}".to_vec()),
+        ]));
+        assert!(DartProvider::get_pubspec(&app)?.is_flutter());
+
+        let plan = DartProvider {}
+            .get_build_plan(&app, &Environment::default())?
+            .unwrap();
+        assert_eq!(
+            plan.get_phase("install").unwrap().cmds,
+            Some(vec!["flutter pub get".to_string()])
+        );
+        assert_eq!(
+            plan.get_phase("build").unwrap().cmds,
+            Some(vec!["flutter build web --release".to_string()])
+        );
+        assert!(plan
+            .get_phase("setup")
+            .unwrap()
+            .nix_pkgs
+            .as_ref()
+            .unwrap()
+            .contains(&"flutter".to_string()));
+        assert!(plan.static_assets.unwrap()["nginx.conf"].contains("root\t  /app/build/web;"));
+        assert!(plan.start_phase.unwrap().cmd.unwrap().contains("nginx -c"));
+
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
     environment::Environment,
@@ -14,15 +14,33 @@ use anyhow::{Context, Result};
 use path_slash::PathBufExt;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct DenoTasks {
-    pub start: Option<String>,
-}
+const DENO_CACHE_DIR: &str = "~/.cache/deno";
 
 #[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct DenoJson {
-    pub tasks: Option<DenoTasks>,
+    /// Tasks are either a command string or, since Deno 2, an object with a `command`.
+    pub tasks: Option<BTreeMap<String, Value>>,
+    /// `true`, `"auto"` or `"manual"` when npm packages are installed into a local `node_modules`.
+    pub node_modules_dir: Option<Value>,
+}
+
+impl DenoJson {
+    pub fn has_task(&self, name: &str) -> bool {
+        self.tasks
+            .as_ref()
+            .is_some_and(|tasks| tasks.contains_key(name))
+    }
+
+    pub fn uses_node_modules_dir(&self) -> bool {
+        match &self.node_modules_dir {
+            Some(Value::Bool(enabled)) => *enabled,
+            Some(Value::String(mode)) => mode == "auto" || mode == "manual",
+            _ => false,
+        }
+    }
 }
 
 pub struct DenoProvider {}
@@ -32,29 +50,50 @@ impl Provider for DenoProvider {
         "deno"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
+    fn language(&self) -> &'static str {
+        "TypeScript"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["deno.json", "deno.jsonc", "**/*.{ts,tsx,js,jsx}"]
+    }
+
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
         let re = Regex::new(
             r##"import .+ from (?:"|'|`)https://deno.land/[^"`']+\.(?:ts|js|tsx|jsx)(?:"|'|`);?"##,
         )
         .unwrap();
-        Ok(app.includes_file("deno.json")
-            || app.includes_file("deno.jsonc")
-            || app.find_match(&re, "**/*.{ts,tsx,js,jsx}")?)
+        let mut detection = Detection::default();
+        let matched = detection.file(app, "deno.json")
+            | detection.file(app, "deno.jsonc")
+            | detection.check(
+                "found a deno.land import",
+                app.find_match(&re, "**/*.{ts,tsx,js,jsx}")?,
+            );
+        Ok(detection.matched(matched))
     }
 
     fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
         let mut plan = BuildPlan::default();
+        let deno_json = DenoProvider::read_deno_json(app)?;
 
         let setup = Phase::setup(Some(vec![Pkg::new("deno")]));
         plan.add_phase(setup);
 
-        if let Some(build_cmd) = DenoProvider::get_build_cmd(app)? {
+        // Install npm packages into the local node_modules, so only the download cache is lost with the cache directory
+        if deno_json.uses_node_modules_dir() {
+            let mut install = Phase::install(Some("deno install".to_string()));
+            install.add_cache_directory(DENO_CACHE_DIR);
+            plan.add_phase(install);
+        }
+
+        if let Some(build_cmd) = DenoProvider::get_build_cmd(app, &deno_json)? {
             let mut build = Phase::build(Some(build_cmd));
             build.depends_on_phase("setup");
             plan.add_phase(build);
         };
 
-        if let Some(start_cmd) = DenoProvider::get_start_cmd(app)? {
+        if let Some(start_cmd) = DenoProvider::get_start_cmd(app, &deno_json)? {
             let start = StartPhase::new(start_cmd);
             plan.set_start_phase(start);
         }
@@ -64,7 +103,21 @@ impl Provider for DenoProvider {
 }
 
 impl DenoProvider {
-    fn get_build_cmd(app: &App) -> Result<Option<String>> {
+    fn read_deno_json(app: &App) -> Result<DenoJson> {
+        if app.includes_file("deno.json") {
+            app.read_json("deno.json")
+        } else if app.includes_file("deno.jsonc") {
+            app.read_json("deno.jsonc")
+        } else {
+            Ok(DenoJson::default())
+        }
+    }
+
+    fn get_build_cmd(app: &App, deno_json: &DenoJson) -> Result<Option<String>> {
+        if deno_json.has_task("build") {
+            return Ok(Some("deno task build".to_string()));
+        }
+
         if let Some(start_file) = DenoProvider::get_start_file(app)? {
             Ok(Some(format!(
                 "deno cache {}",
@@ -77,18 +130,10 @@ impl DenoProvider {
         }
     }
 
-    fn get_start_cmd(app: &App) -> Result<Option<String>> {
-        // First check for a deno.{json,jsonc} and see if we can rip the start command from there
-        if app.includes_file("deno.json") || app.includes_file("deno.jsonc") {
-            let deno_json: DenoJson = app
-                .read_json("deno.json")
-                .or_else(|_| app.read_json("deno.jsonc"))?;
-
-            if let Some(tasks) = deno_json.tasks {
-                if let Some(start) = tasks.start {
-                    return Ok(Some(start));
-                }
-            }
+    fn get_start_cmd(app: &App, deno_json: &DenoJson) -> Result<Option<String>> {
+        // First check for a start task in deno.{json,jsonc}
+        if deno_json.has_task("start") {
+            return Ok(Some("deno task start".to_string()));
         }
 
         // Barring that, just try and start the index file with sane defaults
@@ -106,12 +151,58 @@ impl DenoProvider {
     // Find the first index.{ts,tsx,js,jsx} file to run
     fn get_start_file(app: &App) -> Result<Option<PathBuf>> {
         let matches = app.find_files("**/index.{ts,tsx,js,jsx}")?;
-        let path_to_index = match matches.first() {
-            Some(m) => m,
-            None => return Ok(None),
+        let Some(path_to_index) = matches.first() else {
+            return Ok(None);
         };
 
         let relative_path_to_index = app.strip_source_path(path_to_index)?;
         Ok(Some(relative_path_to_index))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deno_tasks() -> Result<()> {
+        let plan = DenoProvider {}
+            .get_build_plan(&App::new("./examples/deno-tasks")?, &Environment::default())?
+            .unwrap();
+
+        let install = plan.get_phase("install").unwrap();
+        assert_eq!(install.cmds, Some(vec!["deno install".to_string()]));
+
+        assert_eq!(
+            install.cache_directories,
+            Some(vec![DENO_CACHE_DIR.to_string()])
+        );
+
+        // Modules cached during the build are needed at runtime, so the build is not cached
+        let build = plan.get_phase("build").unwrap();
+        assert_eq!(build.cmds, Some(vec!["deno task build".to_string()]));
+        assert_eq!(build.cache_directories, None);
+
+        assert_eq!(
+            plan.start_phase.unwrap().cmd,
+            Some("deno task start".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_node_modules_dir() {
+        let deno_json = |value: Value| DenoJson {
+            node_modules_dir: Some(value),
+            ..Default::default()
+        };
+
+        assert!(deno_json(Value::Bool(true)).uses_node_modules_dir());
+        assert!(deno_json(Value::String("auto".to_string())).uses_node_modules_dir());
+        assert!(deno_json(Value::String("manual".to_string())).uses_node_modules_dir());
+        assert!(!deno_json(Value::String("none".to_string())).uses_node_modules_dir());
+        assert!(!deno_json(Value::Bool(false)).uses_node_modules_dir());
+        assert!(!DenoJson::default().uses_node_modules_dir());
+    }
+}
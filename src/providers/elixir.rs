@@ -11,7 +11,11 @@ use crate::nixpacks::{
 use anyhow::Result;
 use regex::{Match, Regex};
 const DEFAULT_ELIXIR_PKG_NAME: &str = "elixir";
+const DEFAULT_OTP_VERSION: &str = "25";
 const ELIXIR_NIXPKGS_ARCHIVE: &str = "ef99fa5c5ed624460217c31ac4271cfb5cb2502c";
+const DEPS_CACHE_DIR: &str = "deps";
+const BUILD_CACHE_DIR: &str = "_build";
+const RELEASE_DIR: &str = "_release";
 
 pub struct ElixirProvider {}
 
@@ -20,8 +24,12 @@ impl Provider for ElixirProvider {
         "elixir"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("mix.exs"))
+    fn language(&self) -> &'static str {
+        "Elixir"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["mix.exs"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -32,20 +40,35 @@ impl Provider for ElixirProvider {
             "prod".to_string(),
         )]));
 
-        let elixir_pkg = ElixirProvider::get_nix_elixir_package(app, env)?;
-        let mut setup_phase = Phase::setup(Some(vec![elixir_pkg]));
+        // Elixir is built against the same Erlang/OTP release that is installed
+        let erlang_pkg_name = format!("erlangR{}", ElixirProvider::get_otp_version(app)?);
+        let elixir_pkg = ElixirProvider::get_nix_elixir_package(app, env)?
+            .set_override("erlang", &erlang_pkg_name);
+        let mut setup_phase = Phase::setup(Some(vec![elixir_pkg, Pkg::new(&erlang_pkg_name)]));
         setup_phase.set_nix_archive(ELIXIR_NIXPKGS_ARCHIVE.to_string());
         plan.add_phase(setup_phase);
 
+        let mix_exs_content = app.read_file("mix.exs")?;
+        let release = ElixirProvider::get_release_name(&mix_exs_content);
+
         // Install Phase
         let mut install_phase = Phase::install(Some("mix local.hex --force".to_string()));
         install_phase.add_cmd("mix local.rebar --force");
-        install_phase.add_cmd("mix deps.get --only prod");
-        plan.add_phase(install_phase);
 
         // Build Phase
-        let mut build_phase = Phase::build(Some("mix compile".to_string()));
-        let mix_exs_content = app.read_file("mix.exs")?;
+        let mut build_phase = Phase::build(None);
+
+        // Releases are self-contained, so the dependencies and build output are only needed during the build and can be cached
+        if release.is_some() {
+            build_phase.add_cmd("mix deps.get --only prod");
+            build_phase.add_cache_directory(DEPS_CACHE_DIR);
+            build_phase.add_cache_directory(BUILD_CACHE_DIR);
+        } else {
+            install_phase.add_cmd("mix deps.get --only prod");
+        }
+        plan.add_phase(install_phase);
+
+        build_phase.add_cmd("mix compile");
 
         if mix_exs_content.contains("assets.deploy") {
             build_phase.add_cmd("mix assets.deploy".to_string());
@@ -55,10 +78,27 @@ impl Provider for ElixirProvider {
             build_phase.add_cmd("mix ecto.migrate");
             build_phase.add_cmd("mix run priv/repo/seeds.exs");
         }
-        plan.add_phase(build_phase);
 
         // Start Phase
-        let start_phase = StartPhase::new("mix phx.server".to_string());
+        let start_phase = if let Some(release) = release {
+            // The release is moved out of the cached _build directory so that it is kept in the image
+            build_phase.add_cmd(format!(
+                "mix release {release} --overwrite --path {RELEASE_DIR}"
+            ));
+
+            // Phoenix endpoints only start a server in a release when PHX_SERVER is set
+            if mix_exs_content.contains(":phoenix") {
+                plan.add_variables(EnvironmentVariables::from([(
+                    "PHX_SERVER".to_string(),
+                    "true".to_string(),
+                )]));
+            }
+
+            StartPhase::new(format!("{RELEASE_DIR}/bin/{release} start"))
+        } else {
+            StartPhase::new("mix phx.server".to_string())
+        };
+        plan.add_phase(build_phase);
         plan.set_start_phase(start_phase);
 
         Ok(Some(plan))
@@ -66,6 +106,39 @@ impl Provider for ElixirProvider {
 }
 
 impl ElixirProvider {
+    /// The name of the release to build, if the project configures releases in its mix.exs.
+    ///
+    /// This is the first release listed under `releases:`, or the app name if the releases cannot be read.
+    fn get_release_name(mix_exs_content: &str) -> Option<String> {
+        if !mix_exs_content.contains("releases:") {
+            return None;
+        }
+
+        let release_regex = Regex::new(r"releases:\s*\[\s*([a-z_][a-zA-Z0-9_]*):").ok()?;
+        let app_regex = Regex::new(r"app:\s*:([a-z_][a-zA-Z0-9_]*)").ok()?;
+        release_regex
+            .captures(mix_exs_content)
+            .or_else(|| app_regex.captures(mix_exs_content))
+            .map(|c| c[1].to_string())
+    }
+
+    /// The major version of the Erlang/OTP release to install, from the `erlang` entry of an asdf `.tool-versions` file or the OTP release of its `elixir` entry.
+    fn get_otp_version(app: &App) -> Result<String> {
+        let version = if let Some(version) = app.read_tool_version("erlang")? {
+            Some(version)
+        } else {
+            // asdf versions of Elixir include the OTP release they were built for, e.g. 1.14.3-otp-25
+            app.read_tool_version("elixir")?
+                .and_then(|version| version.split_once("-otp-").map(|(_, otp)| otp.to_string()))
+        };
+
+        let major = version
+            .as_deref()
+            .and_then(|version| version.split('.').next())
+            .filter(|major| !major.is_empty() && major.chars().all(|c| c.is_ascii_digit()));
+        Ok(major.unwrap_or(DEFAULT_OTP_VERSION).to_string())
+    }
+
     fn get_nix_elixir_package(app: &App, env: &Environment) -> Result<Pkg> {
         fn as_default(v: Option<Match>) -> &str {
             match v {
@@ -79,11 +152,14 @@ impl ElixirProvider {
 
         let mix_elixir_version_regex = Regex::new(r#"(elixir:[\s].*[> ])([0-9|\.]*)"#)?;
 
-        // If not from env variable, get it from the .elixir-version file then try to parse from mix.exs
+        // If not from env variable, get it from the .elixir-version or .tool-versions file then try to parse from mix.exs
         let custom_version = if custom_version.is_some() {
             custom_version
         } else if custom_version.is_none() && app.includes_file(".elixir-version") {
             Some(app.read_file(".elixir-version")?)
+        } else if let Some(version) = app.read_tool_version("elixir")? {
+            // asdf versions include the OTP release they were built for, e.g. 1.14.3-otp-25
+            version.split('-').next().map(ToString::to_string)
         } else {
             mix_elixir_version_regex
                 .captures(&mix_exs_content)
@@ -123,3 +199,25 @@ impl ElixirProvider {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn otp_version(tool_versions: &str) -> String {
+        let app = App::from_files(HashMap::from([
+            ("mix.exs".into(), b"".to_vec()),
+            (".tool-versions".into(), tool_versions.as_bytes().to_vec()),
+        ]));
+        ElixirProvider::get_otp_version(&app).unwrap()
+    }
+
+    #[test]
+    fn test_otp_version() {
+        assert_eq!(otp_version(""), DEFAULT_OTP_VERSION);
+        assert_eq!(otp_version("elixir 1.14.3"), DEFAULT_OTP_VERSION);
+        assert_eq!(otp_version("elixir 1.14.3-otp-24"), "24");
+        assert_eq!(otp_version("erlang 26.0.2\nelixir 1.15.4-otp-25"), "26");
+    }
+}
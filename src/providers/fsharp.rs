@@ -19,8 +19,12 @@ impl Provider for FSharpProvider {
         "f#"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(!app.find_files("*.fsproj")?.is_empty())
+    fn language(&self) -> &'static str {
+        "F#"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["*.fsproj"]
     }
 
     fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
@@ -10,7 +10,7 @@ use crate::nixpacks::{
     },
 };
 
-use super::Provider;
+use super::{Detection, Provider};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct GleamPackageSpec {
@@ -42,8 +42,16 @@ impl Provider for GleamProvider {
         "gleam"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.has_match("gleam.toml") && app.has_match("manifest.toml"))
+    fn language(&self) -> &'static str {
+        "Gleam"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["gleam.toml", "manifest.toml"]
+    }
+
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        Detection::all(app, self.detect_files())
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
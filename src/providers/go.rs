@@ -16,15 +16,20 @@ const BINARY_NAME: &str = "out";
 const AVAILABLE_GO_VERSIONS: &[(&str, &str)] = &[("1.17", "go"), ("1.18", "go_1_18")];
 const DEFAULT_GO_PKG_NAME: &str = "go";
 
-const GO_BUILD_CACHE_DIR: &str = "/root/.cache/go-build";
+const GO_BUILD_CACHE_DIR: &str = "~/.cache/go-build";
+const GO_MOD_CACHE_DIR: &str = "~/go/pkg/mod";
 
 impl Provider for GolangProvider {
     fn name(&self) -> &str {
         "go"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("main.go") || app.includes_file("go.mod"))
+    fn language(&self) -> &'static str {
+        "Go"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["main.go", "go.mod"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -34,9 +39,15 @@ impl Provider for GolangProvider {
         let nix_pkg = GolangProvider::get_nix_golang_pkg(go_mod.as_ref())?;
         plan.add_phase(Phase::setup(Some(vec![Pkg::new(&nix_pkg)])));
 
+        let build_cache_dir = GolangProvider::get_build_cache_dir(env);
+        let mod_cache_dir = GolangProvider::get_mod_cache_dir(env);
+
         if app.includes_file("go.mod") {
             let mut install = Phase::install(Some("go mod download".to_string()));
-            install.add_cache_directory(GO_BUILD_CACHE_DIR.to_string());
+            install.add_cache_directory(build_cache_dir.clone());
+            if let Some(mod_cache_dir) = &mod_cache_dir {
+                install.add_cache_directory(mod_cache_dir.clone());
+            }
             plan.add_phase(install);
         }
 
@@ -47,7 +58,10 @@ impl Provider for GolangProvider {
         } else {
             Phase::build(None)
         };
-        build.add_cache_directory(GO_BUILD_CACHE_DIR.to_string());
+        build.add_cache_directory(build_cache_dir);
+        if let Some(mod_cache_dir) = mod_cache_dir {
+            build.add_cache_directory(mod_cache_dir);
+        }
         build.depends_on_phase("setup");
         plan.add_phase(build);
 
@@ -74,6 +88,31 @@ impl Provider for GolangProvider {
 }
 
 impl GolangProvider {
+    /// The compiled package cache, `GOCACHE` if it is set.
+    fn get_build_cache_dir(env: &Environment) -> String {
+        env.get_variable("GOCACHE")
+            .map_or(GO_BUILD_CACHE_DIR.to_string(), ToString::to_string)
+    }
+
+    /// The downloaded module cache, `GOMODCACHE` or `$GOPATH/pkg/mod` if set.
+    ///
+    /// There is no module cache when the build uses vendored modules (`GOFLAGS=-mod=vendor`).
+    fn get_mod_cache_dir(env: &Environment) -> Option<String> {
+        let flags = env.get_variable("GOFLAGS").unwrap_or_default();
+        if flags.split_whitespace().any(|flag| flag == "-mod=vendor") {
+            return None;
+        }
+
+        if let Some(dir) = env.get_variable("GOMODCACHE") {
+            return Some(dir.to_string());
+        }
+
+        match env.get_variable("GOPATH") {
+            Some(gopath) => Some(format!("{}/pkg/mod", gopath.trim_end_matches('/'))),
+            None => Some(GO_MOD_CACHE_DIR.to_string()),
+        }
+    }
+
     pub fn read_go_mod_if_exists(&self, app: &App) -> Result<Option<String>> {
         if app.includes_file("go.mod") {
             Ok(Some(app.read_file("go.mod")?))
@@ -147,4 +186,44 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_cache_directories() -> Result<()> {
+        let plan = GolangProvider {}
+            .get_build_plan(&App::new("examples/go-mod")?, &Environment::default())?
+            .unwrap();
+        let build = plan.get_phase("build").unwrap();
+        assert_eq!(
+            build.cache_directories,
+            Some(vec![
+                GO_BUILD_CACHE_DIR.to_string(),
+                GO_MOD_CACHE_DIR.to_string()
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_directories_from_env() -> Result<()> {
+        let env = Environment::from_envs(vec!["GOCACHE=/cache/go", "GOMODCACHE=/cache/mod"])?;
+        let plan = GolangProvider {}
+            .get_build_plan(&App::new("examples/go-mod")?, &env)?
+            .unwrap();
+        assert_eq!(
+            plan.get_phase("install").unwrap().cache_directories,
+            Some(vec!["/cache/go".to_string(), "/cache/mod".to_string()])
+        );
+
+        let env = Environment::from_envs(vec!["GOFLAGS=-mod=vendor"])?;
+        let plan = GolangProvider {}
+            .get_build_plan(&App::new("examples/go-mod")?, &env)?
+            .unwrap();
+        assert_eq!(
+            plan.get_phase("build").unwrap().cache_directories,
+            Some(vec![GO_BUILD_CACHE_DIR.to_string()])
+        );
+
+        Ok(())
+    }
 }
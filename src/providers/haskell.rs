@@ -1,4 +1,4 @@
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
     environment::Environment,
@@ -23,8 +23,16 @@ impl Provider for HaskellStackProvider {
         "haskell"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("package.yaml") && app.has_match("**/*.hs"))
+    fn language(&self) -> &'static str {
+        "Haskell"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["package.yaml", "**/*.hs"]
+    }
+
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        Detection::all(app, self.detect_files())
     }
 
     fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
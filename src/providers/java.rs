@@ -16,21 +16,39 @@ pub struct JavaProvider {}
 const DEFAULT_JDK_VERSION: u32 = 17;
 const DEFAULT_GRADLE_VERSION: u32 = 7;
 
+/// Files that mark a Gradle project, the settings files being used by multi-module projects.
+const GRADLE_BUILD_FILES: &[&str] = &[
+    "build.gradle",
+    "build.gradle.kts",
+    "settings.gradle",
+    "settings.gradle.kts",
+];
+
 impl Provider for JavaProvider {
     fn name(&self) -> &str {
         "java"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("pom.xml")
-            || app.includes_directory("pom.atom")
-            || app.includes_directory("pom.clj")
-            || app.includes_directory("pom.groovy")
-            || app.includes_file("pom.rb")
-            || app.includes_file("pom.scala")
-            || app.includes_file("pom.yaml")
-            || app.includes_file("pom.yml")
-            || app.includes_file("gradlew"))
+    fn language(&self) -> &'static str {
+        "Java"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &[
+            "pom.xml",
+            "pom.atom/",
+            "pom.clj/",
+            "pom.groovy/",
+            "pom.rb",
+            "pom.scala",
+            "pom.yaml",
+            "pom.yml",
+            "gradlew",
+            "build.gradle",
+            "build.gradle.kts",
+            "settings.gradle",
+            "settings.gradle.kts",
+        ]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -46,8 +64,12 @@ impl Provider for JavaProvider {
                 build.add_cmd("chmod +x gradlew");
             }
 
-            build.add_cmd(format!("{gradle_exe} build -x check"));
-            build.add_cache_directory("/root/.gradle");
+            let task = match JavaProvider::get_module(env) {
+                Some(module) => format!(":{}:build", module.replace('/', ":")),
+                None => "build".to_string(),
+            };
+            build.add_cmd(format!("{gradle_exe} {task} -x check"));
+            build.add_cache_directory("~/.gradle/caches");
             build.depends_on_phase("setup");
 
             (setup, build)
@@ -58,15 +80,19 @@ impl Provider for JavaProvider {
             let setup = Phase::setup(Some(vec![jdk_pkg, Pkg::new("maven")]));
 
             let mvn_exe = self.get_maven_exe(app);
-            let mut build = Phase::build(Some(format!("{mvn_exe} -DoutputFile=target/mvn-dependency-list.log -B -DskipTests clean dependency:list install"
+            // Build the module along with the modules it depends on
+            let projects = JavaProvider::get_module(env)
+                .map(|module| format!(" -pl {module} -am"))
+                .unwrap_or_default();
+            let mut build = Phase::build(Some(format!("{mvn_exe}{projects} -DoutputFile=target/mvn-dependency-list.log -B -DskipTests clean dependency:list install"
             )));
-            build.add_cache_directory(".m2/repository");
+            build.add_cache_directory("~/.m2/repository");
             build.depends_on_phase("setup");
 
             (setup, build)
         };
 
-        let start = StartPhase::new(self.get_start_cmd(app)?);
+        let start = StartPhase::new(self.get_start_cmd(app, env)?);
 
         let plan = BuildPlan::new(&vec![setup, build], Some(start));
         Ok(Some(plan))
@@ -93,33 +119,64 @@ impl JavaProvider {
         }
     }
 
-    fn get_start_cmd(&self, app: &App) -> Result<String> {
+    /// The module of a multi-module project to build and run, as a directory relative to the app root.
+    fn get_module(env: &Environment) -> Option<String> {
+        env.get_config_variable("JAVA_MODULE")
+            .map(|module| module.trim_matches('/').to_string())
+            .filter(|module| !module.is_empty())
+    }
+
+    /// The path of `path` within the module being built, if any.
+    fn get_module_path(env: &Environment, path: &str) -> String {
+        match JavaProvider::get_module(env) {
+            Some(module) => format!("{module}/{path}"),
+            None => path.to_string(),
+        }
+    }
+
+    fn get_start_cmd(&self, app: &App, env: &Environment) -> Result<String> {
+        let custom_jar = env.get_config_variable("JAVA_JAR");
+        let jar = |default: &str| {
+            custom_jar
+                .clone()
+                .unwrap_or_else(|| JavaProvider::get_module_path(env, default))
+        };
+
         let cmd = if self.is_using_gradle(app) {
             format!(
-                "java $JAVA_OPTS -jar {} build/libs/*.jar",
-                self.get_gradle_port_config(app)?
+                "java $JAVA_OPTS -jar {} {}",
+                self.get_gradle_port_config(app, env)?,
+                jar("build/libs/*.jar")
             )
         } else if app.includes_file("pom.xml") {
             format!(
-                "java {} $JAVA_OPTS -jar target/*jar",
-                self.get_port_config(app)
+                "java {} $JAVA_OPTS -jar {}",
+                self.get_port_config(app, env),
+                jar("target/*jar")
             )
         } else {
-            "java $JAVA_OPTS -jar target/*jar".to_string()
+            format!("java $JAVA_OPTS -jar {}", jar("target/*jar"))
         };
 
         Ok(cmd)
     }
 
+    /// Gradle is used if the app has a Gradle wrapper, or Gradle build files and no Maven `pom.xml`.
     fn is_using_gradle(&self, app: &App) -> bool {
         app.includes_file("gradlew")
+            || (!app.includes_file("pom.xml")
+                && GRADLE_BUILD_FILES
+                    .iter()
+                    .any(|file| app.includes_file(file)))
     }
 
-    fn get_gradle_port_config(&self, app: &App) -> Result<String> {
-        let file_content = if app.includes_file("build.gradle") {
-            app.read_file("build.gradle")?
-        } else if app.includes_file("build.gradle.kts") {
-            app.read_file("build.gradle.kts")?
+    fn get_gradle_port_config(&self, app: &App, env: &Environment) -> Result<String> {
+        let build_gradle = JavaProvider::get_module_path(env, "build.gradle");
+        let build_gradle_kts = JavaProvider::get_module_path(env, "build.gradle.kts");
+        let file_content = if app.includes_file(&build_gradle) {
+            app.read_file(&build_gradle)?
+        } else if app.includes_file(&build_gradle_kts) {
+            app.read_file(&build_gradle_kts)?
         } else {
             String::new()
         };
@@ -138,8 +195,10 @@ impl JavaProvider {
         Ok(port_arg)
     }
 
-    fn get_port_config(&self, app: &App) -> String {
-        let pom_file = app.read_file("pom.xml").unwrap_or_default();
+    fn get_port_config(&self, app: &App, env: &Environment) -> String {
+        let pom_file = app
+            .read_file(&JavaProvider::get_module_path(env, "pom.xml"))
+            .unwrap_or_default();
         if pom_file.contains("<groupId>org.wildfly.swarm") {
             "-Dswarm.http.port=$PORT".to_string()
         } else if pom_file.contains("<groupId>org.springframework.boot")
@@ -1,4 +1,4 @@
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
     environment::Environment,
@@ -18,13 +18,26 @@ impl Provider for LunaticProvider {
         "lunatic"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        if !app.includes_file("Cargo.toml") {
-            return Ok(false);
+    fn language(&self) -> &'static str {
+        "Rust"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["Cargo.toml", ".cargo/config.toml"]
+    }
+
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        let mut detection = Detection::default();
+        if !detection.file(app, "Cargo.toml") {
+            return Ok(detection);
         }
 
         let re_runner = Regex::new(r##"runner\s*=\s*"lunatic""##).expect("BUG: Broken regex");
-        app.find_match(&re_runner, ".cargo/config.toml")
+        let matched = detection.check(
+            "found the lunatic runner in .cargo/config.toml",
+            app.find_match(&re_runner, ".cargo/config.toml")?,
+        );
+        Ok(detection.matched(matched))
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -36,6 +49,10 @@ impl Provider for LunaticProvider {
 
         Ok(Some(plan))
     }
+
+    fn get_cache_dirs(&self, app: &App, env: &Environment) -> Result<Vec<String>> {
+        RustProvider {}.get_cache_dirs(app, env)
+    }
 }
 
 impl LunaticProvider {
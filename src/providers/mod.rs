@@ -1,6 +1,8 @@
 use crate::nixpacks::{app::App, environment::Environment, plan::BuildPlan};
 use anyhow::Result;
+use serde::Serialize;
 
+pub mod bun;
 pub mod clojure;
 pub mod cobol;
 pub mod crystal;
@@ -27,13 +29,177 @@ pub mod zig;
 
 pub trait Provider: Send + Sync {
     fn name(&self) -> &str;
-    fn detect(&self, _app: &App, _env: &Environment) -> Result<bool> {
-        Ok(false)
+    /// The language of the apps that the provider builds.
+    fn language(&self) -> &'static str;
+    /// Files, directories (with a trailing `/`) and globs that the provider is detected by.
+    fn detect_files(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Whether the provider should build the app, with the checks that decided it.
+    ///
+    /// Unless the provider checks more than the files, it is detected when any of its `detect_files` is found.
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        Detection::any(app, self.detect_files())
     }
     fn get_build_plan(&self, _app: &App, _environment: &Environment) -> Result<Option<BuildPlan>>;
+    /// Default cache directories of the package manager, which are cached in the phase that installs dependencies.
+    fn get_cache_dirs(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+    /// Files of the app whose contents key the incremental cache with `--incremental-cache-checksum`, in addition to the common lockfiles.
+    fn get_cache_inputs(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
     fn metadata(&self, _app: &App, _env: &Environment) -> Result<ProviderMetadata> {
         Ok(ProviderMetadata::default())
     }
+    /// Frameworks, package managers and other languages detected in the app, in addition to the language of the provider.
+    fn app_metadata(&self, _app: &App, _env: &Environment) -> Result<AppMetadata> {
+        Ok(AppMetadata::default())
+    }
+}
+
+/// What was detected about an app, such as its languages and frameworks, for tools that build on nixpacks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppMetadata {
+    pub providers: Vec<String>,
+    pub languages: Vec<String>,
+    pub frameworks: Vec<String>,
+    pub package_managers: Vec<String>,
+}
+
+impl AppMetadata {
+    /// Add the values of another metadata that are not already included, keeping the order they were found in.
+    pub fn extend(&mut self, other: AppMetadata) {
+        fn extend_unique(values: &mut Vec<String>, other: Vec<String>) {
+            for value in other {
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+        }
+
+        extend_unique(&mut self.providers, other.providers);
+        extend_unique(&mut self.languages, other.languages);
+        extend_unique(&mut self.frameworks, other.frameworks);
+        extend_unique(&mut self.package_managers, other.package_managers);
+    }
+}
+
+/// A check that a provider made while detecting an app, such as whether a file exists.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DetectCheck {
+    pub description: String,
+    pub passed: bool,
+}
+
+/// Whether a provider matched an app, and the checks it was decided by.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Detection {
+    pub matched: bool,
+    pub checks: Vec<DetectCheck>,
+}
+
+impl Detection {
+    /// Record a check, returning whether it passed so that checks can be combined.
+    ///
+    /// Combine checks with `|` rather than `||`, so that every check is recorded.
+    pub fn check<S: Into<String>>(&mut self, description: S, passed: bool) -> bool {
+        self.checks.push(DetectCheck {
+            description: description.into(),
+            passed,
+        });
+        passed
+    }
+
+    pub fn file(&mut self, app: &App, name: &str) -> bool {
+        self.check(format!("found {name}"), app.includes_file(name))
+    }
+
+    pub fn directory(&mut self, app: &App, name: &str) -> bool {
+        self.check(format!("found {name}/"), app.includes_directory(name))
+    }
+
+    pub fn glob(&mut self, app: &App, pattern: &str) -> bool {
+        self.check(format!("found {pattern}"), app.has_match(pattern))
+    }
+
+    /// Check for a file, a directory if the name ends with `/`, or a file matching a glob.
+    pub fn path(&mut self, app: &App, name: &str) -> Result<bool> {
+        if let Some(dir) = name.strip_suffix('/') {
+            Ok(self.directory(app, dir))
+        } else if name.contains(['*', '?', '[', '{']) {
+            let found = !app.find_files(name)?.is_empty();
+            Ok(self.check(format!("found {name}"), found))
+        } else {
+            Ok(self.file(app, name))
+        }
+    }
+
+    /// Matches when any of the paths is found.
+    pub fn any(app: &App, names: &[&str]) -> Result<Detection> {
+        let mut detection = Detection::default();
+        let mut matched = false;
+        for name in names {
+            matched |= detection.path(app, name)?;
+        }
+        Ok(detection.matched(matched))
+    }
+
+    /// Matches when all of the paths are found.
+    pub fn all(app: &App, names: &[&str]) -> Result<Detection> {
+        let mut detection = Detection::default();
+        let mut matched = !names.is_empty();
+        for name in names {
+            matched &= detection.path(app, name)?;
+        }
+        Ok(detection.matched(matched))
+    }
+
+    #[must_use]
+    pub fn matched(mut self, matched: bool) -> Detection {
+        self.matched = matched;
+        self
+    }
+
+    /// Describe the checks, e.g. `found package.json ✓, found bun.lockb ✗`.
+    pub fn describe(&self) -> String {
+        if self.checks.is_empty() {
+            return "no checks".to_string();
+        }
+
+        self.checks
+            .iter()
+            .map(|check| {
+                format!(
+                    "{} {}",
+                    check.description,
+                    if check.passed { "✓" } else { "✗" }
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Describes a provider, for documentation and for explaining why an app was not detected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderInfo {
+    pub name: &'static str,
+    pub language: &'static str,
+    pub detect_files: &'static [&'static str],
+}
+
+impl From<&'static dyn Provider> for ProviderInfo {
+    fn from(provider: &'static dyn Provider) -> ProviderInfo {
+        ProviderInfo {
+            name: provider.name(),
+            language: provider.language(),
+            detect_files: provider.detect_files(),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -66,6 +232,26 @@ impl ProviderMetadata {
     }
 }
 
+#[test]
+fn test_app_metadata_extend() {
+    let mut metadata = AppMetadata {
+        providers: vec!["node".to_string()],
+        languages: vec!["JavaScript".to_string()],
+        frameworks: vec!["Next.js".to_string()],
+        package_managers: vec!["pnpm".to_string()],
+    };
+    metadata.extend(AppMetadata {
+        providers: vec!["python".to_string()],
+        languages: vec!["Python".to_string(), "JavaScript".to_string()],
+        ..AppMetadata::default()
+    });
+
+    assert_eq!(metadata.providers, vec!["node", "python"]);
+    assert_eq!(metadata.languages, vec!["JavaScript", "Python"]);
+    assert_eq!(metadata.frameworks, vec!["Next.js"]);
+    assert_eq!(metadata.package_managers, vec!["pnpm"]);
+}
+
 #[test]
 fn test_join_as_comma_separated() {
     let metadata = ProviderMetadata::from(vec![
@@ -1,5 +1,9 @@
-use self::{nx::Nx, turborepo::Turborepo};
-use super::Provider;
+use self::{
+    nx::{Nx, NX_CACHE_DIR},
+    pnpm_workspace::PnpmWorkspace,
+    turborepo::Turborepo,
+};
+use super::{AppMetadata, Provider};
 use crate::nixpacks::plan::merge::Mergeable;
 use crate::nixpacks::{
     app::App,
@@ -18,6 +22,7 @@ use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 
 mod nx;
+mod pnpm_workspace;
 mod turborepo;
 
 pub const NODE_OVERLAY: &str = "https://github.com/railwayapp/nix-npm-overlay/archive/main.tar.gz";
@@ -25,13 +30,58 @@ pub const NODE_OVERLAY: &str = "https://github.com/railwayapp/nix-npm-overlay/ar
 const DEFAULT_NODE_VERSION: u32 = 16;
 const AVAILABLE_NODE_VERSIONS: &[u32] = &[14, 16, 18];
 
+/// Major versions of the LTS release lines, by the codename used in nvm aliases like `lts/hydrogen`.
+const NODE_LTS_CODENAMES: &[(&str, u32)] = &[
+    ("argon", 4),
+    ("boron", 6),
+    ("carbon", 8),
+    ("dubnium", 10),
+    ("erbium", 12),
+    ("fermium", 14),
+    ("gallium", 16),
+    ("hydrogen", 18),
+    ("iron", 20),
+    ("jod", 22),
+];
+
 const YARN_CACHE_DIR: &str = "/usr/local/share/.cache/yarn/v6";
 const PNPM_CACHE_DIR: &str = "/root/.local/share/pnpm/store/v3";
 const NPM_CACHE_DIR: &str = "/root/.npm";
-const BUN_CACHE_DIR: &str = "/root/.bun";
+
+/// Package manager manifests, lockfiles, and config files that installing dependencies can read.
+const INSTALL_FILES: &[&str] = &[
+    "package.json",
+    "package-lock.json",
+    "npm-shrinkwrap.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    ".npmrc",
+    ".yarnrc",
+    ".yarnrc.yml",
+    ".yarn",
+    ".pnpmfile.cjs",
+];
+
+/// Scripts that package managers run during an install, which can read any file of the app.
+const INSTALL_LIFECYCLE_SCRIPTS: &[&str] = &["preinstall", "install", "postinstall", "prepare"];
 const CYPRESS_CACHE_DIR: &str = "/root/.cache/Cypress";
 const NODE_MODULES_CACHE_DIR: &str = "node_modules/.cache";
 
+/// Frameworks that are detected by a dependency of any package in the app.
+const FRAMEWORK_DEPENDENCIES: &[(&str, &str)] = &[
+    ("next", "Next.js"),
+    ("nuxt", "Nuxt"),
+    ("@remix-run/react", "Remix"),
+    ("@sveltejs/kit", "SvelteKit"),
+    ("astro", "Astro"),
+    ("gatsby", "Gatsby"),
+    ("@angular/core", "Angular"),
+    ("@nestjs/core", "NestJS"),
+    ("express", "Express"),
+    ("fastify", "Fastify"),
+    ("vite", "Vite"),
+];
+
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 struct TsConfigJson {
     #[serde(rename = "compilerOptions")]
@@ -102,8 +152,12 @@ impl Provider for NodeProvider {
         "node"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("package.json"))
+    fn language(&self) -> &'static str {
+        "JavaScript"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["package.json"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -134,23 +188,30 @@ impl Provider for NodeProvider {
         }
 
         // Install
+        let install_cmd = if PnpmWorkspace::is_pnpm_workspace(app, env)? {
+            PnpmWorkspace::get_install_cmd(app, env)?
+        } else {
+            NodeProvider::get_install_command(app)
+        };
         let corepack = NodeProvider::uses_corepack(app, env)?;
         let mut install = Phase::install(if corepack {
             Some("npm install -g corepack && corepack enable".to_string())
         } else {
-            NodeProvider::get_install_command(app)
+            install_cmd.clone()
         });
 
         if corepack {
-            let install_cmd = NodeProvider::get_install_command(app);
-
-            if install_cmd.is_some() {
-                install.add_cmd(install_cmd.unwrap_or_default());
+            if let Some(install_cmd) = install_cmd {
+                install.add_cmd(install_cmd);
             }
         }
 
-        install.add_cache_directory(NodeProvider::get_package_manager_cache_dir(app));
-        install.add_path("/app/node_modules/.bin".to_string());
+        install.add_path(app.app_path("node_modules/.bin"));
+
+        // Only copy the files needed to install, so that the install is cached until the dependencies change
+        for file in NodeProvider::get_install_files(app, env) {
+            install.add_file_dependency(file);
+        }
 
         // Cypress cache directory
         let all_deps = NodeProvider::get_all_deps(app)?;
@@ -172,6 +233,11 @@ impl Provider for NodeProvider {
             });
         }
 
+        // Nx computation cache directory
+        if Nx::is_nx_monorepo(app, env) {
+            build.add_cache_directory(NX_CACHE_DIR.to_string());
+        }
+
         // Node modules cache directory
         build.add_cache_directory((*NODE_MODULES_CACHE_DIR).to_string());
         let package_json: PackageJson = app.read_json("package.json").unwrap_or_default();
@@ -191,6 +257,31 @@ impl Provider for NodeProvider {
 
         Ok(Some(plan))
     }
+
+    fn get_cache_dirs(&self, app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(vec![NodeProvider::get_package_manager_cache_dir(app)])
+    }
+
+    fn app_metadata(&self, app: &App, _env: &Environment) -> Result<AppMetadata> {
+        let all_deps = NodeProvider::get_all_deps(app)?;
+        let frameworks = FRAMEWORK_DEPENDENCIES
+            .iter()
+            .filter(|(dep, _)| all_deps.contains(*dep))
+            .map(|(_, framework)| (*framework).to_string())
+            .collect();
+
+        let mut languages = Vec::new();
+        if app.includes_file("tsconfig.json") || all_deps.contains("typescript") {
+            languages.push("TypeScript".to_string());
+        }
+
+        Ok(AppMetadata {
+            languages,
+            frameworks,
+            package_managers: vec![NodeProvider::get_package_manager(app)],
+            ..AppMetadata::default()
+        })
+    }
 }
 
 impl NodeProvider {
@@ -236,7 +327,7 @@ impl NodeProvider {
 
     pub fn get_build_cmd(app: &App, env: &Environment) -> Result<Option<String>> {
         if Nx::is_nx_monorepo(app, env) {
-            if let Some(nx_build_cmd) = Nx::get_nx_build_cmd(app, env) {
+            if let Some(nx_build_cmd) = Nx::get_nx_build_cmd(app, env)? {
                 return Ok(Some(nx_build_cmd));
             }
         }
@@ -247,6 +338,10 @@ impl NodeProvider {
             }
         }
 
+        if PnpmWorkspace::is_pnpm_workspace(app, env)? {
+            return PnpmWorkspace::get_build_cmd(app, env);
+        }
+
         if NodeProvider::has_script(app, "build")? {
             let pkg_manager = NodeProvider::get_package_manager(app);
             Ok(Some(format!("{pkg_manager} run build")))
@@ -256,7 +351,6 @@ impl NodeProvider {
     }
 
     pub fn get_start_cmd(app: &App, env: &Environment) -> Result<Option<String>> {
-        let executor = NodeProvider::get_executor(app);
         let package_json: PackageJson = app.read_json("package.json").unwrap_or_default();
 
         if Nx::is_nx_monorepo(app, env) {
@@ -272,6 +366,10 @@ impl NodeProvider {
             }
         }
 
+        if PnpmWorkspace::is_pnpm_workspace(app, env)? {
+            return PnpmWorkspace::get_start_cmd(app, env);
+        }
+
         let package_manager = NodeProvider::get_package_manager(app);
         if NodeProvider::has_script(app, "start")? {
             return Ok(Some(format!("{package_manager} run start")));
@@ -279,14 +377,12 @@ impl NodeProvider {
 
         if let Some(main) = package_json.main {
             if app.includes_file(&main) {
-                return Ok(Some(format!("{executor} {main}")));
+                return Ok(Some(format!("node {main}")));
             }
         }
 
         if app.includes_file("index.js") {
-            return Ok(Some(format!("{executor} index.js")));
-        } else if app.includes_file("index.ts") && package_manager == "bun" {
-            return Ok(Some("bun index.ts".to_string()));
+            return Ok(Some("node index.js".to_string()));
         }
 
         Ok(None)
@@ -307,17 +403,18 @@ impl NodeProvider {
             .and_then(|engines| engines.get("node").cloned());
 
         let nvmrc_node_version = if app.includes_file(".nvmrc") {
-            let nvmrc = app.read_file(".nvmrc")?;
-            Some(nvmrc.trim().replace('v', ""))
+            parse_nvmrc(&app.read_file(".nvmrc")?)
         } else {
             None
         };
 
-        let node_version = env_node_version.or(pkg_node_version).or(nvmrc_node_version);
+        let node_version = env_node_version
+            .or(pkg_node_version)
+            .or(nvmrc_node_version)
+            .or(app.read_tool_version("nodejs")?);
 
-        let node_version = match node_version {
-            Some(node_version) => node_version,
-            None => return Ok(Pkg::new(default_node_pkg_name.as_str())),
+        let Some(node_version) = node_version else {
+            return Ok(Pkg::new(default_node_pkg_name.as_str()));
         };
 
         // Any version will work, use latest
@@ -335,8 +432,6 @@ impl NodeProvider {
             pkg_manager = "pnpm";
         } else if app.includes_file("yarn.lock") {
             pkg_manager = "yarn";
-        } else if app.includes_file("bun.lockb") {
-            pkg_manager = "bun";
         }
         pkg_manager.to_string()
     }
@@ -351,6 +446,50 @@ impl NodeProvider {
         .to_string()
     }
 
+    /// The files to copy before installing dependencies, or nothing if the install might need the whole app.
+    ///
+    /// Workspaces, local dependencies, and install scripts can read other files, so the whole app is copied for them.
+    fn get_install_files(app: &App, env: &Environment) -> Vec<String> {
+        let package_json: PackageJson = app.read_json("package.json").unwrap_or_default();
+
+        let has_install_scripts = package_json.scripts.as_ref().is_some_and(|scripts| {
+            INSTALL_LIFECYCLE_SCRIPTS
+                .iter()
+                .any(|script| scripts.contains_key(*script))
+        });
+        let has_local_deps = [&package_json.dependencies, &package_json.dev_dependencies]
+            .iter()
+            .filter_map(|deps| deps.as_ref())
+            .flat_map(HashMap::values)
+            .any(|version| {
+                ["file:", "link:", "portal:", "patch:", "workspace:"]
+                    .iter()
+                    .any(|protocol| version.starts_with(protocol))
+            });
+        // The Prisma client is generated from the schema of the app when it is installed
+        let uses_prisma = NodeProvider::uses_node_dependency(app, "prisma");
+        if has_install_scripts
+            || has_local_deps
+            || uses_prisma
+            || package_json.workspaces.is_some()
+            || app.includes_file("pnpm-workspace.yaml")
+            || Nx::is_nx_monorepo(app, env)
+            || Turborepo::is_turborepo(app)
+            // Custom install commands can need any file
+            || env.get_config_variable("INSTALL_CMD").is_some()
+            // Files left out of the Docker build context cannot be copied, which only Docker can tell
+            || app.includes_file(".dockerignore")
+        {
+            return Vec::new();
+        }
+
+        INSTALL_FILES
+            .iter()
+            .filter(|file| app.includes_file(file) || app.includes_directory(file))
+            .map(ToString::to_string)
+            .collect()
+    }
+
     pub fn get_install_command(app: &App) -> Option<String> {
         if !app.includes_file("package.json") {
             return None;
@@ -370,8 +509,6 @@ impl NodeProvider {
             }
         } else if app.includes_file("package-lock.json") {
             install_cmd = "npm ci".to_string();
-        } else if app.includes_file("bun.lockb") {
-            install_cmd = "bun i --no-save".to_string();
         }
 
         Some(install_cmd)
@@ -383,23 +520,11 @@ impl NodeProvider {
             (*YARN_CACHE_DIR).to_string()
         } else if package_manager == "pnpm" {
             (*PNPM_CACHE_DIR).to_string()
-        } else if package_manager == "bun" {
-            (*BUN_CACHE_DIR).to_string()
         } else {
             (*NPM_CACHE_DIR).to_string()
         }
     }
 
-    fn get_executor(app: &App) -> String {
-        let package_manager = NodeProvider::get_package_manager(app);
-        if package_manager == *"bun" {
-            "bun"
-        } else {
-            "node"
-        }
-        .to_string()
-    }
-
     /// Returns the nodejs nix package and the appropriate package manager nix image.
     pub fn get_nix_packages(app: &App, env: &Environment) -> Result<Vec<Pkg>> {
         let package_json: PackageJson = if app.includes_file("package.json") {
@@ -413,9 +538,7 @@ impl NodeProvider {
         let mut pkgs = Vec::<Pkg>::new();
 
         let package_manager = NodeProvider::get_package_manager(app);
-        if package_manager != "bun" {
-            pkgs.push(node_pkg);
-        }
+        pkgs.push(node_pkg);
         if package_manager == "pnpm" {
             let lockfile = app.read_file("pnpm-lock.yaml").unwrap_or_default();
             if lockfile.starts_with("lockfileVersion: 5.3") {
@@ -430,8 +553,6 @@ impl NodeProvider {
             }
         } else if package_manager == "yarn" {
             pm_pkg = Pkg::new("yarn-1_x");
-        } else if package_manager == "bun" {
-            pm_pkg = Pkg::new("bun");
         } else {
             // npm
             let lockfile = app.read_file("package-lock.json").unwrap_or_default();
@@ -578,6 +699,42 @@ fn version_number_to_pkg(version: u32) -> String {
     }
 }
 
+/// The version in an `.nvmrc` file, such as `18`, `v18.17.0` or `lts/hydrogen`, with nvm aliases resolved to a major version.
+fn parse_nvmrc(contents: &str) -> Option<String> {
+    let version = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))?
+        .to_lowercase();
+
+    // The newest LTS line and the latest release both use the newest available version
+    let newest_version = || {
+        AVAILABLE_NODE_VERSIONS
+            .iter()
+            .max()
+            .map(ToString::to_string)
+    };
+    if matches!(
+        version.as_str(),
+        "lts/*" | "node" | "stable" | "latest" | "current"
+    ) {
+        return newest_version();
+    }
+
+    if let Some(codename) = version.strip_prefix("lts/") {
+        let major = NODE_LTS_CODENAMES
+            .iter()
+            .find(|(name, _)| *name == codename)
+            .map(|(_, major)| major.to_string());
+        if major.is_none() {
+            println!("Warning: unknown Node LTS alias {version} in .nvmrc, using the default node version");
+        }
+        return major;
+    }
+
+    Some(version.trim_start_matches('v').to_string())
+}
+
 fn parse_node_version_into_pkg(node_version: &str) -> String {
     let default_node_pkg_name = version_number_to_pkg(DEFAULT_NODE_VERSION);
     let range: Range = node_version.parse().unwrap_or_else(|_| {
@@ -893,6 +1050,41 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_version_from_nvmrc_lts_alias() -> Result<()> {
+        assert_eq!(
+            NodeProvider::get_nix_node_pkg(
+                &PackageJson {
+                    name: Some(String::default()),
+                    ..Default::default()
+                },
+                &App::new("examples/node-nvmrc-lts")?,
+                &Environment::default()
+            )?,
+            Pkg::new("nodejs-18_x")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_nvmrc() {
+        assert_eq!(parse_nvmrc("18\n"), Some("18".to_string()));
+        assert_eq!(parse_nvmrc("18.17.0"), Some("18.17.0".to_string()));
+        assert_eq!(parse_nvmrc("v14.18.1\n"), Some("14.18.1".to_string()));
+        assert_eq!(parse_nvmrc("lts/hydrogen"), Some("18".to_string()));
+        assert_eq!(parse_nvmrc("lts/Gallium\n"), Some("16".to_string()));
+        assert_eq!(parse_nvmrc("lts/*"), Some("18".to_string()));
+        assert_eq!(
+            parse_nvmrc("# pinned\n\nlts/fermium"),
+            Some("14".to_string())
+        );
+        assert_eq!(parse_nvmrc("lts/unknown"), None);
+        assert_eq!(parse_nvmrc(""), None);
+
+        assert_eq!(parse_node_version_into_pkg("18.17.0"), "nodejs-18_x");
+    }
+
     #[test]
     fn test_engine_invalid_version() -> Result<()> {
         // this test now defaults to lts
@@ -913,6 +1105,29 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_cache_dirs() -> Result<()> {
+        let get_cache_dirs = |path: &str| {
+            NodeProvider::default()
+                .get_cache_dirs(&App::new(path).unwrap(), &Environment::default())
+        };
+
+        assert_eq!(
+            get_cache_dirs("examples/node")?,
+            vec![NPM_CACHE_DIR.to_string()]
+        );
+        assert_eq!(
+            get_cache_dirs("examples/node-yarn")?,
+            vec![YARN_CACHE_DIR.to_string()]
+        );
+        assert_eq!(
+            get_cache_dirs("examples/node-pnpm")?,
+            vec![PNPM_CACHE_DIR.to_string()]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_find_next_packages() -> Result<()> {
         assert_eq!(
@@ -1,8 +1,8 @@
 // Code relating to NX Monorepos
 
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -22,8 +22,9 @@ pub struct ProjectJson {
 
 #[derive(Debug, Serialize, PartialEq, Eq, Deserialize)]
 pub struct Targets {
-    pub build: Target,
+    pub build: Option<Target>,
     pub start: Option<Target>,
+    pub serve: Option<Target>,
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq, Deserialize)]
@@ -45,16 +46,28 @@ pub struct Configuration {
     pub production: Option<Value>,
 }
 
+#[derive(Debug, Serialize, PartialEq, Eq, Deserialize)]
+pub struct WorkspaceJson {
+    pub projects: Option<BTreeMap<String, Value>>,
+}
+
 pub struct Nx {}
 
 const NX_APP_NAME_ENV_VAR: &str = "NX_APP_NAME";
 
+/// The computation cache of Nx, which makes unchanged tasks a cache hit in later builds.
+pub const NX_CACHE_DIR: &str = ".nx/cache";
+
+/// Directories that projects are conventionally placed in when they are not listed in `workspace.json`.
+const NX_PROJECT_DIRS: &[&str] = &["apps", "packages", "libs"];
+
 impl Nx {
     pub fn is_nx_monorepo(app: &App, env: &Environment) -> bool {
-        // Only consider an Nx app if an nx app name and project path can be found
+        // Only consider an Nx app if an nx app name and project path can be found, a project.json that cannot be parsed fails the build
         if let Some(nx_app_name) = Nx::get_nx_app_name(app, env) {
             return app.includes_file("nx.json")
-                && Nx::get_nx_project_json_for_app(app, &nx_app_name).is_ok();
+                && Nx::get_nx_project_root(app, &nx_app_name)
+                    .is_ok_and(|root| app.includes_file(&format!("{root}/project.json")));
         }
 
         false
@@ -72,14 +85,46 @@ impl Nx {
         None
     }
 
+    /// Find the root directory of a project, from `workspace.json` or the conventional project directories.
+    pub fn get_nx_project_root(app: &App, nx_app_name: &String) -> Result<String> {
+        if let Ok(workspace_json) = app.read_json::<WorkspaceJson>("workspace.json") {
+            if let Some(project) = workspace_json
+                .projects
+                .and_then(|projects| projects.get(nx_app_name).cloned())
+            {
+                // Projects are either a path, or an object with a `root`
+                let root = match project {
+                    Value::String(root) => Some(root),
+                    Value::Object(project) => project
+                        .get("root")
+                        .and_then(Value::as_str)
+                        .map(ToString::to_string),
+                    _ => None,
+                };
+                if let Some(root) = root {
+                    return Ok(root
+                        .trim_start_matches("./")
+                        .trim_end_matches('/')
+                        .to_string());
+                }
+            }
+        }
+
+        NX_PROJECT_DIRS
+            .iter()
+            .map(|dir| format!("{dir}/{nx_app_name}"))
+            .find(|root| app.includes_file(&format!("{root}/project.json")))
+            .ok_or_else(|| anyhow!("Could not find the Nx project {nx_app_name}"))
+    }
+
     pub fn get_nx_project_json_for_app(app: &App, nx_app_name: &String) -> Result<ProjectJson> {
-        let project_path = format!("./apps/{nx_app_name}/project.json");
-        app.read_json::<ProjectJson>(&project_path)
+        let project_root = Nx::get_nx_project_root(app, nx_app_name)?;
+        app.read_json::<ProjectJson>(&format!("./{project_root}/project.json"))
     }
 
     pub fn get_nx_output_path(app: &App, nx_app_name: &String) -> Result<String> {
         let project_json = Nx::get_nx_project_json_for_app(app, nx_app_name)?;
-        if let Some(options) = project_json.targets.build.options {
+        if let Some(options) = project_json.targets.build.and_then(|build| build.options) {
             if let Some(output_path) = options.output_path {
                 if let Some(the_output_path) = output_path.as_str() {
                     return Ok(the_output_path.to_string());
@@ -87,16 +132,41 @@ impl Nx {
             }
         }
 
-        Ok(format!("dist/apps/{nx_app_name}"))
+        Ok(format!(
+            "dist/{}",
+            Nx::get_nx_project_root(app, nx_app_name)?
+        ))
     }
 
-    pub fn get_nx_build_cmd(app: &App, env: &Environment) -> Option<String> {
-        Nx::get_nx_app_name(app, env).map(|nx_app_name| {
-            format!(
-                "{} nx run {nx_app_name}:build:production",
-                NodeProvider::get_package_manager_dlx_command(app)
-            )
-        })
+    pub fn get_nx_build_cmd(app: &App, env: &Environment) -> Result<Option<String>> {
+        let Some(nx_app_name) = Nx::get_nx_app_name(app, env) else {
+            return Ok(None);
+        };
+        let project_json = Nx::get_nx_project_json_for_app(app, &nx_app_name)?;
+
+        Ok(project_json
+            .targets
+            .build
+            .map(|build| Nx::get_nx_run_cmd(app, &nx_app_name, "build", &build)))
+    }
+
+    /// Run a target of a project through Nx, with its production configuration if it has one.
+    fn get_nx_run_cmd(
+        app: &App,
+        nx_app_name: &String,
+        target_name: &str,
+        target: &Target,
+    ) -> String {
+        let has_production = target
+            .configurations
+            .as_ref()
+            .is_some_and(|configurations| configurations.production.is_some());
+        let configuration = if has_production { ":production" } else { "" };
+
+        format!(
+            "{} nx run {nx_app_name}:{target_name}{configuration}",
+            NodeProvider::get_package_manager_dlx_command(app)
+        )
     }
 
     pub fn get_nx_start_cmd(app: &App, env: &Environment) -> Result<Option<String>> {
@@ -109,37 +179,60 @@ impl Nx {
             let project_json = Nx::get_nx_project_json_for_app(app, &nx_app_name)?;
 
             if let Some(start_target) = project_json.targets.start {
-                if let Some(configurations) = start_target.configurations {
-                    if configurations.production.is_some() {
-                        return Ok(Some(format!(
-                            "{} nx run {nx_app_name}:start:production",
-                            NodeProvider::get_package_manager_dlx_command(app)
-                        )));
-                    }
-                }
-                return Ok(Some(format!(
-                    "{} nx run {nx_app_name}:start",
-                    NodeProvider::get_package_manager_dlx_command(app)
+                return Ok(Some(Nx::get_nx_run_cmd(
+                    app,
+                    &nx_app_name,
+                    "start",
+                    &start_target,
                 )));
             }
 
-            if project_json.targets.build.executor == "@nx/next:build"
-                || project_json.targets.build.executor == "@nrwl/next:build"
-            {
-                return Ok(Some(format!("cd {output_path} && npm run start")));
-            }
+            if let Some(build_target) = project_json.targets.build {
+                if build_target.executor == "@nx/next:build"
+                    || build_target.executor == "@nrwl/next:build"
+                {
+                    return Ok(Some(format!("cd {output_path} && npm run start")));
+                }
 
-            if let Some(options) = project_json.targets.build.options {
-                if let Some(main_path) = options.main {
+                if let Some(main_path) = build_target.options.and_then(|options| options.main) {
                     let current_path = PathBuf::from(main_path);
                     let file_name = current_path.file_stem().unwrap().to_str().unwrap();
 
                     return Ok(Some(format!("node {output_path}/{file_name}.js")));
                 }
             }
+
+            if let Some(serve_target) = project_json.targets.serve {
+                return Ok(Some(Nx::get_nx_run_cmd(
+                    app,
+                    &nx_app_name,
+                    "serve",
+                    &serve_target,
+                )));
+            }
+
             return Ok(Some(format!("node {output_path}/index.js")));
         }
 
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_invalid_project_json_fails() {
+        let app = App::from_files(HashMap::from([
+            ("package.json".into(), b"{}".to_vec()),
+            ("nx.json".into(), br#"{ "defaultProject": "web" }"#.to_vec()),
+            ("apps/web/project.json".into(), b"{ \"targets\": ".to_vec()),
+        ]));
+        let env = Environment::default();
+
+        assert!(Nx::is_nx_monorepo(&app, &env));
+        assert!(Nx::get_nx_build_cmd(&app, &env).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+// Code relating to pnpm workspaces
+
+use anyhow::{bail, Result};
+use path_slash::PathBufExt;
+
+use crate::nixpacks::{app::App, environment::Environment};
+
+use super::{turborepo::pnpm_workspaces, PackageJson};
+
+const PNPM_WORKSPACE_PACKAGE_ENV_VAR: &str = "PNPM_WORKSPACE_PACKAGE";
+
+pub struct PnpmWorkspace {}
+
+impl PnpmWorkspace {
+    /// Only consider a pnpm workspace if a target package is specified, which fails when the package cannot be found.
+    pub fn is_pnpm_workspace(app: &App, env: &Environment) -> Result<bool> {
+        let Some(name) = PnpmWorkspace::get_package_name(env) else {
+            return Ok(false);
+        };
+        if !app.includes_file("pnpm-workspace.yaml") {
+            return Ok(false);
+        }
+
+        if PnpmWorkspace::find_package(app, &name)?.is_none() {
+            bail!("Could not find the pnpm workspace package {name}");
+        }
+
+        Ok(true)
+    }
+
+    pub fn get_package_name(env: &Environment) -> Option<String> {
+        env.get_config_variable(PNPM_WORKSPACE_PACKAGE_ENV_VAR)
+    }
+
+    /// Find the package.json of a workspace package by its name or directory, with the `--filter` that selects it.
+    ///
+    /// pnpm takes a filter without a leading `./` as the name of a package, so a directory is always given with one.
+    pub fn find_package(app: &App, name: &str) -> Result<Option<(String, PackageJson)>> {
+        let target_dir = name.trim_start_matches("./").trim_end_matches('/');
+
+        for glob in pnpm_workspaces(app)? {
+            for dir in app.find_directories(&glob)? {
+                let dir = app.strip_source_path(&dir)?.to_slash_lossy().to_string();
+                let package_json_path = format!("{dir}/package.json");
+                if !app.includes_file(&package_json_path) {
+                    continue;
+                }
+
+                let package_json: PackageJson = app.read_json(&package_json_path)?;
+                if package_json.name.as_deref() == Some(name) {
+                    return Ok(Some((name.to_string(), package_json)));
+                }
+                if dir == target_dir {
+                    return Ok(Some((format!("./{dir}"), package_json)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Install the target package and the workspace packages it depends on.
+    pub fn get_install_cmd(app: &App, env: &Environment) -> Result<Option<String>> {
+        let Some(name) = PnpmWorkspace::get_package_name(env) else {
+            return Ok(None);
+        };
+
+        Ok(PnpmWorkspace::find_package(app, &name)?
+            .map(|(filter, _)| format!("pnpm i --frozen-lockfile --filter {filter}...")))
+    }
+
+    pub fn get_build_cmd(app: &App, env: &Environment) -> Result<Option<String>> {
+        PnpmWorkspace::get_script_cmd(app, env, "build")
+    }
+
+    pub fn get_start_cmd(app: &App, env: &Environment) -> Result<Option<String>> {
+        PnpmWorkspace::get_script_cmd(app, env, "start")
+    }
+
+    fn get_script_cmd(app: &App, env: &Environment, script: &str) -> Result<Option<String>> {
+        if let Some(name) = PnpmWorkspace::get_package_name(env) {
+            if let Some((filter, package_json)) = PnpmWorkspace::find_package(app, &name)? {
+                let has_script = package_json
+                    .scripts
+                    .is_some_and(|scripts| scripts.contains_key(script));
+                if has_script {
+                    return Ok(Some(format!("pnpm --filter {filter} run {script}")));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -13,10 +13,25 @@ use crate::nixpacks::{
 };
 
 use super::{node::NodeProvider, Provider};
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 const DEFAULT_PHP_VERSION: &str = "8.2";
 
+const COMPOSER_CACHE_DIR: &str = "/root/.composer/cache";
+
+/// Extensions that are compiled into PHP, and so are not available as Nix packages.
+const BUILTIN_EXTENSIONS: &[&str] = &[
+    "core",
+    "date",
+    "hash",
+    "libxml",
+    "pcre",
+    "random",
+    "reflection",
+    "spl",
+    "standard",
+];
+
 pub struct PhpProvider;
 
 impl Provider for PhpProvider {
@@ -24,15 +39,19 @@ impl Provider for PhpProvider {
         "php"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("composer.json") || app.includes_file("index.php"))
+    fn language(&self) -> &'static str {
+        "PHP"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["composer.json", "index.php"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
         let setup = PhpProvider::get_setup(app, env)?;
         let install = PhpProvider::get_install(app);
         let build = PhpProvider::get_build(app);
-        let start = PhpProvider::get_start(app);
+        let start = PhpProvider::get_start(app, env)?;
 
         let mut plan = BuildPlan::new(
             &vec![Some(setup), Some(install), build]
@@ -47,6 +66,14 @@ impl Provider for PhpProvider {
 
         Ok(Some(plan))
     }
+
+    fn get_cache_dirs(&self, app: &App, _env: &Environment) -> Result<Vec<String>> {
+        if app.includes_file("composer.json") {
+            Ok(vec![COMPOSER_CACHE_DIR.to_string()])
+        } else {
+            Ok(Vec::new())
+        }
+    }
 }
 
 impl PhpProvider {
@@ -101,7 +128,10 @@ impl PhpProvider {
             "mkdir -p /var/log/nginx && mkdir -p /var/cache/nginx".to_string(),
         ));
         if app.includes_file("composer.json") {
-            install.add_cmd("composer install --ignore-platform-reqs".to_string());
+            install.add_cmd(
+                "composer install --no-dev --optimize-autoloader --ignore-platform-reqs"
+                    .to_string(),
+            );
         };
         if app.includes_file("package.json") {
             if let Some(install_cmd) = NodeProvider::get_install_command(app) {
@@ -126,16 +156,32 @@ impl PhpProvider {
         None
     }
 
-    fn get_start(app: &App) -> StartPhase {
+    fn get_start(app: &App, env: &Environment) -> Result<StartPhase> {
+        match env.get_config_variable("PHP_SERVER").as_deref() {
+            None | Some("nginx") => Ok(PhpProvider::get_nginx_start(app)),
+            // The built-in server of PHP serves a single request at a time, without php-fpm or Nginx
+            Some("builtin") => Ok(StartPhase::new(format!(
+                "php -S 0.0.0.0:${{PORT:-80}} -t ${{NIXPACKS_PHP_ROOT_DIR:-{}}}",
+                app.app_path("").trim_end_matches('/')
+            ))),
+            Some(server) => {
+                bail!("Unknown PHP server `{server}`, expected `nginx` or `builtin`")
+            }
+        }
+    }
+
+    fn get_nginx_start(app: &App) -> StartPhase {
         if app.includes_file("nginx.conf") {
             StartPhase::new(format!(
-                "php-fpm -y {} & nginx -c /app/nginx.conf",
-                app.asset_path("php-fpm.conf")
+                "php-fpm -y {} & nginx -c {}",
+                app.asset_path("php-fpm.conf"),
+                app.app_path("nginx.conf")
             ))
         } else if app.includes_file("nginx.template.conf") {
             StartPhase::new(format!(
-                "perl {} /app/nginx.template.conf /nginx.conf && (php-fpm -y {} & nginx -c /nginx.conf)",
+                "perl {} {} /nginx.conf && (php-fpm -y {} & nginx -c /nginx.conf)",
                 app.asset_path("prestart.pl"),
+                app.app_path("nginx.template.conf"),
                 app.asset_path("php-fpm.conf"),
             ))
         } else {
@@ -166,10 +212,7 @@ impl PhpProvider {
         vars.insert("PORT".to_string(), "80".to_string());
         if app.includes_file("artisan") {
             vars.insert("IS_LARAVEL".to_string(), "yes".to_string());
-            vars.insert(
-                "NIXPACKS_PHP_ROOT_DIR".to_string(),
-                "/app/public".to_string(),
-            );
+            vars.insert("NIXPACKS_PHP_ROOT_DIR".to_string(), app.app_path("public"));
         }
         vars
     }
@@ -209,19 +252,24 @@ impl PhpProvider {
     fn get_php_extensions(app: &App) -> Result<Vec<String>> {
         let composer_json: ComposerJson = app.read_json("composer.json")?;
         let version = PhpProvider::get_php_version(app)?;
-        let mut extensions = Vec::new();
-        for extension in composer_json.require.keys() {
+        let extensions = composer_json
+            .require
+            .keys()
+            .filter_map(|extension| PhpProvider::get_nix_extension_name(extension, &version))
+            .collect::<Vec<_>>();
+        Ok(extensions)
+    }
+
+    /// The name of the Nix package for a `require`d `ext-*` of `composer.json`, or None if it is compiled into PHP.
+    fn get_nix_extension_name(requirement: &str, version: &str) -> Option<String> {
+        let extension = requirement.strip_prefix("ext-")?.to_lowercase();
+        match extension.as_str() {
+            "zend-opcache" | "zend_opcache" => Some("opcache".to_string()),
             // ext-json is included by default in PHP >= 8.0 (and not available in Nix) so skip over it
-            if extension.starts_with("ext-") && (version == "7.4" || extension != "ext-json") {
-                extensions.push(
-                    extension
-                        .strip_prefix("ext-")
-                        .unwrap_or(extension)
-                        .to_string(),
-                );
-            }
+            "json" if version != "7.4" => None,
+            extension if BUILTIN_EXTENSIONS.contains(&extension) => None,
+            extension => Some(extension.replace('-', "_")),
         }
-        Ok(extensions)
     }
 }
 
@@ -229,3 +277,30 @@ impl PhpProvider {
 struct ComposerJson {
     require: HashMap<String, String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nix_extension_name() {
+        assert_eq!(
+            PhpProvider::get_nix_extension_name("ext-pdo_mysql", "8.2"),
+            Some("pdo_mysql".to_string())
+        );
+        assert_eq!(
+            PhpProvider::get_nix_extension_name("ext-Zend-OPcache", "8.2"),
+            Some("opcache".to_string())
+        );
+        assert_eq!(
+            PhpProvider::get_nix_extension_name("ext-json", "7.4"),
+            Some("json".to_string())
+        );
+        assert_eq!(PhpProvider::get_nix_extension_name("ext-json", "8.2"), None);
+        assert_eq!(PhpProvider::get_nix_extension_name("ext-pcre", "8.2"), None);
+        assert_eq!(
+            PhpProvider::get_nix_extension_name("laravel/framework", "8.2"),
+            None
+        );
+    }
+}
@@ -8,6 +8,7 @@ use warnings;
 use FindBin;
 use lib ("$FindBin::RealBin");
 
+use Cwd qw(getcwd);
 use File::Find;
 use Nixpacks::Config::Template qw(compile_template);
 use Nixpacks::Util::Logger;
@@ -16,7 +17,11 @@ use Nixpacks::Util::Laravel qw(is_laravel check_possible_env_errors);
 
 my $server_logger = Nixpacks::Util::Logger->new("server");
 
-Nixpacks::Util::ChmodRecursive::chmod_recursive("/app/storage") if -e "/app/storage";
+# The server is started in the app directory, which is /app unless the plan sets a workdir
+my $app_dir = getcwd();
+$ENV{"NIXPACKS_PHP_ROOT_DIR"} = $app_dir if ($ENV{"NIXPACKS_PHP_ROOT_DIR"} // "") eq "";
+
+Nixpacks::Util::ChmodRecursive::chmod_recursive("$app_dir/storage") if -e "$app_dir/storage";
 
 if ($#ARGV != 1) {
     print STDERR "Usage: $0 <config-file> <output-file>\n";
@@ -24,7 +29,7 @@ if ($#ARGV != 1) {
 }
 
 if(Nixpacks::Util::Laravel::is_laravel()) {
-    Nixpacks::Util::Laravel::check_possible_env_errors("/app");
+    Nixpacks::Util::Laravel::check_possible_env_errors($app_dir);
 }
 
 Nixpacks::Config::Template::compile_template($ARGV[0], $ARGV[1]);
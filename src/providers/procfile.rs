@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use super::Provider;
 use crate::nixpacks::{
@@ -18,6 +18,10 @@ impl Provider for ProcfileProvider {
         "deno"
     }
 
+    fn language(&self) -> &'static str {
+        "Procfile"
+    }
+
     fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
         let mut plan = BuildPlan::default();
 
@@ -32,8 +36,14 @@ impl Provider for ProcfileProvider {
             plan.add_phase(release);
         };
 
-        if let Some(start_cmd) = ProcfileProvider::get_start_cmd(app)? {
-            let start_phase = StartPhase::new(start_cmd);
+        let processes = ProcfileProvider::get_processes(app)?;
+        if let Some(start_cmd) = ProcfileProvider::get_start_cmd(&processes) {
+            let mut start_phase = StartPhase::new(start_cmd);
+
+            // Every process is built into the same image, which runs the one selected by `NIXPACKS_PROCESS`
+            if processes.len() > 1 {
+                start_phase.processes = Some(processes);
+            }
             plan.set_start_phase(start_phase);
         }
 
@@ -42,25 +52,26 @@ impl Provider for ProcfileProvider {
 }
 
 impl ProcfileProvider {
-    fn get_start_cmd(app: &App) -> Result<Option<String>> {
+    /// The processes of the Procfile, other than the release command.
+    fn get_processes(app: &App) -> Result<BTreeMap<String, String>> {
         if app.includes_file("Procfile") {
-            let mut procfile: HashMap<String, String> =
+            let mut procfile: BTreeMap<String, String> =
                 app.read_yaml("Procfile").context("Reading Procfile")?;
             procfile.remove("release");
-
-            if procfile.is_empty() {
-                Ok(None)
-            } else if let Some(cmd) = procfile.get("web") {
-                Ok(Some(cmd.to_string()))
-            } else {
-                let process = procfile.values().collect::<Vec<_>>()[0].to_string();
-                Ok(Some(process))
-            }
+            Ok(procfile)
         } else {
-            Ok(None)
+            Ok(BTreeMap::new())
         }
     }
 
+    /// The `web` process, or else the first process by name.
+    fn get_start_cmd(processes: &BTreeMap<String, String>) -> Option<String> {
+        processes
+            .get("web")
+            .or_else(|| processes.values().next())
+            .cloned()
+    }
+
     fn get_release_cmd(app: &App) -> Result<Option<String>> {
         if app.includes_file("Procfile") {
             let procfile: HashMap<String, String> =
@@ -13,16 +13,18 @@ use crate::{
 use anyhow::{bail, Context, Ok, Result};
 use regex::{Match, Regex};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::result::Result::Ok as OkResult;
-use std::{collections::HashMap, fs};
 
 use super::{Provider, ProviderMetadata};
 
 const DEFAULT_PYTHON_PKG_NAME: &str = "python38";
 const POETRY_VERSION: &str = "1.3.1";
 const PDM_VERSION: &str = "2.7.4";
+const UV_VERSION: &str = "0.4.30";
 const PIP_CACHE_DIR: &str = "/root/.cache/pip";
 const PDM_CACHE_DIR: &str = "/root/.cache/pdm";
+const UV_CACHE_DIR: &str = "/root/.cache/uv";
 const DEFAULT_POETRY_PYTHON_PKG_NAME: &str = "python310";
 
 pub struct PythonProvider {}
@@ -32,12 +34,12 @@ impl Provider for PythonProvider {
         "python"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        let has_python = app.includes_file("main.py")
-            || app.includes_file("requirements.txt")
-            || app.includes_file("pyproject.toml")
-            || app.includes_file("Pipfile");
-        Ok(has_python)
+    fn language(&self) -> &'static str {
+        "Python"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["main.py", "requirements.txt", "pyproject.toml", "Pipfile"]
     }
 
     fn metadata(&self, app: &App, env: &Environment) -> Result<ProviderMetadata> {
@@ -45,12 +47,14 @@ impl Provider for PythonProvider {
         let is_using_postgres = PythonProvider::is_using_postgres(app, env)?;
         let is_poetry = app.includes_file("poetry.lock");
         let is_pdm = app.includes_file("pdm.lock");
+        let is_uv = PythonProvider::is_uv(app);
 
         Ok(ProviderMetadata::from(vec![
             (is_django, "django"),
             (is_using_postgres, "postgres"),
             (is_poetry, "poetry"),
             (is_pdm, "pdm"),
+            (is_uv, "uv"),
         ]))
     }
 
@@ -86,8 +90,32 @@ impl Provider for PythonProvider {
             )]));
         }
 
+        if PythonProvider::is_uv(app) {
+            plan.add_variables(EnvironmentVariables::from([
+                ("NIXPACKS_UV_VERSION".to_string(), UV_VERSION.to_string()),
+                // Sync into the venv uv is installed in, rather than creating /app/.venv
+                (
+                    "UV_PROJECT_ENVIRONMENT".to_string(),
+                    "/opt/venv".to_string(),
+                ),
+            ]));
+        }
+
         Ok(Some(plan))
     }
+
+    fn get_cache_dirs(&self, app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(PackageManager::detect(app)
+            .map(PackageManager::cache_dirs)
+            .unwrap_or_default())
+    }
+
+    fn get_cache_inputs(&self, app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(PackageManager::detect(app)
+            .and_then(PackageManager::lockfile)
+            .map(|lockfile| vec![lockfile.to_string()])
+            .unwrap_or_default())
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -118,6 +146,60 @@ enum EntryPoint {
     Module(String),
 }
 
+/// The tool that installs the dependencies of the app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PackageManager {
+    Uv,
+    Requirements,
+    Poetry,
+    Pdm,
+    Pyproject,
+    Pipenv,
+}
+
+impl PackageManager {
+    fn detect(app: &App) -> Option<PackageManager> {
+        if PythonProvider::is_uv(app) {
+            Some(PackageManager::Uv)
+        } else if app.includes_file("requirements.txt") {
+            Some(PackageManager::Requirements)
+        } else if app.includes_file("pyproject.toml") {
+            if app.includes_file("poetry.lock") {
+                Some(PackageManager::Poetry)
+            } else if app.includes_file("pdm.lock") {
+                Some(PackageManager::Pdm)
+            } else {
+                Some(PackageManager::Pyproject)
+            }
+        } else if app.includes_file("Pipfile") {
+            Some(PackageManager::Pipenv)
+        } else {
+            None
+        }
+    }
+
+    fn cache_dirs(self) -> Vec<String> {
+        // The other package managers are installed with pip, so the pip cache is always used
+        let mut dirs = vec![PIP_CACHE_DIR.to_string()];
+        match self {
+            PackageManager::Uv => dirs.push(UV_CACHE_DIR.to_string()),
+            PackageManager::Pdm => dirs.push(PDM_CACHE_DIR.to_string()),
+            _ => {}
+        }
+        dirs
+    }
+
+    /// The file that pins the dependencies, when it is not one of the common lockfiles.
+    fn lockfile(self) -> Option<&'static str> {
+        match self {
+            PackageManager::Uv => Some("uv.lock"),
+            PackageManager::Pdm => Some("pdm.lock"),
+            PackageManager::Pyproject => Some("pyproject.toml"),
+            _ => None,
+        }
+    }
+}
+
 impl PythonProvider {
     fn setup(&self, app: &App, env: &Environment) -> Result<Option<Phase>> {
         let mut pkgs: Vec<Pkg> = vec![];
@@ -149,75 +231,93 @@ impl PythonProvider {
         Ok(Some(setup))
     }
 
-    fn install(&self, app: &App, _env: &Environment) -> Result<Option<Phase>> {
+    fn install(&self, app: &App, env: &Environment) -> Result<Option<Phase>> {
         let env_loc = "/opt/venv";
         let create_env = format!("python -m venv --copies {env_loc}");
         let activate_env = format!(". {env_loc}/bin/activate");
 
-        if app.includes_file("requirements.txt") {
-            let mut install_phase = Phase::install(Some(format!(
-                "{create_env} && {activate_env} && pip install -r requirements.txt"
-            )));
-
-            install_phase.add_path(format!("{env_loc}/bin"));
-            install_phase.add_cache_directory(PIP_CACHE_DIR.to_string());
-
-            return Ok(Some(install_phase));
-        } else if app.includes_file("pyproject.toml") {
-            if app.includes_file("poetry.lock") {
-                let install_poetry = "pip install poetry==$NIXPACKS_POETRY_VERSION".to_string();
+        // Custom install commands can need any file, and files left out of the Docker build context cannot be copied
+        let copy_install_files =
+            env.get_config_variable("INSTALL_CMD").is_none() && !app.includes_file(".dockerignore");
+
+        let Some(package_manager) = PackageManager::detect(app) else {
+            return Ok(Some(Phase::install(None)));
+        };
+
+        let mut install_phase = match package_manager {
+            // uv is not in the pinned nixpkgs archive, so it is installed with pip like poetry and pdm
+            // An exact sync would remove pip and uv from the venv, as the project does not depend on them
+            PackageManager::Uv => {
+                let install_uv = "pip install uv==$NIXPACKS_UV_VERSION".to_string();
+                let sync = if app.includes_file("uv.lock") {
+                    "uv sync --inexact --frozen"
+                } else {
+                    "uv sync --inexact"
+                };
+                Phase::install(Some(format!(
+                    "{create_env} && {activate_env} && {install_uv} && {sync}"
+                )))
+            }
+            PackageManager::Requirements => {
                 let mut install_phase = Phase::install(Some(format!(
-                    "{create_env} && {activate_env} && {install_poetry} && poetry install --no-dev --no-interaction --no-ansi"
+                    "{create_env} && {activate_env} && pip install -r requirements.txt"
                 )));
 
-                install_phase.add_path(format!("{env_loc}/bin"));
-
-                install_phase.add_cache_directory(PIP_CACHE_DIR.to_string());
+                // Only copy the requirements, so that the install is cached until they change
+                if copy_install_files && PythonProvider::is_self_contained_requirements(app) {
+                    install_phase.add_file_dependency("requirements.txt");
+                }
 
-                return Ok(Some(install_phase));
-            } else if app.includes_file("pdm.lock") {
+                install_phase
+            }
+            PackageManager::Poetry => {
+                let install_poetry = "pip install poetry==$NIXPACKS_POETRY_VERSION".to_string();
+                Phase::install(Some(format!(
+                    "{create_env} && {activate_env} && {install_poetry} && poetry install --no-dev --no-interaction --no-ansi"
+                )))
+            }
+            PackageManager::Pdm => {
                 let install_pdm = "pip install pdm==$NIXPACKS_PDM_VERSION".to_string();
-                let mut install_phase = Phase::install(Some(format!(
+                Phase::install(Some(format!(
                     "{create_env} && {activate_env} && {install_pdm} && pdm install --prod"
+                )))
+            }
+            PackageManager::Pyproject => {
+                let mut install_phase = Phase::install(Some(format!(
+                    "{create_env} && {activate_env} && pip install --upgrade build setuptools && pip install ."
                 )));
-
-                install_phase.add_path(format!("{env_loc}/bin"));
-
-                install_phase.add_cache_directory(PIP_CACHE_DIR.to_string());
-                install_phase.add_cache_directory(PDM_CACHE_DIR.to_string());
-
-                return Ok(Some(install_phase));
+                install_phase.add_file_dependency("pyproject.toml".to_string());
+                install_phase
             }
-            let mut install_phase = Phase::install(Some(format!(
-                "{create_env} && {activate_env} && pip install --upgrade build setuptools && pip install ."
-            )));
-
-            install_phase.add_file_dependency("pyproject.toml".to_string());
-            install_phase.add_path(format!("{env_loc}/bin"));
-
-            install_phase.add_cache_directory(PIP_CACHE_DIR.to_string());
-
-            return Ok(Some(install_phase));
-        } else if app.includes_file("Pipfile") {
-            // By default Pipenv creates an environment directory in some random location (for example `/root/.local/share/virtualenvs/app-4PlAip0Q`).
-            // `PIPENV_VENV_IN_PROJECT` tells it that there is an already activated `venv` environment, So Pipenv will use the same directory instead of creating new one (in our case it's `/app/.venv`)
-
-            let cmd = if app.includes_file("Pipfile.lock") {
-                "PIPENV_VENV_IN_PROJECT=1 pipenv install --deploy"
-            } else {
-                "PIPENV_VENV_IN_PROJECT=1 pipenv install --skip-lock"
-            };
+            PackageManager::Pipenv => {
+                // By default Pipenv creates an environment directory in some random location (for example `/root/.local/share/virtualenvs/app-4PlAip0Q`).
+                // `PIPENV_VENV_IN_PROJECT` tells it that there is an already activated `venv` environment, So Pipenv will use the same directory instead of creating new one (in our case it's `/app/.venv`)
+
+                let cmd = if app.includes_file("Pipfile.lock") {
+                    "PIPENV_VENV_IN_PROJECT=1 pipenv install --deploy"
+                } else {
+                    "PIPENV_VENV_IN_PROJECT=1 pipenv install --skip-lock"
+                };
+
+                let cmd = format!("{create_env} && {activate_env} && {cmd}");
+                let mut install_phase = Phase::install(Some(cmd));
+
+                // Packages installed from a path of the app need the whole app
+                let pipfile = app.read_file("Pipfile").unwrap_or_default();
+                if copy_install_files && !pipfile.contains("path =") && !pipfile.contains("path=") {
+                    install_phase.add_file_dependency("Pipfile");
+                    if app.includes_file("Pipfile.lock") {
+                        install_phase.add_file_dependency("Pipfile.lock");
+                    }
+                }
 
-            let cmd = format!("{create_env} && {activate_env} && {cmd}");
-            let mut install_phase = Phase::install(Some(cmd));
+                install_phase
+            }
+        };
 
-            install_phase.add_path(format!("{env_loc}/bin"));
-            install_phase.add_cache_directory(PIP_CACHE_DIR.to_string());
+        install_phase.add_path(format!("{env_loc}/bin"));
 
-            return Ok(Some(install_phase));
-        }
-
-        Ok(Some(Phase::install(None)))
+        Ok(Some(install_phase))
     }
 
     fn start(&self, app: &App, env: &Environment) -> Result<Option<StartPhase>> {
@@ -247,6 +347,31 @@ impl PythonProvider {
         Ok(None)
     }
 
+    /// Whether requirements.txt can be installed on its own, without options that read other files such as `-r` or `-e .`.
+    fn is_self_contained_requirements(app: &App) -> bool {
+        app.read_file("requirements.txt")
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .all(|line| {
+                !line.starts_with('-')
+                    && !line.starts_with('.')
+                    && !line.starts_with('/')
+                    && !line.contains("file:")
+            })
+    }
+
+    /// uv is used if the app has a `uv.lock` or configures it in `pyproject.toml`.
+    fn is_uv(app: &App) -> bool {
+        app.includes_file("uv.lock")
+            || (app.includes_file("pyproject.toml")
+                && app
+                    .read_file("pyproject.toml")
+                    .unwrap_or_default()
+                    .lines()
+                    .any(|line| line.trim().starts_with("[tool.uv")))
+    }
+
     fn is_django(app: &App, _env: &Environment) -> Result<bool> {
         let has_manage = app.includes_file("manage.py");
         let imports_django = PythonProvider::uses_dep(app, "django")?;
@@ -278,9 +403,7 @@ impl PythonProvider {
 
         // Search all settings.py matches
         for path in paths {
-            let path_buf = fs::canonicalize(path)?;
-
-            if let Some(p) = path_buf.to_str() {
+            if let Some(p) = path.to_str() {
                 let f = app.read_file(p)?;
                 if let Some(value) = re.captures(f.as_str()) {
                     // Get the first and only match
@@ -292,6 +415,15 @@ impl PythonProvider {
         bail!("Failed to find your WSGI_APPLICATION django setting. Add this to continue.")
     }
 
+    /// The first version listed in a pyenv `.python-version` file, which can list several versions and comments.
+    fn parse_python_version_file(file_content: &str) -> Option<String> {
+        file_content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(ToString::to_string)
+    }
+
     fn parse_pipfile_python_version(file_content: &str) -> Result<Option<String>> {
         let matches = Regex::new("(python_version|python_full_version) = ['|\"]([0-9|.]*)")?
             .captures(file_content);
@@ -313,14 +445,22 @@ impl PythonProvider {
         // Fetch version from configs
         let mut custom_version = env.get_config_variable("PYTHON_VERSION");
 
-        // If not from configs, get it from the .python-version file
-        if custom_version.is_none() && app.includes_file(".python-version") {
-            custom_version = Some(app.read_file(".python-version")?);
-        } else if app.includes_file("runtime.txt") {
-            custom_version = Some(app.read_file("runtime.txt")?);
-        } else if app.includes_file("Pipfile") {
-            let file_content = &app.read_file("Pipfile")?;
-            custom_version = PythonProvider::parse_pipfile_python_version(file_content)?;
+        // If not from configs, get it from the .python-version file, runtime.txt, or Pipfile
+        if custom_version.is_none() {
+            custom_version = if app.includes_file(".python-version") {
+                PythonProvider::parse_python_version_file(&app.read_file(".python-version")?)
+            } else if app.includes_file("runtime.txt") {
+                Some(app.read_file("runtime.txt")?)
+            } else if app.includes_file("Pipfile") {
+                let file_content = &app.read_file("Pipfile")?;
+                PythonProvider::parse_pipfile_python_version(file_content)?
+            } else {
+                None
+            };
+        }
+
+        if custom_version.is_none() {
+            custom_version = app.read_tool_version("python")?;
         }
 
         // If it's still none, return default
@@ -494,6 +634,47 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_exact_versions() -> Result<()> {
+        assert_eq!(
+            PythonProvider::get_nix_python_package(
+                &App::new("./examples/python-runtime-txt")?,
+                &Environment::default()
+            )?,
+            Pkg::new("python311")
+        );
+        assert_eq!(
+            PythonProvider::get_nix_python_package(
+                &App::new("./examples/python-version-file")?,
+                &Environment::default()
+            )?,
+            Pkg::new("python310")
+        );
+
+        // The environment variable takes precedence over runtime.txt
+        assert_eq!(
+            PythonProvider::get_nix_python_package(
+                &App::new("./examples/python-runtime-txt")?,
+                &Environment::new(BTreeMap::from([(
+                    "NIXPACKS_PYTHON_VERSION".to_string(),
+                    "3.9".to_string()
+                )]))
+            )?,
+            Pkg::new("python39")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_python_version_file() {
+        assert_eq!(
+            PythonProvider::parse_python_version_file("# pinned by pyenv\n3.11.4\n3.10.12\n"),
+            Some("3.11.4".to_string())
+        );
+        assert_eq!(PythonProvider::parse_python_version_file("\n"), None);
+    }
+
     #[test]
     fn test_version_from_environment_variable() -> Result<()> {
         assert_eq!(
@@ -557,4 +738,62 @@ mod test {
         .unwrap());
         Ok(())
     }
+
+    #[test]
+    fn test_uv_detection() -> Result<()> {
+        assert!(PythonProvider::is_uv(&App::new("./examples/python-uv")?));
+        assert!(!PythonProvider::is_uv(&App::new("./examples/python-pdm")?));
+        assert!(!PythonProvider::is_uv(&App::new("./examples/python")?));
+
+        let install = PythonProvider {}
+            .install(&App::new("./examples/python-uv")?, &Environment::default())?
+            .unwrap();
+        assert!(install.cmds.unwrap()[0].ends_with("uv sync --inexact --frozen"));
+        assert!(PythonProvider {}
+            .get_cache_dirs(&App::new("./examples/python-uv")?, &Environment::default())?
+            .contains(&UV_CACHE_DIR.to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_dirs() -> Result<()> {
+        let get_cache_dirs = |path: &str| {
+            PythonProvider {}.get_cache_dirs(&App::new(path).unwrap(), &Environment::default())
+        };
+
+        assert_eq!(
+            get_cache_dirs("./examples/python-uv")?,
+            vec![PIP_CACHE_DIR.to_string(), UV_CACHE_DIR.to_string()]
+        );
+        assert_eq!(
+            get_cache_dirs("./examples/python-pdm")?,
+            vec![PIP_CACHE_DIR.to_string(), PDM_CACHE_DIR.to_string()]
+        );
+        assert_eq!(
+            get_cache_dirs("./examples/python")?,
+            vec![PIP_CACHE_DIR.to_string()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_inputs() -> Result<()> {
+        let get_cache_inputs = |path: &str| {
+            PythonProvider {}.get_cache_inputs(&App::new(path).unwrap(), &Environment::default())
+        };
+
+        assert_eq!(
+            get_cache_inputs("./examples/python-uv")?,
+            vec!["uv.lock".to_string()]
+        );
+        assert_eq!(
+            get_cache_inputs("./examples/python-pdm")?,
+            vec!["pdm.lock".to_string()]
+        );
+        assert!(get_cache_inputs("./examples/python")?.is_empty());
+
+        Ok(())
+    }
 }
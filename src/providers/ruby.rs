@@ -29,13 +29,20 @@ pub struct RubyProvider {}
 
 const BUNDLE_CACHE_DIR: &str = "/root/.bundle/cache";
 
+/// Bundler groups that are not installed, since they are only needed to develop and test the app.
+const BUNDLE_WITHOUT: &str = "development:test";
+
 impl Provider for RubyProvider {
     fn name(&self) -> &str {
         "ruby"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("Gemfile"))
+    fn language(&self) -> &'static str {
+        "Ruby"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["Gemfile"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -53,9 +60,11 @@ impl Provider for RubyProvider {
         );
 
         let node = NodeProvider::default();
-        if node.detect(app, env)? || self.uses_gem_dep(app, "execjs") {
+        if node.detect(app, env)?.matched || self.uses_gem_dep(app, "execjs") {
             let node_build_plan = node.get_build_plan(app, env)?;
-            if let Some(node_build_plan) = node_build_plan {
+            if let Some(mut node_build_plan) = node_build_plan {
+                node_build_plan.add_cache_directories(node.get_cache_dirs(app, env)?);
+
                 // Include the install phase from the node provider
                 let root_phase_name =
                     plan.add_phases_from_another_plan(&node_build_plan, node.name(), "install");
@@ -67,6 +76,10 @@ impl Provider for RubyProvider {
 
         Ok(Some(plan))
     }
+
+    fn get_cache_dirs(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(vec![BUNDLE_CACHE_DIR.to_string()])
+    }
 }
 
 impl RubyProvider {
@@ -150,7 +163,6 @@ impl RubyProvider {
 
     fn get_install(&self, app: &App, env: &Environment) -> Result<Option<Phase>> {
         let mut install = Phase::install(None);
-        install.add_cache_directory(BUNDLE_CACHE_DIR.to_string());
 
         if !self.uses_gem_dep(app, "local") {
             // Only run install if Gemfile or Gemfile.lock has changed
@@ -206,7 +218,7 @@ impl RubyProvider {
     ) -> Result<EnvironmentVariables> {
         let ruby_version = self.get_ruby_version(app, env)?;
         let mut env_vars = EnvironmentVariables::from([
-            ("BUNDLE_GEMFILE".to_string(), "/app/Gemfile".to_string()),
+            ("BUNDLE_GEMFILE".to_string(), app.app_path("Gemfile")),
             (
                 "GEM_PATH".to_string(),
                 format!(
@@ -218,6 +230,7 @@ impl RubyProvider {
                 format!("/usr/local/rvm/gems/{ruby_version}"),
             ),
             ("MALLOC_ARENA_MAX".to_string(), "2".to_string()),
+            ("BUNDLE_WITHOUT".to_string(), BUNDLE_WITHOUT.to_string()),
         ]);
 
         if self.is_rails_app(app) {
@@ -229,7 +242,10 @@ impl RubyProvider {
     }
 
     fn get_start_command(&self, app: &App) -> Option<String> {
-        if self.is_rails_app(app) {
+        // Rails serves with Puma by default, but Unicorn has to be started directly
+        if self.uses_gem_dep(app, "unicorn") && app.includes_file("config/unicorn.rb") {
+            Some("bundle exec unicorn -p ${PORT:-3000} -c config/unicorn.rb".to_string())
+        } else if self.is_rails_app(app) {
             if app.includes_file("rails") {
                 Some("bundle exec rails server -b 0.0.0.0 -p ${PORT:-3000}".to_string())
             } else {
@@ -241,7 +257,11 @@ impl RubyProvider {
         } else if app.includes_file("config/environment.rb") && app.includes_directory("script") {
             Some("bundle exec ruby script/server -p ${PORT:-3000}".to_string())
         } else if app.includes_file("config.ru") {
-            Some("bundle exec rackup config.ru -p ${PORT:-3000}".to_string())
+            if self.uses_gem_dep(app, "puma") {
+                Some("bundle exec puma -p ${PORT:-3000} config.ru".to_string())
+            } else {
+                Some("bundle exec rackup config.ru -p ${PORT:-3000}".to_string())
+            }
         } else if app.includes_file("Rakefile") {
             Some("bundle exec rake".to_string())
         } else {
@@ -256,6 +276,9 @@ impl RubyProvider {
         if app.includes_file(".ruby-version") {
             return Ok(app.read_file(".ruby-version")?.trim().to_string());
         }
+        if let Some(version) = app.read_tool_version("ruby")? {
+            return Ok(version);
+        }
         let re_gemfile = Regex::new(r#"ruby (?:'|")(.*)(?:'|")[^>]"#).unwrap();
         let gemfile = app.read_file("Gemfile").unwrap_or_default();
         if let Some(value) = re_gemfile.captures(&gemfile) {
@@ -1,5 +1,8 @@
+use std::collections::HashSet;
 use std::env::consts::ARCH;
 use std::fmt::Write as _;
+use std::io;
+use std::path::PathBuf;
 
 use super::Provider;
 use crate::nixpacks::{
@@ -11,8 +14,8 @@ use crate::nixpacks::{
         BuildPlan,
     },
 };
-use anyhow::{Context, Result};
-use cargo_toml::{Manifest, Workspace};
+use anyhow::{bail, Context, Result};
+use cargo_toml::{AbstractFilesystem, Manifest, Workspace};
 use regex::Regex;
 
 const RUST_OVERLAY: &str = "https://github.com/oxalica/rust-overlay/archive/master.tar.gz";
@@ -22,6 +25,63 @@ const CARGO_GIT_CACHE_DIR: &str = "/root/.cargo/git";
 const CARGO_REGISTRY_CACHE_DIR: &str = "/root/.cargo/registry";
 const CARGO_TARGET_CACHE_DIR: &str = "target";
 
+const JUSTFILES: &[&str] = &["justfile", "Justfile", ".justfile"];
+const MAKEFILES: &[&str] = &["GNUmakefile", "makefile", "Makefile"];
+
+/// A `justfile` or `Makefile` that wraps the cargo commands of the app.
+struct TaskRunner {
+    pkg: &'static str,
+    cmd: &'static str,
+    targets: Vec<String>,
+}
+
+impl TaskRunner {
+    /// Find the task runner of the app, preferring `just` over `make`.
+    fn detect(app: &App) -> Result<Option<TaskRunner>> {
+        // Recipes are not indented, and are not `:=` assignments
+        if let Some(file) = JUSTFILES.iter().find(|file| app.includes_file(file)) {
+            let recipe_regex = Regex::new(r"^@?([A-Za-z_][A-Za-z0-9_-]*)[^:=]*:([^=]|$)")?;
+            return Ok(Some(TaskRunner {
+                pkg: "just",
+                cmd: "just",
+                targets: TaskRunner::find_targets(&app.read_file(file)?, &recipe_regex),
+            }));
+        }
+
+        if let Some(file) = MAKEFILES.iter().find(|file| app.includes_file(file)) {
+            let target_regex =
+                Regex::new(r"^([A-Za-z0-9_./-]+(?:[ \t]+[A-Za-z0-9_./-]+)*)[ \t]*:([^=]|$)")?;
+            let targets = TaskRunner::find_targets(&app.read_file(file)?, &target_regex)
+                .iter()
+                .flat_map(|targets| targets.split_whitespace().map(ToString::to_string))
+                .collect();
+            return Ok(Some(TaskRunner {
+                pkg: "gnumake",
+                cmd: "make",
+                targets,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    fn find_targets(contents: &str, regex: &Regex) -> Vec<String> {
+        contents
+            .lines()
+            .filter_map(|line| regex.captures(line))
+            .map(|captures| captures[1].to_string())
+            .collect()
+    }
+
+    fn has_target(&self, target: &str) -> bool {
+        self.targets.iter().any(|t| t == target)
+    }
+
+    fn get_cmd(&self, target: &str) -> String {
+        format!("{} {target}", self.cmd)
+    }
+}
+
 pub struct RustProvider {}
 
 impl Provider for RustProvider {
@@ -29,8 +89,12 @@ impl Provider for RustProvider {
         "rust"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("Cargo.toml"))
+    fn language(&self) -> &'static str {
+        "Rust"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["Cargo.toml"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
@@ -46,6 +110,13 @@ impl Provider for RustProvider {
 
         Ok(Some(plan))
     }
+
+    fn get_cache_dirs(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(vec![
+            CARGO_GIT_CACHE_DIR.to_string(),
+            CARGO_REGISTRY_CACHE_DIR.to_string(),
+        ])
+    }
 }
 
 impl RustProvider {
@@ -76,6 +147,12 @@ impl RustProvider {
             setup.add_nix_pkgs(&[Pkg::new("musl"), Pkg::new("musl.dev")]);
         }
 
+        if let Some(task_runner) = TaskRunner::detect(app)? {
+            if task_runner.has_target("build") || task_runner.has_target("run") {
+                setup.add_nix_pkgs(&[Pkg::new(task_runner.pkg)]);
+            }
+        }
+
         Ok(setup)
     }
 
@@ -122,14 +199,30 @@ impl RustProvider {
             }
         }
 
-        build.add_cache_directory(CARGO_GIT_CACHE_DIR.to_string());
-        build.add_cache_directory(CARGO_REGISTRY_CACHE_DIR.to_string());
-
-        if RustProvider::get_app_name(app)?.is_some() {
+        if RustProvider::get_app_name(app)?.is_some()
+            || RustProvider::resolve_cargo_workspace(app, env)?.is_some()
+        {
             // Cache target directory
             build.add_cache_directory(CARGO_TARGET_CACHE_DIR.to_string());
         }
 
+        // The build recipe replaces cargo, and the binaries are still copied out of `target` unless there is a run recipe
+        if let Some(task_runner) = TaskRunner::detect(app)? {
+            if task_runner.has_target("build") {
+                let build_cmd = task_runner.get_cmd("build");
+                let mut cmds = build.cmds.take().unwrap_or_default();
+                if task_runner.has_target("run") {
+                    cmds = vec![build_cmd];
+                } else if let Some(cmd) = cmds.iter_mut().find(|cmd| cmd.starts_with("cargo build"))
+                {
+                    *cmd = build_cmd;
+                } else {
+                    cmds.push(build_cmd);
+                }
+                build.cmds = Some(cmds);
+            }
+        }
+
         Ok(build)
     }
 
@@ -179,6 +272,13 @@ impl RustProvider {
     }
 
     pub(crate) fn get_start(app: &App, env: &Environment) -> Result<Option<StartPhase>> {
+        // The run recipe needs the task runner, so the app runs in the build image
+        if let Some(task_runner) = TaskRunner::detect(app)? {
+            if task_runner.has_target("run") {
+                return Ok(Some(StartPhase::new(task_runner.get_cmd("run"))));
+            }
+        }
+
         if (RustProvider::get_target(app, env)?).is_some() {
             if let Some(workspace) = RustProvider::resolve_cargo_workspace(app, env)? {
                 let mut start = StartPhase::new(format!("./bin/{workspace}"));
@@ -357,71 +457,93 @@ impl RustProvider {
     }
 
     fn resolve_cargo_workspace(app: &App, env: &Environment) -> Result<Option<String>> {
+        let workspace =
+            RustProvider::parse_cargo_toml(app)?.and_then(|manifest| manifest.workspace);
+
         if let Some(name) = env.get_config_variable("CARGO_WORKSPACE") {
+            if let Some(workspace) = &workspace {
+                let packages = RustProvider::get_workspace_packages(app, workspace)?;
+                if !packages.iter().any(|(package, _)| package == &name) {
+                    bail!(
+                        "NIXPACKS_CARGO_WORKSPACE is set to `{name}`, which is not a member of the workspace. The members are: {}",
+                        packages
+                            .iter()
+                            .map(|(package, _)| package.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+            }
+
             return Ok(Some(name));
         }
 
-        if let Some(workspace) =
-            RustProvider::parse_cargo_toml(app)?.and_then(|manifest| manifest.workspace)
-        {
-            if let Some(binary) = RustProvider::find_binary_in_workspace(app, &workspace)? {
-                return Ok(Some(binary));
-            }
+        if let Some(workspace) = workspace {
+            // Prefer the first binary in the workspace
+            return Ok(RustProvider::get_workspace_packages(app, &workspace)?
+                .into_iter()
+                .find(|(_, is_binary)| *is_binary)
+                .map(|(package, _)| package));
         }
 
         Ok(None)
     }
 
-    fn find_binary_in_workspace(app: &App, workspace: &Workspace) -> Result<Option<String>> {
-        let find_binary = |member: &str| -> Result<Option<String>> {
-            let mut manifest = app.read_toml::<Manifest>(&format!("{member}/Cargo.toml"))?;
-
-            manifest.complete_from_path(&app.source.join(format!("{member}/Cargo.toml")))?;
-
-            if let Some(package) = manifest.package {
-                if !manifest.bin.is_empty() || manifest.lib.is_none() {
-                    return Ok(Some(package.name));
-                }
-            }
-
-            Ok(None)
-        };
-
-        for default_member in workspace
+    /// Returns the name of each package in the workspace and whether it is a binary.
+    ///
+    /// `workspace.default_members` are listed first and then `workspace.members`, without any in `workspace.exclude`.
+    fn get_workspace_packages(app: &App, workspace: &Workspace) -> Result<Vec<(String, bool)>> {
+        let mut member_dirs = Vec::new();
+        for member in workspace
             .default_members
             .iter()
-            .filter(|default_member| !workspace.exclude.contains(default_member))
+            .chain(workspace.members.iter())
+            .filter(|member| !workspace.exclude.contains(member))
         {
             // a member can have globs
-            if default_member.contains('*') || default_member.contains('?') {
-                for member in app.find_directories(default_member)? {
-                    if let Some(bin) = find_binary(&member.to_string_lossy())? {
-                        return Ok(Some(bin));
-                    }
+            if member.contains('*') || member.contains('?') {
+                for dir in app.find_directories(member)? {
+                    member_dirs.push(dir.to_string_lossy().to_string());
                 }
-            } else if let Some(bin) = find_binary(default_member)? {
-                return Ok(Some(bin));
+            } else {
+                member_dirs.push(member.clone());
             }
         }
 
-        for member in workspace
-            .members
-            .iter()
-            .filter(|member| !workspace.exclude.contains(member))
-        {
-            // a member can have globs
-            if member.contains('*') || member.contains('?') {
-                for member in app.find_directories(member)? {
-                    if let Some(bin) = find_binary(&member.to_string_lossy())? {
-                        return Ok(Some(bin));
-                    }
+        let mut packages: Vec<(String, bool)> = Vec::new();
+        for member in member_dirs {
+            let mut manifest = app.read_toml::<Manifest>(&format!("{member}/Cargo.toml"))?;
+
+            manifest.complete_from_abstract_filesystem(AppFilesystem {
+                app,
+                dir: PathBuf::from(&member),
+            })?;
+
+            if let Some(package) = manifest.package {
+                if !packages.iter().any(|(name, _)| name == &package.name) {
+                    let is_binary = !manifest.bin.is_empty() || manifest.lib.is_none();
+                    packages.push((package.name, is_binary));
                 }
-            } else if let Some(bin) = find_binary(member)? {
-                return Ok(Some(bin));
             }
         }
 
-        Ok(None)
+        Ok(packages)
+    }
+}
+
+/// Lists the files of a workspace member through the app, so that apps held in memory can be read too.
+struct AppFilesystem<'a> {
+    app: &'a App,
+    dir: PathBuf,
+}
+
+impl AbstractFilesystem for AppFilesystem<'_> {
+    fn file_names_in(&self, rel_path: &str) -> io::Result<HashSet<Box<str>>> {
+        let names = self
+            .app
+            .list_directory(&self.dir.join(rel_path))
+            .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e.to_string()))?;
+        Ok(names.into_iter().map(String::into_boxed_str).collect())
     }
 }
 
@@ -496,4 +618,22 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_makefile_targets() -> Result<()> {
+        let dir = tempdir::TempDir::new("nixpacks-rust-make")?;
+        std::fs::write(
+            dir.path().join("Makefile"),
+            "CARGO_FLAGS := --release\n.PHONY: build run\n\nbuild test: deps\n\tcargo build $(CARGO_FLAGS)\n# run: is not a target\n",
+        )?;
+
+        let task_runner = TaskRunner::detect(&App::new(dir.path().to_str().unwrap())?)?.unwrap();
+        assert_eq!(task_runner.get_cmd("build"), "make build");
+        assert!(task_runner.has_target("build"));
+        assert!(task_runner.has_target("test"));
+        assert!(!task_runner.has_target("run"));
+        assert!(!task_runner.has_target("CARGO_FLAGS"));
+
+        Ok(())
+    }
 }
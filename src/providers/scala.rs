@@ -28,8 +28,12 @@ impl Provider for ScalaProvider {
         "scala"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("build.sbt"))
+    fn language(&self) -> &'static str {
+        "Scala"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["build.sbt"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
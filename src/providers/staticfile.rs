@@ -20,6 +20,8 @@ pub struct Staticfile {
     pub directory: Option<String>,
     pub gzip: Option<String>,
     pub status_code: Option<HashMap<u32, String>>,
+    /// Serve `index.html` for paths that do not match a file, for single-page apps with client-side routing.
+    pub spa: Option<bool>,
 }
 
 pub struct StaticfileProvider {}
@@ -29,15 +31,28 @@ impl Provider for StaticfileProvider {
         "staticfile"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("Staticfile")
-            || app.includes_directory("public")
-            || app.includes_directory("index")
-            || app.includes_directory("dist")
-            || app.includes_file("index.html"))
+    fn language(&self) -> &'static str {
+        "HTML"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["Staticfile", "public/", "index/", "dist/", "index.html"]
     }
 
     fn get_build_plan(&self, app: &App, env: &Environment) -> Result<Option<BuildPlan>> {
+        Ok(Some(StaticfileProvider::get_serve_plan(app, env, None)?))
+    }
+}
+
+impl StaticfileProvider {
+    /// A plan that serves the files of the app with nginx, from `default_root` if the `Staticfile` and environment do not set a root.
+    ///
+    /// Other providers use this to serve the files they build.
+    pub fn get_serve_plan(
+        app: &App,
+        env: &Environment,
+        default_root: Option<&str>,
+    ) -> Result<BuildPlan> {
         let mut setup = Phase::setup(Some(vec![Pkg::new("nginx")]));
         setup.add_cmd("mkdir /etc/nginx/ /var/log/nginx/ /var/cache/nginx/");
 
@@ -49,16 +64,14 @@ impl Provider for StaticfileProvider {
             conf_location = app.asset_path("nginx.conf"),
         ));
 
-        let static_assets = StaticfileProvider::get_static_assets(app, env)?;
+        let static_assets = StaticfileProvider::get_static_assets(app, env, default_root)?;
 
         let mut plan = BuildPlan::new(&vec![setup], Some(start));
         plan.add_static_assets(static_assets);
 
-        Ok(Some(plan))
+        Ok(plan)
     }
-}
 
-impl StaticfileProvider {
     pub fn get_root(app: &App, env: &Environment, staticfile_root: String) -> String {
         let mut root = String::new();
         if let Some(staticfile_root) = env.get_config_variable("STATICFILE_ROOT") {
@@ -76,7 +89,11 @@ impl StaticfileProvider {
         root
     }
 
-    fn get_static_assets(app: &App, env: &Environment) -> Result<StaticAssets> {
+    fn get_static_assets(
+        app: &App,
+        env: &Environment,
+        default_root: Option<&str>,
+    ) -> Result<StaticAssets> {
         let mut assets = StaticAssets::new();
 
         let mut mime_types = "include /nix/store/*-user-environment/conf/mime.types;".to_string();
@@ -95,15 +112,42 @@ impl StaticfileProvider {
         }
 
         let staticfile: Staticfile = app.read_yaml("Staticfile").unwrap_or_default();
-        let root = StaticfileProvider::get_root(app, env, staticfile.root.unwrap_or_default());
+        let staticfile_root = staticfile
+            .root
+            .or_else(|| default_root.map(ToString::to_string))
+            .unwrap_or_default();
+        let root = StaticfileProvider::get_root(app, env, staticfile_root);
         let gzip = staticfile.gzip.unwrap_or_else(|| "on".to_string());
         let directory = staticfile.directory.unwrap_or_else(|| "off".to_string());
-        let status_code = staticfile.status_code.unwrap_or_default();
+        let spa =
+            staticfile.spa.unwrap_or(false) || env.is_config_variable_truthy("STATICFILE_SPA");
+
+        // Sort the error pages so the config is the same on every build
+        let mut status_code = staticfile
+            .status_code
+            .unwrap_or_default()
+            .into_iter()
+            .collect::<Vec<_>>();
+        status_code.sort();
         let mut error_page = String::new();
         for (key, value) in status_code {
             writeln!(error_page, "\terror_page {key} {value};")?;
         }
 
+        // nginx only compresses text/html unless told otherwise
+        let gzip_types = if gzip == "on" {
+            "\n        gzip_types  text/plain text/css text/xml application/javascript application/json application/xml image/svg+xml;"
+        } else {
+            ""
+        };
+
+        // Unknown paths are routed by the app in the browser, so they are served the app itself
+        let try_files = if spa {
+            "\n            try_files $uri $uri/ /index.html;"
+        } else {
+            ""
+        };
+
         let nginx_conf = formatdoc! {"
         daemon off;
         error_log /dev/stdout info;
@@ -121,11 +165,11 @@ impl StaticfileProvider {
             types_hash_max_size 4096;
             server {{
                 listen    0.0.0.0:80;
-                gzip  	  {gzip};
-                root	  /app/{root};
+                gzip  	  {gzip};{gzip_types}
+                root	  {root};
                 location / {{
                     {auth_basic}
-                    autoindex {directory};
+                    autoindex {directory};{try_files}
                 }}
         {error_page}
             }}
@@ -133,9 +177,11 @@ impl StaticfileProvider {
         ", 
         mime_types = mime_types,
         gzip = gzip,
-        root = root,
+        gzip_types = gzip_types,
+        root = app.app_path(&root),
         auth_basic = auth_basic,
         directory = directory,
+        try_files = try_files,
         error_page = error_page
         };
         assets.insert("nginx.conf".to_string(), nginx_conf);
@@ -36,8 +36,12 @@ impl Provider for SwiftProvider {
         "swift"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("Package.swift"))
+    fn language(&self) -> &'static str {
+        "Swift"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["Package.swift"]
     }
 
     fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
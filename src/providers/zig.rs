@@ -9,6 +9,7 @@ use crate::nixpacks::{
     },
 };
 use anyhow::Result;
+use regex::Regex;
 use std::{env::consts::ARCH, ffi::OsStr};
 
 pub struct ZigProvider;
@@ -21,8 +22,12 @@ impl Provider for ZigProvider {
         "zig"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.has_match("*.zig") || app.has_match("**/*.zig") || app.has_match("gyro.zzz"))
+    fn language(&self) -> &'static str {
+        "Zig"
+    }
+
+    fn detect_files(&self) -> &'static [&'static str] {
+        &["build.zig", "*.zig", "**/*.zig", "gyro.zzz"]
     }
 
     fn get_build_plan(&self, app: &App, _env: &Environment) -> Result<Option<BuildPlan>> {
@@ -46,22 +51,49 @@ impl Provider for ZigProvider {
             install.add_cmd(format!("{gyro_exe_path} fetch"));
         }
 
-        let build = Phase::build(Some("zig build -Drelease-safe=true".to_string()));
+        let build_zig = if app.includes_file("build.zig") {
+            app.read_file("build.zig")?
+        } else {
+            String::new()
+        };
+        let build = Phase::build(Some(format!(
+            "zig build {}",
+            ZigProvider::get_release_flag(&build_zig)
+        )));
 
-        let start = StartPhase::new(format!(
-            "./zig-out/bin/{}",
+        let exe_name = ZigProvider::get_exe_name(&build_zig).unwrap_or_else(|| {
             app.source
                 .file_name()
                 .map(OsStr::to_str)
                 .map_or("*", Option::unwrap)
-        ));
+                .to_string()
+        });
+        let start = StartPhase::new(format!("./zig-out/bin/{exe_name}"));
 
-        let plan = BuildPlan::new(&vec![setup, install, build], Some(start));
+        let plan = BuildPlan::new(&[setup, install, build], Some(start));
         Ok(Some(plan))
     }
 }
 
 impl ZigProvider {
+    /// Build with ReleaseFast, using the release options of Zig before 0.11 if `build.zig` still uses them.
+    fn get_release_flag(build_zig: &str) -> &'static str {
+        if build_zig.contains("standardReleaseOptions") {
+            "-Drelease-fast=true"
+        } else {
+            "-Doptimize=ReleaseFast"
+        }
+    }
+
+    /// The name of the first executable in `build.zig`, from either `addExecutable(.{ .name = "app", ... })` or the older `addExecutable("app", ...)`.
+    fn get_exe_name(build_zig: &str) -> Option<String> {
+        let exe_regex =
+            Regex::new(r#"addExecutable\(\s*(?:\.\{[^}]*?\.name\s*=\s*)?"([^"]+)""#).ok()?;
+        exe_regex
+            .captures(build_zig)
+            .map(|captures| captures[1].to_string())
+    }
+
     pub fn get_gyro_download_url() -> String {
         let gyro_supported_archs: Vec<&str> = vec!["x86_64", "aarch64", "i386"];
         if gyro_supported_archs.contains(&ARCH) {
@@ -73,3 +105,48 @@ impl ZigProvider {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_and_start_commands() -> Result<()> {
+        let plan = ZigProvider {}
+            .get_build_plan(&App::new("./examples/zig-build")?, &Environment::default())?
+            .unwrap();
+
+        assert_eq!(
+            plan.get_phase("setup").unwrap().nix_pkgs,
+            Some(vec!["zig".to_string()])
+        );
+        assert_eq!(
+            plan.get_phase("build").unwrap().cmds,
+            Some(vec!["zig build -Doptimize=ReleaseFast".to_string()])
+        );
+        assert_eq!(
+            plan.start_phase.unwrap().cmd,
+            Some("./zig-out/bin/hello".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_zig_before_0_11() -> Result<()> {
+        let plan = ZigProvider {}
+            .get_build_plan(&App::new("./examples/zig-gyro")?, &Environment::default())?
+            .unwrap();
+
+        assert_eq!(
+            plan.get_phase("build").unwrap().cmds,
+            Some(vec!["zig build -Drelease-fast=true".to_string()])
+        );
+        assert_eq!(
+            plan.start_phase.unwrap().cmd,
+            Some("./zig-out/bin/zig-gyro".to_string())
+        );
+
+        Ok(())
+    }
+}
@@ -2,8 +2,9 @@ use anyhow::Context;
 use nixpacks::{
     create_docker_image,
     nixpacks::{
-        builder::docker::DockerBuilderOptions, environment::EnvironmentVariables,
-        plan::generator::GeneratePlanOptions,
+        builder::docker::DockerBuilderOptions,
+        environment::EnvironmentVariables,
+        plan::{generator::GeneratePlanOptions, phase::Phase, BuildPlan},
     },
 };
 use std::io::{BufRead, BufReader};
@@ -345,6 +346,14 @@ async fn test_elixir_no_ecto() {
     assert!(run_image(&name, None).await.contains("Hello from Phoenix"));
 }
 
+#[tokio::test]
+async fn test_elixir_release() {
+    let name = simple_build("./examples/elixir-release").await;
+    assert!(run_image(&name, None)
+        .await
+        .contains("Hello from Elixir release"));
+}
+
 #[tokio::test]
 async fn test_node() {
     let name = simple_build("./examples/node").await;
@@ -613,6 +622,30 @@ async fn test_python_procfile() {
     assert!(output.contains("Hello from Python"));
 }
 
+#[tokio::test]
+async fn test_nixpkgs_archive_sha256_mismatch() {
+    let mut setup = Phase::setup(None);
+    setup.nixpkgs_archive_sha256 =
+        Some("0000000000000000000000000000000000000000000000000000".to_string());
+
+    let result = create_docker_image(
+        "./examples/node",
+        Vec::new(),
+        &GeneratePlanOptions {
+            plan: Some(BuildPlan::new(&[setup], None)),
+            ..Default::default()
+        },
+        &DockerBuilderOptions {
+            name: Some(Uuid::new_v4().to_string()),
+            quiet: true,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert!(result.is_err());
+}
+
 #[tokio::test]
 async fn test_python_2() {
     let name = simple_build("./examples/python-2").await;
@@ -866,6 +899,13 @@ async fn test_dart() {
     assert!(output.contains("Hello from Dart"));
 }
 
+#[tokio::test]
+async fn test_dart_server() {
+    let name = simple_build("./examples/dart-server").await;
+    let output = run_image(&name, None).await;
+    assert!(output.contains("Hello from Dart server"));
+}
+
 #[tokio::test]
 async fn test_java_maven() {
     let name = simple_build("./examples/java-maven").await;
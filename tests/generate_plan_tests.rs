@@ -1,5 +1,19 @@
-use nixpacks::{generate_build_plan, nixpacks::plan::generator::GeneratePlanOptions};
-use std::env::consts::ARCH;
+use nixpacks::{
+    explain_providers, generate_build_plan, generate_build_plan_for_app, get_app_metadata,
+    get_plan_providers, get_plan_providers_json, get_provider_info,
+    nixpacks::{
+        app::App,
+        nix::create_nix_expressions_for_phases,
+        plan::{generator::GeneratePlanOptions, BuildPlan},
+    },
+};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env::consts::ARCH,
+    path::PathBuf,
+};
+use tempdir::TempDir;
 
 test_helper::generate_plan_tests!();
 
@@ -9,7 +23,7 @@ fn test_custom_plan_path() {
         "./examples/custom-plan-path",
         Vec::new(),
         &GeneratePlanOptions {
-            config_file: Some("custom-nixpacks.toml".to_string()),
+            config_files: vec!["custom-nixpacks.toml".to_string()],
             ..Default::default()
         },
     )
@@ -18,6 +32,102 @@ fn test_custom_plan_path() {
     assert_plan_snapshot!(plan);
 }
 
+#[test]
+fn test_provider_paths_use_workdir() {
+    let options = GeneratePlanOptions {
+        plan: Some(BuildPlan::from_toml("workdir = '/srv/app'").unwrap()),
+        ..Default::default()
+    };
+
+    let plan = generate_build_plan("./examples/node", Vec::new(), &options).unwrap();
+    assert_eq!(
+        plan.get_phase("install").unwrap().paths,
+        Some(vec!["/srv/app/node_modules/.bin".to_string()])
+    );
+
+    let plan = generate_build_plan("./examples/clojure", Vec::new(), &options).unwrap();
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("bash -c \"java $JAVA_OPTS -jar /srv/app/target/*standalone.jar\"".to_string())
+    );
+
+    let plan = generate_build_plan("./examples/php-laravel", Vec::new(), &options).unwrap();
+    assert_eq!(
+        plan.variables.unwrap().get("NIXPACKS_PHP_ROOT_DIR"),
+        Some(&"/srv/app/public".to_string())
+    );
+}
+
+#[test]
+fn test_provider_cache_dirs_are_merged_with_config() {
+    let plan = generate_build_plan(
+        "./examples/node",
+        Vec::new(),
+        &GeneratePlanOptions {
+            plan: Some(
+                BuildPlan::from_toml(
+                    "[phases.install]\ncacheDirectories = ['...', '/root/.cache/custom']",
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.get_phase("install").unwrap().cache_directories,
+        Some(vec![
+            "/root/.npm".to_string(),
+            "/root/.cache/custom".to_string()
+        ])
+    );
+
+    let plan = generate_build_plan(
+        "./examples/rust-custom-version",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    let build_cache_dirs = plan.get_phase("build").unwrap().cache_directories.clone();
+    assert!(build_cache_dirs
+        .unwrap()
+        .contains(&"/root/.cargo/registry".to_string()));
+}
+
+#[test]
+fn test_merge_multiple_config_files() {
+    let plan = generate_build_plan(
+        "./examples/custom-plan-path",
+        Vec::new(),
+        &GeneratePlanOptions {
+            config_files: vec![
+                "base-nixpacks.toml".to_string(),
+                "service-nixpacks.toml".to_string(),
+            ],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let setup = plan.get_phase("setup").unwrap();
+
+    assert_eq!(
+        setup.nix_pkgs,
+        Some(vec!["hello".to_string(), "cowsay".to_string()])
+    );
+    // Lists are appended to unless they are explicitly replaced, while commands are always replaced
+    assert_eq!(setup.apt_pkgs, Some(vec!["curl".to_string()]));
+    assert_eq!(
+        plan.get_phase("build").unwrap().cmds,
+        Some(vec!["make service".to_string()])
+    );
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("service start command".to_string())
+    );
+}
+
 #[test]
 fn test_custom_rust_version() {
     let plan = simple_gen_plan("./examples/rust-custom-version");
@@ -62,6 +172,23 @@ fn test_rust_rocket() {
     assert!(start.run_image.is_some());
 }
 
+#[test]
+fn test_rust_just() {
+    let plan = simple_gen_plan("./examples/rust-just");
+    let setup = plan.get_phase("setup").unwrap();
+    let build = plan.get_phase("build").unwrap();
+    let start = plan.start_phase.clone().unwrap();
+
+    assert!(setup
+        .nix_pkgs
+        .clone()
+        .unwrap()
+        .contains(&"just".to_string()));
+    assert_eq!(build.cmds, Some(vec!["just build".to_string()]));
+    assert_eq!(start.cmd, Some("just run".to_string()));
+    assert!(start.run_image.is_none());
+}
+
 #[test]
 fn test_rust_rocket_no_musl() {
     let plan = generate_build_plan(
@@ -84,6 +211,48 @@ fn test_rust_cargo_workspaces() {
     );
 }
 
+#[test]
+fn test_rust_cargo_workspace_member() {
+    let plan = generate_build_plan(
+        "./examples/rust-cargo-workspaces-multiple-bins",
+        vec!["NIXPACKS_CARGO_WORKSPACE=worker"],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    let build = plan.get_phase("build").unwrap();
+
+    assert_eq!(
+        build.clone().cmds.unwrap()[1..],
+        [
+            format!("cargo build --release --package worker --target {ARCH}-unknown-linux-musl"),
+            format!("cp target/{ARCH}-unknown-linux-musl/release/worker bin"),
+        ]
+    );
+    assert!(build
+        .cache_directories
+        .clone()
+        .unwrap()
+        .contains(&"target".to_string()));
+
+    let start = plan.start_phase.unwrap();
+    assert_eq!(start.cmd, Some("./bin/worker".to_string()));
+    assert_eq!(
+        start.only_include_files,
+        Some(vec!["./bin/worker".to_string()])
+    );
+
+    // The first binary is built without selecting a member
+    let plan = simple_gen_plan("./examples/rust-cargo-workspaces-multiple-bins");
+    assert_eq!(plan.start_phase.unwrap().cmd, Some("./bin/api".to_string()));
+
+    assert!(generate_build_plan(
+        "./examples/rust-cargo-workspaces-multiple-bins",
+        vec!["NIXPACKS_CARGO_WORKSPACE=missing"],
+        &GeneratePlanOptions::default(),
+    )
+    .is_err());
+}
+
 #[test]
 fn test_rust_multiple_bins() {
     let plan = simple_gen_plan("./examples/rust-multiple-bins");
@@ -117,7 +286,7 @@ fn test_zig_gyro() {
 
     assert_eq!(
         build.cmds,
-        Some(vec!["zig build -Drelease-safe=true".to_string()])
+        Some(vec!["zig build -Drelease-fast=true".to_string()])
     );
     assert_eq!(start.cmd, Some("./zig-out/bin/zig-gyro".to_string()));
     assert!(install
@@ -138,3 +307,709 @@ fn test_node_turborepo_custom_app() {
     .unwrap();
     assert!(plan.start_phase.unwrap().cmd.unwrap().contains("docs"));
 }
+
+#[test]
+fn test_bun_detected_from_lockfile() {
+    let plan = simple_gen_plan("./examples/node-bun");
+    let install = plan.get_phase("install").unwrap().clone();
+
+    assert_eq!(
+        plan.variables.unwrap().get("NIXPACKS_METADATA"),
+        Some(&"bun".to_string())
+    );
+    assert_eq!(
+        install.cmds,
+        Some(vec!["bun install --frozen-lockfile".to_string()])
+    );
+    // The cache is in the home directory of whichever user runs the build
+    assert_eq!(
+        install.cache_directories,
+        Some(vec!["~/.bun/install/cache".to_string()])
+    );
+}
+
+#[test]
+fn test_java_maven_single_module() {
+    let plan = simple_gen_plan("./examples/java-maven-wrapper");
+    let build = plan.get_phase("build").unwrap();
+    assert_eq!(
+        build.cmds,
+        Some(vec!["chmod +x ./mvnw && ./mvnw -DoutputFile=target/mvn-dependency-list.log -B -DskipTests clean dependency:list install".to_string()])
+    );
+    assert_eq!(
+        build.cache_directories,
+        Some(vec!["~/.m2/repository".to_string()])
+    );
+    assert!(plan
+        .start_phase
+        .unwrap()
+        .cmd
+        .unwrap()
+        .ends_with("-jar target/*jar"));
+
+    let plan = generate_build_plan(
+        "./examples/java-maven",
+        vec!["NIXPACKS_JAVA_MODULE=api"],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        plan.get_phase("build").unwrap().cmds,
+        Some(vec!["mvn -pl api -am -DoutputFile=target/mvn-dependency-list.log -B -DskipTests clean dependency:list install".to_string()])
+    );
+}
+
+#[test]
+fn test_java_gradle_multi_module() {
+    let plan = generate_build_plan(
+        "./examples/java-gradle-multi-module",
+        vec!["NIXPACKS_JAVA_MODULE=app"],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    let build = plan.get_phase("build").unwrap();
+    assert_eq!(
+        build.cmds,
+        Some(vec!["gradle :app:build -x check".to_string()])
+    );
+    assert_eq!(
+        build.cache_directories,
+        Some(vec!["~/.gradle/caches".to_string()])
+    );
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("java $JAVA_OPTS -jar  app/build/libs/*.jar".to_string())
+    );
+
+    let plan = generate_build_plan(
+        "./examples/java-gradle-multi-module",
+        vec![
+            "NIXPACKS_JAVA_MODULE=app",
+            "NIXPACKS_JAVA_JAR=app/build/libs/app.jar",
+        ],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("java $JAVA_OPTS -jar  app/build/libs/app.jar".to_string())
+    );
+}
+
+#[test]
+fn test_python_uv_install() {
+    let plan = generate_build_plan(
+        "./examples/python-uv",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.get_phase("install").unwrap().cmds,
+        Some(vec![
+            "python -m venv --copies /opt/venv && . /opt/venv/bin/activate && pip install uv==$NIXPACKS_UV_VERSION && uv sync --inexact --frozen".to_string()
+        ])
+    );
+    assert_eq!(
+        plan.variables.unwrap().get("UV_PROJECT_ENVIRONMENT"),
+        Some(&"/opt/venv".to_string())
+    );
+}
+
+#[test]
+fn test_node_pnpm_workspace_package() {
+    let plan = generate_build_plan(
+        "./examples/node-pnpm-workspaces",
+        vec!["NIXPACKS_PNPM_WORKSPACE_PACKAGE=web"],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.get_phase("install").unwrap().cmds,
+        Some(vec!["pnpm i --frozen-lockfile --filter web...".to_string()])
+    );
+    assert_eq!(
+        plan.get_phase("build").unwrap().cmds,
+        Some(vec!["pnpm --filter web run build".to_string()])
+    );
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("pnpm --filter web run start".to_string())
+    );
+}
+
+#[test]
+fn test_node_pnpm_workspace_package_by_directory() {
+    // pnpm only takes a directory with a leading `./`, which is added to the bare form
+    for package in ["./packages/api", "packages/api", "packages/api/"] {
+        let plan = generate_build_plan(
+            "./examples/node-pnpm-workspaces",
+            vec![&format!("NIXPACKS_PNPM_WORKSPACE_PACKAGE={package}")],
+            &GeneratePlanOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            plan.get_phase("install").unwrap().cmds,
+            Some(vec![
+                "pnpm i --frozen-lockfile --filter ./packages/api...".to_string()
+            ])
+        );
+        assert_eq!(plan.get_phase("build").unwrap().cmds, None);
+        assert_eq!(
+            plan.start_phase.unwrap().cmd,
+            Some("pnpm --filter ./packages/api run start".to_string())
+        );
+    }
+}
+
+#[test]
+fn test_node_pnpm_workspace_unknown_package() {
+    let err = generate_build_plan(
+        "./examples/node-pnpm-workspaces",
+        vec!["NIXPACKS_PNPM_WORKSPACE_PACKAGE=missing"],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap_err();
+
+    assert!(format!("{err:#}").contains("Could not find the pnpm workspace package missing"));
+}
+
+#[test]
+fn test_php_extensions_from_composer() {
+    let plan = generate_build_plan(
+        "./examples/php-pdo-mysql",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    let setup = plan.get_phase("setup").unwrap();
+    let nix_pkgs = setup.nix_pkgs.clone().unwrap();
+    assert!(nix_pkgs
+        .contains(&"(php82.withExtensions (pe: pe.enabled ++ [pe.all.pdo_mysql]))".to_string()));
+    assert!(nix_pkgs.contains(&"php82Extensions.pdo_mysql".to_string()));
+    assert!(!nix_pkgs.iter().any(|pkg| pkg.contains("pcre")));
+
+    let install = plan.get_phase("install").unwrap();
+    assert!(install.cmds.clone().unwrap().contains(
+        &"composer install --no-dev --optimize-autoloader --ignore-platform-reqs".to_string()
+    ));
+    assert_eq!(
+        install.cache_directories,
+        Some(vec!["/root/.composer/cache".to_string()])
+    );
+
+    let plan = generate_build_plan(
+        "./examples/php-pdo-mysql",
+        vec!["NIXPACKS_PHP_SERVER=builtin"],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("php -S 0.0.0.0:${PORT:-80} -t ${NIXPACKS_PHP_ROOT_DIR:-/app}".to_string())
+    );
+}
+
+#[test]
+fn test_ruby_rails_precompiles_assets() {
+    let plan = generate_build_plan(
+        "./examples/ruby-rails-unicorn",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.get_phase("build").unwrap().cmds,
+        Some(vec!["bundle exec rake assets:precompile".to_string()])
+    );
+    assert_eq!(
+        plan.variables.unwrap().get("BUNDLE_WITHOUT"),
+        Some(&"development:test".to_string())
+    );
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("bundle exec unicorn -p ${PORT:-3000} -c config/unicorn.rb".to_string())
+    );
+}
+
+#[test]
+fn test_node_nx_project_in_packages() {
+    let plan = generate_build_plan(
+        "./examples/node-nx-packages",
+        vec!["NIXPACKS_NX_APP_NAME=web"],
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    let build = plan.get_phase("build").unwrap();
+    assert_eq!(build.cmds, Some(vec!["npx nx run web:build".to_string()]));
+    assert!(build
+        .cache_directories
+        .clone()
+        .unwrap()
+        .contains(&".nx/cache".to_string()));
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("node dist/packages/web/index.js".to_string())
+    );
+
+    let plan = generate_build_plan(
+        "./examples/node-nx-packages",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.get_phase("build").unwrap().cmds,
+        Some(vec!["npx nx run api:build:production".to_string()])
+    );
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("npx nx run api:serve:production".to_string())
+    );
+}
+
+#[test]
+fn test_in_memory_app() {
+    let app = App::from_files(HashMap::from([
+        (
+            PathBuf::from("package.json"),
+            br#"{ "name": "app", "scripts": { "build": "tsc", "start": "node dist/index.js" } }"#
+                .to_vec(),
+        ),
+        (PathBuf::from("package-lock.json"), b"{}".to_vec()),
+        (PathBuf::from("src/index.ts"), b"console.log(1);".to_vec()),
+    ]));
+    let plan =
+        generate_build_plan_for_app(&app, Vec::new(), &GeneratePlanOptions::default()).unwrap();
+
+    assert_eq!(
+        plan.get_phase("install").unwrap().cmds,
+        Some(vec!["npm ci".to_string()])
+    );
+    assert_eq!(
+        plan.get_phase("build").unwrap().cmds,
+        Some(vec!["npm run build".to_string()])
+    );
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("npm run start".to_string())
+    );
+}
+
+#[test]
+fn test_install_copies_whole_app_with_dockerignore() {
+    let mut files = HashMap::from([
+        (
+            PathBuf::from("package.json"),
+            br#"{ "name": "app", "scripts": { "start": "node index.js" } }"#.to_vec(),
+        ),
+        (PathBuf::from("package-lock.json"), b"{}".to_vec()),
+    ]);
+    let plan = generate_build_plan_for_app(
+        &App::from_files(files.clone()),
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        plan.get_phase("install").unwrap().only_include_files,
+        Some(vec![
+            "package.json".to_string(),
+            "package-lock.json".to_string()
+        ])
+    );
+
+    // Only Docker knows which of the files a .dockerignore leaves out of the build context
+    files.insert(PathBuf::from(".dockerignore"), b"*.json\n".to_vec());
+    let plan = generate_build_plan_for_app(
+        &App::from_files(files),
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(plan.get_phase("install").unwrap().only_include_files, None);
+}
+
+#[test]
+fn test_in_memory_django_app() {
+    let app = App::from_files(HashMap::from([
+        (
+            PathBuf::from("requirements.txt"),
+            b"Django\ngunicorn".to_vec(),
+        ),
+        (PathBuf::from("manage.py"), Vec::new()),
+        (
+            PathBuf::from("mysite/settings.py"),
+            b"WSGI_APPLICATION = 'mysite.wsgi.application'".to_vec(),
+        ),
+    ]));
+    let plan =
+        generate_build_plan_for_app(&app, Vec::new(), &GeneratePlanOptions::default()).unwrap();
+
+    assert!(plan
+        .start_phase
+        .unwrap()
+        .cmd
+        .unwrap()
+        .contains("gunicorn mysite.wsgi"));
+}
+
+#[test]
+fn test_in_memory_cargo_workspace() {
+    let app = App::from_files(HashMap::from([
+        (
+            PathBuf::from("Cargo.toml"),
+            b"[workspace]\nmembers = [\"shared\", \"server\"]".to_vec(),
+        ),
+        (
+            PathBuf::from("shared/Cargo.toml"),
+            b"[package]\nname = \"shared\"\nversion = \"0.1.0\"".to_vec(),
+        ),
+        (PathBuf::from("shared/src/lib.rs"), Vec::new()),
+        (
+            PathBuf::from("server/Cargo.toml"),
+            b"[package]\nname = \"server\"\nversion = \"0.1.0\"".to_vec(),
+        ),
+        (
+            PathBuf::from("server/src/main.rs"),
+            b"fn main() {}".to_vec(),
+        ),
+    ]));
+    let plan =
+        generate_build_plan_for_app(&app, Vec::new(), &GeneratePlanOptions::default()).unwrap();
+
+    // The library is skipped because its files are read from the app instead of the disk
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("./bin/server".to_string())
+    );
+}
+
+#[test]
+fn test_cmd_patch_keeps_detected_cmd() {
+    let plan = generate_build_plan(
+        "./examples/node",
+        Vec::new(),
+        &GeneratePlanOptions {
+            plan: Some(
+                BuildPlan::from_toml(
+                    r#"
+                    [[phases.install.cmdPatches]]
+                    prefix = "npm config set fund false"
+                    "#,
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.get_phase("install").unwrap().cmds,
+        Some(vec!["npm config set fund false && npm ci".to_string()])
+    );
+}
+
+#[test]
+fn test_dart_server() {
+    let plan = generate_build_plan(
+        "./examples/dart-server",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.get_phase("build").unwrap().cmds,
+        Some(vec!["dart compile exe bin/server.dart".to_string()])
+    );
+    assert_eq!(
+        plan.get_phase("install").unwrap().cache_directories,
+        Some(vec!["/root/.pub-cache".to_string()])
+    );
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("./bin/server.exe".to_string())
+    );
+}
+
+#[test]
+fn test_procfile_processes() {
+    let plan = generate_build_plan(
+        "./examples/python-procfile",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    let start = plan.start_phase.unwrap();
+
+    assert_eq!(start.cmd, Some("python src/main.py".to_string()));
+    assert_eq!(
+        start.processes,
+        Some(BTreeMap::from([
+            ("web".to_string(), "python src/main.py".to_string()),
+            ("worker".to_string(), "echo \"another process\"".to_string()),
+        ]))
+    );
+}
+
+#[test]
+fn test_detect_json() {
+    let detect_json = |path: &str| {
+        let providers =
+            get_plan_providers(path, Vec::new(), &GeneratePlanOptions::default()).unwrap();
+        get_plan_providers_json(&providers)
+    };
+
+    assert_eq!(
+        detect_json("./examples/node"),
+        json!({ "providers": ["node"] })
+    );
+
+    let empty_dir = TempDir::new("nixpacks-detect").unwrap();
+    assert_eq!(
+        detect_json(empty_dir.path().to_str().unwrap()),
+        json!({ "providers": [] })
+    );
+}
+
+#[test]
+fn test_require_provider() {
+    let empty_dir = TempDir::new("nixpacks-require-provider").unwrap();
+    let path = empty_dir.path().to_str().unwrap();
+
+    assert!(generate_build_plan(path, Vec::new(), &GeneratePlanOptions::default()).is_ok());
+
+    let err = generate_build_plan(
+        path,
+        Vec::new(),
+        &GeneratePlanOptions {
+            require_provider: true,
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+    assert!(err.to_string().starts_with("No provider was detected"));
+
+    assert!(generate_build_plan(
+        "./examples/node",
+        Vec::new(),
+        &GeneratePlanOptions {
+            require_provider: true,
+            ..Default::default()
+        },
+    )
+    .is_ok());
+}
+
+#[test]
+fn test_force_provider() {
+    let path = "./examples/python-node";
+    assert_eq!(
+        get_plan_providers(path, Vec::new(), &GeneratePlanOptions::default()).unwrap(),
+        vec!["node"]
+    );
+
+    let options = GeneratePlanOptions {
+        plan: Some(BuildPlan {
+            providers: Some(vec!["python".to_string()]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        get_plan_providers(path, Vec::new(), &options).unwrap(),
+        vec!["python"]
+    );
+
+    let plan = generate_build_plan(path, Vec::new(), &options).unwrap();
+    assert!(plan
+        .get_phase("install")
+        .unwrap()
+        .cmds
+        .clone()
+        .unwrap_or_default()
+        .iter()
+        .any(|cmd| cmd.contains("pip install -r requirements.txt")));
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("python main.py".to_string())
+    );
+}
+
+#[test]
+fn test_exclude_provider() {
+    let path = "./examples/python-node";
+    let options = GeneratePlanOptions {
+        plan: Some(BuildPlan {
+            exclude_providers: Some(vec!["node".to_string()]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        get_plan_providers(path, Vec::new(), &options).unwrap(),
+        vec!["python"]
+    );
+
+    let plan = generate_build_plan(path, Vec::new(), &options).unwrap();
+    assert_eq!(
+        plan.variables.unwrap().get("NIXPACKS_METADATA"),
+        Some(&"python".to_string())
+    );
+
+    // Excluding a provider also removes it when it is forced
+    let options = GeneratePlanOptions {
+        plan: Some(BuildPlan {
+            providers: Some(vec!["node".to_string(), "python".to_string()]),
+            exclude_providers: Some(vec!["node".to_string()]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        get_plan_providers(path, Vec::new(), &options).unwrap(),
+        vec!["python"]
+    );
+}
+
+#[test]
+fn test_app_metadata() {
+    let metadata = get_app_metadata(
+        "./examples/node-next",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(metadata.providers, vec!["node"]);
+    assert_eq!(metadata.languages, vec!["JavaScript"]);
+    assert_eq!(metadata.frameworks, vec!["Next.js"]);
+    assert_eq!(metadata.package_managers, vec!["npm"]);
+
+    let metadata = get_app_metadata(
+        "./examples/node-pnpm",
+        Vec::new(),
+        &GeneratePlanOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(metadata.languages, vec!["JavaScript", "TypeScript"]);
+    assert_eq!(metadata.frameworks, vec!["Fastify"]);
+    assert_eq!(metadata.package_managers, vec!["pnpm"]);
+}
+
+#[test]
+fn test_explain_providers() {
+    let explanation = explain_providers("./examples/node", Vec::new()).unwrap();
+    let find = |name: &str| {
+        explanation
+            .iter()
+            .find(|(provider, _)| provider == name)
+            .map(|(_, detection)| detection)
+            .unwrap()
+    };
+
+    assert!(find("node").matched);
+    assert_eq!(find("node").describe(), "found package.json ✓");
+    assert!(!find("bun").matched);
+    assert_eq!(
+        find("bun").describe(),
+        "found bun.lockb ✗, found bunfig.toml ✗"
+    );
+    assert_eq!(
+        explanation
+            .iter()
+            .filter(|(_, detection)| detection.matched)
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_provider_info() {
+    let providers = get_provider_info();
+    let find = |name: &str| providers.iter().find(|info| info.name == name).unwrap();
+
+    assert_eq!(find("node").language, "JavaScript");
+    assert!(find("node").detect_files.contains(&"package.json"));
+    assert_eq!(find("python").language, "Python");
+    assert!(find("python").detect_files.contains(&"requirements.txt"));
+    assert!(providers.iter().all(|info| !info.detect_files.is_empty()));
+}
+
+#[test]
+fn test_nix_overrides() {
+    let plan = simple_gen_plan("./examples/node-nix-overrides");
+    assert_eq!(
+        plan.get_phase("setup").unwrap().nix_pkgs,
+        Some(vec!["nodejs-slim-18_x".to_string(), "npm-8_x".to_string()])
+    );
+
+    let nix_expressions =
+        create_nix_expressions_for_phases(&plan.phases.unwrap(), &[], "/app/", None);
+    let setup_expression = nix_expressions.values().next().unwrap();
+    assert!(setup_expression.contains("nodejs-slim-18_x"));
+    assert!(!setup_expression.contains("nodejs-18_x"));
+}
+
+#[test]
+fn test_node_tool_versions() {
+    let plan = simple_gen_plan("./examples/node-tool-versions");
+    assert_eq!(
+        plan.get_phase("setup").unwrap().nix_pkgs,
+        Some(vec!["nodejs-18_x".to_string(), "npm-8_x".to_string()])
+    );
+}
+
+#[test]
+fn test_staticfile_spa_fallback() {
+    let plan = simple_gen_plan("./examples/staticfile-spa");
+    let nginx_conf = &plan.static_assets.unwrap()["nginx.conf"];
+    assert!(nginx_conf.contains("root\t  /app/build;"));
+    assert!(nginx_conf.contains("try_files $uri $uri/ /index.html;"));
+
+    let plan = simple_gen_plan("./examples/staticfile");
+    assert!(!plan.static_assets.unwrap()["nginx.conf"].contains("try_files"));
+}
+
+#[test]
+fn test_elixir_release() {
+    let plan = simple_gen_plan("./examples/elixir-release");
+    assert_eq!(
+        plan.variables.clone().unwrap().get("NIXPACKS_METADATA"),
+        Some(&"elixir".to_string())
+    );
+    assert_eq!(
+        plan.get_phase("setup").unwrap().nix_pkgs,
+        Some(vec![
+            "(elixir.override { erlang = erlangR25; })".to_string(),
+            "erlangR25".to_string()
+        ])
+    );
+
+    let build = plan.get_phase("build").unwrap();
+    assert_eq!(
+        build.cmds.clone().unwrap(),
+        vec![
+            "mix deps.get --only prod",
+            "mix compile",
+            "mix release hello --overwrite --path _release"
+        ]
+    );
+    assert_eq!(
+        build.cache_directories.clone().unwrap(),
+        vec!["_build", "deps"]
+    );
+
+    assert_eq!(
+        plan.start_phase.unwrap().cmd,
+        Some("_release/bin/hello start".to_string())
+    );
+}